@@ -0,0 +1,60 @@
+//! Checks a downloaded file against a .torrent, piece by piece, without starting a client.
+//!
+//! cargo run --example verify -- <torrent> <file>
+
+use std::env;
+use std::process::ExitCode;
+
+use anyhow::Result;
+use rittorrent::file::{DownloadFile, PieceState};
+use rittorrent::hash::Sha1PieceHasher;
+use rittorrent::torrent::MetaInfo;
+
+fn verify(torrent: &str, path: &str) -> Result<bool> {
+    let metainfo = MetaInfo::from_file(torrent)?;
+    let info = &metainfo.info;
+    let hashes = metainfo.piece_hashes();
+
+    // verifying refuses a file of the wrong length, rather than resizing it to fit
+    let file = DownloadFile::new_seeding(
+        path,
+        &hashes,
+        info.piece_length,
+        info.length,
+        Box::new(Sha1PieceHasher::default()),
+        true,
+    )?;
+    let bad: Vec<usize> = (0..hashes.len())
+        .filter(|&piece| file.piece_state(piece) != Some(PieceState::Complete))
+        .collect();
+
+    for piece in &bad {
+        println!("piece {} does not match", piece);
+    }
+    let good = hashes.len() - bad.len();
+    println!(
+        "{}/{} pieces of {} verified",
+        good,
+        hashes.len(),
+        metainfo.name().display
+    );
+
+    Ok(bad.is_empty())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let [_, torrent, path] = &args[..] else {
+        eprintln!("usage: verify <torrent> <file>");
+        return ExitCode::FAILURE;
+    };
+
+    match verify(torrent, path) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Noticing when our own address changes mid-session, as it does when a DSL line reconnects
+//!
+//! Nothing tells us directly. Either a tracker starts reporting a different external IP for
+//! us, or every connection we have is reset at once. Once either happens, trackers still
+//! list the old address and peers we lost are being held off for having dropped us, so the
+//! session announces again and forgets the reconnect backoff.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How close together resets have to come to count as one event
+pub const RESET_WINDOW: Duration = Duration::from_secs(5);
+
+/// Fewest peers that have to be reset together, so losing a couple to chance doesn't count
+pub const MIN_RESETS: usize = 3;
+
+/// Why we think our address changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A tracker sees us at `new`, where it or another tracker saw `old` before
+    ExternalIp { old: IpAddr, new: IpAddr },
+
+    /// Every peer we had was reset within [RESET_WINDOW], this many of them
+    PeersReset(usize),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::ExternalIp { old, new } => {
+                write!(f, "a tracker sees us at {} rather than {}", new, old)
+            }
+            Change::PeersReset(count) => write!(f, "all {} peers were reset at once", count),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AddressWatch {
+    // our external address as trackers last reported it, for each family, since an IPv4
+    // tracker and an IPv6 one never agree
+    v4: Option<IpAddr>,
+    v6: Option<IpAddr>,
+
+    // when each of the peers lost in the latest burst of resets went
+    resets: Vec<Instant>,
+
+    // a change that hasn't been acted on yet
+    changed: Option<Change>,
+}
+
+impl AddressWatch {
+    /// Takes note of the external IP a tracker says we have
+    pub fn external_ip(&mut self, ip: IpAddr) {
+        let known = match ip {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        };
+        match known.replace(ip) {
+            Some(old) if old != ip => self.changed = Some(Change::ExternalIp { old, new: ip }),
+            _ => {}
+        }
+    }
+
+    /// Takes note of a peer connection that was reset at `now`, leaving `remaining` peers
+    pub fn peer_reset(&mut self, now: Instant, remaining: usize) {
+        self.resets
+            .retain(|&at| now.saturating_duration_since(at) < RESET_WINDOW);
+        self.resets.push(now);
+        if remaining == 0 && self.resets.len() >= MIN_RESETS {
+            self.changed = Some(Change::PeersReset(self.resets.len()));
+            self.resets.clear();
+        }
+    }
+
+    /// The change noticed since this was last called, if any
+    pub fn take_change(&mut self) -> Option<Change> {
+        self.changed.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Instant;
+
+    use super::{AddressWatch, Change, MIN_RESETS, RESET_WINDOW};
+
+    #[test]
+    fn a_new_external_ip_is_a_change() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut watch = AddressWatch::default();
+        watch.external_ip(ip("203.0.113.7"));
+        watch.external_ip(ip("203.0.113.7"));
+        assert_eq!(watch.take_change(), None);
+
+        // an IPv6 tracker has its own idea of where we are
+        watch.external_ip(ip("2001:db8::1"));
+        assert_eq!(watch.take_change(), None);
+
+        watch.external_ip(ip("198.51.100.2"));
+        assert_eq!(
+            watch.take_change(),
+            Some(Change::ExternalIp {
+                old: ip("203.0.113.7"),
+                new: ip("198.51.100.2"),
+            })
+        );
+        assert_eq!(watch.take_change(), None);
+    }
+
+    #[test]
+    fn only_every_peer_reset_at_once_is_a_change() {
+        let start = Instant::now();
+        let mut watch = AddressWatch::default();
+
+        // peers reset one at a time, however many, are just peers going away
+        for i in 0..MIN_RESETS as u32 {
+            watch.peer_reset(start + RESET_WINDOW * i, 0);
+        }
+        assert_eq!(watch.take_change(), None);
+
+        // nor is a burst that leaves peers standing
+        let later = start + RESET_WINDOW * 10;
+        for left in (1..=MIN_RESETS).rev() {
+            watch.peer_reset(later, left);
+        }
+        assert_eq!(watch.take_change(), None);
+        watch.peer_reset(later, 0);
+        assert_eq!(
+            watch.take_change(),
+            Some(Change::PeersReset(MIN_RESETS + 1))
+        );
+    }
+}
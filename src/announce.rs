@@ -0,0 +1,723 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use url::Url;
+
+use crate::timer::Token;
+use crate::tracker::response::Peer;
+
+/// How long to wait between announces to a working tracker that doesn't say
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// How far either way of the interval a regular announce may land, so clients started
+/// together drift apart instead of announcing in lockstep
+pub const INTERVAL_JITTER: f64 = 0.1;
+
+/// The longest an event announce such as Completed is held back, for the same reason
+pub const EVENT_SPREAD: Duration = Duration::from_secs(3);
+
+/// Fraction of --max-connections below which losing peers gets us announcing early for more
+pub const LOW_WATER: f64 = 0.2;
+
+/// Most early announces a session makes, so a swarm that keeps emptying out doesn't have us
+/// pestering its trackers
+pub const MAX_EARLY_ANNOUNCES: u32 = 5;
+
+/// Base and maximum delay before retrying a tracker that failed
+const BACKOFF_BASE: Duration = Duration::from_secs(15);
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Which trackers from the announce-list we talk to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceMode {
+    /// Strict BEP 12: one tracker at a time, failing over within and then across tiers
+    Tiered,
+    /// One tracker per tier, with every tier announced to simultaneously
+    AllTiers,
+    /// Every tracker in every tier, simultaneously
+    AllTrackers,
+}
+
+impl AnnounceMode {
+    pub fn from_flags(all_tiers: bool, all_trackers: bool) -> Self {
+        if all_trackers {
+            AnnounceMode::AllTrackers
+        } else if all_tiers {
+            AnnounceMode::AllTiers
+        } else {
+            AnnounceMode::Tiered
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackerStatus {
+    NotContacted,
+    Working { peers: usize },
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct Tracker {
+    pub url: String,
+
+    // timer used to schedule the next announce to this tracker
+    pub timer_id: Token,
+
+    // have we sent this tracker a Started event?
+    pub started: bool,
+
+    // consecutive failures, used for backoff
+    pub failures: u32,
+
+    pub status: TrackerStatus,
+
+    // the shortest time it wants between announces, from its last response that said
+    pub min_interval: Option<Duration>,
+
+    // when we last announced to it, and whether it has yet to answer
+    pub last_announce: Option<Instant>,
+    pub in_flight: bool,
+
+    // the peers it gave us last time it answered
+    pub peers: Vec<Peer>,
+}
+
+impl Tracker {
+    fn new(url: String, timer_id: Token) -> Self {
+        Self {
+            url,
+            timer_id,
+            started: false,
+            failures: 0,
+            status: TrackerStatus::NotContacted,
+            min_interval: None,
+            last_announce: None,
+            in_flight: false,
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// Scheduling state for every tracker of the torrent
+#[derive(Debug)]
+pub struct Trackers {
+    mode: AnnounceMode,
+    tiers: Vec<Vec<Tracker>>,
+
+    // in Tiered mode, the tier we are currently using
+    current_tier: usize,
+
+    // announces made ahead of schedule for running low on peers, up to MAX_EARLY_ANNOUNCES
+    early_announces: u32,
+
+    // for jittering announce times
+    rng: StdRng,
+}
+
+impl Trackers {
+    /// Builds the tracker list from an announce-list style list of tiers.
+    /// Trackers within a tier are shuffled, as BEP 12 requires.
+    pub fn new(tiers: Vec<Vec<String>>, mode: AnnounceMode, rng: &mut impl Rng) -> Self {
+        let mut seen = HashSet::new();
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<Tracker> = tier
+                    .into_iter()
+                    .filter(|url| seen.insert(url.clone()))
+                    .map(|url| Tracker::new(url, rng.gen()))
+                    .collect();
+                tier.shuffle(rng);
+                tier
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+
+        Self {
+            mode,
+            tiers,
+            current_tier: 0,
+            early_announces: 0,
+            rng: StdRng::seed_from_u64(rng.gen()),
+        }
+    }
+
+    /// All trackers, in tier order
+    pub fn iter(&self) -> impl Iterator<Item = &Tracker> {
+        self.tiers.iter().flatten()
+    }
+
+    /// The trackers we are currently announcing to
+    pub fn active(&self) -> Vec<&Tracker> {
+        match self.mode {
+            AnnounceMode::Tiered => self
+                .tiers
+                .get(self.current_tier)
+                .and_then(|tier| tier.first())
+                .into_iter()
+                .collect(),
+            AnnounceMode::AllTiers => self.tiers.iter().filter_map(|tier| tier.first()).collect(),
+            AnnounceMode::AllTrackers => self.iter().collect(),
+        }
+    }
+
+    /// Trackers that have been sent a Started event, and so must hear about Completed/Stopped
+    pub fn started(&self) -> impl Iterator<Item = &Tracker> {
+        self.iter().filter(|t| t.started)
+    }
+
+    pub fn get(&self, url: &str) -> Option<&Tracker> {
+        self.iter().find(|t| t.url == url)
+    }
+
+    pub fn by_timer(&self, id: Token) -> Option<&Tracker> {
+        self.iter().find(|t| t.timer_id == id)
+    }
+
+    /// Every peer the trackers gave us in their latest responses, once each
+    pub fn peers(&self) -> Vec<&Peer> {
+        merge_peers(self.iter().map(|t| &t.peers[..]))
+    }
+
+    /// Each tracker and how announcing to it is going, in tier order
+    pub fn statuses(&self) -> Vec<(String, TrackerStatus)> {
+        self.iter()
+            .map(|t| (t.url.clone(), t.status.clone()))
+            .collect()
+    }
+
+    fn position(&self, url: &str) -> Option<(usize, usize)> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .find_map(|(i, tier)| tier.iter().position(|t| t.url == url).map(|j| (i, j)))
+    }
+
+    pub fn mark_started(&mut self, url: &str) {
+        if let Some((i, j)) = self.position(url) {
+            self.tiers[i][j].started = true;
+        }
+    }
+
+    /// Records that an announce to `url` went out at `now`
+    pub fn mark_announced(&mut self, url: &str, now: Instant) {
+        if let Some((i, j)) = self.position(url) {
+            self.tiers[i][j].last_announce = Some(now);
+            self.tiers[i][j].in_flight = true;
+        }
+    }
+
+    /// The trackers to announce to ahead of schedule, as we're running out of peers: those in
+    /// use that are working, aren't already being announced to, and have had their minimum
+    /// interval since the last announce. Counts towards [MAX_EARLY_ANNOUNCES] if there are
+    /// any, and once that many have been made there never are.
+    pub fn early_announce(&mut self, now: Instant) -> Vec<String> {
+        if self.early_announces >= MAX_EARLY_ANNOUNCES {
+            return Vec::new();
+        }
+
+        let urls: Vec<String> = self
+            .active()
+            .into_iter()
+            .filter(|t| matches!(t.status, TrackerStatus::Working { .. }) && !t.in_flight)
+            .filter(|t| {
+                t.last_announce.is_none_or(|last| {
+                    now.saturating_duration_since(last) >= t.min_interval.unwrap_or_default()
+                })
+            })
+            .map(|t| t.url.clone())
+            .collect();
+        if !urls.is_empty() {
+            self.early_announces += 1;
+        }
+        urls
+    }
+
+    /// Records a successful announce with the peers it gave us, along with the `interval` and
+    /// `min interval` the tracker sent if any. Returns how long to wait before the next one:
+    /// its interval (or [ANNOUNCE_INTERVAL]) give or take [INTERVAL_JITTER], but never less
+    /// than its minimum.
+    pub fn on_success(
+        &mut self,
+        url: &str,
+        peers: Vec<Peer>,
+        interval: Option<Duration>,
+        min_interval: Option<Duration>,
+    ) -> Option<Duration> {
+        let (i, j) = self.position(url)?;
+
+        let tracker = &mut self.tiers[i][j];
+        tracker.failures = 0;
+        tracker.in_flight = false;
+        tracker.status = TrackerStatus::Working { peers: peers.len() };
+        tracker.peers = peers;
+        if min_interval.is_some() {
+            tracker.min_interval = min_interval;
+        }
+        let floor = tracker.min_interval.unwrap_or_default();
+
+        // BEP 12: a tracker that works moves to the front of its tier
+        let tracker = self.tiers[i].remove(j);
+        self.tiers[i].insert(0, tracker);
+
+        let jitter = self.rng.gen_range(-INTERVAL_JITTER..=INTERVAL_JITTER);
+        let interval = interval.unwrap_or(ANNOUNCE_INTERVAL);
+        Some(interval.mul_f64(1.0 + jitter).max(floor))
+    }
+
+    /// How long to hold back an announce for an event, up to [EVENT_SPREAD]
+    pub fn event_delay(&mut self) -> Duration {
+        EVENT_SPREAD.mul_f64(self.rng.gen())
+    }
+
+    /// Records a failed announce. Trackers that failed `permanently` are only retried after
+    /// the longest backoff.
+    ///
+    /// Returns the tracker that should be announced to next along with how long to wait
+    /// before doing so. In the tiered modes this is the next tracker in line; otherwise it is
+    /// the same tracker after a backoff.
+    pub fn on_failure(
+        &mut self,
+        url: &str,
+        reason: String,
+        permanent: bool,
+    ) -> Option<(String, Duration)> {
+        let (i, j) = self.position(url)?;
+
+        let tracker = &mut self.tiers[i][j];
+        tracker.failures += 1;
+        tracker.in_flight = false;
+        tracker.status = TrackerStatus::Failed(reason);
+        let backoff = if permanent {
+            BACKOFF_MAX
+        } else {
+            backoff(tracker.failures)
+        };
+
+        match self.mode {
+            AnnounceMode::AllTrackers => Some((url.to_owned(), backoff)),
+            AnnounceMode::AllTiers => {
+                // rotate the failed tracker to the back of its tier
+                let tier = &mut self.tiers[i];
+                let tracker = tier.remove(j);
+                tier.push(tracker);
+
+                let next = tier.first().unwrap();
+                let delay = if next.url == url || next.failures > 0 {
+                    backoff
+                } else {
+                    Duration::ZERO
+                };
+                Some((next.url.clone(), delay))
+            }
+            AnnounceMode::Tiered => {
+                let tracker = self.tiers[i].remove(j);
+                self.tiers[i].push(tracker);
+
+                // move on to the next tier once every tracker in this one has failed
+                if self.tiers[i].iter().all(|t| t.failures > 0) {
+                    self.current_tier = (i + 1) % self.tiers.len();
+                }
+
+                let next = self.tiers[self.current_tier].first().unwrap();
+                let delay = if next.failures > 0 {
+                    backoff
+                } else {
+                    Duration::ZERO
+                };
+                Some((next.url.clone(), delay))
+            }
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+/// Checks that a tracker URL from the command line is one we can announce to
+pub fn parse_tracker_url(s: &str) -> Result<String, String> {
+    let url = Url::parse(s).map_err(|e| format!("{:?} is not a valid URL: {}", s, e))?;
+    match url.scheme() {
+        "http" => Ok(s.to_owned()),
+        scheme => Err(format!("{} trackers are not supported, only http", scheme)),
+    }
+}
+
+/// Adds trackers from the command line to the torrent's tiers, as a tier of their own that is
+/// tried first. With `replace`, the torrent's own trackers are dropped.
+pub fn add_tiers(embedded: Vec<Vec<String>>, extra: &[String], replace: bool) -> Vec<Vec<String>> {
+    let mut seen = HashSet::new();
+    let extra: Vec<String> = extra
+        .iter()
+        .filter(|url| seen.insert(url.as_str()))
+        .cloned()
+        .collect();
+
+    if replace {
+        return vec![extra];
+    }
+    if extra.is_empty() {
+        return embedded;
+    }
+
+    // a tracker the torrent already lists is only announced to once, from our tier
+    let mut tiers = vec![extra];
+    for tier in embedded {
+        let tier: Vec<String> = tier
+            .into_iter()
+            .filter(|url| !tiers[0].contains(url))
+            .collect();
+        if !tier.is_empty() {
+            tiers.push(tier);
+        }
+    }
+    tiers
+}
+
+/// Merge the peer lists from several trackers, dropping duplicates
+pub fn merge_peers<'a>(lists: impl IntoIterator<Item = &'a [Peer]>) -> Vec<&'a Peer> {
+    let mut seen = HashSet::new();
+    lists
+        .into_iter()
+        .flatten()
+        .filter(|p| seen.insert((p.ip.as_str(), p.port)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{
+        add_tiers, merge_peers, parse_tracker_url, AnnounceMode, TrackerStatus, Trackers,
+        ANNOUNCE_INTERVAL, EVENT_SPREAD, MAX_EARLY_ANNOUNCES,
+    };
+    use crate::tracker::response::Peer;
+
+    fn tiers() -> Vec<Vec<String>> {
+        vec![
+            vec!["http://a.example/announce".to_owned()],
+            vec!["http://b.example/announce".to_owned()],
+        ]
+    }
+
+    fn urls(trackers: &Trackers) -> Vec<String> {
+        trackers.active().iter().map(|t| t.url.clone()).collect()
+    }
+
+    // `count` peers on consecutive ports, starting from `first`
+    fn peers_from(first: u16, count: u16) -> Vec<Peer> {
+        (first..first + count)
+            .map(|port| Peer {
+                ip: "10.0.0.1".to_owned(),
+                port,
+            })
+            .collect()
+    }
+
+    fn peers(count: u16) -> Vec<Peer> {
+        peers_from(1, count)
+    }
+
+    #[test]
+    fn tiered_uses_first_tier_only() {
+        let trackers = Trackers::new(tiers(), AnnounceMode::Tiered, &mut StdRng::seed_from_u64(0));
+        assert_eq!(urls(&trackers), vec!["http://a.example/announce"]);
+    }
+
+    #[test]
+    fn tiered_fails_over_to_next_tier() {
+        let mut trackers =
+            Trackers::new(tiers(), AnnounceMode::Tiered, &mut StdRng::seed_from_u64(0));
+
+        let (next, delay) = trackers
+            .on_failure("http://a.example/announce", "dead".to_owned(), false)
+            .unwrap();
+        assert_eq!(next, "http://b.example/announce");
+        assert_eq!(delay, Duration::ZERO);
+        assert_eq!(urls(&trackers), vec!["http://b.example/announce"]);
+    }
+
+    #[test]
+    fn all_tiers_announces_to_each_tier() {
+        let mut trackers = Trackers::new(
+            tiers(),
+            AnnounceMode::AllTiers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert_eq!(
+            urls(&trackers),
+            vec!["http://a.example/announce", "http://b.example/announce"]
+        );
+
+        // each tracker keeps its own status and backoff
+        trackers.on_success("http://a.example/announce", peers(3), None, None);
+        let (next, delay) = trackers
+            .on_failure("http://b.example/announce", "dead".to_owned(), false)
+            .unwrap();
+        assert_eq!(next, "http://b.example/announce");
+        assert!(delay > Duration::ZERO);
+
+        assert_eq!(
+            trackers.get("http://a.example/announce").unwrap().status,
+            TrackerStatus::Working { peers: 3 }
+        );
+        assert_eq!(
+            trackers.get("http://b.example/announce").unwrap().failures,
+            1
+        );
+    }
+
+    #[test]
+    fn all_trackers_includes_every_tracker() {
+        let mut tiers = tiers();
+        tiers[0].push("http://c.example/announce".to_owned());
+        let trackers = Trackers::new(
+            tiers,
+            AnnounceMode::AllTrackers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert_eq!(trackers.active().len(), 3);
+    }
+
+    #[test]
+    fn completed_goes_to_started_trackers() {
+        let mut trackers = Trackers::new(
+            tiers(),
+            AnnounceMode::AllTiers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        trackers.mark_started("http://b.example/announce");
+
+        let started: Vec<&str> = trackers.started().map(|t| t.url.as_str()).collect();
+        assert_eq!(started, vec!["http://b.example/announce"]);
+    }
+
+    #[test]
+    fn backoff_grows_with_failures() {
+        let mut trackers = Trackers::new(
+            tiers(),
+            AnnounceMode::AllTrackers,
+            &mut StdRng::seed_from_u64(0),
+        );
+
+        let (_, first) = trackers
+            .on_failure("http://a.example/announce", "dead".to_owned(), false)
+            .unwrap();
+        let (_, second) = trackers
+            .on_failure("http://a.example/announce", "dead".to_owned(), false)
+            .unwrap();
+        assert!(second > first);
+    }
+
+    #[test]
+    fn announces_are_jittered_but_respect_min_interval() {
+        let mut trackers = Trackers::new(
+            tiers(),
+            AnnounceMode::AllTrackers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let url = "http://a.example/announce";
+
+        // the tracker's own interval is what gets jittered
+        let interval = Duration::from_secs(1800);
+        let delays: Vec<Duration> = (0..100)
+            .map(|_| {
+                trackers
+                    .on_success(url, peers(5), Some(interval), None)
+                    .unwrap()
+            })
+            .collect();
+        let (low, high) = (interval.mul_f64(0.9), interval.mul_f64(1.1));
+        assert!(delays.iter().all(|&d| low <= d && d <= high));
+        assert!(delays.iter().any(|&d| d != delays[0]));
+
+        // a longer minimum wins, and is remembered when the tracker stops repeating it
+        let min = interval * 2;
+        assert_eq!(
+            trackers.on_success(url, peers(5), Some(interval), Some(min)),
+            Some(min)
+        );
+        assert_eq!(
+            trackers.on_success(url, peers(5), Some(interval), None),
+            Some(min)
+        );
+
+        // a shorter one leaves the jittered interval alone
+        let min = interval / 2;
+        let delay = trackers
+            .on_success(url, peers(5), Some(interval), Some(min))
+            .unwrap();
+        assert!(low <= delay && delay <= high);
+
+        // without an interval from the tracker, our own is jittered instead
+        let delay = trackers
+            .on_success("http://b.example/announce", peers(5), None, None)
+            .unwrap();
+        let (low, high) = (
+            ANNOUNCE_INTERVAL.mul_f64(0.9),
+            ANNOUNCE_INTERVAL.mul_f64(1.1),
+        );
+        assert!(low <= delay && delay <= high);
+
+        assert!((0..100).all(|_| trackers.event_delay() <= EVENT_SPREAD));
+    }
+
+    #[test]
+    fn jitter_follows_the_seed() {
+        let delays = |seed| {
+            let mut trackers = Trackers::new(
+                tiers(),
+                AnnounceMode::AllTrackers,
+                &mut StdRng::seed_from_u64(seed),
+            );
+            let url = "http://a.example/announce";
+            (0..4)
+                .map(|_| trackers.on_success(url, Vec::new(), None, None).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(1), delays(1));
+        assert_ne!(delays(1), delays(2));
+    }
+
+    #[test]
+    fn merge_disjoint_and_duplicate_peers() {
+        let peer = |ip: &str, port| Peer {
+            ip: ip.to_owned(),
+            port,
+        };
+        let a = [peer("1.1.1.1", 1), peer("2.2.2.2", 2)];
+        let b = [peer("3.3.3.3", 3), peer("1.1.1.1", 1)];
+
+        let merged = merge_peers([&a[..], &b[..]]);
+        assert_eq!(merged.len(), 3);
+    }
+
+    #[test]
+    fn latest_peers_are_merged_across_trackers() {
+        let mut trackers = Trackers::new(
+            tiers(),
+            AnnounceMode::AllTiers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let (a, b) = ("http://a.example/announce", "http://b.example/announce");
+        trackers.on_success(a, peers_from(1, 3), None, None);
+        trackers.on_success(b, peers_from(3, 3), None, None);
+        let ports: Vec<u16> = trackers.peers().iter().map(|p| p.port).collect();
+        assert_eq!(ports, [1, 2, 3, 4, 5]);
+
+        // each tracker's peers are replaced by its next response, and outlive it failing
+        trackers.on_success(a, peers_from(10, 1), None, None);
+        trackers.on_failure(b, "dead".to_owned(), false);
+        let ports: Vec<u16> = trackers.peers().iter().map(|p| p.port).collect();
+        assert_eq!(ports, [10, 3, 4, 5]);
+        assert_eq!(
+            trackers.statuses(),
+            [
+                (a.to_owned(), TrackerStatus::Working { peers: 1 }),
+                (b.to_owned(), TrackerStatus::Failed("dead".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn permanent_failure_backs_off_fully() {
+        let mut trackers = Trackers::new(
+            tiers(),
+            AnnounceMode::AllTrackers,
+            &mut StdRng::seed_from_u64(0),
+        );
+
+        let (next, delay) = trackers
+            .on_failure("http://a.example/announce", "unregistered".to_owned(), true)
+            .unwrap();
+        assert_eq!(next, "http://a.example/announce");
+        assert_eq!(delay, super::BACKOFF_MAX);
+    }
+
+    #[test]
+    fn command_line_trackers_come_first() {
+        let extra = [
+            "http://c.example/announce".to_owned(),
+            "http://b.example/announce".to_owned(),
+            "http://c.example/announce".to_owned(),
+        ];
+        assert_eq!(
+            add_tiers(tiers(), &extra, false),
+            vec![
+                vec!["http://c.example/announce", "http://b.example/announce"],
+                vec!["http://a.example/announce"],
+            ]
+        );
+        assert_eq!(add_tiers(tiers(), &[], false), tiers());
+    }
+
+    #[test]
+    fn command_line_trackers_can_replace_the_torrents() {
+        let extra = ["http://c.example/announce".to_owned()];
+        assert_eq!(
+            add_tiers(tiers(), &extra, true),
+            vec![vec!["http://c.example/announce"]]
+        );
+    }
+
+    #[test]
+    fn early_announces_wait_for_the_tracker() {
+        const A: &str = "http://a.example/announce";
+        let mut trackers =
+            Trackers::new(tiers(), AnnounceMode::Tiered, &mut StdRng::seed_from_u64(0));
+        let now = Instant::now();
+
+        // nothing to go on until the tracker has answered
+        trackers.mark_announced(A, now);
+        assert!(trackers.early_announce(now).is_empty());
+        trackers.on_success(A, Vec::new(), None, Some(Duration::from_secs(60)));
+
+        // and then not before its minimum interval is up
+        assert!(trackers
+            .early_announce(now + Duration::from_secs(59))
+            .is_empty());
+        let later = now + Duration::from_secs(60);
+        assert_eq!(trackers.early_announce(later), [A]);
+
+        // a failed tracker is left to its backoff
+        trackers.mark_announced(A, later);
+        trackers.on_failure(A, "dead".to_owned(), false);
+        assert!(trackers
+            .early_announce(later + Duration::from_secs(600))
+            .is_empty());
+    }
+
+    #[test]
+    fn early_announces_are_capped() {
+        const A: &str = "http://a.example/announce";
+        let mut trackers =
+            Trackers::new(tiers(), AnnounceMode::Tiered, &mut StdRng::seed_from_u64(0));
+        trackers.on_success(A, Vec::new(), None, None);
+
+        let now = Instant::now();
+        for _ in 0..MAX_EARLY_ANNOUNCES {
+            assert_eq!(trackers.early_announce(now), [A]);
+        }
+        assert!(trackers.early_announce(now).is_empty());
+    }
+
+    #[test]
+    fn only_http_trackers_are_accepted() {
+        assert!(parse_tracker_url("http://a.example:6969/announce").is_ok());
+
+        let err = parse_tracker_url("udp://a.example:6969").unwrap_err();
+        assert!(err.contains("udp trackers are not supported"), "{}", err);
+        let err = parse_tracker_url("a.example/announce").unwrap_err();
+        assert!(err.contains("not a valid URL"), "{}", err);
+    }
+}
@@ -1,27 +1,54 @@
-use std::{collections::HashMap, fs::File, io::Read, path::PathBuf};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use bendy::serde::from_bytes;
-use clap::Parser;
-use lazy_static::lazy_static;
-use rand::{Rng, RngCore};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
 
-use crate::torrent::{Info, MetaInfo};
+use crate::announce::parse_tracker_url;
+use crate::file::{
+    FlushPolicy, DEFAULT_CACHE_PIECES, DEFAULT_MAX_BLOCK_LEN, DEFAULT_READ_CACHE_BYTES,
+};
+use crate::scheduler::parse_weight;
+use crate::torrent::DEFAULT_MAX_PIECE_LENGTH;
+use crate::units::{parse_duration, parse_size};
 
 /// A moderately functional BitTorrent client written in Rust
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Clone, Serialize)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// Name of the torrent file to download
-    #[arg(short, long)]
-    pub torrent: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Name of the torrent file to download. May be given more than once, to run several
+    /// torrents in one process that share --max-connections and --dial-rate
+    #[arg(short, long, required = true)]
+    pub torrent: Vec<String>,
+
+    /// Share of --max-connections and --dial-rate a torrent gets when running several, as
+    /// NAME=WEIGHT, where NAME is its torrent file's name without `.torrent`. Torrents not
+    /// given a weight have a weight of 1. May be given more than once
+    #[arg(long = "torrent-weight", value_name = "NAME=WEIGHT", value_parser = parse_weight)]
+    pub torrent_weights: Vec<(String, u32)>,
 
     /// Maximum number of peer connections to maintain
     #[arg(short, long, default_value_t = 10)]
     pub max_connections: usize,
 
-    /// Port to listen on. Random if not provided
-    #[arg(short, long, default_value_t = rand::thread_rng().gen_range(1025..65535))]
-    pub port: u16,
+    /// Port to listen on. Defaults to the port the last session for the torrent listened on,
+    /// if it is free, and otherwise a random one
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Don't listen for peers at all, and only make outgoing connections, ignoring --port.
+    /// For places where binding a port is impossible or unwanted, at the cost of never being
+    /// found by peers
+    #[arg(long, default_value_t = false)]
+    pub no_listen: bool,
+
+    /// Seed every random decision the session makes from this, so that two runs with the
+    /// same seed and the same inputs behave the same way
+    #[arg(long)]
+    pub seed_rng: Option<u64>,
 
     /// Continue seeding after file has been downloaded
     #[arg(short, long, default_value_t = false)]
@@ -31,69 +58,282 @@ pub struct Args {
     #[arg(short = 'e', long, default_value_t = false)]
     pub seed_existing: bool,
 
+    /// With --seed-existing, hash every piece before seeding rather than trusting the file,
+    /// and download again any that don't match. A file of the wrong length is refused
+    #[arg(long, default_value_t = false, requires = "seed_existing")]
+    pub verify_existing: bool,
+
+    /// With --seed-existing, open the file read-only rather than trying for write access
+    /// first. Pieces that don't match their hash can't be downloaded again, so they are
+    /// skipped
+    #[arg(long, default_value_t = false, requires = "seed_existing")]
+    pub read_only: bool,
+
+    /// Before starting, copy every piece that matches its hash from another copy of the data,
+    /// such as a partial download from another client
+    #[arg(long)]
+    pub import_from: Option<PathBuf>,
+
     /// Number of outstanding requests to have per-peer
-    #[arg(short, long, default_value_t = 10)]
+    #[arg(long, default_value_t = 10)]
     pub pipeline_depth: usize,
 
-    /// Number of seconds to wait before dropping peer
-    #[arg(short, long, default_value_t = 12)]
-    pub request_timeout: u64,
+    /// Pieces held by fewer than this many peers are rare, and are requested from the
+    /// fastest peer that has them
+    #[arg(long, default_value_t = 3)]
+    pub rare_piece_threshold: usize,
+
+    /// How long to wait for a requested block before dropping the peer, such as 12s or 1m.
+    /// A bare number is in seconds
+    #[arg(short, long, default_value = "12s", value_parser = parse_duration)]
+    pub request_timeout: Duration,
+
+    /// Hash the file at startup, and on a recheck, without keeping what is read in the page
+    /// cache, so checking a big download doesn't push everything else out of memory
+    #[arg(long, default_value_t = false)]
+    pub uncached_verify: bool,
+
+    /// How many pieces to assemble in memory before they are checked and written out. Pieces
+    /// beyond this are written a block at a time, and 0 turns the cache off
+    #[arg(long, default_value_t = DEFAULT_CACHE_PIECES)]
+    pub write_cache_pieces: usize,
+
+    /// How much of the pieces peers recently asked for to keep in memory to serve blocks
+    /// from, such as 8MiB. A bare number is in bytes, and 0 turns the cache off
+    #[arg(long, default_value_t = DEFAULT_READ_CACHE_BYTES, value_parser = parse_size)]
+    pub read_cache_bytes: usize,
+
+    /// Largest block to accept from peers or serve to them, such as 64KiB. A bare number is
+    /// in bytes. Peers that ask for more count as misbehaving
+    #[arg(long, default_value_t = DEFAULT_MAX_BLOCK_LEN, value_parser = parse_size)]
+    pub max_block_size: usize,
+
+    /// Longest pieces to download or seed a torrent with, such as 256MiB. A bare number is in
+    /// bytes. Torrents with longer pieces are refused
+    #[arg(long, default_value_t = DEFAULT_MAX_PIECE_LENGTH, value_parser = parse_size)]
+    pub max_piece_size: usize,
+
+    /// How many pieces to complete between saves of the fast-resume file, which is also saved
+    /// on exit and spares hashing the whole file on the next start. 0 only saves it on exit
+    #[arg(long, default_value_t = 64)]
+    pub resume_every: usize,
+
+    /// Reserve disk space for the whole file before downloading, rather than leaving it
+    /// sparse. Either way, a download the disk doesn't have room for is refused up front
+    #[arg(long, default_value_t = false)]
+    pub preallocate: bool,
+
+    /// When to sync the file to disk as pieces are verified: `never`, `piece` for after each
+    /// one, or how long to wait between syncs, such as 30s. The file is synced whenever the
+    /// fast-resume file is saved and on exit either way, and the resume file only counts what
+    /// was synced
+    #[arg(long, default_value = "never", value_name = "POLICY")]
+    pub flush: FlushPolicy,
+
+    /// Map the file into memory, so blocks are copied straight in and pieces hashed where they
+    /// lie instead of going through a system call each. Needs address space for the whole
+    /// file, and nothing else may shrink the file while it is mapped
+    #[arg(long, default_value_t = false)]
+    pub mmap: bool,
+
+    /// How long an unchoked peer may sit on our requests without delivering anything before
+    /// its requests are reassigned and it is put on probation. A bare number is in seconds
+    #[arg(long, default_value = "6s", value_parser = parse_duration)]
+    pub snub_timeout: Duration,
+
+    /// How long the download may go without verifying a piece, despite not being finished,
+    /// before a diagnosis of what's holding it up is logged
+    #[arg(long, default_value = "5m", value_parser = parse_duration)]
+    pub stall_window: Duration,
+
+    /// Fewest peers to upload to at once, however slow our upload is
+    #[arg(long, default_value_t = 4)]
+    pub min_upload_slots: usize,
+
+    /// Most peers to upload to at once, however fast our upload is
+    #[arg(long, default_value_t = 20)]
+    pub max_upload_slots: usize,
+
+    /// Maximum number of incoming connections that may be partway through the handshake
+    #[arg(long, default_value_t = 32)]
+    pub max_handshaking: usize,
+
+    /// Most Have messages a peer may send in a second. The rest are ignored and count against
+    /// the peer, which is disconnected if it sends several times this many
+    #[arg(long, default_value_t = 2000)]
+    pub max_have_rate: usize,
+
+    /// Most Request messages a peer may send in a second, which are treated like Haves over
+    /// --max-have-rate
+    #[arg(long, default_value_t = 4000)]
+    pub max_request_rate: usize,
+
+    /// Maximum number of incoming connections to accept per second
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub accept_rate: u32,
+
+    /// Maximum number of peers to dial per second. Peers from trackers past that wait their
+    /// turn, so a big tracker response doesn't set off a burst of connection attempts
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub dial_rate: u32,
+
+    /// Number of queued events past which the main thread starts skipping non-essential work
+    #[arg(long, default_value_t = 4096)]
+    pub channel_soft_limit: usize,
+
+    /// Largest tracker response to accept, such as 512K or 2MiB. A bare number is in bytes.
+    /// Anything bigger is treated as a failed announce rather than read into memory.
+    #[arg(long, default_value = "1MiB", value_parser = parse_size)]
+    pub max_tracker_response: usize,
+
+    /// Stop requesting pieces once this much piece data has been received, such as 500MiB.
+    /// A bare number is in bytes
+    #[arg(long, value_parser = parse_size)]
+    pub max_download_bytes: Option<usize>,
+
+    /// Stop uploading once this much piece data has been sent, such as 2GiB.
+    /// A bare number is in bytes
+    #[arg(long, value_parser = parse_size)]
+    pub max_upload_bytes: Option<usize>,
+
+    /// Most piece data to have queued for peers at once, such as 16MiB. Further requests
+    /// wait until some of it has been written. A bare number is in bytes
+    #[arg(long, default_value = "16MiB", value_parser = parse_size)]
+    pub max_queued_upload_bytes: usize,
+
+    /// Stop uploading to any one peer once it has been sent this much piece data
+    #[arg(long, value_parser = parse_size)]
+    pub max_peer_upload_bytes: Option<usize>,
+
+    /// Exit once --max-download-bytes or --max-upload-bytes is reached, rather than staying
+    /// connected without downloading or uploading
+    #[arg(long, default_value_t = false)]
+    pub exit_at_cap: bool,
 
     /// Skip getting peers from tracker, only accepting new manual connections
     #[arg(short = 'a', long, default_value_t = false)]
     pub skip_announce: bool,
 
+    /// Directory to download into (or seed from). Downloads go to `<name>.part` until they're
+    /// complete
+    #[arg(short = 'd', long, default_value = ".")]
+    pub output_dir: PathBuf,
+
+    /// Directory to write crash reports and fast-resume files to. Defaults to --output-dir
+    #[arg(long)]
+    pub state_dir: Option<PathBuf>,
+
     /// Add a single peer manually at the download's start
     #[arg(short = 'o', long)]
     pub add_peer: Option<String>,
+
+    /// Also try connecting to the torrent's DHT bootstrap nodes, which are often peers too.
+    /// Ignored for private torrents
+    #[arg(long, default_value_t = false)]
+    pub connect_nodes: bool,
+
+    /// Serve the file over HTTP on this local port while it downloads, for media players
+    #[arg(long)]
+    pub stream_port: Option<u16>,
+
+    /// Announce to this tracker too, ahead of the torrent's own. May be given more than once
+    #[arg(long = "tracker", value_name = "URL", value_parser = parse_tracker_url)]
+    pub trackers: Vec<String>,
+
+    /// Only announce to trackers given with --tracker, ignoring the torrent's own
+    #[arg(long, default_value_t = false, requires = "trackers")]
+    pub replace_trackers: bool,
+
+    /// Add trackers with --tracker even if the torrent is private
+    #[arg(long, default_value_t = false)]
+    pub force: bool,
+
+    /// Announce to one tracker from every tier at once, rather than failing over between tiers
+    #[arg(long, default_value_t = false)]
+    pub announce_all_tiers: bool,
+
+    /// Announce to every tracker in every tier at once
+    #[arg(long, default_value_t = false)]
+    pub announce_all_trackers: bool,
+
+    /// Run this command once the download completes. It is told about the torrent through
+    /// RITTORRENT_* environment variables
+    #[arg(long, value_name = "CMD")]
+    pub on_complete: Option<String>,
+
+    /// Run this command if the session has to give up because of an error, which is given
+    /// as RITTORRENT_ERROR along with the variables --on-complete gets
+    #[arg(long, value_name = "CMD")]
+    pub on_error: Option<String>,
+
+    /// Run hook commands with sh -c, rather than splitting them on whitespace
+    #[arg(long, default_value_t = false)]
+    pub shell: bool,
+
+    /// After the first announce, check whether peers can connect to the port we listen on.
+    /// Outgoing connections use whatever source port the OS picks, so only the listen port
+    /// needs to be open
+    #[arg(long, default_value_t = false)]
+    pub check_port: bool,
+
+    /// Web service for --check-port, sent `?port=<port>` and answering `open` or `closed`.
+    /// Without one, we try connecting to the external IP the tracker says we have
+    #[arg(long, value_name = "URL", requires = "check_port")]
+    pub port_check_url: Option<String>,
+
+    /// Write progress to stdout as JSON, one object per line: a snapshot of the download every
+    /// --progress-interval, and a line for each piece completed, peer gained or lost and
+    /// announce answered. Logs go to stderr either way
+    #[arg(long, default_value_t = false)]
+    pub progress_json: bool,
+
+    /// How often --progress-json writes a snapshot. A bare number is in seconds
+    #[arg(long, default_value = "1s", value_parser = parse_duration, requires = "progress_json")]
+    pub progress_interval: Duration,
+
+    /// Record every message exchanged with each peer to a file in this directory.
+    /// Read the files back with the decode-capture command
+    #[arg(long)]
+    pub capture_dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone, Serialize)]
+pub enum Command {
+    /// Download a randomly generated file between two local sessions to check that everything works
+    Selftest,
+
+    /// Print what a torrent file describes
+    Show {
+        /// Torrent file to print
+        torrent: PathBuf,
+    },
+
+    /// Copy every piece of another copy of the data that matches the torrent into the download,
+    /// so that only the rest has to be downloaded
+    Import {
+        /// Torrent file of the download
+        #[arg(short, long)]
+        torrent: PathBuf,
+
+        /// Copy of the data to take pieces from, which may be corrupt or the wrong length
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Directory the download is in
+        #[arg(short = 'd', long, default_value = ".")]
+        output_dir: PathBuf,
+    },
+
+    /// Print a capture file written by --capture-dir
+    DecodeCapture {
+        /// Capture file to print
+        file: PathBuf,
+    },
 }
 
-const PEER_ID_LEN: usize = 20;
-
-lazy_static! {
-    // Command-line arguments
-    pub static ref ARGS: Args = Args::parse();
-
-    // Ranodmly-generated peer id
-    pub static ref PEER_ID: [u8; PEER_ID_LEN] = {
-        let mut data = [0u8; PEER_ID_LEN];
-        rand::thread_rng().fill_bytes(&mut data);
-        data
-    };
-
-    // Parsed metainfo file
-    pub static ref METAINFO: MetaInfo<'static> = {
-        let torrent_path = PathBuf::from(&ARGS.torrent);
-        let mut torrent_file = File::open(torrent_path)
-            .expect("Failed to open provided torrent file");
-        let mut result = Vec::new();
-        torrent_file
-            .read_to_end(&mut result)
-            .expect("Failed to read from provided torrent file");
-
-        let metainfo = from_bytes::<MetaInfo>(&result)
-            .expect("Failed to parse provided torrent file");
-
-        let announce = metainfo.announce.clone();
-        let piece_length = metainfo.info.piece_length;
-        let pieces = metainfo.info.pieces.clone();
-        let name = metainfo.info.name.clone();
-        let length = metainfo.info.length;
-
-        let mut remaining = HashMap::new();
-        for (k, v) in metainfo.info.remaining.iter() {
-            remaining.insert(k.clone(), v.clone().into_owned());
-        }
-
-        MetaInfo {
-            announce,
-            info: Info {
-                piece_length,
-                pieces,
-                name,
-                length,
-                remaining,
-            }
-        }
-    };
+impl Args {
+    /// Where crash reports and fast-resume files go
+    pub fn state_dir(&self) -> &Path {
+        self.state_dir.as_deref().unwrap_or(&self.output_dir)
+    }
 }
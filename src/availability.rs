@@ -0,0 +1,155 @@
+//! How many connected peers have each piece, and what that says about the swarm
+
+use bitvec::prelude::*;
+
+use crate::piece_set::PieceSet;
+
+/// Number of connected peers that have each piece, kept up to date as peers come, go, and
+/// announce pieces
+#[derive(Debug, Clone)]
+pub struct Availability {
+    counts: Vec<usize>,
+}
+
+impl Availability {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            counts: vec![0; piece_count],
+        }
+    }
+
+    /// Counts every piece a peer has. Call when the peer's pieces are first known.
+    pub fn add(&mut self, has: &PieceSet) {
+        for piece in has.iter_ones() {
+            if let Some(count) = self.counts.get_mut(piece) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Stops counting a peer's pieces, when it leaves or replaces its bitfield
+    pub fn remove(&mut self, has: &PieceSet) {
+        for piece in has.iter_ones() {
+            if let Some(count) = self.counts.get_mut(piece) {
+                *count -= 1;
+            }
+        }
+    }
+
+    /// Counts one more peer having `piece`
+    pub fn add_piece(&mut self, piece: usize) {
+        if let Some(count) = self.counts.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    /// Number of connected peers with `piece`
+    pub fn get(&self, piece: usize) -> usize {
+        self.counts.get(piece).copied().unwrap_or(0)
+    }
+
+    // Copies of each piece in the swarm, counting our own
+    fn copies<'a>(&'a self, ours: &'a BitSlice<u8, Msb0>) -> impl Iterator<Item = usize> + 'a {
+        self.counts
+            .iter()
+            .zip(ours.iter().by_vals())
+            .map(|(&count, have)| count + have as usize)
+    }
+
+    /// Number of distributed copies, as classic clients report it: the fewest copies of any
+    /// piece, plus the fraction of pieces with more copies than that.
+    /// Below 1.0, some piece isn't available from anyone we're connected to.
+    pub fn distributed_copies(&self, ours: &BitSlice<u8, Msb0>) -> f64 {
+        let Some(min) = self.copies(ours).min() else {
+            return 0.0;
+        };
+
+        let above = self.copies(ours).filter(|&copies| copies > min).count();
+        min as f64 + above as f64 / self.counts.len() as f64
+    }
+
+    /// Number of pieces with each number of copies, counting our own, indexed by copies
+    pub fn histogram(&self, ours: &BitSlice<u8, Msb0>) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        for copies in self.copies(ours) {
+            if histogram.len() <= copies {
+                histogram.resize(copies + 1, 0);
+            }
+            histogram[copies] += 1;
+        }
+        histogram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+
+    use super::Availability;
+    use crate::piece_set::PieceSet;
+
+    fn availability(peers: &[&[u8]], piece_count: usize) -> Availability {
+        let mut availability = Availability::new(piece_count);
+        for has in peers {
+            let mut has = BitVec::<u8, Msb0>::from_slice(has);
+            has.truncate(piece_count);
+            availability.add(&PieceSet::from_bitvec(has));
+        }
+        availability
+    }
+
+    #[test]
+    fn distributed_copies_of_contrived_swarms() {
+        let none = bitvec![u8, Msb0; 0; 4];
+
+        // nobody at all
+        assert_eq!(availability(&[], 4).distributed_copies(&none), 0.0);
+
+        // one seed
+        assert_eq!(availability(&[&[0xf0]], 4).distributed_copies(&none), 1.0);
+
+        // a seed, and a peer with half the pieces
+        assert_eq!(
+            availability(&[&[0xf0], &[0xc0]], 4).distributed_copies(&none),
+            1.5
+        );
+
+        // two peers that only have the last piece between them, so it's not completable
+        assert_eq!(
+            availability(&[&[0xe0], &[0xe0]], 4).distributed_copies(&none),
+            0.75
+        );
+
+        // but it is once our own copy of it is counted
+        let ours = bitvec![u8, Msb0; 0, 0, 0, 1];
+        assert_eq!(
+            availability(&[&[0xe0], &[0xe0]], 4).distributed_copies(&ours),
+            1.75
+        );
+        assert_eq!(
+            availability(&[&[0xe0], &[0xe0]], 4).histogram(&ours),
+            vec![0, 1, 3]
+        );
+    }
+
+    #[test]
+    fn peers_coming_and_going() {
+        let seed = PieceSet::all(3);
+        let partial = PieceSet::from_bitvec(bitvec![u8, Msb0; 1, 0, 0]);
+
+        let mut availability = Availability::new(3);
+        availability.add(&seed);
+        availability.add(&partial);
+        availability.add_piece(2);
+        assert_eq!(
+            (0..3).map(|p| availability.get(p)).collect::<Vec<_>>(),
+            vec![2, 1, 2]
+        );
+
+        availability.remove(&seed);
+        assert_eq!(
+            (0..3).map(|p| availability.get(p)).collect::<Vec<_>>(),
+            vec![1, 0, 1]
+        );
+    }
+}
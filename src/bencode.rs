@@ -0,0 +1,219 @@
+//! Saying what is wrong with bencode that doesn't parse
+//!
+//! bendy's errors say what it expected but not where, or what it found instead, which leaves a
+//! bug report about a torrent that won't load with nothing to go on. When a torrent or tracker
+//! response fails to parse, [from_bytes] goes over it again for what can be said about it: how
+//! long it is, which keys it has at the top level, and what it starts with.
+
+use std::fmt;
+
+use bendy::decoding::{Decoder, FromBencode, Object};
+use bendy::value::Value;
+use serde::Deserialize;
+use thiserror::Error;
+
+// How much of the input goes in the hex dump
+const HEAD_LEN: usize = 64;
+
+// Most top-level keys listed, as a broken document could have any number of them
+const MAX_KEYS: usize = 20;
+
+/// What was being parsed, which decides what else is worth checking
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Document {
+    Torrent,
+
+    /// A tracker's answer to an announce, which is often an error page or a tracker's front
+    /// page when the URL is wrong
+    TrackerResponse,
+}
+
+#[derive(Debug, Error)]
+#[error("{error} ({diagnosis})")]
+pub struct ParseError {
+    pub error: String,
+    pub diagnosis: Diagnosis,
+}
+
+/// What could be made of a document that didn't parse
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub len: usize,
+
+    /// Keys of the top-level dictionary, up to wherever the bencode breaks off
+    pub keys: Vec<String>,
+
+    /// Why the input isn't bencode at all, if it isn't
+    pub malformed: Option<String>,
+
+    /// The first bytes of the input
+    pub head: Vec<u8>,
+
+    /// Whether the input looks like a web page, for tracker responses
+    pub html: bool,
+}
+
+/// Parses `input` as bencode, saying as much as it can about the input if that fails
+pub fn from_bytes<'a, T: Deserialize<'a>>(
+    input: &'a [u8],
+    document: Document,
+) -> Result<T, ParseError> {
+    bendy::serde::from_bytes(input).map_err(|e| ParseError {
+        error: e.to_string(),
+        diagnosis: diagnose(input, document),
+    })
+}
+
+pub fn diagnose(input: &[u8], document: Document) -> Diagnosis {
+    let (keys, malformed) = match Value::from_bencode(input) {
+        Ok(Value::Dict(dict)) => (
+            dict.keys()
+                .map(|k| String::from_utf8_lossy(k).into_owned())
+                .collect(),
+            None,
+        ),
+        Ok(_) => (Vec::new(), None),
+
+        // Value gives up on the whole thing, so go over it again for the keys before the break
+        Err(e) => (keys_before_error(input), Some(e.to_string())),
+    };
+
+    Diagnosis {
+        len: input.len(),
+        keys,
+        malformed,
+        head: input[..input.len().min(HEAD_LEN)].to_vec(),
+        html: document == Document::TrackerResponse && looks_like_html(input),
+    }
+}
+
+fn keys_before_error(input: &[u8]) -> Vec<String> {
+    let mut decoder = Decoder::new(input);
+    let Ok(Some(Object::Dict(mut dict))) = decoder.next_object() else {
+        return Vec::new();
+    };
+
+    let mut keys = Vec::new();
+    while let Ok(Some((key, _))) = dict.next_pair() {
+        keys.push(String::from_utf8_lossy(key).into_owned());
+    }
+    keys
+}
+
+fn looks_like_html(input: &[u8]) -> bool {
+    let start = input
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(input.len());
+    let head = input[start..input.len().min(start + 1024)].to_ascii_lowercase();
+
+    head.starts_with(b"<")
+        && [&b"<!doctype html"[..], b"<html", b"<head", b"<body"]
+            .iter()
+            .any(|tag| head.windows(tag.len()).any(|w| w == *tag))
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.len)?;
+        if self.html {
+            write!(
+                f,
+                ", which look like an HTML page rather than a tracker response"
+            )?;
+        }
+
+        if self.keys.is_empty() {
+            write!(f, "; no top-level keys")?;
+        } else {
+            let shown: Vec<&str> = self
+                .keys
+                .iter()
+                .take(MAX_KEYS)
+                .map(String::as_str)
+                .collect();
+            write!(f, "; top-level keys {:?}", shown)?;
+            if self.keys.len() > MAX_KEYS {
+                write!(f, " and {} more", self.keys.len() - MAX_KEYS)?;
+            }
+        }
+
+        if let Some(malformed) = &self.malformed {
+            write!(f, "; not valid bencode: {}", malformed)?;
+        }
+
+        write!(f, "; starts with")?;
+        for byte in &self.head {
+            write!(f, " {:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::{diagnose, from_bytes, Document};
+
+    #[derive(Debug, Deserialize)]
+    struct Required {
+        #[allow(dead_code)]
+        interval: u64,
+    }
+
+    #[test]
+    fn html_instead_of_a_tracker_response() {
+        let page = b"\r\n<!DOCTYPE html>\n<html><head><title>Tracker</title></head></html>";
+        let error = from_bytes::<Required>(page, Document::TrackerResponse).unwrap_err();
+        assert!(error.diagnosis.html);
+        assert!(error.diagnosis.keys.is_empty());
+        assert!(error.diagnosis.malformed.is_some());
+
+        let message = error.to_string();
+        assert!(
+            message.contains("66 bytes, which look like an HTML page"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("starts with 0d 0a 3c 21 44 4f 43"),
+            "{}",
+            message
+        );
+
+        // nobody expects a web page from a torrent file, so nobody is told
+        assert!(!diagnose(page, Document::Torrent).html);
+        assert!(!diagnose(b"d1:<4:htmle", Document::TrackerResponse).html);
+    }
+
+    #[test]
+    fn truncated_torrents_still_show_their_keys() {
+        let torrent =
+            b"d8:announce17:http://t/announce4:infod6:lengthi100e4:name3:abc12:piece leng";
+        let error = from_bytes::<Required>(torrent, Document::Torrent).unwrap_err();
+        assert_eq!(error.diagnosis.keys, ["announce", "info"]);
+        assert_eq!(error.diagnosis.head.len(), 64);
+        assert!(!error.diagnosis.html);
+
+        let message = error.to_string();
+        assert!(message.starts_with(&error.error), "{}", message);
+        assert!(
+            message
+                .contains("75 bytes; top-level keys [\"announce\", \"info\"]; not valid bencode"),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(" 61 62 63 31 32)"), "{}", message);
+
+        // a whole document that is missing what's needed says which keys it has instead
+        let error = from_bytes::<Required>(b"d8:completei3ee", Document::TrackerResponse)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("top-level keys [\"complete\"]; starts with"),
+            "{}",
+            error
+        );
+    }
+}
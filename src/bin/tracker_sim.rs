@@ -0,0 +1,54 @@
+//! Runs an in-memory tracker for local testing, logging every announce it answers
+
+use std::net::{IpAddr, TcpListener};
+use std::thread;
+
+use anyhow::Result;
+use clap::Parser;
+
+use rittorrent::tracker_sim::{SimConfig, TrackerSim};
+
+#[derive(Parser, Debug)]
+#[command(name = "tracker-sim")]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: IpAddr,
+
+    /// Port to listen on
+    #[arg(short, long, default_value_t = 6969)]
+    port: u16,
+
+    /// Seconds between announces to ask for
+    #[arg(long, default_value_t = SimConfig::default().interval)]
+    interval: u64,
+
+    /// Also send a `min interval` of this many seconds
+    #[arg(long)]
+    min_interval: Option<u64>,
+
+    /// Refuse every announce with this failure reason
+    #[arg(long)]
+    fail_with: Option<String>,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+
+    let listener = TcpListener::bind((args.bind, args.port))?;
+    let sim = TrackerSim::spawn(
+        listener,
+        SimConfig {
+            interval: args.interval,
+            min_interval: args.min_interval,
+            failure: args.fail_with,
+        },
+    )?;
+    println!("Announce to {}", sim.announce_url());
+
+    // the tracker runs on its own threads until the process is killed
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,152 @@
+//! Hard limits on how much piece data we download and upload, for metered connections
+//!
+//! Only piece data counts towards the caps. Requests are made and served while there is any
+//! allowance left, so a cap may be overshot by up to one block.
+
+use crate::args::Args;
+use crate::session::{MainState, PeerInfo};
+use crate::stats::Stats;
+
+/// Which of the session-wide caps have been reached
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reached {
+    pub download: bool,
+    pub upload: bool,
+}
+
+/// Why the session has nothing left to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The download finished, and we weren't asked to seed
+    Complete,
+    DownloadCap,
+    UploadCap,
+}
+
+pub fn reached(args: &Args, stats: &Stats) -> Reached {
+    Reached {
+        download: args
+            .max_download_bytes
+            .is_some_and(|cap| stats.received >= cap),
+        upload: args.max_upload_bytes.is_some_and(|cap| stats.served >= cap),
+    }
+}
+
+/// Bytes we may still request before the download cap is spoken for, counting requests that
+/// are still outstanding. [None] if there is no download cap.
+pub fn download_budget(state: &MainState) -> Option<usize> {
+    let cap = state.args.max_download_bytes?;
+    let outstanding: usize = state
+        .requested
+        .values()
+        .map(|(block, _)| block.range.len())
+        .sum();
+    Some(cap.saturating_sub(state.stats.received + outstanding))
+}
+
+/// Bytes we may still upload to `peer`, under both the session cap and the per-peer cap
+pub fn upload_allowance(args: &Args, stats: &Stats, peer: &PeerInfo) -> usize {
+    let session = args
+        .max_upload_bytes
+        .map_or(usize::MAX, |cap| cap.saturating_sub(stats.served));
+    let peer = args
+        .max_peer_upload_bytes
+        .map_or(usize::MAX, |cap| cap.saturating_sub(peer.served));
+    session.min(peer)
+}
+
+/// Whether the session should stop, and why. A finished download wins over a cap reached at
+/// the same time, and caps only stop the session with --exit-at-cap.
+pub fn should_stop(state: &MainState) -> Option<Stop> {
+    let args = &state.args;
+    if state.file.is_complete() && !args.seed && !args.seed_existing {
+        return Some(Stop::Complete);
+    }
+    if !args.exit_at_cap {
+        return None;
+    }
+
+    let reached = reached(args, &state.stats);
+    if reached.download {
+        Some(Stop::DownloadCap)
+    } else if reached.upload {
+        Some(Stop::UploadCap)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::Block;
+    use crate::test_utils::{main_state, main_state_with_disk, peer_info, settle};
+
+    use super::{download_budget, reached, should_stop, upload_allowance, Reached, Stop};
+
+    const PIECE_LEN: usize = 16384;
+
+    #[test]
+    fn allowance_is_the_tighter_cap() {
+        let (mut state, _timer_rx) = main_state(1, PIECE_LEN);
+        let (mut peer, _peer_rx) = peer_info(1);
+        assert_eq!(
+            upload_allowance(&state.args, &state.stats, &peer),
+            usize::MAX
+        );
+
+        state.args.max_upload_bytes = Some(100_000);
+        state.args.max_peer_upload_bytes = Some(40_000);
+        state.stats.served = 70_000;
+        peer.served = 10_000;
+        assert_eq!(upload_allowance(&state.args, &state.stats, &peer), 30_000);
+
+        peer.served = 50_000;
+        assert_eq!(upload_allowance(&state.args, &state.stats, &peer), 0);
+    }
+
+    #[test]
+    fn outstanding_requests_count_against_the_budget() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        assert_eq!(download_budget(&state), None);
+
+        state.args.max_download_bytes = Some(3 * PIECE_LEN);
+        state.stats.received = PIECE_LEN;
+        let block = Block::new(0, 0, &[0; PIECE_LEN]).info();
+        state
+            .requested
+            .insert(1, (block, "10.0.0.1:6881".parse().unwrap()));
+        assert_eq!(download_budget(&state), Some(PIECE_LEN));
+
+        state.stats.received = 3 * PIECE_LEN;
+        assert_eq!(download_budget(&state), Some(0));
+    }
+
+    #[test]
+    fn finishing_wins_over_caps() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(1, PIECE_LEN);
+        state.args.seed = true;
+        state.args.max_download_bytes = Some(PIECE_LEN);
+        state.args.max_upload_bytes = Some(PIECE_LEN);
+        state.stats.received = PIECE_LEN;
+        state.stats.served = PIECE_LEN;
+        assert_eq!(
+            reached(&state.args, &state.stats),
+            Reached {
+                download: true,
+                upload: true
+            }
+        );
+
+        // caps only stop the session when asked to
+        assert_eq!(should_stop(&state), None);
+        state.args.exit_at_cap = true;
+        assert_eq!(should_stop(&state), Some(Stop::DownloadCap));
+        state.args.max_download_bytes = None;
+        assert_eq!(should_stop(&state), Some(Stop::UploadCap));
+
+        state.args.seed = false;
+        state.file.write(Block::new(0, 0, &[0; PIECE_LEN])).unwrap();
+        settle(&mut state, &disk_rx);
+        assert_eq!(should_stop(&state), Some(Stop::Complete));
+    }
+}
@@ -0,0 +1,347 @@
+//! Recording every message exchanged with a peer, for debugging interop with other clients
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::peers::{Message, PeerError};
+
+const MAGIC: &[u8; 8] = b"RTCAP\0\0\x01";
+
+// how a record stores its message
+const TAG_MESSAGE: u8 = 0;
+const TAG_PIECE: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("not a capture file")]
+    BadMagic,
+
+    #[error("bad record: {0}")]
+    BadRecord(&'static str),
+
+    #[error("bad message in record: {0}")]
+    Message(#[from] PeerError),
+}
+
+type Result<T> = std::result::Result<T, CaptureError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// A message as it appears in a capture.
+/// Block payloads are replaced by their length and hash, so captures stay small.
+#[derive(Debug, PartialEq)]
+pub enum Captured {
+    Message(Message),
+    Piece {
+        index: u32,
+        begin: u32,
+        len: u32,
+        sha1: [u8; 20],
+    },
+}
+
+impl From<&Message> for Captured {
+    fn from(msg: &Message) -> Self {
+        match msg {
+            &Message::Piece(index, begin, ref data) => Captured::Piece {
+                index,
+                begin,
+                len: data.len() as u32,
+                sha1: Sha1::digest(data).into(),
+            },
+            msg => Captured::Message(msg.clone()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+
+    // time since the capture started
+    pub elapsed: Duration,
+
+    pub message: Captured,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Received => "<-",
+            Direction::Sent => "->",
+        };
+        write!(f, "{:>12.6}s {} ", self.elapsed.as_secs_f64(), arrow)?;
+
+        match &self.message {
+            Captured::Message(msg) => write!(f, "{:?}", msg),
+            Captured::Piece {
+                index,
+                begin,
+                len,
+                sha1,
+            } => {
+                write!(f, "Piece({}, {}, {} bytes, sha1 ", index, begin, len)?;
+                for byte in sha1 {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Capture of one peer connection, shared by the threads that send and receive on it
+#[derive(Debug)]
+pub struct Capture {
+    start: Instant,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    /// Starts a new capture file in `dir` for a connection to `addr`
+    pub fn create(dir: &Path, addr: SocketAddr) -> Result<Self> {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let name: String = addr
+            .to_string()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = dir.join(format!("{}-{}.rtcap", name, since_epoch.as_millis()));
+
+        Self::new(File::create(path)?, addr, since_epoch)
+    }
+
+    fn new(file: File, addr: SocketAddr, since_epoch: Duration) -> Result<Self> {
+        let mut writer = BufWriter::new(file);
+        let addr = addr.to_string();
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(since_epoch.as_millis() as u64).to_be_bytes())?;
+        writer.write_all(&(addr.len() as u16).to_be_bytes())?;
+        writer.write_all(addr.as_bytes())?;
+        writer.flush()?;
+
+        Ok(Self {
+            start: Instant::now(),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Appends a message to the capture. Each record is flushed straight away, so nothing is
+    /// lost if the process exits with the connection still open.
+    pub fn record(&self, direction: Direction, msg: &Message) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.push(direction as u8);
+        buf.extend((self.start.elapsed().as_micros() as u64).to_be_bytes());
+
+        match Captured::from(msg) {
+            Captured::Message(msg) => {
+                buf.push(TAG_MESSAGE);
+                msg.write_to(&mut buf)?;
+            }
+            Captured::Piece {
+                index,
+                begin,
+                len,
+                sha1,
+            } => {
+                buf.push(TAG_PIECE);
+                buf.extend(index.to_be_bytes());
+                buf.extend(begin.to_be_bytes());
+                buf.extend(len.to_be_bytes());
+                buf.extend(sha1);
+            }
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads a capture file back, in the order its records were written
+pub struct Reader<R: Read> {
+    reader: BufReader<R>,
+
+    /// Address of the peer the capture is of
+    pub addr: String,
+
+    /// When the capture started, as time since the UNIX epoch
+    pub started: Duration,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Result<Self> {
+        let mut reader = BufReader::new(inner);
+
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(CaptureError::BadMagic);
+        }
+
+        let started = Duration::from_millis(u64::from_be_bytes(read_array(&mut reader)?));
+        let len = u16::from_be_bytes(read_array(&mut reader)?);
+        let mut addr = vec![0u8; len as usize];
+        reader.read_exact(&mut addr)?;
+        let addr = String::from_utf8(addr).map_err(|_| CaptureError::BadRecord("address"))?;
+
+        Ok(Self {
+            reader,
+            addr,
+            started,
+        })
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // a clean end of file can only come between records
+        let mut direction = [0u8; 1];
+        match self.reader.read(&mut direction) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(e) => return Some(Err(e.into())),
+        }
+
+        Some(read_record(&mut self.reader, direction[0]))
+    }
+}
+
+fn read_record(reader: &mut BufReader<impl Read>, direction: u8) -> Result<Record> {
+    let direction = match direction {
+        0 => Direction::Received,
+        1 => Direction::Sent,
+        _ => return Err(CaptureError::BadRecord("direction")),
+    };
+    let elapsed = Duration::from_micros(u64::from_be_bytes(read_array(reader)?));
+
+    let [tag] = read_array(reader)?;
+    let message = match tag {
+        TAG_MESSAGE => Captured::Message(Message::recv(reader)?),
+        TAG_PIECE => Captured::Piece {
+            index: u32::from_be_bytes(read_array(reader)?),
+            begin: u32::from_be_bytes(read_array(reader)?),
+            len: u32::from_be_bytes(read_array(reader)?),
+            sha1: read_array(reader)?,
+        },
+        _ => return Err(CaptureError::BadRecord("tag")),
+    };
+
+    Ok(Record {
+        direction,
+        elapsed,
+        message,
+    })
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Prints a capture file in human-readable form
+pub fn print(path: &Path) -> anyhow::Result<()> {
+    let reader = Reader::new(File::open(path)?)?;
+    println!(
+        "Capture of {}, started {:.3}s after the UNIX epoch",
+        reader.addr,
+        reader.started.as_secs_f64()
+    );
+
+    for record in reader {
+        println!("{}", record?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+    use std::time::Duration;
+
+    use super::{Capture, Captured, Direction, Reader};
+    use crate::peers::Message;
+
+    #[test]
+    fn capture_round_trips() {
+        let mut file = tempfile::tempfile().unwrap();
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let capture =
+            Capture::new(file.try_clone().unwrap(), addr, Duration::from_secs(1234)).unwrap();
+
+        let messages = [
+            (Direction::Sent, Message::Interested),
+            (Direction::Received, Message::Bitfield(vec![0xff, 0x80])),
+            (Direction::Sent, Message::Request(3, 16384, 16384)),
+            (
+                Direction::Received,
+                Message::Piece(3, 16384, vec![0; 16384]),
+            ),
+            (Direction::Received, Message::Keepalive),
+        ];
+        for (direction, msg) in &messages {
+            capture.record(*direction, msg).unwrap();
+        }
+        drop(capture);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let reader = Reader::new(file).unwrap();
+        assert_eq!(reader.addr, "10.0.0.1:6881");
+        assert_eq!(reader.started, Duration::from_secs(1234));
+
+        let records: Vec<_> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), messages.len());
+        for (record, (direction, msg)) in records.iter().zip(&messages) {
+            assert_eq!(record.direction, *direction);
+            assert_eq!(record.message, Captured::from(msg));
+        }
+        assert!(records.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+
+        // the block itself is summarized rather than stored
+        assert!(matches!(
+            records[3].message,
+            Captured::Piece { len: 16384, .. }
+        ));
+        assert!(records[3].to_string().ends_with(
+            "<- Piece(3, 16384, 16384 bytes, sha1 897256b6709e1a4da9daba92b6bde39ccfccd8c1)"
+        ));
+    }
+
+    #[test]
+    fn truncated_capture_is_an_error() {
+        let mut file = tempfile::tempfile().unwrap();
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let capture = Capture::new(file.try_clone().unwrap(), addr, Duration::ZERO).unwrap();
+        capture
+            .record(Direction::Sent, &Message::Request(1, 2, 3))
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut reader = Reader::new(file).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Deciding which interested peers we upload to
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::debug;
+use rand::seq::SliceRandom;
+
+use crate::caps;
+use crate::events::EventKind;
+use crate::peers::{Message, PeerRequest};
+use crate::session::{MainState, SessionPhase};
+
+/// How often the unchoked set is re-evaluated
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of regular upload slots for an upload rate in bytes per second.
+/// Follows the usual rule of thumb of one slot per sqrt(kB/s), so slow links don't spread
+/// themselves too thin and fast ones don't sit idle.
+pub fn upload_slots(upload_rate: f64, min: usize, max: usize) -> usize {
+    let slots = (upload_rate / 1024.0).sqrt().round() as usize;
+    slots.min(max).max(min)
+}
+
+// Sends Choke or Unchoke if the peer isn't already in that state.
+// Returns false if the peer thread has gone away.
+fn set_choked(state: &mut MainState, addr: SocketAddr, choked: bool) -> bool {
+    let Some(peer_info) = state.peers.get_mut(&addr) else {
+        return true;
+    };
+    if peer_info.choked == choked {
+        return true;
+    }
+
+    peer_info.choked = choked;
+    let (msg, kind) = if choked {
+        (Message::Choke, EventKind::Choked)
+    } else {
+        (Message::Unchoke, EventKind::Unchoked)
+    };
+    let alive = peer_info.sender.send(PeerRequest::SendMessage(msg)).is_ok();
+    state.events.record(kind, Some(addr), None);
+    alive
+}
+
+/// Marks a peer as having stopped taking what we write, or as having caught up again.
+/// A congested peer is choked straight away, since more blocks would only pile up behind the
+/// ones it hasn't taken, and stays choked until it catches up.
+/// Returns false if the peer thread has gone away.
+pub fn set_congested(state: &mut MainState, addr: SocketAddr, congested: bool) -> bool {
+    let Some(peer_info) = state.peers.get_mut(&addr) else {
+        return true;
+    };
+    peer_info.congested = congested;
+    !congested || set_choked(state, addr, true)
+}
+
+// Orders peers best first for the regular upload slots.
+// While leeching that's whoever uploads to us fastest. While seeding it's whoever we can upload
+// to fastest, with ties going to the peer with fewer pieces, since it has the most to gain.
+fn rank(state: &MainState, addrs: &mut [SocketAddr]) {
+    let peers = &state.peers;
+    match state.phase {
+        SessionPhase::Leeching => addrs.sort_by(|a, b| {
+            let (a, b) = (&peers[a], &peers[b]);
+            b.upload_rate.rate().total_cmp(&a.upload_rate.rate())
+        }),
+        SessionPhase::Seeding => addrs.sort_by(|a, b| {
+            let (a, b) = (&peers[a], &peers[b]);
+            b.download_rate
+                .rate()
+                .total_cmp(&a.download_rate.rate())
+                .then(a.has.count().cmp(&b.has.count()))
+        }),
+    }
+}
+
+/// Re-evaluates how many peers we upload to and which ones.
+///
+/// The best interested peers by [rank] get the regular slots, and one more gets an extra slot.
+/// While leeching the extra slot is an optimistic unchoke at random, so new peers get a chance
+/// to prove themselves. While seeding it goes to whoever has waited longest, so every
+/// interested peer is served within as many rounds as there are interested peers.
+/// Returns the peers that turned out to have gone away.
+pub fn choke_round(state: &mut MainState) -> Vec<SocketAddr> {
+    let slots = upload_slots(
+        state.stats.upload_rate.rate(),
+        state.args.min_upload_slots,
+        state.args.max_upload_slots,
+    );
+    if slots != state.upload_slots {
+        debug!("Upload slots: {} -> {}", state.upload_slots, slots);
+        state.upload_slots = slots;
+    }
+
+    // peers we may not upload any more to stay choked, like everyone once the upload cap is hit,
+    // and so do peers that aren't taking what we already sent
+    let mut interested: Vec<SocketAddr> = state
+        .peers
+        .iter()
+        .filter(|(_, peer_info)| {
+            peer_info.peer_interested
+                && !peer_info.congested
+                && caps::upload_allowance(&state.args, &state.stats, peer_info) > 0
+        })
+        .map(|(&addr, _)| addr)
+        .collect();
+    interested.sort();
+    interested.shuffle(&mut state.rng);
+    rank(state, &mut interested);
+
+    let regular = slots.min(interested.len());
+    let mut unchoke: HashSet<SocketAddr> = interested[..regular].iter().copied().collect();
+    let rest = &interested[regular..];
+    let extra = match state.phase {
+        SessionPhase::Leeching => rest.choose(&mut state.rng),
+        SessionPhase::Seeding => rest
+            .iter()
+            .max_by_key(|&addr| state.peers[addr].choked_rounds),
+    };
+    if let Some(&addr) = extra {
+        unchoke.insert(addr);
+    }
+
+    for (addr, peer_info) in state.peers.iter_mut() {
+        if peer_info.peer_interested && !unchoke.contains(addr) {
+            peer_info.choked_rounds += 1;
+        } else {
+            peer_info.choked_rounds = 0;
+        }
+    }
+
+    let addrs: Vec<SocketAddr> = state.peers.keys().copied().collect();
+    addrs
+        .into_iter()
+        .filter(|&addr| !set_choked(state, addr, !unchoke.contains(&addr)))
+        .collect()
+}
+
+/// Unchokes a peer that just became interested straight away if there is a slot free,
+/// rather than making it wait for the next round.
+/// Returns false if the peer thread has gone away.
+pub fn unchoke_if_free(state: &mut MainState, addr: SocketAddr) -> bool {
+    let unchoked = state
+        .peers
+        .values()
+        .filter(|peer_info| peer_info.peer_interested && !peer_info.choked)
+        .count();
+    if unchoked >= state.upload_slots {
+        return true;
+    }
+    let Some(peer_info) = state.peers.get(&addr) else {
+        return true;
+    };
+    if peer_info.congested || caps::upload_allowance(&state.args, &state.stats, peer_info) == 0 {
+        return true;
+    }
+
+    set_choked(state, addr, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::peers::{Message, PeerRequest};
+    use crate::test_utils::{main_state, peer_info};
+
+    use super::{choke_round, rank, set_congested, unchoke_if_free, upload_slots};
+    use crate::session::SessionPhase;
+
+    #[test]
+    fn slots_scale_with_upload_rate() {
+        let slots: Vec<usize> = [0, 4, 16, 100, 400, 10_000]
+            .iter()
+            .map(|&kbps| upload_slots(kbps as f64 * 1024.0, 2, 20))
+            .collect();
+        assert_eq!(slots, vec![2, 2, 4, 10, 20, 20]);
+    }
+
+    #[test]
+    fn fastest_interested_peers_are_unchoked() {
+        let (mut state, _timer_rx) = main_state(1, 16384);
+        state.args.min_upload_slots = 2;
+        state.args.max_upload_slots = 2;
+
+        // four interested peers, each faster than the last, and one that isn't interested
+        let addrs: Vec<SocketAddr> = (1..=5)
+            .map(|i| format!("10.0.0.{}:6881", i).parse().unwrap())
+            .collect();
+        let mut receivers = Vec::new();
+        for (i, &addr) in addrs.iter().enumerate() {
+            let (mut peer, peer_rx) = peer_info(1);
+            peer.choked = true;
+            peer.peer_interested = i < 4;
+            peer.upload_rate.record(1000 * (i + 1));
+            peer.upload_rate
+                .advance(Instant::now() + Duration::from_secs(1));
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        assert!(choke_round(&mut state).is_empty());
+        assert_eq!(state.upload_slots, 2);
+
+        // the two fastest, plus one of the others optimistically
+        assert!(!state.peers[&addrs[3]].choked);
+        assert!(!state.peers[&addrs[2]].choked);
+        assert_eq!(
+            addrs[..2].iter().filter(|a| !state.peers[a].choked).count(),
+            1
+        );
+        assert!(state.peers[&addrs[4]].choked);
+
+        let unchoked = |rx: &crossbeam::channel::Receiver<PeerRequest>| {
+            matches!(
+                rx.try_recv(),
+                Ok(PeerRequest::SendMessage(Message::Unchoke))
+            )
+        };
+        assert!(unchoked(&receivers[3]));
+        assert!(receivers[4].try_recv().is_err());
+
+        // with every slot taken, a newly interested peer waits for the next round
+        state.peers.get_mut(&addrs[4]).unwrap().peer_interested = true;
+        assert!(unchoke_if_free(&mut state, addrs[4]));
+        assert!(state.peers[&addrs[4]].choked);
+
+        // but gets one straight away if there's room
+        state.upload_slots = 4;
+        assert!(unchoke_if_free(&mut state, addrs[4]));
+        assert!(!state.peers[&addrs[4]].choked);
+        assert!(unchoked(&receivers[4]));
+    }
+
+    #[test]
+    fn ranking_depends_on_phase() {
+        let (mut state, _timer_rx) = main_state(4, 16384);
+        let uploader: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let downloader: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let newcomer: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [uploader, downloader, newcomer] {
+            let (mut peer, peer_rx) = peer_info(4);
+            if addr == uploader {
+                peer.upload_rate.record(100_000);
+            } else if addr == downloader {
+                peer.download_rate.record(100_000);
+            }
+            if addr != newcomer {
+                for piece in 0..2 {
+                    peer.has.insert(piece);
+                }
+            }
+            peer.upload_rate
+                .advance(Instant::now() + Duration::from_secs(1));
+            peer.download_rate
+                .advance(Instant::now() + Duration::from_secs(1));
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        let mut addrs = vec![newcomer, downloader, uploader];
+        state.phase = SessionPhase::Leeching;
+        rank(&state, &mut addrs);
+        assert_eq!(addrs[0], uploader);
+
+        // the uploader and the newcomer both take nothing from us, and the newcomer has less
+        state.phase = SessionPhase::Seeding;
+        rank(&state, &mut addrs);
+        assert_eq!(addrs, vec![downloader, newcomer, uploader]);
+    }
+
+    #[test]
+    fn seeding_serves_everyone_in_turn() {
+        const PEERS: usize = 6;
+
+        let (mut state, _timer_rx) = main_state(1, 16384);
+        state.phase = SessionPhase::Seeding;
+        state.args.min_upload_slots = 1;
+        state.args.max_upload_slots = 1;
+
+        let mut receivers = Vec::new();
+        let mut addrs = Vec::new();
+        for i in 0..PEERS {
+            let addr: SocketAddr = format!("10.0.0.{}:6881", i + 1).parse().unwrap();
+            let (mut peer, peer_rx) = peer_info(1);
+            peer.peer_interested = true;
+
+            // one peer is much faster than the rest and keeps its regular slot
+            if i == 0 {
+                peer.download_rate.record(100_000);
+                peer.download_rate
+                    .advance(Instant::now() + Duration::from_secs(1));
+            }
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+            addrs.push(addr);
+        }
+
+        let mut served = vec![0; PEERS];
+        for _ in 0..PEERS - 1 {
+            assert!(choke_round(&mut state).is_empty());
+            for (i, addr) in addrs.iter().enumerate() {
+                if !state.peers[addr].choked {
+                    served[i] += 1;
+                }
+            }
+        }
+
+        // the rest took turns with the one extra slot
+        assert_eq!(served, vec![PEERS - 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn congested_peers_stay_choked_until_they_catch_up() {
+        let (mut state, _timer_rx) = main_state(1, 16384);
+        state.args.min_upload_slots = 4;
+        state.args.max_upload_slots = 4;
+
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut peer, peer_rx) = peer_info(1);
+        peer.peer_interested = true;
+        state.peers.insert(addr, peer);
+        assert!(choke_round(&mut state).is_empty());
+        assert!(!state.peers[&addr].choked);
+        while peer_rx.try_recv().is_ok() {}
+
+        assert!(set_congested(&mut state, addr, true));
+        assert!(state.peers[&addr].choked);
+        assert!(matches!(
+            peer_rx.try_recv(),
+            Ok(PeerRequest::SendMessage(Message::Choke))
+        ));
+
+        // free slots don't go to it, in a round or otherwise
+        assert!(choke_round(&mut state).is_empty());
+        assert!(unchoke_if_free(&mut state, addr));
+        assert!(state.peers[&addr].choked);
+
+        assert!(set_congested(&mut state, addr, false));
+        assert!(state.peers[&addr].choked);
+        assert!(choke_round(&mut state).is_empty());
+        assert!(!state.peers[&addr].choked);
+    }
+}
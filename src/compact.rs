@@ -0,0 +1,174 @@
+//! The compact peer encoding, shared by tracker responses (BEP 23), PEX (BEP 11) and the DHT
+//! (BEP 5)
+//!
+//! An IPv4 peer takes 6 bytes and an IPv6 peer 18: the address, then the port, both big
+//! endian, packed back to back with nothing in between. The two families are always kept in
+//! separate strings.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use thiserror::Error;
+
+/// Bytes an IPv4 peer takes up
+pub const V4_LEN: usize = 6;
+
+/// Bytes an IPv6 peer takes up
+pub const V6_LEN: usize = 18;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CompactError {
+    #[error("{len} bytes is not a whole number of {entry} byte peers")]
+    Length { len: usize, entry: usize },
+}
+
+type Result<T> = std::result::Result<T, CompactError>;
+
+fn encode(peers: impl IntoIterator<Item = SocketAddr>, v6: bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) if !v6 => bytes.extend(ip.octets()),
+            IpAddr::V6(ip) if v6 => bytes.extend(ip.octets()),
+            _ => continue,
+        }
+        bytes.extend(peer.port().to_be_bytes());
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8], entry: usize) -> Result<Vec<SocketAddr>> {
+    if !bytes.len().is_multiple_of(entry) {
+        return Err(CompactError::Length {
+            len: bytes.len(),
+            entry,
+        });
+    }
+
+    let peers = bytes
+        .chunks_exact(entry)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(entry - 2);
+            let ip: IpAddr = match ip.len() {
+                4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).into(),
+                _ => Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).into(),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes(port.try_into().unwrap()))
+        })
+        .collect();
+    Ok(peers)
+}
+
+/// Encodes the IPv4 peers in `peers`, skipping any IPv6 ones
+pub fn encode_v4(peers: impl IntoIterator<Item = SocketAddr>) -> Vec<u8> {
+    encode(peers, false)
+}
+
+/// Encodes the IPv6 peers in `peers`, skipping any IPv4 ones
+pub fn encode_v6(peers: impl IntoIterator<Item = SocketAddr>) -> Vec<u8> {
+    encode(peers, true)
+}
+
+/// Decodes a string of IPv4 peers, which must be a whole number of [V4_LEN] byte entries
+pub fn decode_v4(bytes: &[u8]) -> Result<Vec<SocketAddr>> {
+    decode(bytes, V4_LEN)
+}
+
+/// Decodes a string of IPv6 peers, which must be a whole number of [V6_LEN] byte entries
+pub fn decode_v6(bytes: &[u8]) -> Result<Vec<SocketAddr>> {
+    decode(bytes, V6_LEN)
+}
+
+/// Sorts `peers` and drops any duplicates, the canonical form some BEPs ask for so the same
+/// set of peers always encodes the same way
+pub fn canonical(peers: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let mut peers: Vec<SocketAddr> = peers.into_iter().collect();
+    peers.sort_unstable();
+    peers.dedup();
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{
+        canonical, decode_v4, decode_v6, encode_v4, encode_v6, CompactError, V4_LEN, V6_LEN,
+    };
+
+    fn random_peers(rng: &mut StdRng, v6: bool) -> Vec<SocketAddr> {
+        let count = rng.gen_range(0..50);
+        (0..count)
+            .map(|_| {
+                let ip: IpAddr = if v6 {
+                    Ipv6Addr::from(rng.gen::<[u8; 16]>()).into()
+                } else {
+                    Ipv4Addr::from(rng.gen::<[u8; 4]>()).into()
+                };
+                SocketAddr::new(ip, rng.gen())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let v4 = random_peers(&mut rng, false);
+            let v6 = random_peers(&mut rng, true);
+            let mixed = v4.iter().chain(&v6).copied();
+
+            let bytes = encode_v4(mixed.clone());
+            assert_eq!(bytes.len(), v4.len() * V4_LEN);
+            assert_eq!(decode_v4(&bytes).unwrap(), v4);
+
+            let bytes = encode_v6(mixed);
+            assert_eq!(bytes.len(), v6.len() * V6_LEN);
+            assert_eq!(decode_v6(&bytes).unwrap(), v6);
+        }
+    }
+
+    #[test]
+    fn known_encoding() {
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        assert_eq!(encode_v4([peer]), [10, 0, 0, 1, 0x1a, 0xe1]);
+
+        let peer: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let mut bytes = vec![0x20, 0x01, 0x0d, 0xb8];
+        bytes.extend([0; 11]);
+        bytes.extend([1, 0x01, 0xbb]);
+        assert_eq!(decode_v6(&bytes).unwrap(), [peer]);
+    }
+
+    #[test]
+    fn partial_entries_are_rejected() {
+        assert_eq!(decode_v4(&[]).unwrap(), []);
+        assert_eq!(
+            decode_v4(&[0; 7]),
+            Err(CompactError::Length { len: 7, entry: 6 })
+        );
+        assert_eq!(
+            decode_v6(&[0; 12]),
+            Err(CompactError::Length { len: 12, entry: 18 })
+        );
+    }
+
+    #[test]
+    fn canonical_form_is_sorted_and_unique() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..50 {
+            let mut peers = random_peers(&mut rng, false);
+            peers.extend(peers.clone());
+            peers.extend(random_peers(&mut rng, true));
+
+            let sorted = canonical(peers.clone());
+            assert!(sorted.windows(2).all(|w| w[0] < w[1]));
+            assert!(peers.iter().all(|peer| sorted.contains(peer)));
+
+            // and it doesn't depend on the order it was given things in
+            peers.reverse();
+            assert_eq!(canonical(peers), sorted);
+        }
+    }
+}
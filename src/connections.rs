@@ -1,28 +1,161 @@
+use crate::rate::TokenBucket;
+use crate::shutdown;
 use crate::threads::Response;
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use crossbeam::channel::Sender;
-use log::{info, warn};
+use log::{debug, info, warn};
 
 const CONNECTION_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub struct ConnectionData {
     pub peer: TcpStream,
+
+    // taken when the connection is made, as the socket can't be asked once it has been reset
+    pub addr: SocketAddr,
+
+    // held until the handshake with an incoming peer is done
+    pub handshake: Option<HandshakeSlot>,
+}
+
+/// Caps how many incoming connections can be in the middle of a handshake at once,
+/// so peers that connect and then say nothing can't tie up threads without bound
+#[derive(Debug)]
+pub struct HandshakeLimiter {
+    max: usize,
+    in_progress: AtomicUsize,
+
+    // connections turned away because every slot was taken
+    rejected: AtomicUsize,
+
+    // handshakes that didn't finish before the deadline
+    expired: AtomicUsize,
+
+    // handshakes for a torrent we aren't serving
+    unknown_info_hash: AtomicUsize,
 }
 
-pub fn spawn_accept_thread(listener: TcpListener, sender: Sender<Response>) {
+impl HandshakeLimiter {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            in_progress: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            expired: AtomicUsize::new(0),
+            unknown_info_hash: AtomicUsize::new(0),
+        })
+    }
+
+    /// Reserves a slot for a handshake, or returns [None] if they are all taken
+    pub fn try_acquire(self: &Arc<Self>) -> Option<HandshakeSlot> {
+        let acquired = self
+            .in_progress
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .is_ok();
+
+        if !acquired {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        Some(HandshakeSlot {
+            limiter: Arc::clone(self),
+        })
+    }
+
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn expired(&self) -> usize {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    pub fn unknown_info_hash(&self) -> usize {
+        self.unknown_info_hash.load(Ordering::Relaxed)
+    }
+}
+
+/// A handshake in progress, which gives its slot back when dropped
+#[derive(Debug)]
+pub struct HandshakeSlot {
+    limiter: Arc<HandshakeLimiter>,
+}
+
+impl HandshakeSlot {
+    /// Gives the slot back, counting the handshake as having run out of time
+    pub fn expire(self) {
+        self.limiter.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gives the slot back, counting the peer as having asked for a torrent we aren't serving
+    pub fn unknown_info_hash(self) {
+        self.limiter
+            .unknown_info_hash
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        self.limiter.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts incoming connections at no more than `accept_rate` per second.
+/// Connections beyond that wait in the kernel's backlog, and connections that arrive while
+/// every handshake slot is taken are closed straight away.
+///
+/// The thread exits once the listener is shut down or the main thread goes away.
+pub fn spawn_accept_thread(
+    listener: TcpListener,
+    sender: Sender<Response>,
+    handshakes: Arc<HandshakeLimiter>,
+    accept_rate: u32,
+) -> JoinHandle<()> {
     thread::spawn(move || {
-        for stream in listener.incoming() {
-            if let Ok(stream) = stream {
-                sender
-                    .send(Response::Connection(ConnectionData { peer: stream }))
-                    .expect("Receiver hung up!")
+        let rate = accept_rate as f64;
+        let mut bucket = TokenBucket::new(rate, rate, Instant::now());
+
+        while !shutdown::requested() {
+            while let Err(wait) = bucket.take(Instant::now()) {
+                thread::sleep(wait);
+            }
+
+            let (stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if shutdown::listener_closed(&e) => break,
+                Err(_) => continue,
+            };
+
+            let Some(slot) = handshakes.try_acquire() else {
+                debug!("Too many handshakes in progress, turning away {:?}", addr);
+                continue;
+            };
+
+            let connection = Response::Connection(ConnectionData {
+                peer: stream,
+                addr,
+                handshake: Some(slot),
+            });
+            if sender.send(connection).is_err() {
+                break;
             }
         }
-    });
+
+        debug!("Accept thread exiting");
+    })
 }
 
 pub fn async_connect(sender: Sender<Response>, addr: SocketAddr) {
@@ -30,12 +163,99 @@ pub fn async_connect(sender: Sender<Response>, addr: SocketAddr) {
         info!("Connecting to peer at {:?}", addr);
         let Ok(stream) = TcpStream::connect_timeout(&addr, CONNECTION_TIMEOUT) else {
             warn!(" --> Connection to peer at {:?} timed out", addr);
+            let _ = sender.send(Response::ConnectFailed(addr));
             return;
         };
         info!(" --> Connection successful");
 
-        sender
-            .send(Response::Connection(ConnectionData { peer: stream }))
-            .expect("Receiver hung up!");
+        // if main has gone away, dropping the stream is all there is to do
+        let _ = sender.send(Response::Connection(ConnectionData {
+            peer: stream,
+            addr,
+            handshake: None,
+        }));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crossbeam::channel;
+
+    use super::{spawn_accept_thread, HandshakeLimiter};
+    use crate::threads::Response;
+
+    #[test]
+    fn slots_are_bounded_and_returned() {
+        let limiter = HandshakeLimiter::new(2);
+
+        let a = limiter.try_acquire().unwrap();
+        let b = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+        assert_eq!(limiter.rejected(), 1);
+
+        drop(a);
+        b.expire();
+        assert_eq!(limiter.in_progress(), 0);
+        assert_eq!(limiter.expired(), 1);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn silent_connections_cannot_crowd_out_peers() {
+        const MAX: usize = 4;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel::unbounded();
+        let limiter = HandshakeLimiter::new(MAX);
+        spawn_accept_thread(listener, tx, limiter.clone(), 1000);
+
+        // a flood of connections that never say anything
+        let mut flood: Vec<TcpStream> =
+            (0..20).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        // only as many as there are slots make it through to main
+        let mut accepted = Vec::new();
+        for _ in 0..MAX {
+            match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Response::Connection(data) => accepted.push(data),
+                _ => panic!("expected a connection"),
+            }
+        }
+
+        // the rest are closed on us
+        let start = Instant::now();
+        while limiter.rejected() < flood.len() - MAX {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut closed = 0;
+        for stream in &mut flood {
+            stream
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            if let Ok(0) = stream.read(&mut [0; 1]) {
+                closed += 1;
+            }
+        }
+        assert_eq!(closed, flood.len() - MAX);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(limiter.in_progress(), MAX);
+
+        // once the stalled handshakes time out, a real peer gets through
+        for data in accepted {
+            data.handshake.unwrap().expire();
+        }
+        let _peer = TcpStream::connect(addr).unwrap();
+        let Response::Connection(data) = rx.recv_timeout(Duration::from_secs(5)).unwrap() else {
+            panic!("expected a connection");
+        };
+        assert!(data.handshake.is_some());
+        assert_eq!(limiter.expired(), MAX);
+    }
+}
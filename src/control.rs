@@ -0,0 +1,79 @@
+use std::{mem::MaybeUninit, thread};
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::Sender;
+use log::{info, warn};
+
+use crate::helpers::strerror;
+use crate::threads::Response;
+
+/// Commands that can be issued to a running session from outside
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    /// Re-verify every piece we have against the data on disk
+    Recheck,
+}
+
+/// Signal used to request a recheck of all pieces
+pub fn recheck_signal() -> libc::c_int {
+    libc::SIGRTMIN() + 1
+}
+
+/// Blocks the control signals on the calling thread and spawns a thread that waits for them,
+/// and passes them on to every session in `senders`.
+///
+/// This must be called before any other threads are spawned, since threads inherit the
+/// signal mask of their parent and we want the signals to only be delivered to [sigwait].
+///
+/// [sigwait]: libc::sigwait
+pub(crate) fn spawn_signal_thread(senders: Vec<Sender<Response>>) -> Result<()> {
+    let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+
+    // Safety: sigemptyset initializes the set, and we only assume_init after it succeeds
+    let set = unsafe {
+        if libc::sigemptyset(set.as_mut_ptr()) == -1 {
+            return Err(anyhow!("spawn_signal_thread: {}", strerror()));
+        }
+        if libc::sigaddset(set.as_mut_ptr(), recheck_signal()) == -1 {
+            return Err(anyhow!("spawn_signal_thread: {}", strerror()));
+        }
+        set.assume_init()
+    };
+
+    // Safety: set is a valid, initialized sigset_t
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if ret != 0 {
+        return Err(anyhow!(
+            "spawn_signal_thread: pthread_sigmask failed ({})",
+            ret
+        ));
+    }
+
+    thread::spawn(move || loop {
+        let mut sig: libc::c_int = 0;
+
+        // Safety: set is a valid sigset_t that outlives this call, and sig is a valid pointer
+        let ret = unsafe { libc::sigwait(&set, &mut sig) };
+        if ret != 0 {
+            warn!("Signal thread failed to wait for signals ({})", ret);
+            return;
+        }
+
+        if sig == recheck_signal() {
+            info!("Received recheck signal");
+            let sent = senders
+                .iter()
+                .filter(|sender| {
+                    sender
+                        .send(Response::Control(ControlCommand::Recheck))
+                        .is_ok()
+                })
+                .count();
+            if sent == 0 {
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
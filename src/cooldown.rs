@@ -0,0 +1,74 @@
+//! Keys we hold off on for a while, such as banned hosts and peers we shouldn't redial yet
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// When each key may be used again
+#[derive(Debug)]
+pub struct Cooldowns<K> {
+    until: HashMap<K, Instant>,
+}
+
+impl<K: Eq + Hash> Cooldowns<K> {
+    /// Holds off on `key` until `len` from `now`, replacing any cooldown it already had
+    pub fn start(&mut self, key: K, now: Instant, len: Duration) {
+        self.until.insert(key, now + len);
+    }
+
+    /// Lets `key` be used straight away
+    pub fn clear(&mut self, key: &K) {
+        self.until.remove(key);
+    }
+
+    /// How much longer `key` is held off for, if at all
+    pub fn remaining(&self, key: &K, now: Instant) -> Option<Duration> {
+        let until = *self.until.get(key)?;
+        (now < until).then(|| until - now)
+    }
+
+    pub fn is_active(&self, key: &K, now: Instant) -> bool {
+        self.remaining(key, now).is_some()
+    }
+
+    /// Forgets cooldowns that have run out
+    pub fn expire(&mut self, now: Instant) {
+        self.until.retain(|_, &mut until| now < until);
+    }
+}
+
+impl<K> Default for Cooldowns<K> {
+    fn default() -> Self {
+        Self {
+            until: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Cooldowns;
+
+    #[test]
+    fn cooldowns_run_out() {
+        let now = Instant::now();
+        let mut cooldowns = Cooldowns::default();
+        cooldowns.start("a", now, Duration::from_secs(10));
+        cooldowns.start("b", now, Duration::from_secs(20));
+
+        assert_eq!(
+            cooldowns.remaining(&"a", now + Duration::from_secs(4)),
+            Some(Duration::from_secs(6))
+        );
+        assert!(!cooldowns.is_active(&"a", now + Duration::from_secs(10)));
+        assert!(!cooldowns.is_active(&"c", now));
+
+        cooldowns.expire(now + Duration::from_secs(15));
+        assert_eq!(cooldowns.until.len(), 1);
+
+        cooldowns.clear(&"b");
+        assert!(!cooldowns.is_active(&"b", now));
+    }
+}
@@ -0,0 +1,193 @@
+//! Leaving a report behind when the session dies unexpectedly
+//!
+//! The log rarely says enough about what led up to a panic or a fatal disk error, so the main
+//! thread keeps the last few things that happened to the swarm in memory, and writes them out
+//! along with the counters, every piece's state and the configuration it ran with.
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::args::Args;
+use crate::events::SessionEvent;
+use crate::file::PieceState;
+use crate::session::MainState;
+use crate::stats::Stats;
+
+/// Milliseconds since the epoch, or 0 if the clock is set before it
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// The counters most worth having, plus the summary the log gets
+#[derive(Serialize)]
+struct StatsSnapshot {
+    uploaded: usize,
+    downloaded: usize,
+    received: usize,
+    unrequested: usize,
+    redundant: usize,
+    protocol_sent: usize,
+    protocol_received: usize,
+    seeds: usize,
+    partial_peers: usize,
+    misbehavior_bans: usize,
+    probation_events: usize,
+    summary: String,
+}
+
+impl From<&Stats> for StatsSnapshot {
+    fn from(stats: &Stats) -> Self {
+        Self {
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            received: stats.received,
+            unrequested: stats.unrequested,
+            redundant: stats.redundant,
+            protocol_sent: stats.protocol_sent,
+            protocol_received: stats.protocol_received,
+            seeds: stats.seeds,
+            partial_peers: stats.partial_peers,
+            misbehavior_bans: stats.misbehavior_bans,
+            probation_events: stats.probation_events,
+            summary: stats.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    reason: &'a str,
+    at_ms: u64,
+    stats: StatsSnapshot,
+    events: Vec<&'a SessionEvent>,
+    pieces: Vec<&'static str>,
+    config: &'a Args,
+}
+
+fn report<'a>(state: &'a MainState, reason: &'a str) -> Report<'a> {
+    let pieces = (0..state.piece_count)
+        .map(|piece| match state.file.piece_state(piece) {
+            Some(PieceState::Complete) => "complete",
+            Some(PieceState::Partial) => "partial",
+            Some(PieceState::Missing) | None => "missing",
+        })
+        .collect();
+    Report {
+        reason,
+        at_ms: now_ms(),
+        stats: (&state.stats).into(),
+        events: state.events.iter().collect(),
+        pieces,
+        config: &state.args,
+    }
+}
+
+/// Writes a report on `state` to `crash-<timestamp>.json` in the state directory, saying the
+/// session died because of `reason`. Returns where it went, or None if it couldn't be written,
+/// which is only logged since we are already on the way out.
+pub fn dump(state: &MainState, reason: &str) -> Option<PathBuf> {
+    let report = report(state, reason);
+    let path = state
+        .args
+        .state_dir()
+        .join(format!("crash-{}.json", report.at_ms));
+    let written = serde_json::to_vec_pretty(&report)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+    match written {
+        Ok(()) => {
+            error!("Wrote a crash report to {}", path.display());
+            Some(path)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to write a crash report to {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+    use serde_json::Value;
+
+    use super::dump;
+    use crate::args::Args;
+    use crate::events::{EventKind, EVENT_CAPACITY};
+    use crate::test_utils::main_state;
+
+    #[test]
+    fn reports_describe_the_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut state, _timer_rx) = main_state(3, 16384);
+        state.args = Args::parse_from([
+            "rittorrent",
+            "--torrent",
+            "x.torrent",
+            "--state-dir",
+            dir.path().to_str().unwrap(),
+        ]);
+        state.stats.downloaded = 16384;
+
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        state.events.record(EventKind::PeerAdded, Some(addr), None);
+        for piece in 0..EVENT_CAPACITY {
+            state
+                .events
+                .record(EventKind::PieceFailed, None, Some(piece));
+        }
+        state
+            .events
+            .record_detail(EventKind::AnnounceFailed, "http://t/announce".to_owned());
+
+        let path = dump(&state, "panic").unwrap();
+        assert_eq!(path.parent().unwrap(), dir.path());
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("crash-") && name.ends_with(".json"));
+
+        let report: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(report["reason"], "panic");
+        assert!(report["at_ms"].is_u64());
+        assert_eq!(report["stats"]["downloaded"], 16384);
+        assert!(report["stats"]["summary"].is_string());
+        assert_eq!(
+            report["pieces"],
+            serde_json::json!(["missing", "missing", "missing"])
+        );
+        assert_eq!(
+            report["config"]["torrent"],
+            serde_json::json!(["x.torrent"])
+        );
+        assert_eq!(report["config"]["max_connections"], 10);
+
+        // only the most recent events are kept, oldest first
+        let events = report["events"].as_array().unwrap();
+        assert_eq!(events.len(), EVENT_CAPACITY);
+        assert_eq!(events[0]["kind"], "piece_failed");
+        assert_eq!(events[0]["piece"], 1);
+        assert!(events[0]["peer"].is_null());
+        assert!(events[0]["at_ms"].is_u64());
+        let last = &events[EVENT_CAPACITY - 1];
+        assert_eq!(last["kind"], "announce_failed");
+        assert_eq!(last["detail"], "http://t/announce");
+    }
+
+    #[test]
+    fn unwritable_reports_are_only_logged() {
+        let (mut state, _timer_rx) = main_state(1, 16384);
+        state.args.state_dir = Some("/nonexistent/state".into());
+        assert_eq!(dump(&state, "disk"), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! Pacing how fast we dial peers, so a tracker response with dozens of peers in it doesn't
+//! turn into dozens of connection attempts at once
+//!
+//! Peers from trackers wait in a queue, and only so many are dialed per round. The session
+//! starts a new round every second.
+
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+
+/// Most peers kept waiting to be dialed. Trackers hand the rest out again soon enough.
+const MAX_CANDIDATES: usize = 1000;
+
+/// What to make of a peer that is up to be dialed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Candidate {
+    /// Nothing against it
+    Fresh,
+    /// It failed or dropped us lately, though we may dial it again
+    Failed,
+    /// It may not be dialed, as we're connected to it or holding off on it
+    Skip,
+}
+
+/// Peers waiting to be dialed, in the order they were handed to us
+#[derive(Debug, Default)]
+pub struct DialQueue {
+    candidates: VecDeque<SocketAddr>,
+    queued: HashSet<SocketAddr>,
+
+    // peers dialed since the round started
+    dialed: usize,
+}
+
+impl DialQueue {
+    /// Queues `addr` unless it is queued already or the queue is full.
+    /// Returns whether it was queued.
+    pub fn push(&mut self, addr: SocketAddr) -> bool {
+        if self.candidates.len() >= MAX_CANDIDATES || !self.queued.insert(addr) {
+            return false;
+        }
+        self.candidates.push_back(addr);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Starts a new round of dialing
+    pub fn new_round(&mut self) {
+        self.dialed = 0;
+    }
+
+    /// Takes the next peer to dial, unless `limit` have been dialed this round already.
+    /// That is the first one `judge` has nothing against, or failing that the first that may
+    /// be dialed at all. Peers that may not be dialed are dropped from the queue.
+    pub fn next(
+        &mut self,
+        limit: usize,
+        judge: impl Fn(SocketAddr) -> Candidate,
+    ) -> Option<SocketAddr> {
+        if self.dialed >= limit {
+            return None;
+        }
+
+        let queued = &mut self.queued;
+        let mut failed = None;
+        let mut fresh = None;
+        self.candidates.retain(|&addr| match judge(addr) {
+            Candidate::Fresh => {
+                fresh = fresh.or(Some(addr));
+                true
+            }
+            Candidate::Failed => {
+                failed = failed.or(Some(addr));
+                true
+            }
+            Candidate::Skip => {
+                queued.remove(&addr);
+                false
+            }
+        });
+
+        let addr = fresh.or(failed)?;
+        self.candidates.retain(|&a| a != addr);
+        self.queued.remove(&addr);
+        self.dialed += 1;
+        Some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Candidate, DialQueue, MAX_CANDIDATES};
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 6881))
+    }
+
+    #[test]
+    fn a_round_dials_no_more_than_the_limit() {
+        let mut queue = DialQueue::default();
+        for i in 0..12 {
+            assert!(queue.push(addr(i)));
+        }
+        assert!(!queue.push(addr(3)));
+        assert_eq!(queue.len(), 12);
+
+        let mut rounds = Vec::new();
+        for _ in 0..4 {
+            queue.new_round();
+            let round: Vec<SocketAddr> =
+                std::iter::from_fn(|| queue.next(5, |_| Candidate::Fresh)).collect();
+            rounds.push(round);
+        }
+        assert_eq!(rounds[0], (0..5).map(addr).collect::<Vec<_>>());
+        assert_eq!(rounds[1], (5..10).map(addr).collect::<Vec<_>>());
+        assert_eq!(rounds[2], (10..12).map(addr).collect::<Vec<_>>());
+        assert!(rounds[3].is_empty());
+        assert_eq!(queue.len(), 0);
+
+        // once dialed, a peer can be queued again
+        assert!(queue.push(addr(3)));
+    }
+
+    #[test]
+    fn peers_that_failed_lately_wait_for_the_rest() {
+        let mut queue = DialQueue::default();
+        for i in 0..6 {
+            queue.push(addr(i));
+        }
+        let judge = |a: SocketAddr| match a {
+            _ if a == addr(0) || a == addr(2) => Candidate::Failed,
+            _ if a == addr(4) => Candidate::Skip,
+            _ => Candidate::Fresh,
+        };
+
+        let order: Vec<SocketAddr> = std::iter::from_fn(|| queue.next(10, judge)).collect();
+        assert_eq!(order, [1, 3, 5, 0, 2].map(addr));
+
+        // skipped peers are dropped, so they can come back from the next tracker response
+        assert_eq!(queue.len(), 0);
+        assert!(queue.push(addr(4)));
+    }
+
+    #[test]
+    fn the_queue_is_bounded() {
+        let mut queue = DialQueue::default();
+        for i in 0..MAX_CANDIDATES {
+            assert!(queue.push(addr(i)));
+        }
+        assert!(!queue.push(addr(MAX_CANDIDATES)));
+        assert_eq!(queue.len(), MAX_CANDIDATES);
+    }
+}
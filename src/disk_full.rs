@@ -0,0 +1,84 @@
+//! Waiting for space to be freed once the disk fills up
+//!
+//! A write that fails for want of space throws away its piece, and the session stops
+//! requesting and tells every peer it is no longer interested, rather than downloading blocks
+//! it has nowhere to put. It then asks the disk thread how much space is free every so often,
+//! waiting longer each time it is still full, and carries on once there is room for a whole
+//! piece again.
+
+use std::time::{Duration, Instant};
+
+/// How long after the disk filled up to first check whether space has been freed
+pub const FIRST_PROBE: Duration = Duration::from_secs(5);
+
+/// Longest wait between checks, however long the disk has been full
+pub const MAX_PROBE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// When to next check for free space
+#[derive(Debug)]
+pub struct DiskFull {
+    interval: Duration,
+    next_probe: Instant,
+
+    // whether a check has been asked for and not answered
+    probing: bool,
+}
+
+impl DiskFull {
+    /// The disk filled up at `now`
+    pub fn new(now: Instant) -> Self {
+        Self {
+            interval: FIRST_PROBE,
+            next_probe: now + FIRST_PROBE,
+            probing: false,
+        }
+    }
+
+    /// Whether it is time to check for free space, in which case the check counts as started.
+    /// Only one check is out at a time.
+    pub fn probe_due(&mut self, now: Instant) -> bool {
+        if self.probing || now < self.next_probe {
+            return false;
+        }
+        self.probing = true;
+        true
+    }
+
+    /// Takes note that a check found too little space, and waits twice as long for the next
+    pub fn still_full(&mut self, now: Instant) {
+        self.probing = false;
+        self.interval = (self.interval * 2).min(MAX_PROBE_INTERVAL);
+        self.next_probe = now + self.interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{DiskFull, FIRST_PROBE, MAX_PROBE_INTERVAL};
+
+    #[test]
+    fn checks_back_off_while_the_disk_stays_full() {
+        let start = Instant::now();
+        let mut full = DiskFull::new(start);
+        assert!(!full.probe_due(start));
+        assert!(full.probe_due(start + FIRST_PROBE));
+
+        // one check at a time, however long it takes to come back
+        assert!(!full.probe_due(start + FIRST_PROBE * 10));
+
+        let mut now = start + FIRST_PROBE;
+        let mut waits = Vec::new();
+        for _ in 0..8 {
+            full.still_full(now);
+            let wait = full.next_probe - now;
+            assert!(!full.probe_due(now + wait - Duration::from_millis(1)));
+            now += wait;
+            assert!(full.probe_due(now));
+            waits.push(wait.as_secs());
+        }
+        assert_eq!(waits, [10, 20, 40, 80, 160, 300, 300, 300]);
+        assert_eq!(full.interval, MAX_PROBE_INTERVAL);
+    }
+}
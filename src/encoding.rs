@@ -0,0 +1,193 @@
+//! Decoding names from torrents made by clients that didn't use UTF-8, and making file names
+//! out of them
+//!
+//! Only UTF-8 and the Western European single-byte encodings are understood. Anything else is
+//! left for the caller to fall back on [transliterate].
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DecodeError {
+    #[error("unsupported encoding {0:?}")]
+    Unsupported(String),
+
+    #[error("not valid {0}")]
+    Invalid(String),
+}
+
+// What windows-1252 puts in 0x80..=0xA0, where ISO-8859-1 has control characters.
+// The five bytes it leaves undefined map to those control characters, as browsers do.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Utf8,
+    Latin1,
+    Windows1252,
+}
+
+// Matches the labels clients actually write, ignoring case and punctuation
+fn lookup(label: &str) -> Option<Encoding> {
+    let label: String = label
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    match label.as_str() {
+        "utf8" => Some(Encoding::Utf8),
+        "iso88591" | "latin1" | "l1" | "iso8859" => Some(Encoding::Latin1),
+        "windows1252" | "cp1252" => Some(Encoding::Windows1252),
+        _ => None,
+    }
+}
+
+fn windows_1252(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+/// Decodes `bytes` as the encoding named by `label`, such as `UTF-8` or `ISO-8859-1`
+pub fn decode(bytes: &[u8], label: &str) -> Result<String, DecodeError> {
+    match lookup(label) {
+        Some(Encoding::Utf8) => std::str::from_utf8(bytes)
+            .map(str::to_owned)
+            .map_err(|_| DecodeError::Invalid(label.to_owned())),
+        // every byte is a code point of the same value
+        Some(Encoding::Latin1) => Ok(bytes.iter().map(|&b| b as char).collect()),
+        Some(Encoding::Windows1252) => Ok(bytes.iter().map(|&b| windows_1252(b)).collect()),
+        None => Err(DecodeError::Unsupported(label.to_owned())),
+    }
+}
+
+// The closest ASCII to a character, if there's an obvious one
+fn fold(c: char) -> Option<&'static str> {
+    Some(match c {
+        'À'..='Å' => "A",
+        'à'..='å' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' => "C",
+        'ç' => "c",
+        'È'..='Ë' => "E",
+        'è'..='ë' => "e",
+        'Ì'..='Ï' => "I",
+        'ì'..='ï' => "i",
+        'Ð' => "D",
+        'ð' => "d",
+        'Ñ' => "N",
+        'ñ' => "n",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'ò'..='ö' | 'ø' => "o",
+        'Ù'..='Ü' => "U",
+        'ù'..='ü' => "u",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Þ' => "TH",
+        'þ' => "th",
+        'ß' => "ss",
+        'Š' => "S",
+        'š' => "s",
+        'Ž' => "Z",
+        'ž' => "z",
+        'Œ' => "OE",
+        'œ' => "oe",
+        '‘' | '’' | '‚' => "'",
+        '“' | '”' | '„' => "\"",
+        '–' | '—' => "-",
+        '…' => "...",
+        _ => return None,
+    })
+}
+
+/// Makes an ASCII name out of bytes in an unknown encoding, for when nothing better is possible.
+///
+/// The bytes are guessed to be windows-1252, which most old Western clients used. Accented
+/// letters lose their accents, and whatever has no ASCII equivalent becomes `_`.
+pub fn transliterate(bytes: &[u8]) -> String {
+    let mut name = String::with_capacity(bytes.len());
+    for c in bytes.iter().map(|&b| windows_1252(b)) {
+        match fold(c) {
+            _ if c.is_ascii() => name.push(c),
+            Some(ascii) => name.push_str(ascii),
+            None => name.push('_'),
+        }
+    }
+    name
+}
+
+/// Makes `name` safe to use as a single file name: path separators and control characters
+/// become `_`, and names that would refer to a directory are replaced.
+pub fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match name.as_str() {
+        "" | "." | ".." => "_".to_owned(),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, sanitize_file_name, transliterate, DecodeError};
+
+    #[test]
+    fn single_byte_encodings() {
+        let bytes = b"Caf\xe9 \x93cr\xe8me\x94";
+        assert_eq!(
+            decode(bytes, "ISO-8859-1").unwrap(),
+            "Café \u{93}crème\u{94}"
+        );
+        assert_eq!(decode(bytes, "latin1").unwrap(), "Café \u{93}crème\u{94}");
+        assert_eq!(decode(bytes, "cp1252").unwrap(), "Café “crème”");
+        assert_eq!(decode(bytes, "Windows-1252").unwrap(), "Café “crème”");
+    }
+
+    #[test]
+    fn utf8_and_unknown_encodings() {
+        assert_eq!(decode("Café".as_bytes(), "UTF-8").unwrap(), "Café");
+        assert_eq!(decode("Café".as_bytes(), "utf8").unwrap(), "Café");
+        assert_eq!(
+            decode(b"Caf\xe9", "UTF-8"),
+            Err(DecodeError::Invalid("UTF-8".to_owned()))
+        );
+        assert_eq!(
+            decode(b"\x83e\x83X\x83g", "Shift_JIS"),
+            Err(DecodeError::Unsupported("Shift_JIS".to_owned()))
+        );
+    }
+
+    #[test]
+    fn transliteration_is_ascii() {
+        assert_eq!(transliterate(b"Caf\xe9 cr\xe8me.txt"), "Cafe creme.txt");
+        assert_eq!(
+            transliterate(b"Stra\xdfe \x96 \x93\xc6\x94"),
+            "Strasse - \"AE\""
+        );
+        // shift_jis, which we can't decode, still comes out as something usable
+        assert_eq!(transliterate(b"\x83e\x83X\x83g.avi"), "_e_X_g.avi");
+        assert!(transliterate(&(0..=255).collect::<Vec<u8>>()).is_ascii());
+    }
+
+    #[test]
+    fn file_names_stay_in_their_directory() {
+        assert_eq!(sanitize_file_name("Café.txt"), "Café.txt");
+        assert_eq!(sanitize_file_name("../etc/passwd"), ".._etc_passwd");
+        assert_eq!(sanitize_file_name("a\\b\0c\nd"), "a_b_c_d");
+        assert_eq!(sanitize_file_name(".."), "_");
+        assert_eq!(sanitize_file_name("."), "_");
+        assert_eq!(sanitize_file_name(""), "_");
+    }
+}
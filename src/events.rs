@@ -0,0 +1,225 @@
+//! What happens during a session, as kept for crash reports and handed to embedders
+//!
+//! The main thread records each event once. The last [EVENT_CAPACITY] are kept for a crash
+//! report, and every [Subscription] taken with [Session::subscribe] gets its own copy. A
+//! subscriber that falls behind never holds up the main thread: once its queue is full, the
+//! oldest event in it is dropped to make room, and counted in [Subscription::dropped].
+//!
+//! [Session::subscribe]: crate::session::Session::subscribe
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use serde::Serialize;
+
+use crate::crash::now_ms;
+
+/// How many of the most recent events a crash report includes
+pub(crate) const EVENT_CAPACITY: usize = 100;
+
+/// What sort of thing happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum EventKind {
+    /// A connection to a peer was set up, with the peer in [SessionEvent::peer]
+    PeerAdded,
+    PeerRemoved,
+
+    /// A piece matched its hash, with the piece in [SessionEvent::piece]
+    PieceVerified,
+
+    /// A piece failed its hash check, or was lost before it reached the disk
+    PieceFailed,
+
+    /// A tracker answered, with the tracker and how many peers it gave in
+    /// [SessionEvent::detail]
+    Announced,
+    AnnounceFailed,
+    Choked,
+    Unchoked,
+
+    /// Every piece is verified, and the session is seeding
+    Completed,
+
+    /// The session gave up, with why in [SessionEvent::detail]
+    Fatal,
+}
+
+/// Something that happened during a session, which is worth knowing about when piecing
+/// together how it died
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SessionEvent {
+    pub kind: EventKind,
+    pub peer: Option<SocketAddr>,
+    pub piece: Option<usize>,
+
+    /// Anything else there is to say, such as which tracker was announced to
+    pub detail: Option<String>,
+
+    /// Milliseconds since the epoch
+    pub at_ms: u64,
+}
+
+/// Events as they happen, from [Session::subscribe]
+///
+/// [Session::subscribe]: crate::session::Session::subscribe
+#[derive(Debug)]
+pub struct Subscription {
+    rx: Receiver<SessionEvent>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Subscription {
+    /// Waits for the next event. Returns None once the session is over and every event it
+    /// recorded has been taken.
+    pub fn recv(&self) -> Option<SessionEvent> {
+        self.rx.recv().ok()
+    }
+
+    /// Like [Subscription::recv], giving up after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<SessionEvent> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+
+    /// Takes the next event, if there is one waiting
+    pub fn try_recv(&self) -> Option<SessionEvent> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Takes every event waiting
+    pub fn try_iter(&self) -> impl Iterator<Item = SessionEvent> + '_ {
+        self.rx.try_iter()
+    }
+
+    /// How many events were dropped because they weren't taken in time
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// The session's end of a [Subscription]. It keeps a receiver of its own to drop the oldest
+// event with when the queue is full.
+#[derive(Debug)]
+struct Subscriber {
+    tx: Sender<SessionEvent>,
+    rx: Receiver<SessionEvent>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Subscriber {
+    // Queues `event`, dropping the oldest to make room if need be. Returns false once the
+    // subscription has been dropped.
+    fn send(&self, mut event: SessionEvent) -> bool {
+        if Arc::strong_count(&self.dropped) == 1 {
+            return false;
+        }
+        loop {
+            match self.tx.try_send(event) {
+                Ok(()) => return true,
+                Err(TrySendError::Full(back)) => {
+                    // the subscriber may have made room in the meantime, which is as good
+                    if self.rx.try_recv().is_ok() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    event = back;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+    }
+}
+
+/// The most recent [EVENT_CAPACITY] events, and everyone subscribed to them
+#[derive(Debug, Default)]
+pub(crate) struct Events {
+    events: VecDeque<SessionEvent>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Events {
+    /// Starts handing events to a new [Subscription], which holds up to `capacity` of them
+    pub fn subscribe(&mut self, capacity: usize) -> Subscription {
+        let (tx, rx) = channel::bounded(capacity.max(1));
+        let dropped = Arc::default();
+        self.subscribers.push(Subscriber {
+            tx,
+            rx: rx.clone(),
+            dropped: Arc::clone(&dropped),
+        });
+        Subscription { rx, dropped }
+    }
+
+    pub fn record(&mut self, kind: EventKind, peer: Option<SocketAddr>, piece: Option<usize>) {
+        self.push(kind, peer, piece, None);
+    }
+
+    pub fn record_detail(&mut self, kind: EventKind, detail: String) {
+        self.push(kind, None, None, Some(detail));
+    }
+
+    fn push(
+        &mut self,
+        kind: EventKind,
+        peer: Option<SocketAddr>,
+        piece: Option<usize>,
+        detail: Option<String>,
+    ) {
+        let event = SessionEvent {
+            kind,
+            peer,
+            piece,
+            detail,
+            at_ms: now_ms(),
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()));
+
+        if self.events.len() == EVENT_CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SessionEvent> {
+        self.events.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventKind, Events};
+
+    #[test]
+    fn slow_subscribers_lose_the_oldest_events() {
+        let mut events = Events::default();
+        let slow = events.subscribe(3);
+        let quick = events.subscribe(3);
+        let gone = events.subscribe(3);
+        drop(gone);
+
+        for piece in 0..5 {
+            events.record(EventKind::PieceVerified, None, Some(piece));
+            if piece % 2 == 0 {
+                assert_eq!(quick.try_iter().count(), 1 + (piece > 0) as usize);
+            }
+        }
+        assert_eq!(quick.dropped(), 0);
+        assert_eq!(events.subscribers.len(), 2);
+
+        let pieces: Vec<usize> = slow.try_iter().filter_map(|e| e.piece).collect();
+        assert_eq!(pieces, [2, 3, 4]);
+        assert_eq!(slow.dropped(), 2);
+        assert_eq!(events.iter().count(), 5);
+
+        // once the session is gone, so is anything more to wait for
+        events.record_detail(EventKind::Fatal, "disk".to_owned());
+        drop(events);
+        assert_eq!(slow.recv().unwrap().detail.as_deref(), Some("disk"));
+        assert_eq!(slow.recv(), None);
+    }
+}
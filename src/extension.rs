@@ -0,0 +1,307 @@
+//! The extension protocol (BEP 10), and the ut_metadata extension on top of it (BEP 9), which
+//! lets peers that only have a magnet link fetch the info dictionary from us
+
+use std::collections::BTreeMap;
+
+use bendy::serde::{from_bytes, to_bytes};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Extended message id of the extension handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Extended message id peers use to send us ut_metadata messages, as our handshake tells them
+pub const UT_METADATA_ID: u8 = 1;
+
+/// Metadata is sent in pieces of this size, except for the last
+pub const METADATA_PIECE_LEN: usize = 16384;
+
+// ut_metadata message types
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("invalid bencoding: {0}")]
+    Bencode(String),
+
+    #[error("unknown ut_metadata message type {0}")]
+    UnknownType(u8),
+
+    #[error("ut_metadata data message without total_size")]
+    MissingSize,
+}
+
+type Result<T> = std::result::Result<T, ExtensionError>;
+
+/// The extension handshake, as far as we care about it.
+/// Anything else peers put in theirs is ignored.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Handshake {
+    /// Extension names, mapped to the extended message id the sender wants them sent with.
+    /// An id of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// Length of the info dictionary, or 0 if the sender doesn't have it
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub metadata_size: usize,
+
+    /// Port the sender listens on, or 0 if it didn't say. Kept wide, so a nonsense port
+    /// doesn't make the rest of the handshake unreadable.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub p: i64,
+}
+
+impl Handshake {
+    /// Our own handshake, offering `metadata` over ut_metadata if we have any
+    pub fn ours(metadata: &[u8]) -> Self {
+        Self {
+            m: BTreeMap::from([("ut_metadata".to_owned(), UT_METADATA_ID)]),
+            metadata_size: metadata.len(),
+            p: 0,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        to_bytes(self).unwrap()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        from_bytes(payload).map_err(|e| ExtensionError::Bencode(e.to_string()))
+    }
+
+    /// The id the sender wants ut_metadata messages sent with, if it supports the extension
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m.get("ut_metadata").copied().filter(|&id| id != 0)
+    }
+
+    /// The port the sender listens for connections on, if it said
+    pub fn listen_port(&self) -> Option<u16> {
+        u16::try_from(self.p).ok().filter(|&port| port != 0)
+    }
+}
+
+/// A ut_metadata message
+#[derive(Debug, PartialEq)]
+pub enum MetadataMessage {
+    Request(usize),
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(usize),
+}
+
+// The dictionary at the start of every ut_metadata message, with keys in bencode order.
+// Only data messages have a total_size, and metadata is never empty.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    total_size: usize,
+}
+
+// bendy would encode an Option as a list, so absent fields are zero instead
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (header, data) = match self {
+            &MetadataMessage::Request(piece) => (
+                Header {
+                    msg_type: REQUEST,
+                    piece,
+                    total_size: 0,
+                },
+                &[][..],
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                Header {
+                    msg_type: DATA,
+                    piece: *piece,
+                    total_size: *total_size,
+                },
+                &data[..],
+            ),
+            &MetadataMessage::Reject(piece) => (
+                Header {
+                    msg_type: REJECT,
+                    piece,
+                    total_size: 0,
+                },
+                &[][..],
+            ),
+        };
+
+        let mut buf = to_bytes(&header).unwrap();
+        buf.extend(data);
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let len = bencode_len(payload)
+            .ok_or_else(|| ExtensionError::Bencode("truncated dictionary".to_owned()))?;
+        let (header, data) = payload.split_at(len);
+        let header: Header =
+            from_bytes(header).map_err(|e| ExtensionError::Bencode(e.to_string()))?;
+
+        match header.msg_type {
+            REQUEST => Ok(MetadataMessage::Request(header.piece)),
+            DATA => Ok(MetadataMessage::Data {
+                piece: header.piece,
+                total_size: match header.total_size {
+                    0 => return Err(ExtensionError::MissingSize),
+                    size => size,
+                },
+                data: data.to_vec(),
+            }),
+            REJECT => Ok(MetadataMessage::Reject(header.piece)),
+            other => Err(ExtensionError::UnknownType(other)),
+        }
+    }
+}
+
+/// Answers a request for a piece of `metadata`, rejecting pieces past the end of it
+pub fn serve(metadata: &[u8], piece: usize) -> MetadataMessage {
+    let start = piece.saturating_mul(METADATA_PIECE_LEN);
+    if start >= metadata.len() {
+        return MetadataMessage::Reject(piece);
+    }
+
+    let end = metadata.len().min(start + METADATA_PIECE_LEN);
+    MetadataMessage::Data {
+        piece,
+        total_size: metadata.len(),
+        data: metadata[start..end].to_vec(),
+    }
+}
+
+// Length of the bencoded value at the start of `data`. A ut_metadata data message has the
+// piece itself tacked on after its dictionary, so the two have to be told apart.
+fn bencode_len(data: &[u8]) -> Option<usize> {
+    match *data.first()? {
+        b'i' => Some(data.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while *data.get(pos)? != b'e' {
+                pos += bencode_len(&data[pos..])?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = data.iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&data[..colon]).ok()?.parse().ok()?;
+            let end = (colon + 1).checked_add(len)?;
+            (end <= data.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bencode_len, serve, Handshake, MetadataMessage, METADATA_PIECE_LEN};
+
+    #[test]
+    fn metadata_messages_match_the_spec() {
+        let request = MetadataMessage::Request(0);
+        assert_eq!(request.encode(), b"d8:msg_typei0e5:piecei0ee");
+
+        let reject = MetadataMessage::Reject(3);
+        assert_eq!(reject.encode(), b"d8:msg_typei2e5:piecei3ee");
+
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            data: b"d4:name".to_vec(),
+        };
+        assert_eq!(
+            data.encode(),
+            b"d8:msg_typei1e5:piecei1e10:total_sizei20000eed4:name"
+        );
+
+        for msg in [request, reject, data] {
+            assert_eq!(MetadataMessage::decode(&msg.encode()).unwrap(), msg);
+        }
+
+        assert!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei1e5:piecei0ee").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei0e5:piece").is_err());
+    }
+
+    #[test]
+    fn handshakes_ignore_what_we_dont_speak() {
+        // roughly what libtorrent sends
+        let theirs = Handshake::decode(
+            b"d12:complete_agoi-1e1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei5717e4:reqqi500e11:upload_onlyi0e1:v17:qBittorrent/4.5.26:yourip4:\x7f\0\0\x01e",
+        )
+        .unwrap();
+        assert_eq!(theirs.ut_metadata(), Some(2));
+        assert_eq!(theirs.metadata_size, 5717);
+        assert_eq!(theirs.listen_port(), None);
+
+        let listening = Handshake::decode(b"d1:md11:ut_metadatai2ee1:pi6881ee").unwrap();
+        assert_eq!(listening.listen_port(), Some(6881));
+        for nonsense in [&b"d1:pi0ee"[..], b"d1:pi70000ee", b"d1:pi-1ee"] {
+            assert_eq!(Handshake::decode(nonsense).unwrap().listen_port(), None);
+        }
+
+        let disabled = Handshake::decode(b"d1:md11:ut_metadatai0eee").unwrap();
+        assert_eq!(disabled.ut_metadata(), None);
+
+        let ours = Handshake::ours(&[0; 100]);
+        assert_eq!(
+            ours.encode(),
+            b"d1:md11:ut_metadatai1ee13:metadata_sizei100ee"
+        );
+        assert_eq!(Handshake::decode(&ours.encode()).unwrap(), ours);
+        assert_eq!(Handshake::ours(&[]).encode(), b"d1:md11:ut_metadatai1eee");
+    }
+
+    #[test]
+    fn serving_splits_into_pieces() {
+        let metadata: Vec<u8> = (0..METADATA_PIECE_LEN * 2 + 10).map(|i| i as u8).collect();
+
+        let pieces: Vec<Vec<u8>> = (0..3)
+            .map(|piece| match serve(&metadata, piece) {
+                MetadataMessage::Data {
+                    total_size, data, ..
+                } => {
+                    assert_eq!(total_size, metadata.len());
+                    data
+                }
+                msg => panic!("unexpected {:?}", msg),
+            })
+            .collect();
+        assert_eq!(pieces[2].len(), 10);
+        assert_eq!(pieces.concat(), metadata);
+
+        assert_eq!(serve(&metadata, 3), MetadataMessage::Reject(3));
+        assert_eq!(
+            serve(&metadata, usize::MAX),
+            MetadataMessage::Reject(usize::MAX)
+        );
+        assert_eq!(serve(&[], 0), MetadataMessage::Reject(0));
+    }
+
+    #[test]
+    fn bencode_len_finds_the_end_of_a_value() {
+        assert_eq!(bencode_len(b"i42etrailing"), Some(4));
+        assert_eq!(bencode_len(b"4:spamtrailing"), Some(6));
+        assert_eq!(bencode_len(b"d1:ali1ei2eee1:b"), Some(13));
+        assert_eq!(bencode_len(b"d1:ali1e"), None);
+        assert_eq!(bencode_len(b"9:short"), None);
+        assert_eq!(bencode_len(b"99999999999999999999999:x"), None);
+        assert_eq!(bencode_len(b"x"), None);
+    }
+}
@@ -1,71 +1,444 @@
+//! Assembling a torrent's data on disk from blocks, and verifying it piece by piece
+
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    ops::Range,
-    path::Path,
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    ops::{Deref, Range},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use bitvec::prelude::*;
-use sha1::{Digest, Sha1};
-
-use anyhow::{bail, Result};
+use crossbeam::channel::{self, Sender};
+use log::{info, warn};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::hash::PieceHasher;
+use crate::mapped::Mapped;
+use crate::piece_cache::PieceCache;
+use crate::positioned::FileExt;
+use crate::reader::{Prefix, PrefixReader};
+use crate::resume::{ResumeData, ResumeError};
+use crate::threads::Response;
+use crate::units::{format_size, parse_duration};
 
 const DIGEST_SIZE: usize = 20;
 const BLOCK_SIZE: usize = 16384;
 
+// how much [DownloadFile::preallocate] reserves between progress reports
+const PREALLOCATE_CHUNK: usize = 1024 * 1024 * 1024;
+
+/// How many pieces [DownloadFile] assembles in memory at once, unless told otherwise
+pub const DEFAULT_CACHE_PIECES: usize = 16;
+
+// Longest piece assembled in memory. Longer pieces always go straight to disk a block at a
+// time, so a cache of a few pieces never costs more than a few times this.
+const MAX_CACHED_PIECE_LEN: usize = 4 * 1024 * 1024;
+
+/// How many bytes of recently read or completed pieces [DownloadFile] keeps to serve blocks
+/// from, unless told otherwise
+pub const DEFAULT_READ_CACHE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Largest block [DownloadFile] takes in or hands out, unless told otherwise. Peers ask for
+/// 16KiB, and nobody sends more than 128KiB.
+pub const DEFAULT_MAX_BLOCK_LEN: usize = 128 * 1024;
+
+/// The name a download of `path` is written under until it is complete, so that a file under
+/// the real name is always whole
+pub fn part_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FileError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("file on disk is shorter than expected")]
+    Truncated,
+
+    #[error("piece {0} is out of range")]
+    InvalidPiece(usize),
+
+    #[error("range {0:?} is not within piece")]
+    InvalidRange(Range<usize>),
+
+    #[error("piece {0} is not complete")]
+    Incomplete(usize),
+
+    #[error("block of {len} bytes is larger than the {max} allowed")]
+    BlockTooLarge { len: usize, max: usize },
+
+    #[error("range extends past end of file")]
+    PastEnd,
+
+    #[error("file on disk is {actual} bytes, but should be {expected}")]
+    WrongLength { actual: usize, expected: usize },
+
+    #[error(
+        "not enough free disk space: the download needs {} more, but only {} is free",
+        format_size(*needed),
+        format_size(*available)
+    )]
+    NoSpace { needed: usize, available: usize },
+
+    #[error("{} already exists", .0.display())]
+    Exists(PathBuf),
+
+    #[error("the file is only being seeded, and can't be written to")]
+    ReadOnly,
+
+    #[error(
+        "failed to flush the file to disk, so {} piece(s) will be downloaded again: {source}",
+        pieces.len()
+    )]
+    FlushFailed {
+        pieces: Vec<usize>,
+        source: io::Error,
+    },
+}
+
+/// What became of a block handed to [DownloadFile::process_block]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The block was stored, and its piece is still missing others
+    Accepted,
+
+    /// We already had the block, so it was thrown away
+    Duplicate,
+
+    /// The block completed its piece, which matched its hash
+    PieceComplete,
+
+    /// The block completed its piece, but it didn't match its hash and was reset. Any of the
+    /// peers that sent part of it may be to blame.
+    HashMismatch {
+        piece: usize,
+        contributors: Vec<SocketAddr>,
+    },
+}
+
+/// What became of each piece of the download when importing another copy of its data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    /// Pieces copied over from the source
+    pub imported: usize,
+
+    /// Pieces the download file already had
+    pub present: usize,
+
+    /// Pieces the source had, but which didn't match their hash
+    pub rejected: usize,
+
+    /// Pieces that run past the end of the source
+    pub missing: usize,
+
+    /// Bytes of the imported pieces
+    pub bytes_copied: usize,
+}
+
+impl fmt::Display for Imported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "imported {} pieces ({} bytes), {} were already present, {} didn't match and {} are past the end of the source",
+            self.imported, self.bytes_copied, self.present, self.rejected, self.missing
+        )
+    }
+}
+
+impl FileError {
+    /// Whether this error means the file itself can't be trusted, rather than a bad request
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, FileError::Io(_) | FileError::Truncated)
+    }
+}
+
+type Result<T> = std::result::Result<T, FileError>;
+
+/// Where a block sits: its piece, and the byte range within that piece
 #[derive(Clone, Debug, PartialEq)]
 pub struct BlockInfo {
     pub piece: usize,
     pub range: Range<usize>,
 }
 
+/// A block of data, as received from a peer
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Block {
     piece: usize,
     offset: usize,
     data: Vec<u8>,
+
+    // the peer that sent it, if it came from one
+    source: Option<SocketAddr>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Piece {
     unfilled: Vec<Range<usize>>, // this is really more of a Set, but we want to be able to return it as a slice
-    all_blocks: Vec<Range<usize>>,
     offset: usize,
     length: usize,
     hash: [u8; DIGEST_SIZE],
+
+    // bumped every time the piece is reset, so reads from before then can be told apart
+    generation: u64,
+    priority: Priority,
+
+    // which peer sent each filled range, until the piece is verified or reset
+    contributors: Vec<(Range<usize>, SocketAddr)>,
 }
 
-#[derive(Debug)]
-pub struct DownloadFile {
+/// How far along a piece is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PieceState {
+    /// No blocks of the piece have been written
+    Missing,
+
+    /// Some blocks have been written, but not all of them
+    Partial,
+
+    /// Every block has been written and the piece matched its hash
+    Complete,
+}
+
+/// How much a piece is wanted, set with [DownloadFile::set_piece_priority]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Never requested, and not needed for the download to be complete
+    Skip,
+
+    #[default]
+    Normal,
+
+    /// Requested before any normal piece
+    High,
+}
+
+/// When [DownloadFile] syncs what it has written to disk, set with
+/// [DownloadFile::set_flush_policy]. Written as `never`, `piece`, or how long to wait between
+/// flushes, such as `30s`.
+///
+/// Whatever the policy, the file is flushed by [DownloadFile::flush] and whatever calls it,
+/// such as saving a resume file in the disk thread, and a resume file never counts data that
+/// wasn't flushed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum FlushPolicy {
+    /// Only when told to
+    #[default]
+    Never,
+
+    /// After every piece that is verified, so that no piece is ever announced before it is
+    /// on disk
+    OnPieceComplete,
+
+    /// After a piece is verified, if it has been this long since the last flush
+    Every(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "never" => Ok(FlushPolicy::Never),
+            "piece" => Ok(FlushPolicy::OnPieceComplete),
+            _ => parse_duration(s)
+                .map(FlushPolicy::Every)
+                .map_err(|e| format!("expected never, piece or a duration: {}", e)),
+        }
+    }
+}
+
+/// Which pieces of a file have been verified, and which blocks of the rest are still missing.
+///
+/// This is everything about a download but the data. Each [DownloadFile] keeps one, and the
+/// session keeps a copy in step with the disk thread's, so deciding what to ask peers for never
+/// has to wait on the disk.
+#[derive(Clone, Debug)]
+pub struct FileMap {
     pieces: Vec<Piece>,
     bitfield: BitVec<u8, Msb0>,
-    file: File,
-    downloaded: usize,
     total_size: usize,
+
+    // blocks longer than this are refused either way
+    max_block_len: usize,
+
+    // bytes written to every piece, verified or not, which is taken back when a piece is
+    // thrown away
+    bytes_filled: usize,
+
+    // pieces set to [Priority::Skip]
+    skipped: usize,
+
+    // seeding a file we can't write to, where pieces can't be downloaded again
+    read_only: bool,
+}
+
+/// A file being downloaded, made up of pieces that are each checked against a hash once all
+/// their blocks are in.
+///
+/// ```
+/// use rittorrent::file::{Block, DownloadFile, PieceState};
+/// use rittorrent::hash::Sha1PieceHasher;
+/// use sha1::{Digest, Sha1};
+///
+/// // a full piece, then a short one
+/// let data = vec![7u8; 40000];
+/// let hashes: Vec<[u8; 20]> = data.chunks(32768).map(|p| Sha1::digest(p).into()).collect();
+/// let mut file = DownloadFile::new_from_file(
+///     tempfile::tempfile()?,
+///     &hashes,
+///     32768,
+///     data.len(),
+///     Box::new(Sha1PieceHasher::default()),
+/// )?;
+///
+/// file.process_block(Block::new(1, 0, &data[32768..]))?;
+/// assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+/// assert_eq!(file.piece_state(1), Some(PieceState::Complete));
+/// assert_eq!(file.left_verified(), 32768);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct DownloadFile {
+    map: FileMap,
+    file: File,
+
+    // the file mapped into memory, which reads and writes go through instead if it is
+    mapped: Option<Mapped>,
+
+    // where the file is, if it was opened by name, for [DownloadFile::finalize] to move it
+    path: Option<PathBuf>,
+    hasher: Box<dyn PieceHasher>,
+
+    // Pieces being assembled in memory, which are only written out once they match their
+    // hash. Pieces that don't fit go straight to disk a block at a time.
+    cache: HashMap<usize, Vec<u8>>,
+    cache_pieces: usize,
+
+    // Hashes of the pieces going straight to disk, fed each block as it is written for as
+    // long as they arrive in order, with how far into the piece each has got. A piece whose
+    // blocks came out of order is read back from disk once it's complete instead.
+    streams: HashMap<usize, (usize, Box<dyn PieceHasher>)>,
+    read_back: usize,
+
+    // verified pieces that blocks were recently read from or that were just completed
+    read_cache: PieceCache,
+
+    // how much of the start of the file is verified, for readers from [DownloadFile::reader]
+    prefix: Arc<Prefix>,
+
+    flush_policy: FlushPolicy,
+    last_flush: Instant,
+
+    // What the file held as of the last flush, taken before anything is written after it.
+    // None if nothing has been since, in which case that is what the map says.
+    flushed: Option<Flushed>,
+}
+
+// The pieces of a file that were verified, and the blocks of the rest that were on disk
+#[derive(Clone, Debug)]
+struct Flushed {
+    verified: BitVec<u8, Msb0>,
+    partial: Vec<(usize, Vec<Range<usize>>)>,
 }
 
 impl Block {
+    /// A block starting `offset` bytes into `piece`
     pub fn new(piece: usize, offset: usize, data: &[u8]) -> Self {
         Block {
             piece,
             offset,
             data: data.to_vec(),
+            source: None,
         }
     }
 
+    /// The same block, as sent by the peer at `addr`, who is blamed if its piece turns out bad
+    pub fn sent_by(mut self, addr: SocketAddr) -> Self {
+        self.source = Some(addr);
+        self
+    }
+
+    /// Where the block sits. One that would run past the end of memory ends there instead,
+    /// so it is still refused for running past the end of its piece.
     pub fn info(&self) -> BlockInfo {
         BlockInfo {
             piece: self.piece,
-            range: self.offset..(self.offset + self.data.len()),
+            range: self.offset..self.offset.saturating_add(self.data.len()),
         }
     }
 }
 
 impl Piece {
+    // Bytes of the piece written so far
+    fn filled(&self) -> usize {
+        self.length - self.unfilled.iter().map(|r| r.len()).sum::<usize>()
+    }
+
+    // Whether nothing of the piece has been written
+    fn is_untouched(&self) -> bool {
+        self.filled() == 0
+    }
+
     fn is_complete(&self) -> bool {
         //self.range.start.checked_add(self.offset).unwrap() == self.range.end
         self.unfilled.is_empty()
     }
+
+    // The parts of `range` that are still unfilled, in order
+    fn missing(&self, range: &Range<usize>) -> Vec<Range<usize>> {
+        let mut missing: Vec<Range<usize>> = self
+            .unfilled
+            .iter()
+            .map(|r| r.start.max(range.start)..r.end.min(range.end))
+            .filter(|r| !r.is_empty())
+            .collect();
+        missing.sort_by_key(|r| r.start);
+        missing
+    }
+
+    // Takes `range` out of the unfilled ranges, keeping whatever is left of those it only
+    // partly covers
+    fn fill(&mut self, range: &Range<usize>) {
+        self.unfilled = self
+            .unfilled
+            .iter()
+            .flat_map(|r| {
+                [
+                    r.start..r.end.min(range.start),
+                    r.start.max(range.end)..r.end,
+                ]
+            })
+            .filter(|r| !r.is_empty())
+            .collect();
+    }
+
+    // Throw away everything we know about this piece so it gets downloaded again
+    fn reset(&mut self) {
+        self.unfilled = get_block_ranges(0, self.length, BLOCK_SIZE);
+        self.contributors.clear();
+        self.generation += 1;
+    }
+
+    // Read this piece back from disk and check it against the expected hash
+    fn verify(&self, file: &File, hasher: &mut dyn PieceHasher) -> Result<bool> {
+        hasher.verify(file, self.offset, self.length, &self.hash)
+    }
 }
 
 fn get_block_ranges(start: usize, end: usize, size: usize) -> Vec<Range<usize>> {
@@ -84,99 +457,17 @@ fn get_block_ranges(start: usize, end: usize, size: usize) -> Vec<Range<usize>>
     ranges
 }
 
-impl DownloadFile {
-    pub fn new(
-        file_name: impl AsRef<Path>,
-        hashes: &[[u8; DIGEST_SIZE]],
-        piece_size: usize,
-        total_size: usize,
-    ) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file_name)?;
-
-        Self::new_from_file(file, hashes, piece_size, total_size)
-    }
-
-    pub fn new_seeding(
-        file_name: impl AsRef<Path>,
-        hashes: &[[u8; DIGEST_SIZE]],
-        piece_size: usize,
-        total_size: usize,
-    ) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(file_name)?;
-        let mut download_file = Self::new_from_file(file, hashes, piece_size, total_size)?;
-        download_file.downloaded = download_file.total_size;
-
-        for mut bit in download_file.bitfield.iter_mut() {
-            *bit = true;
-        }
-
-        // loop through each piece and empty unfilled, since we have entire file
-        for piece in download_file.pieces.iter_mut() {
-            piece.unfilled.clear();
-        }
-
-        Ok(download_file)
-    }
-
-    fn new_from_file(
-        file: File,
-        hashes: &[[u8; DIGEST_SIZE]],
-        piece_size: usize,
-        total_size: usize,
-    ) -> Result<Self> {
-        let mut pieces = Vec::new();
-        let mut offset = 0;
-
-        file.set_len(total_size as u64)?;
-
-        // loop through all but last piece
-        for hash in hashes.iter().rev().skip(1).rev() {
-            let all_blocks = get_block_ranges(0, piece_size, BLOCK_SIZE);
-            let unfilled = all_blocks.clone();
-
-            pieces.push(Piece {
-                unfilled,
-                all_blocks,
-                offset,
-                length: piece_size,
-                hash: *hash,
-            });
-
-            offset += piece_size;
-        }
-
-        // special case for last piece since it can be short
-        let all_blocks = get_block_ranges(0, total_size - offset, BLOCK_SIZE);
-        let unfilled = all_blocks.clone();
-        pieces.push(Piece {
-            unfilled,
-            all_blocks,
-            offset,
-            length: total_size - offset,
-            hash: *hashes.last().expect("invalid size of hash list"),
-        });
-
-        let num_pieces = pieces.len();
-
-        Ok(DownloadFile {
-            pieces,
-            bitfield: bitvec![u8, Msb0; 0; num_pieces],
-            file,
-            downloaded: 0,
-            total_size,
-        })
-    }
-
+impl FileMap {
+    /// Whether every piece that isn't skipped is verified
     pub fn is_complete(&self) -> bool {
-        self.bitfield.all()
+        if self.skipped == 0 {
+            return self.bitfield.all();
+        }
+        self.bitfield
+            .iter()
+            .by_vals()
+            .zip(&self.pieces)
+            .all(|(verified, p)| verified || p.priority == Priority::Skip)
     }
 
     pub fn bitfield(&self) -> &[u8] {
@@ -194,318 +485,3576 @@ impl DownloadFile {
         self.pieces.get(piece).map(|x| &x.unfilled[..])
     }
 
-    pub fn piece_is_complete(&self, piece: usize) -> Result<bool> {
-        let Some(piece) = self.pieces.get(piece) else {
-            bail!("invalid piece index");
-        };
+    /// Returns the pieces that aren't verified and may be requested, the high priority ones
+    /// first. Each priority goes in piece order.
+    pub fn unfilled_by_priority(&self) -> impl Iterator<Item = usize> + '_ {
+        [Priority::High, Priority::Normal]
+            .into_iter()
+            .flat_map(move |priority| {
+                (0..self.pieces.len()).filter(move |&piece| {
+                    !self.bitfield[piece] && self.pieces[piece].priority == priority
+                })
+            })
+    }
 
-        Ok(piece.is_complete())
+    /// Whether the file is being seeded without write access
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
 
-    /// Returns number of bytes left to download.
-    /// This has a resolution of piece sizes, and only goes down when we get a full valid piece.
-    pub fn left(&self) -> usize {
-        self.total_size
-            .checked_sub(self.downloaded)
-            .expect("violated invariant total_size >= downloaded")
+    /// Returns how much `piece` is wanted, or [None] if it is out of bounds
+    pub fn priority(&self, piece: usize) -> Option<Priority> {
+        self.pieces.get(piece).map(|p| p.priority)
     }
 
-    /// Returns the bytes matching the given [BlockInfo]
-    /// Returns [None] if the passed [BlockInfo] does not exist
-    pub fn get_block(&mut self, block: BlockInfo) -> Result<Vec<u8>> {
-        let Some(piece) = self.pieces.get(block.piece) else {
-            bail!("invalid piece index");
-        };
+    /// Returns how far along `piece` is, or [None] if it is out of bounds.
+    /// A piece with every block written that hasn't been verified yet is still partial.
+    pub fn piece_state(&self, piece: usize) -> Option<PieceState> {
+        let p = self.pieces.get(piece)?;
+        Some(if self.bitfield[piece] {
+            PieceState::Complete
+        } else if p.is_untouched() {
+            PieceState::Missing
+        } else {
+            PieceState::Partial
+        })
+    }
 
-        if !piece.is_complete() {
-            bail!("piece is not complete");
-        }
+    /// Returns how many bytes of `piece` have been written and how long it is, or [None] if
+    /// it is out of bounds. A verified piece is all written.
+    pub fn piece_progress(&self, piece: usize) -> Option<(usize, usize)> {
+        let p = self.pieces.get(piece)?;
+        Some((p.filled(), p.length))
+    }
 
-        let range = 0..piece.length;
-        if block.range.start < range.start || block.range.end > range.end {
-            bail!("block range invalid");
-        }
+    /// Returns every partial piece, in order, along with its [FileMap::piece_progress]
+    pub fn incomplete_pieces(&self) -> impl Iterator<Item = (usize, (usize, usize))> + '_ {
+        (0..self.pieces.len())
+            .filter(|&piece| self.piece_state(piece) == Some(PieceState::Partial))
+            .map(|piece| (piece, self.piece_progress(piece).unwrap()))
+    }
 
-        let mut data = vec![0u8; block.range.end - block.range.start];
-        self.file
-            .seek(SeekFrom::Start((piece.offset + block.range.start) as u64))?;
-        self.file.read_exact(&mut data)?;
+    /// Returns how many times `piece` has been thrown away to be downloaded again, or [None]
+    /// if it is out of bounds.
+    ///
+    /// Data read from a piece is only good while the piece stays valid. A read that isn't
+    /// used straight away, such as an upload waiting its turn, should note the generation
+    /// when it is queued and check it with [FileMap::is_current] before the data goes
+    /// anywhere.
+    pub fn generation(&self, piece: usize) -> Option<u64> {
+        self.pieces.get(piece).map(|p| p.generation)
+    }
 
-        Ok(data)
+    /// Whether `piece` is complete and hasn't been reset since it was at `generation`
+    pub fn is_current(&self, piece: usize, generation: u64) -> bool {
+        self.pieces
+            .get(piece)
+            .is_some_and(|p| self.bitfield[piece] && p.generation == generation)
     }
 
-    /// Pass a block to the DownloadFile in order to be processed
-    /// Returns [Err] if block is for an out-of-range piece/file operations failed, and [Ok] otherwise
-    pub fn process_block(&mut self, block: Block) -> Result<()> {
-        let Some(piece) = self.pieces.get_mut(block.piece) else {
-            bail!("piece out of range");
-        };
+    pub fn piece_is_complete(&self, piece: usize) -> Result<bool> {
+        match self.bitfield.get(piece) {
+            Some(bit) => Ok(*bit),
+            None => Err(FileError::InvalidPiece(piece)),
+        }
+    }
 
-        let range = block.offset..(block.offset + block.data.len());
+    /// Returns number of bytes left to download, which is the length of every piece that
+    /// isn't verified or skipped. This goes down a whole piece at a time as pieces pass their
+    /// hash check, and back up if one is found to be bad after all, even in a file we started
+    /// out seeding. It's what trackers are told; see [FileMap::left_estimated] for something
+    /// smoother.
+    pub fn left_verified(&self) -> usize {
+        self.pieces
+            .iter()
+            .zip(self.bitfield.iter().by_vals())
+            .filter(|(p, verified)| !verified && p.priority != Priority::Skip)
+            .map(|(p, _)| p.length)
+            .sum()
+    }
 
-        // if the piece is already done we don't need to do any work
-        if piece.is_complete() {
-            return Ok(());
+    /// Returns number of bytes left to download, counting every block written as done before
+    /// its piece is verified. This goes down a block at a time, and back up by whatever was
+    /// written of a piece that fails its hash check. Skipped pieces count for nothing.
+    pub fn left_estimated(&self) -> usize {
+        if self.skipped == 0 {
+            return self.total_size - self.bytes_filled;
         }
+        let skipped_left: usize = self
+            .pieces
+            .iter()
+            .zip(self.bitfield.iter().by_vals())
+            .filter(|(p, verified)| !verified && p.priority == Priority::Skip)
+            .map(|(p, _)| p.length - p.filled())
+            .sum();
+        self.total_size - self.bytes_filled - skipped_left
+    }
 
-        // find this block
-        let Some(idx) = piece.unfilled.iter().position(|x| *x == range) else {
-            return Ok(());
-        };
-
-        // seek to position in file and write this block, since by this point we know it is unfilled
-        self.file
-            .seek(SeekFrom::Start((range.start + piece.offset) as u64))?;
-        self.file.write_all(&block.data[..])?;
+    /// Returns how many bytes starting at the absolute file offset `offset` are covered by
+    /// complete pieces, i.e. the longest prefix [DownloadFile::read_range] would serve.
+    pub fn verified_len(&self, offset: usize) -> usize {
+        let first = self
+            .pieces
+            .partition_point(|p| p.offset + p.length <= offset);
+
+        let end = self.pieces[first..]
+            .iter()
+            .zip(&self.bitfield[first..])
+            .take_while(|(_, verified)| **verified)
+            .last()
+            .map_or(offset, |(p, _)| p.offset + p.length);
+
+        end.saturating_sub(offset)
+    }
 
-        // this block now counts as filled, so remove from unfilled
-        piece.unfilled.swap_remove(idx);
+    /// Returns the index of the piece containing the absolute file offset `offset`
+    pub fn piece_at(&self, offset: usize) -> Option<usize> {
+        let piece = self
+            .pieces
+            .partition_point(|p| p.offset + p.length <= offset);
 
-        // if piece is complete, do hashing to verify integrity
-        if piece.is_complete() {
-            let mut hasher = Sha1::new();
-            let mut buf = vec![0u8; 4096];
+        (piece < self.pieces.len()).then_some(piece)
+    }
 
-            self.file.seek(SeekFrom::Start(piece.offset as u64))?;
-            let mut remaining = piece.length;
-            while remaining > 0 {
-                let to_read = buf.len().min(remaining);
-                let bytes_read = self.file.read(&mut buf[..to_read])?;
+    /// Returns the absolute byte range of the file that `piece` covers
+    pub fn piece_range(&self, piece: usize) -> Option<Range<usize>> {
+        self.pieces
+            .get(piece)
+            .map(|p| p.offset..p.offset + p.length)
+    }
 
-                hasher.update(&buf[..bytes_read]);
-                remaining -= bytes_read;
-            }
+    // Checks that `block` is a sensible size and lies within its piece, which it returns
+    fn check_bounds(&self, block: &BlockInfo) -> Result<&Piece> {
+        let Some(piece) = self.pieces.get(block.piece) else {
+            return Err(FileError::InvalidPiece(block.piece));
+        };
 
-            let hash = hasher.finalize();
-            if hash == piece.hash.into() {
-                *self.bitfield.get_mut(block.piece).unwrap() = true;
-                self.downloaded += piece.length;
-                Ok(())
-            } else {
-                piece.unfilled = piece.all_blocks.clone();
-                Ok(())
-            }
-        } else {
-            Ok(())
+        let len = block.range.end.saturating_sub(block.range.start);
+        if len > self.max_block_len {
+            return Err(FileError::BlockTooLarge {
+                len,
+                max: self.max_block_len,
+            });
+        }
+        if block.range.is_empty() || block.range.end > piece.length {
+            return Err(FileError::InvalidRange(block.range.clone()));
         }
+        Ok(piece)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::io::{Read, Seek, SeekFrom};
 
-    use hex_literal::hex;
-    use tempfile;
+    // The parts of `block` we still want, in order. A block needn't line up with the ranges
+    // we ask for, but may not run past the end of its piece.
+    fn wanted(&self, block: &BlockInfo) -> Result<Vec<Range<usize>>> {
+        let piece = self.check_bounds(block)?;
 
-    use crate::file::{BlockInfo, BLOCK_SIZE};
+        // if the piece is already done we don't need to do any work
+        if self.bitfield[block.piece] {
+            return Ok(Vec::new());
+        }
+        if self.read_only {
+            return Err(FileError::ReadOnly);
+        }
 
-    use super::{get_block_ranges, Block, DownloadFile, DIGEST_SIZE};
+        Ok(piece.missing(&block.range))
+    }
 
-    #[test]
-    fn get_block_ranges_test() {
-        let ranges = get_block_ranges(0, 33, 10);
-        assert_eq!(&ranges, &[(0..10), (10..20), (20..30), (30..33)]);
+    // Marks whatever parts of `block` we still want as written by `source`, and returns them
+    fn fill(&mut self, block: &BlockInfo, source: Option<SocketAddr>) -> Result<Vec<Range<usize>>> {
+        let wanted = self.wanted(block)?;
+        let piece = &mut self.pieces[block.piece];
+        if !wanted.is_empty() {
+            piece.fill(&block.range);
+            self.bytes_filled += wanted.iter().map(|r| r.len()).sum::<usize>();
+        }
+        if let Some(addr) = source {
+            piece
+                .contributors
+                .extend(wanted.iter().map(|range| (range.clone(), addr)));
+        }
+        Ok(wanted)
     }
 
-    #[test]
-    fn get_block_ranges_test_current_is_end() {
-        let ranges = get_block_ranges(0, 0, 10);
-        assert_eq!(&ranges, &[]);
+    /// The peers that sent the parts of `piece` we have, while it is yet to be verified
+    pub fn contributors(&self, piece: usize) -> Vec<SocketAddr> {
+        let Some(piece) = self.pieces.get(piece) else {
+            return Vec::new();
+        };
+        let mut addrs: Vec<SocketAddr> = piece.contributors.iter().map(|&(_, a)| a).collect();
+        addrs.sort();
+        addrs.dedup();
+        addrs
     }
 
-    #[test]
-    fn file_one_piece_success() {
-        let data = vec![0; 1024];
-        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
-        let temp_file = tempfile::tempfile().unwrap();
+    // Counts `piece` as verified. Returns how many bytes that adds to what we have.
+    fn mark_verified(&mut self, piece: usize) -> usize {
+        if self.bitfield.replace(piece, true) {
+            return 0;
+        }
 
-        let mut file = DownloadFile::new_from_file(temp_file, hashes, 1024, data.len()).unwrap();
+        let p = &mut self.pieces[piece];
+        self.bytes_filled += p.length - p.filled();
+        // nothing is left to fill, so the ranges are given back rather than kept empty
+        p.unfilled = Vec::new();
+        p.contributors.clear();
+        p.length
+    }
 
-        let block = Block::new(0, 0, &data[..]);
+    // Takes on what a resume file says, if it fits this download. Meant for a map nothing has
+    // been written to yet.
+    fn restore(&mut self, data: &ResumeData) -> std::result::Result<(), ResumeError> {
+        if data.total_size != self.total_size {
+            return Err(ResumeError::Mismatch("the length differs"));
+        }
+        if data.verified.len() != self.pieces.len() {
+            return Err(ResumeError::Mismatch("the piece count differs"));
+        }
+        for (piece, unfilled) in &data.partial {
+            let Some(p) = self.pieces.get(*piece) else {
+                return Err(ResumeError::Mismatch("a partial piece is out of range"));
+            };
+            let fits =
+                !unfilled.is_empty() && unfilled.iter().all(|r| !r.is_empty() && r.end <= p.length);
+            if data.verified[*piece] || !fits {
+                return Err(ResumeError::Mismatch(
+                    "a partial piece has the wrong blocks",
+                ));
+            }
+        }
 
-        file.process_block(block).unwrap();
-        assert!(file.pieces[0].is_complete());
+        // only now that all of it checks out
+        for piece in data.verified.iter_ones() {
+            self.mark_verified(piece);
+        }
+        for (piece, unfilled) in &data.partial {
+            let p = &mut self.pieces[*piece];
+            p.unfilled = unfilled.clone();
+            self.bytes_filled += p.filled();
+        }
+        Ok(())
+    }
 
-        // check file contents
-        let mut buf = Vec::new();
-        file.file.seek(SeekFrom::Start(0)).unwrap();
+    fn set_priority(&mut self, piece: usize, priority: Priority) -> Result<()> {
+        let p = self
+            .pieces
+            .get_mut(piece)
+            .ok_or(FileError::InvalidPiece(piece))?;
+        match (p.priority == Priority::Skip, priority == Priority::Skip) {
+            (false, true) => self.skipped += 1,
+            (true, false) => self.skipped -= 1,
+            _ => (),
+        }
+        p.priority = priority;
+        Ok(())
+    }
 
-        file.file.read_to_end(&mut buf).unwrap();
-        assert_eq!(buf, data);
-        assert_eq!(file.left(), 0);
+    // Throws away everything we know about `piece` so it gets downloaded again, or in a
+    // read-only file, so it is skipped
+    fn reset(&mut self, piece: usize) {
+        self.bytes_filled -= self.pieces[piece].filled();
+        self.pieces[piece].reset();
+        self.bitfield.set(piece, false);
+        if self.read_only {
+            let _ = self.set_priority(piece, Priority::Skip);
+        }
     }
 
-    #[test]
+    // Checks that `block` lies within a verified piece
+    fn check_readable(&self, block: &BlockInfo) -> Result<()> {
+        self.check_bounds(block)?;
+        if !self.bitfield[block.piece] {
+            return Err(FileError::Incomplete(block.piece));
+        }
+        Ok(())
+    }
+}
+
+impl DownloadFile {
+    /// Creates (or truncates) the file at `file_name` to download into
+    pub fn new(
+        file_name: impl AsRef<Path>,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&file_name)?;
+
+        let mut download_file = Self::new_from_file(file, hashes, piece_size, total_size, hasher)?;
+        download_file.path = Some(file_name.as_ref().to_path_buf());
+        Ok(download_file)
+    }
+
+    /// Opens the file at `file_name` to carry on downloading into it, creating it if need be.
+    ///
+    /// Every piece the file already holds in full is checked against its hash, and those that
+    /// match count as downloaded. A file shorter than `total_size` is extended, and its
+    /// missing tail downloaded along with every piece that didn't match.
+    pub fn new_resume(
+        file_name: impl AsRef<Path>,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+    ) -> Result<Self> {
+        let (mut download_file, existing) =
+            Self::open_existing(file_name, hashes, piece_size, total_size, hasher)?;
+        download_file.verify_existing(existing)?;
+        Ok(download_file)
+    }
+
+    /// Like [DownloadFile::new_resume], but takes the pieces that are done from the resume file
+    /// at `resume_path` rather than hashing them, if there is one for the torrent with
+    /// `info_hash` and the file is still the length it was.
+    ///
+    /// A resume file that is stale or corrupt is only warned about, and every piece hashed.
+    pub fn new_fast_resume(
+        file_name: impl AsRef<Path>,
+        resume_path: &Path,
+        info_hash: &[u8; 20],
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+    ) -> Result<Self> {
+        let (mut download_file, existing) =
+            Self::open_existing(file_name, hashes, piece_size, total_size, hasher)?;
+
+        if resume_path.exists() {
+            let loaded = if existing == total_size {
+                download_file.load_resume(resume_path, info_hash)
+            } else {
+                Err(ResumeError::Mismatch("the file's length has changed"))
+            };
+            match loaded {
+                Ok(()) => return Ok(download_file),
+                Err(e) => warn!(
+                    "Ignoring {}: {}. Checking every piece instead",
+                    resume_path.display(),
+                    e
+                ),
+            }
+        }
+
+        download_file.verify_existing(existing)?;
+        Ok(download_file)
+    }
+
+    // Opens the file at `file_name` without truncating it, along with how long it was
+    fn open_existing(
+        file_name: impl AsRef<Path>,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+    ) -> Result<(Self, usize)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(&file_name)?;
+        let existing = file.metadata()?.len() as usize;
+        let mut download_file = Self::new_from_file(file, hashes, piece_size, total_size, hasher)?;
+        download_file.path = Some(file_name.as_ref().to_path_buf());
+        Ok((download_file, existing))
+    }
+
+    // Marks every piece within the first `existing` bytes that matches its hash as verified
+    fn verify_existing(&mut self, existing: usize) -> Result<()> {
+        for idx in 0..self.map.pieces.len() {
+            let piece = &self.map.pieces[idx];
+
+            // anything past the old end of the file is zeroes we just added
+            if piece.offset + piece.length > existing {
+                break;
+            }
+
+            if self.verify_piece(idx)? {
+                self.map.mark_verified(idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens a file that is already complete, to seed it.
+    ///
+    /// Unless told to `verify` it, every piece is taken to be good, and the file is resized
+    /// to `total_size` if it isn't that already. Verifying hashes every piece and only keeps
+    /// those that match, leaving the rest to be downloaded again, and refuses a file that
+    /// isn't `total_size` bytes long with [FileError::WrongLength].
+    ///
+    /// A file that can't be opened for writing, such as one on a read-only mount, is seeded
+    /// read-only as with [DownloadFile::new_seeding_read_only].
+    pub fn new_seeding(
+        file_name: impl AsRef<Path>,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+        verify: bool,
+    ) -> Result<Self> {
+        let file = match OpenOptions::new().read(true).write(true).open(&file_name) {
+            Ok(file) => file,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+                ) =>
+            {
+                info!(
+                    "Can't write to {} ({}), so seeding it read-only",
+                    file_name.as_ref().display(),
+                    e
+                );
+                return Self::new_seeding_read_only(
+                    file_name, hashes, piece_size, total_size, hasher, verify,
+                );
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let actual = file.metadata()?.len() as usize;
+        if verify && actual != total_size {
+            return Err(FileError::WrongLength {
+                actual,
+                expected: total_size,
+            });
+        }
+        let mut download_file = Self::new_from_file(file, hashes, piece_size, total_size, hasher)?;
+        download_file.path = Some(file_name.as_ref().to_path_buf());
+
+        if verify {
+            download_file.verify_existing(total_size)?;
+        } else {
+            // we have the entire file
+            for idx in 0..download_file.map.pieces.len() {
+                download_file.map.mark_verified(idx);
+            }
+        }
+
+        Ok(download_file)
+    }
+
+    /// Opens a file that is already complete to seed it, without asking for write access.
+    ///
+    /// The file must be `total_size` bytes long, and is never written to: any block handed
+    /// to [DownloadFile::process_block] is refused with [FileError::ReadOnly]. Pieces that
+    /// don't match their hash, when told to `verify` or on a recheck later, can't be
+    /// downloaded again, so they are skipped instead.
+    pub fn new_seeding_read_only(
+        file_name: impl AsRef<Path>,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+        verify: bool,
+    ) -> Result<Self> {
+        let file = File::open(&file_name)?;
+        let actual = file.metadata()?.len() as usize;
+        if actual != total_size {
+            return Err(FileError::WrongLength {
+                actual,
+                expected: total_size,
+            });
+        }
+        let mut download_file = Self::with_pieces(file, hashes, piece_size, total_size, hasher);
+        download_file.path = Some(file_name.as_ref().to_path_buf());
+
+        if verify {
+            download_file.verify_existing(total_size)?;
+        } else {
+            for idx in 0..download_file.map.pieces.len() {
+                download_file.map.mark_verified(idx);
+            }
+        }
+        for idx in download_file.map.bitfield.iter_zeros().collect::<Vec<_>>() {
+            download_file.map.set_priority(idx, Priority::Skip)?;
+        }
+        download_file.map.read_only = true;
+
+        Ok(download_file)
+    }
+
+    /// Downloads into an already open file, which is resized to `total_size`.
+    ///
+    /// The file is left sparse, but is refused with [FileError::NoSpace] up front if the disk
+    /// doesn't have room for the rest of it.
+    pub fn new_from_file(
+        file: File,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+    ) -> Result<Self> {
+        check_space(&file, total_size)?;
+        file.set_len(total_size as u64)?;
+
+        let mut download_file = Self::with_pieces(file, hashes, piece_size, total_size, hasher);
+
+        // built with the mmap feature, files are mapped unless they can't be
+        if cfg!(feature = "mmap") {
+            download_file.map_into_memory()?;
+        }
+        Ok(download_file)
+    }
+
+    // A download of nothing yet, in a file that is `total_size` bytes long already
+    fn with_pieces(
+        file: File,
+        hashes: &[[u8; DIGEST_SIZE]],
+        piece_size: usize,
+        total_size: usize,
+        hasher: Box<dyn PieceHasher>,
+    ) -> Self {
+        let mut pieces = Vec::new();
+        let mut offset = 0;
+
+        // loop through all but last piece
+        for hash in hashes.iter().rev().skip(1).rev() {
+            pieces.push(Piece {
+                unfilled: get_block_ranges(0, piece_size, BLOCK_SIZE),
+                offset,
+                length: piece_size,
+                hash: *hash,
+                generation: 0,
+                priority: Priority::Normal,
+                contributors: Vec::new(),
+            });
+
+            offset += piece_size;
+        }
+
+        // special case for last piece since it can be short
+        pieces.push(Piece {
+            unfilled: get_block_ranges(0, total_size - offset, BLOCK_SIZE),
+            offset,
+            length: total_size - offset,
+            hash: *hashes.last().expect("invalid size of hash list"),
+            generation: 0,
+            priority: Priority::Normal,
+            contributors: Vec::new(),
+        });
+
+        let num_pieces = pieces.len();
+
+        DownloadFile {
+            map: FileMap {
+                pieces,
+                bitfield: bitvec![u8, Msb0; 0; num_pieces],
+                total_size,
+                max_block_len: DEFAULT_MAX_BLOCK_LEN,
+                bytes_filled: 0,
+                skipped: 0,
+                read_only: false,
+            },
+            file,
+            mapped: None,
+            path: None,
+            hasher,
+            cache: HashMap::new(),
+            cache_pieces: DEFAULT_CACHE_PIECES,
+            streams: HashMap::new(),
+            read_back: 0,
+            read_cache: PieceCache::new(DEFAULT_READ_CACHE_BYTES),
+            prefix: Arc::default(),
+            flush_policy: FlushPolicy::default(),
+            last_flush: Instant::now(),
+            flushed: None,
+        }
+    }
+
+    /// Maps the file into memory, so blocks are copied straight in and pieces hashed where
+    /// they lie rather than read back. Completed pieces are written back to the file with
+    /// msync as they are verified.
+    ///
+    /// The process is killed with SIGBUS if anything else shrinks the file while it is
+    /// mapped. Returns [Err] if the file is too big for the address space, which only
+    /// happens on 32-bit systems. Read-only files are left as they are, since the map is
+    /// written through.
+    pub fn map_into_memory(&mut self) -> Result<()> {
+        if self.mapped.is_none() && self.map.total_size > 0 && !self.map.read_only {
+            self.mapped = Some(Mapped::new(&self.file, self.map.total_size)?);
+        }
+        Ok(())
+    }
+
+    /// Whether the file is mapped into memory
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+
+    // Writes `data` at `offset` into the file, or its map. A full disk is told apart from
+    // any other failure as [FileError::NoSpace], which the download can wait out.
+    fn write_at(&mut self, data: &[u8], offset: usize) -> Result<()> {
+        let written = match &mut self.mapped {
+            Some(mapped) => {
+                mapped[offset..offset + data.len()].copy_from_slice(data);
+                Ok(())
+            }
+            None => self.file.write_all_at(data, offset as u64),
+        };
+        written.map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOSPC | libc::EDQUOT) => FileError::NoSpace {
+                needed: self.map.left_estimated(),
+                available: free_space(&self.file).unwrap_or(0),
+            },
+            _ => e.into(),
+        })
+    }
+
+    // Writes part of `piece`, throwing the whole piece away if the disk is full. The session
+    // already counts the block as filled, and resets the piece on its side to match.
+    fn write_piece_at(&mut self, piece: usize, data: &[u8], offset: usize) -> Result<()> {
+        let written = self.write_at(data, offset);
+        if let Err(FileError::NoSpace { .. }) = written {
+            self.map.reset(piece);
+            self.cache.remove(&piece);
+            self.streams.remove(&piece);
+            self.read_cache.remove(piece);
+        }
+        written
+    }
+
+    // Reads `buf.len()` bytes at `offset` from the file, or its map
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<()> {
+        match &self.mapped {
+            Some(mapped) => buf.copy_from_slice(&mapped[offset..offset + buf.len()]),
+            None => self.file.read_exact_at(buf, offset as u64)?,
+        }
+        Ok(())
+    }
+
+    // Checks `piece` against its hash as it is on disk, or in the map. A mapped file is
+    // checked for having been truncated first, as touching the lost part would be fatal.
+    fn verify_piece(&mut self, piece: usize) -> Result<bool> {
+        let piece = &self.map.pieces[piece];
+        let Some(mapped) = &self.mapped else {
+            return piece.verify(&self.file, self.hasher.as_mut());
+        };
+
+        if ((piece.offset + piece.length) as u64) > self.file.metadata()?.len() {
+            return Err(FileError::Truncated);
+        }
+        self.hasher
+            .update(&mapped[piece.offset..piece.offset + piece.length]);
+        Ok(self.hasher.finalize_reset() == piece.hash)
+    }
+
+    // Writes a verified piece back to the file now, if it is mapped
+    fn flush_piece(&self, piece: usize) -> Result<()> {
+        if let Some(mapped) = &self.mapped {
+            mapped.flush_range(self.map.piece_range(piece).unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Sets how many pieces may be assembled in memory at once. With 0, every block is
+    /// written to disk as it arrives, as are blocks of pieces longer than a few MiB.
+    pub fn set_cache_pieces(&mut self, pieces: usize) {
+        self.cache_pieces = pieces;
+    }
+
+    /// Sets the largest block that may be written or read. Anything bigger is refused with
+    /// [FileError::BlockTooLarge].
+    pub fn set_max_block_len(&mut self, len: usize) {
+        self.map.max_block_len = len;
+    }
+
+    /// Sets how many bytes of verified pieces may be kept in memory to serve blocks from.
+    /// With 0, every block is read from disk.
+    pub fn set_read_cache_bytes(&mut self, bytes: usize) {
+        self.read_cache.set_capacity(bytes);
+    }
+
+    /// Sets when the file is synced to disk as pieces are verified. See [FlushPolicy].
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    /// How many [DownloadFile::get_block] calls were served from memory
+    pub fn read_cache_hits(&self) -> usize {
+        self.read_cache.hits()
+    }
+
+    /// How many [DownloadFile::get_block] calls had to go to the disk
+    pub fn read_cache_misses(&self) -> usize {
+        self.read_cache.misses()
+    }
+
+    /// How many pieces have had to be read back from disk to be checked, because their
+    /// blocks didn't arrive in order
+    pub fn read_back_pieces(&self) -> usize {
+        self.read_back
+    }
+
+    /// Which pieces are verified and which blocks are missing, without the data
+    pub fn map(&self) -> &FileMap {
+        &self.map
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.map.is_complete()
+    }
+
+    pub fn bitfield(&self) -> &[u8] {
+        self.map.bitfield()
+    }
+
+    // Return a copy of our current BitVec
+    pub fn bitvec(&self) -> &BitVec<u8, Msb0> {
+        self.map.bitvec()
+    }
+
+    /// See [FileMap::get_unfilled]
+    pub fn get_unfilled(&self, piece: usize) -> Option<&[Range<usize>]> {
+        self.map.get_unfilled(piece)
+    }
+
+    /// See [FileMap::unfilled_by_priority]
+    pub fn unfilled_by_priority(&self) -> impl Iterator<Item = usize> + '_ {
+        self.map.unfilled_by_priority()
+    }
+
+    /// See [FileMap::is_read_only]
+    pub fn is_read_only(&self) -> bool {
+        self.map.is_read_only()
+    }
+
+    /// See [FileMap::priority]
+    pub fn priority(&self, piece: usize) -> Option<Priority> {
+        self.map.priority(piece)
+    }
+
+    /// Sets how much `piece` is wanted. Skipped pieces are never requested, and the download
+    /// is complete without them. The session goes by the priorities the file has when it is
+    /// handed over.
+    pub fn set_piece_priority(&mut self, piece: usize, priority: Priority) -> Result<()> {
+        self.map.set_priority(piece, priority)
+    }
+
+    /// See [FileMap::piece_state]
+    pub fn piece_state(&self, piece: usize) -> Option<PieceState> {
+        self.map.piece_state(piece)
+    }
+
+    /// See [FileMap::piece_progress]
+    pub fn piece_progress(&self, piece: usize) -> Option<(usize, usize)> {
+        self.map.piece_progress(piece)
+    }
+
+    /// See [FileMap::incomplete_pieces]
+    pub fn incomplete_pieces(&self) -> impl Iterator<Item = (usize, (usize, usize))> + '_ {
+        self.map.incomplete_pieces()
+    }
+
+    /// See [FileMap::generation]
+    pub fn generation(&self, piece: usize) -> Option<u64> {
+        self.map.generation(piece)
+    }
+
+    /// See [FileMap::is_current]
+    pub fn is_current(&self, piece: usize, generation: u64) -> bool {
+        self.map.is_current(piece, generation)
+    }
+
+    pub fn piece_is_complete(&self, piece: usize) -> Result<bool> {
+        self.map.piece_is_complete(piece)
+    }
+
+    /// See [FileMap::left_verified]
+    pub fn left_verified(&self) -> usize {
+        self.map.left_verified()
+    }
+
+    /// See [FileMap::left_estimated]
+    pub fn left_estimated(&self) -> usize {
+        self.map.left_estimated()
+    }
+
+    /// See [FileMap::verified_len]
+    pub fn verified_len(&self, offset: usize) -> usize {
+        self.map.verified_len(offset)
+    }
+
+    /// See [FileMap::piece_at]
+    pub fn piece_at(&self, offset: usize) -> Option<usize> {
+        self.map.piece_at(offset)
+    }
+
+    /// Returns the bytes matching the given [BlockInfo].
+    /// Returns [Err] if it isn't within a verified piece, is empty, or is longer than the
+    /// largest block allowed, which is [FileError::BlockTooLarge]
+    pub fn get_block(&mut self, block: BlockInfo) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.get_block_into(block, &mut data)?;
+        Ok(data)
+    }
+
+    /// Like [DownloadFile::get_block], into `buf` in place of whatever it held, so a buffer
+    /// kept from one block to the next is only allocated for once. `buf` is left empty if
+    /// the block can't be read.
+    pub fn get_block_into(&mut self, block: BlockInfo, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        self.map.check_readable(&block)?;
+        if let Some(data) = self.read_cache.get(block.piece) {
+            buf.extend_from_slice(&data[block.range]);
+            return Ok(());
+        }
+        let piece = &self.map.pieces[block.piece];
+        let offset = piece.offset;
+
+        // the rest of the piece is likely to be asked for next, if it can be kept
+        if self.read_cache.fits(piece.length) {
+            let mut data = vec![0u8; piece.length];
+            self.read_at(&mut data, offset)?;
+            buf.extend_from_slice(&data[block.range]);
+            self.read_cache.insert(block.piece, data);
+            return Ok(());
+        }
+
+        buf.resize(block.range.len(), 0);
+        let read = self.read_at(buf, offset + block.range.start);
+        if read.is_err() {
+            buf.clear();
+        }
+        read
+    }
+
+    /// Returns `len` bytes starting at the absolute file offset `offset`, which may span
+    /// any number of pieces.
+    /// Returns [Err] if the range extends past the end of the file or overlaps a piece that
+    /// has not been verified; see [DownloadFile::verified_len] for how much can be read.
+    pub fn read_range(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.map.total_size => {}
+            _ => return Err(FileError::PastEnd),
+        }
+
+        let verified = self.verified_len(offset);
+        if verified < len {
+            let piece = self.piece_at(offset + verified).unwrap();
+            return Err(FileError::Incomplete(piece));
+        }
+
+        let mut data = vec![0u8; len];
+        self.read_at(&mut data, offset)?;
+
+        Ok(data)
+    }
+
+    /// Stores whatever parts of `block` we still want, then checks its piece against its hash
+    /// if that completed it. The block needn't line up with the ranges we asked for.
+    /// Returns [Err] if block is for an out-of-range piece, is empty, runs past the end of its
+    /// piece, is longer than the largest block allowed, or file operations failed, and what
+    /// became of it otherwise
+    pub fn process_block(&mut self, block: Block) -> Result<BlockOutcome> {
+        let info = block.info();
+        let wanted = self.map.wanted(&info)?;
+        if wanted.is_empty() {
+            return Ok(BlockOutcome::Duplicate);
+        }
+        if self.flushed.is_none() {
+            self.flushed = Some(self.flushed_state());
+        }
+
+        // A piece is only cached from its first block, so the cache always holds all of it.
+        // Otherwise, write the parts of this block we want in place, since we know they are
+        // unfilled. Anything we already had is left as it was.
+        let piece = &self.map.pieces[block.piece];
+        let offset = piece.offset;
+        let fresh = piece.is_untouched();
+        if !self.cache.contains_key(&block.piece)
+            && fresh
+            && self.cache.len() < self.cache_pieces
+            && piece.length <= MAX_CACHED_PIECE_LEN
+        {
+            self.cache.insert(block.piece, vec![0; piece.length]);
+        }
+        for range in wanted {
+            let part = &block.data[range.start - info.range.start..range.end - info.range.start];
+            match self.cache.get_mut(&block.piece) {
+                Some(data) => data[range].copy_from_slice(part),
+                None => {
+                    self.write_piece_at(block.piece, part, range.start + offset)?;
+
+                    if fresh && range.start == 0 {
+                        self.streams.insert(block.piece, (0, self.hasher.fresh()));
+                    }
+                    match self.streams.get_mut(&block.piece) {
+                        Some((at, hasher)) if *at == range.start => {
+                            hasher.update(part);
+                            *at = range.end;
+                        }
+                        Some(_) => {
+                            self.streams.remove(&block.piece);
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+
+        // this block now counts as filled
+        self.map.fill(&info, block.source)?;
+
+        // if piece is complete, do hashing to verify integrity
+        let piece = &self.map.pieces[block.piece];
+        if !piece.is_complete() {
+            return Ok(BlockOutcome::Accepted);
+        }
+
+        // a cached piece is hashed where it is, and only ever written out if it's good
+        let valid = match self.cache.remove(&block.piece) {
+            Some(data) => {
+                self.hasher.update(&data);
+                let valid = self.hasher.finalize_reset() == piece.hash;
+                if valid {
+                    self.write_piece_at(block.piece, &data, offset)?;
+                    self.read_cache.insert(block.piece, data);
+                }
+                valid
+            }
+            None => match self.streams.remove(&block.piece) {
+                Some((at, mut hasher)) if at == piece.length => {
+                    hasher.finalize_reset() == piece.hash
+                }
+                _ => {
+                    self.read_back += 1;
+                    self.verify_piece(block.piece)?
+                }
+            },
+        };
+        if valid {
+            self.flush_piece(block.piece)?;
+            self.map.mark_verified(block.piece);
+            self.publish_prefix();
+            if self.flush_due() {
+                self.flush()?;
+            }
+            Ok(BlockOutcome::PieceComplete)
+        } else {
+            let contributors = self.map.contributors(block.piece);
+            self.map.reset(block.piece);
+            self.read_cache.remove(block.piece);
+            Ok(BlockOutcome::HashMismatch {
+                piece: block.piece,
+                contributors,
+            })
+        }
+    }
+
+    /// Re-hash every piece we currently consider complete against the data on disk.
+    /// Pieces that no longer verify are reset so they will be downloaded again.
+    /// Returns the indices of the pieces that were invalidated.
+    pub fn verify_all(&mut self) -> Result<Vec<usize>> {
+        let mut invalidated = Vec::new();
+
+        for idx in self.map.bitfield.iter_ones().collect::<Vec<_>>() {
+            if !self.verify_piece(idx)? {
+                self.map.reset(idx);
+                self.read_cache.remove(idx);
+                if let Some(flushed) = &mut self.flushed {
+                    flushed.verified.set(idx, false);
+                }
+                invalidated.push(idx);
+            }
+        }
+        self.publish_prefix();
+
+        Ok(invalidated)
+    }
+
+    /// Copies every piece of the file at `path` that matches its hash, leaving the pieces we
+    /// already have alone. Only pieces that lie wholly within the source are tried, so a
+    /// shorter copy still gives up the pieces it has, and anything past our length is ignored.
+    pub fn import_from(&mut self, path: impl AsRef<Path>) -> Result<Imported> {
+        let source = File::open(path)?;
+        let source_len = source.metadata()?.len() as usize;
+        let mut imported = Imported::default();
+
+        for piece in 0..self.map.pieces.len() {
+            let range = self.map.piece_range(piece).unwrap();
+            if self.piece_is_complete(piece)? {
+                imported.present += 1;
+                continue;
+            }
+            if range.end > source_len {
+                imported.missing += 1;
+                continue;
+            }
+
+            // written a block at a time the way a download would be, which checks the piece
+            // once it's all there, so long pieces are never read in whole
+            let unfilled = self.get_unfilled(piece).unwrap_or_default().to_vec();
+            let mut outcome = BlockOutcome::Duplicate;
+            for block in unfilled {
+                let mut data = vec![0; block.len()];
+                source.read_exact_at(&mut data, (range.start + block.start) as u64)?;
+                outcome = self.process_block(Block::new(piece, block.start, &data))?;
+            }
+            match outcome {
+                BlockOutcome::PieceComplete => {
+                    imported.imported += 1;
+                    imported.bytes_copied += range.len();
+                }
+                _ => imported.rejected += 1,
+            }
+        }
+
+        self.flush()?;
+        Ok(imported)
+    }
+
+    /// Saves which pieces were verified, and which blocks of the rest were on disk, as of the
+    /// last [DownloadFile::flush] to `path` for [DownloadFile::load_resume] to pick up next
+    /// time. Anything written since could still be lost, so isn't saved, and should be flushed
+    /// first if it is to be.
+    pub fn save_resume(&self, path: impl AsRef<Path>, info_hash: &[u8; 20]) -> Result<()> {
+        let flushed = match &self.flushed {
+            Some(flushed) => flushed.clone(),
+            None => self.flushed_state(),
+        };
+        let data = ResumeData {
+            info_hash: *info_hash,
+            total_size: self.map.total_size,
+            verified: flushed.verified,
+            partial: flushed.partial,
+        };
+
+        // written alongside and renamed into place, so a crash never leaves half a file
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data.encode())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Takes which pieces are verified and which blocks are on disk from the resume file at
+    /// `path`, rather than hashing the file. Meant for a file nothing has been written to yet,
+    /// which is left as it was if the resume file is for another torrent, doesn't fit this
+    /// download, or is corrupt.
+    pub fn load_resume(
+        &mut self,
+        path: impl AsRef<Path>,
+        info_hash: &[u8; 20],
+    ) -> std::result::Result<(), ResumeError> {
+        let data = ResumeData::decode(&fs::read(path)?)?;
+        if data.info_hash != *info_hash {
+            return Err(ResumeError::WrongTorrent);
+        }
+        self.map.restore(&data)?;
+        self.publish_prefix();
+        Ok(())
+    }
+
+    /// Reads the file from the start in order, as far as it is verified at the time, which
+    /// carries on as more pieces are. See [PrefixReader].
+    pub fn reader(&self) -> Result<PrefixReader> {
+        self.publish_prefix();
+        Ok(PrefixReader::new(
+            self.file.try_clone()?,
+            self.prefix.clone(),
+            self.map.total_size,
+        ))
+    }
+
+    // Tells readers how much of the start of the file is verified
+    fn publish_prefix(&self) {
+        let len = match self.map.bitfield.first_zero() {
+            Some(piece) => self.map.pieces[piece].offset,
+            None => self.map.total_size,
+        };
+        self.prefix.set(len);
+    }
+
+    /// Makes sure everything written so far has reached the disk. If it can't be, the pieces
+    /// verified and blocks written since the last flush can't be counted on, so they are
+    /// thrown away to be downloaded again and returned in [FileError::FlushFailed].
+    pub fn flush(&mut self) -> Result<()> {
+        let Err(source) = self.sync() else {
+            self.flushed = None;
+            self.last_flush = Instant::now();
+            return Ok(());
+        };
+        let flushed = self.flushed.take().unwrap_or_else(|| self.flushed_state());
+
+        let before: HashMap<usize, &[Range<usize>]> = flushed
+            .partial
+            .iter()
+            .map(|(piece, unfilled)| (*piece, &unfilled[..]))
+            .collect();
+        let pieces: Vec<usize> = (0..self.map.pieces.len())
+            .filter(|&piece| {
+                let p = &self.map.pieces[piece];
+                if self.map.bitfield[piece] {
+                    !flushed.verified[piece]
+                } else {
+                    // pieces in memory haven't been written at all
+                    !p.is_untouched()
+                        && !self.cache.contains_key(&piece)
+                        && before.get(&piece) != Some(&&p.unfilled[..])
+                }
+            })
+            .collect();
+        for &piece in &pieces {
+            self.map.reset(piece);
+            self.read_cache.remove(piece);
+            self.streams.remove(&piece);
+        }
+        self.publish_prefix();
+
+        // what's left is what was flushed before, which is all a resume file may count on
+        self.flushed = Some(self.flushed_state());
+        Err(FileError::FlushFailed { pieces, source })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        if let Some(mapped) = &self.mapped {
+            mapped.flush()?;
+        }
+        self.file.sync_data()
+    }
+
+    // Whether the flush policy calls for a flush, now that a piece has been verified
+    fn flush_due(&self) -> bool {
+        match self.flush_policy {
+            FlushPolicy::Never => false,
+            FlushPolicy::OnPieceComplete => true,
+            FlushPolicy::Every(interval) => self.last_flush.elapsed() >= interval,
+        }
+    }
+
+    // Which pieces are verified and which blocks of the rest are on disk. Pieces still being
+    // assembled in memory count as missing, since none of their data has reached the disk.
+    fn flushed_state(&self) -> Flushed {
+        let partial = self
+            .map
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|&(idx, piece)| {
+                !self.map.bitfield[idx] && !self.cache.contains_key(&idx) && !piece.is_untouched()
+            })
+            .map(|(idx, piece)| (idx, piece.unfilled.clone()))
+            .collect();
+        Flushed {
+            verified: self.map.bitfield.clone(),
+            partial,
+        }
+    }
+
+    /// Moves a complete download from its [part_path] to the real name, creating the directory
+    /// that goes in if need be. The data is synced first, so the real name never holds less
+    /// than the whole file. An existing file under the real name is left alone, and refused
+    /// with [FileError::Exists].
+    ///
+    /// Does nothing for a file that wasn't opened under a part path, or was already moved.
+    pub fn finalize(&mut self) -> Result<()> {
+        let Some(path) = self
+            .path
+            .clone()
+            .filter(|p| p.extension() == Some("part".as_ref()))
+        else {
+            return Ok(());
+        };
+        let target = path.with_extension("");
+
+        // skipped pieces aren't needed, and are left as holes
+        if let Some(piece) = self.map.unfilled_by_priority().min() {
+            return Err(FileError::Incomplete(piece));
+        }
+        if target.exists() {
+            return Err(FileError::Exists(target));
+        }
+
+        self.flush()?;
+        if let Some(dir) = target.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(&path, &target)?;
+        info!("Moved {} to {}", path.display(), target.display());
+
+        // the handle follows the file, so reads and writes carry on as before
+        self.path = Some(target);
+        Ok(())
+    }
+
+    /// Bytes free on the disk the file is on, for anyone but root
+    pub fn free_space(&self) -> Result<usize> {
+        Ok(free_space(&self.file)?)
+    }
+
+    /// Reserves disk space for the whole file, rather than leaving it sparse to be filled in
+    /// as blocks arrive. Big files are done a chunk at a time with the progress logged, since
+    /// reserving the space can take a while. A filesystem that can't do it is only warned
+    /// about.
+    pub fn preallocate(&self) -> Result<()> {
+        // a read-only file is complete, and nothing more is written to it
+        if self.map.read_only {
+            return Ok(());
+        }
+
+        let total = self.map.total_size;
+        check_space(&self.file, total)?;
+
+        let mut done = 0;
+        while done < total {
+            let len = PREALLOCATE_CHUNK.min(total - done);
+
+            // Safety: the descriptor stays open for as long as self.file is borrowed
+            let ret = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    0,
+                    done as libc::off_t,
+                    len as libc::off_t,
+                )
+            };
+            if ret != 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                    warn!("The filesystem can't preallocate, so the file is left sparse");
+                    return Ok(());
+                }
+                return Err(err.into());
+            }
+
+            done += len;
+            if total > PREALLOCATE_CHUNK {
+                info!(
+                    "Preallocated {} of {}",
+                    format_size(done),
+                    format_size(total)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Bytes free on the filesystem holding `file`, as far as an unprivileged user is concerned
+fn free_space(file: &File) -> io::Result<usize> {
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // Safety: the descriptor stays open for as long as file is borrowed, and stat is only
+    // read once fstatvfs says it filled it in
+    let stat = unsafe {
+        if libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64) as usize)
+}
+
+// Refuses to grow `file` to `total_size` if there isn't room for it. Space the file already
+// takes up counts toward it, so a resumed download only needs room for what it's missing.
+fn check_space(file: &File, total_size: usize) -> Result<()> {
+    let allocated = file.metadata()?.blocks() as usize * 512;
+    let needed = total_size.saturating_sub(allocated);
+    let available = free_space(file)?;
+    if needed > available {
+        return Err(FileError::NoSpace { needed, available });
+    }
+    Ok(())
+}
+
+/// Work for the disk thread, which is done in the order it is sent. A read therefore sees
+/// every write sent before it.
+#[derive(Debug)]
+pub(crate) enum DiskRequest {
+    WriteBlock(Block),
+
+    /// Read a block to send to the peer at `addr`, into `buf`. The piece's generation as of
+    /// when the read was asked for comes back with the data.
+    ReadBlock {
+        addr: SocketAddr,
+        block: BlockInfo,
+        generation: u64,
+        buf: Vec<u8>,
+    },
+
+    /// Read as much of a range as has been verified, for the stream server
+    ReadRange {
+        offset: usize,
+        len: usize,
+        reply: Sender<Vec<u8>>,
+    },
+
+    /// Hash every verified piece again
+    Recheck,
+
+    /// Flush the file, and reply once everything sent before this is done
+    Flush(Sender<Result<()>>),
+
+    /// Move the complete file to its real name, replying once it's done
+    Finalize(Sender<Result<()>>),
+
+    /// Flush the file and save a resume file for the torrent with `info_hash` to `path`
+    SaveResume {
+        path: PathBuf,
+        info_hash: [u8; 20],
+    },
+
+    /// Find out how much space is free on the file's disk
+    FreeSpace,
+}
+
+/// What the disk thread tells main
+#[derive(Debug)]
+pub(crate) enum DiskResponse {
+    /// A write completed the piece, and it matched its hash
+    Verified(usize),
+
+    /// A write completed the piece, but it didn't match its hash and was reset, along with
+    /// the peers that sent it
+    HashFailed {
+        piece: usize,
+        contributors: Vec<SocketAddr>,
+    },
+
+    /// A block read for the peer at `addr`, which is only good to send if its piece is still
+    /// at `generation`
+    Read {
+        addr: SocketAddr,
+        block: BlockInfo,
+        generation: u64,
+        data: Result<Vec<u8>>,
+    },
+
+    /// The pieces a recheck reset
+    Rechecked(Result<Vec<usize>>),
+
+    /// The file couldn't be flushed, so these pieces were reset, whether they were verified
+    /// or partly written
+    FlushFailed(Vec<usize>),
+
+    /// A block of `piece` couldn't be written for the disk being full, so the piece was reset
+    NoSpace { piece: usize, error: FileError },
+
+    /// Bytes free on the file's disk
+    FreeSpace(Result<usize>),
+
+    /// A block couldn't be written, which leaves the file in a state we can't trust
+    WriteFailed(FileError),
+}
+
+/// Spawns the disk thread, which owns `file` and does all its I/O. It exits once main hangs up
+/// on it.
+pub(crate) fn spawn_disk_thread(
+    mut file: DownloadFile,
+    sender: Sender<Response>,
+) -> (Sender<DiskRequest>, JoinHandle<()>) {
+    let (tx, rx) = channel::unbounded::<DiskRequest>();
+
+    let handle = thread::spawn(move || {
+        for req in rx.iter() {
+            let resp = match req {
+                DiskRequest::WriteBlock(block) => {
+                    let piece = block.piece;
+                    match file.process_block(block) {
+                        Ok(BlockOutcome::Accepted | BlockOutcome::Duplicate) => continue,
+                        Ok(BlockOutcome::PieceComplete) => DiskResponse::Verified(piece),
+                        Ok(BlockOutcome::HashMismatch {
+                            piece,
+                            contributors,
+                        }) => DiskResponse::HashFailed {
+                            piece,
+                            contributors,
+                        },
+                        Err(FileError::FlushFailed { pieces, source }) => {
+                            flush_failed(pieces, &source)
+                        }
+                        Err(error @ FileError::NoSpace { .. }) => {
+                            DiskResponse::NoSpace { piece, error }
+                        }
+                        Err(e) => DiskResponse::WriteFailed(e),
+                    }
+                }
+                DiskRequest::ReadBlock {
+                    addr,
+                    block,
+                    generation,
+                    mut buf,
+                } => DiskResponse::Read {
+                    addr,
+                    data: file.get_block_into(block.clone(), &mut buf).map(|()| buf),
+                    block,
+                    generation,
+                },
+                DiskRequest::ReadRange { offset, len, reply } => {
+                    let len = len.min(file.verified_len(offset));
+                    let data = file.read_range(offset, len).unwrap_or_else(|e| {
+                        warn!(
+                            "Failed to read {} bytes at {} for stream: {}",
+                            len, offset, e
+                        );
+                        Vec::new()
+                    });
+
+                    // the connection may have gone away in the meantime, which is fine
+                    let _ = reply.send(data);
+                    continue;
+                }
+                DiskRequest::Recheck => DiskResponse::Rechecked(file.verify_all()),
+                DiskRequest::Flush(reply) => {
+                    let _ = reply.send(file.flush());
+                    continue;
+                }
+                DiskRequest::Finalize(reply) => {
+                    let _ = reply.send(file.finalize());
+                    continue;
+                }
+                DiskRequest::SaveResume { path, info_hash } => {
+                    // the resume file is still saved, as of the last flush that worked
+                    let flushed = file.flush();
+
+                    // only costs a full recheck next time
+                    if let Err(e) = file.save_resume(&path, &info_hash) {
+                        warn!("Failed to save resume file {}: {}", path.display(), e);
+                    }
+                    match flushed {
+                        Err(FileError::FlushFailed { pieces, source }) => {
+                            flush_failed(pieces, &source)
+                        }
+                        Err(e) => DiskResponse::WriteFailed(e),
+                        Ok(()) => continue,
+                    }
+                }
+                DiskRequest::FreeSpace => DiskResponse::FreeSpace(file.free_space()),
+            };
+
+            // main may be shutting down, and will hang up on us once it's done
+            let _ = sender.send(Response::Disk(resp));
+        }
+    });
+
+    (tx, handle)
+}
+
+fn flush_failed(pieces: Vec<usize>, source: &io::Error) -> DiskResponse {
+    warn!(
+        "Failed to flush the file to disk, so {} piece(s) will be downloaded again: {}",
+        pieces.len(),
+        source
+    );
+    DiskResponse::FlushFailed(pieces)
+}
+
+/// The session's end of the disk thread, along with a copy of the thread's [FileMap]. The copy
+/// is changed as blocks are sent off and brought up to date by the thread's [DiskResponse]s,
+/// and everything that doesn't need the data itself is answered from it.
+#[derive(Debug)]
+pub(crate) struct Disk {
+    map: FileMap,
+    sender: Sender<DiskRequest>,
+
+    // bytes of blocks asked for by peers that the disk thread hasn't read yet
+    reading: usize,
+}
+
+impl Disk {
+    /// Hands `file` to a new disk thread
+    pub fn spawn(file: DownloadFile, sender: Sender<Response>) -> Self {
+        let map = file.map().clone();
+        let (sender, _) = spawn_disk_thread(file, sender);
+        Self {
+            map,
+            sender,
+            reading: 0,
+        }
+    }
+
+    fn send(&self, req: DiskRequest) {
+        self.sender
+            .send(req)
+            .expect("Main thread failed to communicate with disk thread!");
+    }
+
+    /// Sends `block` to be written, if we still want it. Whether it completes its piece is
+    /// only known once the disk thread has hashed it, which it reports as a [DiskResponse], so
+    /// this is either [BlockOutcome::Accepted] or [BlockOutcome::Duplicate].
+    pub fn write(&mut self, block: Block) -> Result<BlockOutcome> {
+        if self.map.fill(&block.info(), block.source)?.is_empty() {
+            return Ok(BlockOutcome::Duplicate);
+        }
+
+        self.send(DiskRequest::WriteBlock(block));
+        Ok(BlockOutcome::Accepted)
+    }
+
+    /// Asks for `block` to be read into `buf` for the peer at `addr`, if it is in a verified
+    /// piece. The read comes back with the piece's generation as of now, to be checked with
+    /// [FileMap::is_current] before the data is sent.
+    pub fn read(&mut self, addr: SocketAddr, block: BlockInfo, buf: Vec<u8>) -> Result<()> {
+        self.map.check_readable(&block)?;
+        let generation = self.map.pieces[block.piece].generation;
+        self.reading += block.range.len();
+        self.send(DiskRequest::ReadBlock {
+            addr,
+            block,
+            generation,
+            buf,
+        });
+        Ok(())
+    }
+
+    /// Takes note that the disk thread has answered a [Disk::read] of `block`
+    pub fn read_done(&mut self, block: &BlockInfo) {
+        self.reading -= block.range.len();
+    }
+
+    /// Bytes asked for with [Disk::read] that haven't been read yet
+    pub fn reading(&self) -> usize {
+        self.reading
+    }
+
+    /// Asks for up to `len` verified bytes at `offset`, which are sent to `reply`
+    pub fn read_range(&self, offset: usize, len: usize, reply: Sender<Vec<u8>>) {
+        self.send(DiskRequest::ReadRange { offset, len, reply });
+    }
+
+    pub fn recheck(&self) {
+        self.send(DiskRequest::Recheck);
+    }
+
+    /// Asks how much space is free on the file's disk, which comes back as
+    /// [DiskResponse::FreeSpace]
+    pub fn probe_space(&self) {
+        self.send(DiskRequest::FreeSpace);
+    }
+
+    /// Asks for a resume file to be saved to `path`, once everything sent before is written
+    pub fn save_resume(&self, path: PathBuf, info_hash: [u8; 20]) {
+        self.send(DiskRequest::SaveResume { path, info_hash });
+    }
+
+    /// Waits for everything sent so far to be done and flushed to disk. If the flush fails,
+    /// the pieces the disk thread reset are in [FileError::FlushFailed], and should be
+    /// [Disk::reset] here too.
+    pub fn flush(&self) -> Result<()> {
+        let (reply, rx) = channel::bounded(1);
+        self.send(DiskRequest::Flush(reply));
+        rx.recv()
+            .expect("Disk thread exited without flushing the file!")
+    }
+
+    /// Waits for the disk thread to move the complete file to its real name
+    pub fn finalize(&self) -> Result<()> {
+        let (reply, rx) = channel::bounded(1);
+        self.send(DiskRequest::Finalize(reply));
+        rx.recv()
+            .expect("Disk thread exited without finalizing the file!")
+    }
+
+    /// Counts `piece` as verified, once the disk thread has. Returns how many bytes that
+    /// adds to what we have.
+    pub fn verified(&mut self, piece: usize) -> usize {
+        self.map.mark_verified(piece)
+    }
+
+    /// Resets `piece`, once the disk thread has
+    pub fn reset(&mut self, piece: usize) {
+        self.map.reset(piece);
+    }
+}
+
+impl Deref for Disk {
+    type Target = FileMap;
+
+    fn deref(&self) -> &FileMap {
+        &self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::net::SocketAddr;
+    use std::ops::Range;
+    use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+    use std::thread;
+    use std::time::Duration;
+
+    use bitvec::prelude::*;
+    use crossbeam::channel;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use hex_literal::hex;
+    use tempfile;
+
+    use crate::file::{BlockInfo, BLOCK_SIZE};
+
+    use sha1::{Digest, Sha1};
+
+    use super::{
+        get_block_ranges, part_path, spawn_disk_thread, Block, BlockOutcome, DiskRequest,
+        DiskResponse, DownloadFile, FileError, FlushPolicy, PieceState, Priority,
+        DEFAULT_READ_CACHE_BYTES, DIGEST_SIZE, MAX_CACHED_PIECE_LEN,
+    };
+    use crate::hash::{PieceHasher, Sha1PieceHasher, Uncached};
+    use crate::reader::PrefixReader;
+    use crate::resume::ResumeData;
+    use crate::threads::Response;
+
+    fn sha1() -> Box<dyn PieceHasher> {
+        Box::new(Sha1PieceHasher::default())
+    }
+
+    const RANGE_PIECE_LEN: usize = 1024;
+
+    // Three pieces (the last one short), with only the pieces in `complete` downloaded.
+    // Returns the file along with the full expected contents.
+    fn range_file(complete: &[usize]) -> (DownloadFile, Vec<u8>) {
+        let data: Vec<u8> = (0..RANGE_PIECE_LEN * 3 - 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(RANGE_PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            RANGE_PIECE_LEN,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+
+        for &piece in complete {
+            let start = piece * RANGE_PIECE_LEN;
+            let end = (start + RANGE_PIECE_LEN).min(data.len());
+            file.process_block(Block::new(piece, 0, &data[start..end]))
+                .unwrap();
+        }
+
+        (file, data)
+    }
+
+    #[test]
+    fn get_block_ranges_test() {
+        let ranges = get_block_ranges(0, 33, 10);
+        assert_eq!(&ranges, &[(0..10), (10..20), (20..30), (30..33)]);
+    }
+
+    #[test]
+    fn get_block_ranges_test_current_is_end() {
+        let ranges = get_block_ranges(0, 0, 10);
+        assert_eq!(&ranges, &[]);
+    }
+
+    #[test]
+    fn file_one_piece_success() {
+        let data = vec![0; 1024];
+        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, 1024, data.len(), sha1()).unwrap();
+
+        let block = Block::new(0, 0, &data[..]);
+
+        assert_eq!(
+            file.process_block(block).unwrap(),
+            BlockOutcome::PieceComplete
+        );
+        assert!(file.map.pieces[0].is_complete());
+
+        // check file contents
+        let mut buf = Vec::new();
+        file.file.seek(SeekFrom::Start(0)).unwrap();
+
+        file.file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(file.left_verified(), 0);
+    }
+
+    #[test]
     fn file_one_piece_badhash() {
         let data = vec![1; 1024];
         let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
         let temp_file = tempfile::tempfile().unwrap();
 
-        let mut file = DownloadFile::new_from_file(temp_file, hashes, 1024, data.len()).unwrap();
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, 1024, data.len(), sha1()).unwrap();
+
+        let block = Block::new(0, 0, &data[..]);
+
+        assert_eq!(
+            file.process_block(block).unwrap(),
+            BlockOutcome::HashMismatch {
+                piece: 0,
+                contributors: Vec::new()
+            }
+        );
+        assert!(!file.map.pieces[0].is_complete());
+    }
+
+    #[test]
+    fn file_one_piece_badhash_then_success() {
+        let data = vec![1; 1024];
+        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, 1024, data.len(), sha1()).unwrap();
+
+        let block = Block::new(0, 0, &data[..]);
+        assert_eq!(
+            file.process_block(block).unwrap(),
+            BlockOutcome::HashMismatch {
+                piece: 0,
+                contributors: Vec::new()
+            }
+        );
+        assert!(!file.map.pieces[0].is_complete());
+
+        let data_good = vec![0; 1024];
+        let block = Block::new(0, 0, &data_good[..]);
+        assert_eq!(
+            file.process_block(block).unwrap(),
+            BlockOutcome::PieceComplete
+        );
+
+        assert!(file.map.pieces[0].is_complete());
+
+        // check file contents
+        let mut buf = Vec::new();
+        file.file.seek(SeekFrom::Start(0)).unwrap();
+
+        file.file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data_good);
+    }
+
+    // Bytes the pieces of `file` hold on the heap for their unfilled ranges
+    fn range_bytes(file: &DownloadFile) -> usize {
+        let ranges: usize = file.map.pieces.iter().map(|p| p.unfilled.capacity()).sum();
+        ranges * std::mem::size_of::<Range<usize>>()
+    }
+
+    #[test]
+    fn pieces_keep_one_set_of_ranges_and_drop_it_once_verified() {
+        const PIECES: usize = 64;
+        let piece_len = BLOCK_SIZE * 16;
+        let data = vec![0; piece_len * PIECES];
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(piece_len)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            piece_len,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+        let per_piece = 16 * std::mem::size_of::<Range<usize>>();
+        assert_eq!(range_bytes(&file), per_piece * PIECES);
+
+        for offset in (0..piece_len).step_by(BLOCK_SIZE) {
+            file.process_block(Block::new(0, offset, &data[..BLOCK_SIZE]))
+                .unwrap();
+        }
+        assert_eq!(file.piece_state(0), Some(PieceState::Complete));
+        assert_eq!(range_bytes(&file), per_piece * (PIECES - 1));
+
+        // a bad piece gets its blocks back, to be downloaded again
+        for offset in (0..piece_len).step_by(BLOCK_SIZE) {
+            file.process_block(Block::new(1, offset, &[1; BLOCK_SIZE]))
+                .unwrap();
+        }
+        assert_eq!(file.piece_state(1), Some(PieceState::Missing));
+        assert_eq!(
+            file.get_unfilled(1).unwrap(),
+            get_block_ranges(0, piece_len, BLOCK_SIZE)
+        );
+        assert!(range_bytes(&file) <= per_piece * PIECES);
+    }
+
+    #[test]
+    fn skipped_pieces_are_not_needed_to_complete() {
+        let (mut file, data) = range_file(&[0]);
+        file.set_piece_priority(1, Priority::Skip).unwrap();
+        file.set_piece_priority(2, Priority::High).unwrap();
+        assert!(file.set_piece_priority(3, Priority::Skip).is_err());
+        assert_eq!(file.priority(1), Some(Priority::Skip));
+        assert_eq!(file.unfilled_by_priority().collect::<Vec<_>>(), [2]);
+
+        let last = data.len() - 2 * RANGE_PIECE_LEN;
+        assert_eq!(file.left_verified(), last);
+        assert_eq!(file.left_estimated(), last);
+        assert!(!file.is_complete());
+
+        // blocks of a skipped piece that turn up anyway don't count either way
+        let skipped = &data[RANGE_PIECE_LEN..RANGE_PIECE_LEN + 100];
+        file.process_block(Block::new(1, 0, skipped)).unwrap();
+        assert_eq!(file.left_estimated(), last);
+
+        file.process_block(Block::new(2, 0, &data[2 * RANGE_PIECE_LEN..]))
+            .unwrap();
+        assert!(file.is_complete());
+        assert_eq!(file.left_verified(), 0);
+        assert_eq!(file.left_estimated(), 0);
+        assert_eq!(file.piece_state(1), Some(PieceState::Partial));
+        assert_eq!(file.unfilled_by_priority().count(), 0);
+
+        // wanting it after all takes the download back out of complete
+        file.set_piece_priority(1, Priority::Normal).unwrap();
+        assert!(!file.is_complete());
+        assert_eq!(file.left_verified(), RANGE_PIECE_LEN);
+        assert_eq!(file.left_estimated(), RANGE_PIECE_LEN - 100);
+        assert_eq!(file.unfilled_by_priority().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn estimated_left_goes_back_up_when_a_piece_is_bad() {
+        let piece_len = BLOCK_SIZE * 2;
+        let total = piece_len * 2 + 100;
+        let data = vec![0; total];
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(piece_len)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            piece_len,
+            total,
+            sha1(),
+        )
+        .unwrap();
+        assert_eq!(file.left_estimated(), total);
+
+        // every block counts as it arrives, while trackers only hear about verified pieces
+        file.process_block(Block::new(0, 0, &[1; BLOCK_SIZE]))
+            .unwrap();
+        assert_eq!(file.left_estimated(), total - BLOCK_SIZE);
+        assert_eq!(file.left_verified(), total);
+
+        // and the whole piece is taken back once it turns out to be bad
+        let outcome = file
+            .process_block(Block::new(0, BLOCK_SIZE, &data[..BLOCK_SIZE]))
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            BlockOutcome::HashMismatch { piece: 0, .. }
+        ));
+        assert_eq!(file.left_estimated(), total);
+
+        // overlapping blocks count the part that was new, and copies count for nothing
+        let half = BLOCK_SIZE / 2;
+        for offset in [0, 0, half] {
+            file.process_block(Block::new(1, offset, &data[..BLOCK_SIZE]))
+                .unwrap();
+        }
+        assert_eq!(file.left_estimated(), total - BLOCK_SIZE - half);
+        let rest = &data[..piece_len - BLOCK_SIZE - half];
+        let outcome = file
+            .process_block(Block::new(1, BLOCK_SIZE + half, rest))
+            .unwrap();
+        assert_eq!(outcome, BlockOutcome::PieceComplete);
+        assert_eq!(file.left_estimated(), total - piece_len);
+        assert_eq!(file.left_verified(), total - piece_len);
+    }
+
+    #[test]
+    fn bad_pieces_name_everyone_who_sent_part() {
+        let piece_len = BLOCK_SIZE * 2;
+        let hash: [u8; DIGEST_SIZE] = Sha1::digest(vec![0; piece_len]).into();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &[hash; 2],
+            piece_len,
+            piece_len * 2,
+            sha1(),
+        )
+        .unwrap();
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let good = vec![0; BLOCK_SIZE];
+
+        let block = Block::new(0, 0, &[1; BLOCK_SIZE]).sent_by(a);
+        assert_eq!(file.process_block(block).unwrap(), BlockOutcome::Accepted);
+        assert_eq!(file.map().contributors(0), [a]);
+
+        // a copy of a block we already have is no part of the piece
+        let block = Block::new(0, 0, &good).sent_by(c);
+        assert_eq!(file.process_block(block).unwrap(), BlockOutcome::Duplicate);
+        let block = Block::new(0, BLOCK_SIZE, &good).sent_by(b);
+        assert_eq!(
+            file.process_block(block).unwrap(),
+            BlockOutcome::HashMismatch {
+                piece: 0,
+                contributors: vec![a, b]
+            }
+        );
+        assert!(file.map().contributors(0).is_empty());
+
+        // and once a piece is good, nobody needs remembering
+        for (offset, addr) in [(0, a), (BLOCK_SIZE, b)] {
+            file.process_block(Block::new(1, offset, &good).sent_by(addr))
+                .unwrap();
+        }
+        assert_eq!(file.piece_state(1), Some(PieceState::Complete));
+        assert!(file.map().contributors(1).is_empty());
+        assert!(file.map().contributors(7).is_empty());
+    }
+
+    #[test]
+    fn file_two_piece_partial_success() {
+        let data1 = vec![0; BLOCK_SIZE * 2];
+        let data2 = vec![1; BLOCK_SIZE * 2];
+        let hashes = &[
+            hex!("5188431849b4613152fd7bdba6a3ff0a4fd6424b"),
+            hex!("d3a26f5cc20679c826302154ccd89edd238cfaca"),
+        ];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, BLOCK_SIZE * 2, BLOCK_SIZE * 4, sha1())
+                .unwrap();
+
+        let (data1_0, data1_1) = data1.split_at(BLOCK_SIZE);
+        let (data2_0, data2_1) = data2.split_at(BLOCK_SIZE);
+
+        let block1_0 = Block::new(0, 0, &data1_0[..]);
+        let block1_1 = Block::new(0, BLOCK_SIZE, &data1_1[..]);
+        let block2_0 = Block::new(1, 0, &data2_0[..]);
+        let block2_1 = Block::new(1, BLOCK_SIZE, &data2_1[..]);
+
+        assert_eq!(
+            file.process_block(block1_0).unwrap(),
+            BlockOutcome::Accepted
+        );
+        assert_eq!(
+            file.process_block(block1_1).unwrap(),
+            BlockOutcome::PieceComplete
+        );
+        assert_eq!(
+            file.process_block(block2_0).unwrap(),
+            BlockOutcome::Accepted
+        );
+        assert!(file.map.pieces[0].is_complete());
+        assert!(!file.map.pieces[1].is_complete());
+        assert_eq!(
+            file.process_block(block2_1).unwrap(),
+            BlockOutcome::PieceComplete
+        );
+        eprintln!("{:?}", file.map.pieces[1].unfilled);
+        assert!(file.map.pieces[0].is_complete());
+        assert!(file.map.pieces[1].is_complete());
+
+        // check file contents
+        let mut buf = Vec::new();
+        file.file.seek(SeekFrom::Start(0)).unwrap();
+
+        file.file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf[..BLOCK_SIZE * 2], data1);
+        assert_eq!(buf[BLOCK_SIZE * 2..], data2);
+    }
+
+    #[test]
+    fn piece_state_tracks_blocks() {
+        let hashes = &[hex!("5188431849b4613152fd7bdba6a3ff0a4fd6424b")];
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            hashes,
+            BLOCK_SIZE * 2,
+            BLOCK_SIZE * 2,
+            sha1(),
+        )
+        .unwrap();
+        assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+        assert_eq!(file.piece_state(1), None);
+
+        assert_eq!(
+            file.process_block(Block::new(0, 0, &[0; BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::Accepted
+        );
+        assert_eq!(file.piece_state(0), Some(PieceState::Partial));
+
+        // a bad hash sends the piece back to square one
+        assert_eq!(
+            file.process_block(Block::new(0, BLOCK_SIZE, &[1; BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::HashMismatch {
+                piece: 0,
+                contributors: Vec::new()
+            }
+        );
+        assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+
+        assert_eq!(
+            file.process_block(Block::new(0, 0, &[0; BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::Accepted
+        );
+        assert_eq!(
+            file.process_block(Block::new(0, BLOCK_SIZE, &[0; BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::PieceComplete
+        );
+        assert_eq!(file.piece_state(0), Some(PieceState::Complete));
+
+        // and a block that arrives after that is of no use
+        assert_eq!(
+            file.process_block(Block::new(0, 0, &[0; BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::Duplicate
+        );
+    }
+
+    #[test]
+    fn blocks_need_not_line_up_with_our_ranges() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i % 253) as u8).collect();
+        let hashes = [Sha1::digest(&data).into()];
+
+        let last = BLOCK_SIZE * 3..BLOCK_SIZE * 4;
+
+        // assembled in memory, and written straight to disk
+        for cache_pieces in [1, 0] {
+            let mut file = DownloadFile::new_from_file(
+                tempfile::tempfile().unwrap(),
+                &hashes,
+                data.len(),
+                data.len(),
+                sha1(),
+            )
+            .unwrap();
+            file.set_cache_pieces(cache_pieces);
+
+            // a block inside one of our ranges leaves the rest of it to ask for
+            assert_eq!(
+                file.process_block(Block::new(0, 100, &data[100..200]))
+                    .unwrap(),
+                BlockOutcome::Accepted
+            );
+            assert_eq!(
+                file.get_unfilled(0).unwrap(),
+                [
+                    0..100,
+                    200..BLOCK_SIZE,
+                    BLOCK_SIZE..BLOCK_SIZE * 2,
+                    BLOCK_SIZE * 2..BLOCK_SIZE * 3,
+                    last.clone()
+                ]
+            );
+
+            // an oversized one fills both the ranges it spans
+            assert_eq!(
+                file.process_block(Block::new(0, BLOCK_SIZE, &data[BLOCK_SIZE..BLOCK_SIZE * 3]))
+                    .unwrap(),
+                BlockOutcome::Accepted
+            );
+            assert_eq!(
+                file.get_unfilled(0).unwrap(),
+                [0..100, 200..BLOCK_SIZE, last.clone()]
+            );
+
+            // one overlapping data we have only fills the gaps, so what it says about the
+            // rest doesn't matter
+            let mut overlapping = data[..BLOCK_SIZE * 2].to_vec();
+            overlapping[150] ^= 0xff;
+            overlapping[BLOCK_SIZE + 5] ^= 0xff;
+            assert_eq!(
+                file.process_block(Block::new(0, 0, &overlapping)).unwrap(),
+                BlockOutcome::Accepted
+            );
+            assert_eq!(file.get_unfilled(0).unwrap(), std::slice::from_ref(&last));
+
+            // but one running past the end of the piece is refused outright
+            let result = file.process_block(Block::new(0, BLOCK_SIZE * 3, &[0; BLOCK_SIZE + 1]));
+            assert!(matches!(result, Err(FileError::InvalidRange(_))));
+            assert_eq!(file.get_unfilled(0).unwrap(), std::slice::from_ref(&last));
+
+            let rest = BLOCK_SIZE * 3 - 10;
+            assert_eq!(
+                file.process_block(Block::new(0, rest, &data[rest..]))
+                    .unwrap(),
+                BlockOutcome::PieceComplete
+            );
+            assert_eq!(file.read_range(0, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn file_one_piece_irregular_size_success() {
+        let data = vec![0; 727];
+        let hashes = &[hex!("baa70378f8c072730b9d16869f32a65b7e5d8237")];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, 727, data.len(), sha1()).unwrap();
+
+        let block = Block::new(0, 0, &data[..]);
+
+        file.process_block(block).unwrap();
+        assert!(file.map.pieces[0].is_complete());
+
+        // check file contents
+        let mut buf = Vec::new();
+        file.file.seek(SeekFrom::Start(0)).unwrap();
+
+        file.file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn file_two_piece_bitmap() {
+        let data1 = vec![0; BLOCK_SIZE * 2];
+        let data2 = vec![1; BLOCK_SIZE * 2];
+        let hashes = &[
+            hex!("5188431849b4613152fd7bdba6a3ff0a4fd6424b"),
+            hex!("d3a26f5cc20679c826302154ccd89edd238cfaca"),
+        ];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, BLOCK_SIZE * 2, BLOCK_SIZE * 4, sha1())
+                .unwrap();
+
+        let (data1_0, data1_1) = data1.split_at(16384);
+        let (data2_0, data2_1) = data2.split_at(16384);
+
+        let block1_0 = Block::new(0, 0, &data1_0[..]);
+        let block1_1 = Block::new(0, BLOCK_SIZE, &data1_1[..]);
+        let block2_0 = Block::new(1, 0, &data2_0[..]);
+        let block2_1 = Block::new(1, BLOCK_SIZE, &data2_1[..]);
+
+        file.process_block(block1_0).unwrap();
+        file.process_block(block1_1).unwrap();
+        file.process_block(block2_0).unwrap();
+        assert_eq!(file.bitfield(), &[0x80]);
+        file.process_block(block2_1).unwrap();
+        assert_eq!(file.bitfield(), &[0xc0]);
+
+        // check file contents
+        let mut buf = Vec::new();
+        file.file.seek(SeekFrom::Start(0)).unwrap();
+
+        file.file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf[..BLOCK_SIZE * 2], data1);
+        assert_eq!(buf[BLOCK_SIZE * 2..], data2);
+    }
+
+    #[test]
+    fn file_get_block_success() {
+        let data = vec![0; 1024];
+        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, 1024, data.len(), sha1()).unwrap();
+
+        let block = Block::new(0, 0, &data[..]);
+
+        file.process_block(block).unwrap();
+        assert!(file.map.pieces[0].is_complete());
+
+        // check file contents
+        let buf = file
+            .get_block(BlockInfo {
+                piece: 0,
+                range: 0..1024,
+            })
+            .unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn new_seeding_invariants() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let hashes = &[[0u8; DIGEST_SIZE]; 4];
+        let mut file = DownloadFile::new_seeding(
+            temp_file.path(),
+            hashes,
+            BLOCK_SIZE * 4,
+            BLOCK_SIZE * 16,
+            sha1(),
+            false,
+        )
+        .unwrap();
+
+        assert!(file.is_complete());
+        assert_eq!(file.bitfield(), &[0b11110000]);
+        assert_eq!(file.left_verified(), 0);
+
+        // none of it matches, which a recheck finds out
+        assert_eq!(file.verify_all().unwrap(), [0, 1, 2, 3]);
+        assert_eq!(file.left_verified(), BLOCK_SIZE * 16);
+    }
+
+    #[test]
+    fn verified_seeding_keeps_only_good_pieces() {
+        let (_, data) = range_file(&[]);
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(RANGE_PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let seed = |contents: &[u8]| {
+            let temp_file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(temp_file.path(), contents).unwrap();
+            DownloadFile::new_seeding(
+                temp_file.path(),
+                &hashes,
+                RANGE_PIECE_LEN,
+                data.len(),
+                sha1(),
+                true,
+            )
+        };
+
+        assert!(seed(&data).unwrap().is_complete());
+
+        // the middle piece is bad, so it's left to be downloaded again
+        let mut corrupt = data.clone();
+        corrupt[RANGE_PIECE_LEN + 10] ^= 1;
+        let file = seed(&corrupt).unwrap();
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(file.piece_state(1), Some(PieceState::Missing));
+        assert_eq!(file.left_verified(), RANGE_PIECE_LEN);
+
+        // and a file of the wrong length isn't seeded at all
+        let short = &data[..data.len() - 1];
+        assert!(matches!(
+            seed(short),
+            Err(FileError::WrongLength { actual, expected })
+                if actual == data.len() - 1 && expected == data.len()
+        ));
+    }
+
+    #[test]
+    fn uncached_verification_finds_the_same_pieces() {
+        let (_, mut data) = range_file(&[]);
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(RANGE_PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        data[RANGE_PIECE_LEN * 2 + 1] ^= 1;
+
+        // the last piece is bad from the start, and the middle one goes bad before a recheck
+        let resume = |hasher: Box<dyn PieceHasher>| {
+            let temp_file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(temp_file.path(), &data).unwrap();
+            let mut file = DownloadFile::new_resume(
+                temp_file.path(),
+                &hashes,
+                RANGE_PIECE_LEN,
+                data.len(),
+                hasher,
+            )
+            .unwrap();
+            let verified: Vec<usize> = file.bitvec().iter_ones().collect();
+            file.file
+                .write_all_at(&[0], RANGE_PIECE_LEN as u64)
+                .unwrap();
+            (verified, file.verify_all().unwrap())
+        };
+        let plain = resume(sha1());
+        assert_eq!(plain, (vec![0, 1], vec![1]));
+        assert_eq!(resume(Box::new(Uncached(sha1()))), plain);
+    }
+
+    #[test]
+    fn read_only_files_are_seeded_without_writing() {
+        let (_, data) = range_file(&[]);
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(RANGE_PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut contents = data.clone();
+        contents[RANGE_PIECE_LEN * 2] ^= 1;
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(temp_file.path(), &contents).unwrap();
+        let mut permissions = temp_file.as_file().metadata().unwrap().permissions();
+        permissions.set_mode(0o444);
+        std::fs::set_permissions(temp_file.path(), permissions).unwrap();
+        let seed = |read_only: bool| {
+            let open = match read_only {
+                true => DownloadFile::new_seeding_read_only,
+                false => DownloadFile::new_seeding,
+            };
+            open(
+                temp_file.path(),
+                &hashes,
+                RANGE_PIECE_LEN,
+                data.len(),
+                sha1(),
+                true,
+            )
+            .unwrap()
+        };
+
+        // root may write to it all the same, so only anyone else falls back to read-only
+        let fallback = seed(false);
+        assert_eq!(fallback.is_read_only(), unsafe { libc::geteuid() } != 0);
+        drop(fallback);
+
+        // the bad piece can't be downloaded again, so it is left out
+        let mut file = seed(true);
+        assert!(file.is_read_only());
+        assert!(file.is_complete());
+        assert_eq!(file.priority(2), Some(Priority::Skip));
+        assert_eq!(file.left_verified(), 0);
+        assert_eq!(file.unfilled_by_priority().count(), 0);
+
+        // and the rest is served as usual
+        let block = BlockInfo {
+            piece: 1,
+            range: 0..RANGE_PIECE_LEN,
+        };
+        assert_eq!(
+            file.get_block(block).unwrap(),
+            &data[RANGE_PIECE_LEN..RANGE_PIECE_LEN * 2]
+        );
+        assert_eq!(
+            file.read_range(0, RANGE_PIECE_LEN * 2).unwrap(),
+            &data[..RANGE_PIECE_LEN * 2]
+        );
+
+        // blocks for pieces we have are copies as ever, but nothing else gets written
+        let good = &data[RANGE_PIECE_LEN * 2..];
+        let outcome = file.process_block(Block::new(0, 0, &data[..100])).unwrap();
+        assert_eq!(outcome, BlockOutcome::Duplicate);
+        let err = file.process_block(Block::new(2, 0, good)).unwrap_err();
+        assert!(matches!(err, FileError::ReadOnly));
+        assert!(!err.is_fatal());
+        assert_eq!(std::fs::read(temp_file.path()).unwrap(), contents);
+
+        // with nothing to write, the file has to be whole
+        std::fs::set_permissions(temp_file.path(), std::fs::Permissions::from_mode(0o644)).unwrap();
+        temp_file.as_file().set_len(100).unwrap();
+        assert!(matches!(
+            DownloadFile::new_seeding_read_only(
+                temp_file.path(),
+                &hashes,
+                RANGE_PIECE_LEN,
+                data.len(),
+                sha1(),
+                false,
+            ),
+            Err(FileError::WrongLength { actual: 100, .. })
+        ));
+    }
+
+    #[test]
+    fn readers_only_get_as_far_as_the_verified_prefix() {
+        let (mut file, data) = range_file(&[]);
+        let mut reader = file.reader().unwrap();
+        let mut buf = vec![0; data.len()];
+        let read = |reader: &mut PrefixReader, buf: &mut [u8]| match reader.read(buf) {
+            Ok(n) => Some(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => panic!("{}", e),
+        };
+        let write = |file: &mut DownloadFile, piece: usize| {
+            let start = piece * RANGE_PIECE_LEN;
+            let end = (start + RANGE_PIECE_LEN).min(data.len());
+            let outcome = file
+                .process_block(Block::new(piece, 0, &data[start..end]))
+                .unwrap();
+            assert_eq!(outcome, BlockOutcome::PieceComplete);
+        };
+
+        // the middle piece comes first, which doesn't get the reader anywhere
+        assert_eq!(read(&mut reader, &mut buf), None);
+        write(&mut file, 1);
+        assert_eq!(reader.available(), 0);
+        assert_eq!(read(&mut reader, &mut buf), None);
+
+        // then the first, which lets it read both
+        write(&mut file, 0);
+        assert_eq!(reader.available(), RANGE_PIECE_LEN * 2);
+        assert_eq!(read(&mut reader, &mut buf[..100]), Some(100));
+        assert_eq!(
+            read(&mut reader, &mut buf[100..]),
+            Some(RANGE_PIECE_LEN * 2 - 100)
+        );
+        assert_eq!(buf[..RANGE_PIECE_LEN * 2], data[..RANGE_PIECE_LEN * 2]);
+        assert_eq!(read(&mut reader, &mut buf), None);
+        assert!(!reader.wait_timeout(Duration::from_millis(10)));
+
+        // a reader waiting on another thread is woken by the last piece
+        let waiting = thread::spawn(move || {
+            reader.wait();
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            rest
+        });
+        write(&mut file, 2);
+        assert_eq!(waiting.join().unwrap(), data[RANGE_PIECE_LEN * 2..]);
+
+        // a new reader starts from the beginning
+        let mut reader = file.reader().unwrap();
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn verify_all_detects_corruption() {
+        let data1 = vec![0; BLOCK_SIZE * 2];
+        let data2 = vec![1; BLOCK_SIZE * 2];
+        let hashes = &[
+            hex!("5188431849b4613152fd7bdba6a3ff0a4fd6424b"),
+            hex!("d3a26f5cc20679c826302154ccd89edd238cfaca"),
+        ];
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let mut file =
+            DownloadFile::new_from_file(temp_file, hashes, BLOCK_SIZE * 2, BLOCK_SIZE * 4, sha1())
+                .unwrap();
+
+        for (piece, data) in [(0, &data1), (1, &data2)] {
+            let (first, second) = data.split_at(BLOCK_SIZE);
+            file.process_block(Block::new(piece, 0, first)).unwrap();
+            file.process_block(Block::new(piece, BLOCK_SIZE, second))
+                .unwrap();
+        }
+        assert!(file.is_complete());
+
+        // nothing has changed on disk yet, so everything should still verify
+        assert_eq!(file.verify_all().unwrap(), Vec::<usize>::new());
+
+        // corrupt the second piece behind the DownloadFile's back
+        file.file
+            .seek(SeekFrom::Start((BLOCK_SIZE * 3) as u64))
+            .unwrap();
+        file.file.write_all(&[0xff; 16]).unwrap();
+
+        assert_eq!(file.verify_all().unwrap(), vec![1]);
+        assert_eq!(file.bitfield(), &[0x80]);
+        assert_eq!(file.left_verified(), BLOCK_SIZE * 2);
+        assert_eq!(file.get_unfilled(1).unwrap().len(), 2);
+
+        // we must stop serving the invalidated piece
+        assert!(file
+            .get_block(BlockInfo {
+                piece: 1,
+                range: 0..BLOCK_SIZE,
+            })
+            .is_err());
+
+        // and it should be possible to download it again
+        let (first, second) = data2.split_at(BLOCK_SIZE);
+        file.process_block(Block::new(1, 0, first)).unwrap();
+        file.process_block(Block::new(1, BLOCK_SIZE, second))
+            .unwrap();
+        assert!(file.is_complete());
+        assert_eq!(file.left_verified(), 0);
+    }
 
-        let block = Block::new(0, 0, &data[..]);
+    #[test]
+    fn read_range_starts_mid_piece() {
+        let (file, data) = range_file(&[0, 1]);
 
-        file.process_block(block).unwrap();
-        assert!(!file.pieces[0].is_complete());
+        let buf = file.read_range(1000, 1000).unwrap();
+        assert_eq!(buf, data[1000..2000]);
     }
 
     #[test]
-    fn file_one_piece_badhash_then_success() {
-        let data = vec![1; 1024];
-        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
-        let temp_file = tempfile::tempfile().unwrap();
+    fn read_range_ends_mid_piece() {
+        let (file, data) = range_file(&[0]);
 
-        let mut file = DownloadFile::new_from_file(temp_file, hashes, 1024, data.len()).unwrap();
+        let buf = file.read_range(0, 500).unwrap();
+        assert_eq!(buf, data[..500]);
+        let buf = file.read_range(100, 924).unwrap();
+        assert_eq!(buf, data[100..RANGE_PIECE_LEN]);
+    }
 
-        let block = Block::new(0, 0, &data[..]);
-        file.process_block(block).unwrap();
-        assert!(!file.pieces[0].is_complete());
+    #[test]
+    fn read_range_spans_three_pieces() {
+        let (file, data) = range_file(&[0, 1, 2]);
 
-        let data_good = vec![0; 1024];
-        let block = Block::new(0, 0, &data_good[..]);
-        file.process_block(block).unwrap();
+        let buf = file.read_range(10, data.len() - 20).unwrap();
+        assert_eq!(buf, data[10..data.len() - 10]);
+        let buf = file.read_range(0, data.len()).unwrap();
+        assert_eq!(buf, data);
+    }
 
-        assert!(file.pieces[0].is_complete());
+    #[test]
+    fn read_range_past_eof() {
+        let (file, data) = range_file(&[0, 1, 2]);
 
-        // check file contents
-        let mut buf = Vec::new();
-        file.file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(file.read_range(data.len() - 10, 11).is_err());
+        assert!(file.read_range(data.len() + 1, 0).is_err());
+        assert!(file.read_range(1, usize::MAX).is_err());
+        assert_eq!(file.read_range(data.len(), 0).unwrap(), Vec::<u8>::new());
+    }
 
-        file.file.read_to_end(&mut buf).unwrap();
-        assert_eq!(buf, data_good);
+    #[test]
+    fn read_range_requires_complete_pieces() {
+        let (file, data) = range_file(&[0, 2]);
+
+        assert!(file.read_range(1000, 100).is_err());
+        assert!(file.read_range(RANGE_PIECE_LEN + 10, 10).is_err());
+
+        // pieces on either side of the missing one are still readable
+        let buf = file.read_range(RANGE_PIECE_LEN * 2, 10).unwrap();
+        assert_eq!(buf, data[RANGE_PIECE_LEN * 2..RANGE_PIECE_LEN * 2 + 10]);
     }
 
     #[test]
-    fn file_two_piece_partial_success() {
-        let data1 = vec![0; BLOCK_SIZE * 2];
-        let data2 = vec![1; BLOCK_SIZE * 2];
-        let hashes = &[
-            hex!("5188431849b4613152fd7bdba6a3ff0a4fd6424b"),
-            hex!("d3a26f5cc20679c826302154ccd89edd238cfaca"),
-        ];
-        let temp_file = tempfile::tempfile().unwrap();
+    fn verified_len_stops_at_first_incomplete_piece() {
+        let (file, data) = range_file(&[0, 2]);
+
+        assert_eq!(file.verified_len(0), RANGE_PIECE_LEN);
+        assert_eq!(file.verified_len(1000), RANGE_PIECE_LEN - 1000);
+        assert_eq!(file.verified_len(RANGE_PIECE_LEN), 0);
+        assert_eq!(
+            file.verified_len(RANGE_PIECE_LEN * 2 + 1),
+            data.len() - RANGE_PIECE_LEN * 2 - 1
+        );
+        assert_eq!(file.verified_len(data.len()), 0);
+    }
 
-        let mut file =
-            DownloadFile::new_from_file(temp_file, hashes, BLOCK_SIZE * 2, BLOCK_SIZE * 4).unwrap();
+    #[test]
+    fn bad_requests_are_not_fatal() {
+        let (mut file, _) = range_file(&[0]);
 
-        let (data1_0, data1_1) = data1.split_at(BLOCK_SIZE);
-        let (data2_0, data2_1) = data2.split_at(BLOCK_SIZE);
+        let err = file.process_block(Block::new(7, 0, &[0; 16])).unwrap_err();
+        assert!(matches!(err, FileError::InvalidPiece(7)));
+        assert!(!err.is_fatal());
 
-        let block1_0 = Block::new(0, 0, &data1_0[..]);
-        let block1_1 = Block::new(0, BLOCK_SIZE, &data1_1[..]);
-        let block2_0 = Block::new(1, 0, &data2_0[..]);
-        let block2_1 = Block::new(1, BLOCK_SIZE, &data2_1[..]);
+        let err = file
+            .get_block(BlockInfo {
+                piece: 1,
+                range: 0..16,
+            })
+            .unwrap_err();
+        assert!(matches!(err, FileError::Incomplete(1)));
+        assert!(!err.is_fatal());
 
-        file.process_block(block1_0).unwrap();
-        file.process_block(block1_1).unwrap();
-        file.process_block(block2_0).unwrap();
-        assert!(file.pieces[0].is_complete());
-        assert!(!file.pieces[1].is_complete());
-        file.process_block(block2_1).unwrap();
-        eprintln!("{:?}", file.pieces[1].unfilled);
-        assert!(file.pieces[0].is_complete());
-        assert!(file.pieces[1].is_complete());
+        let err = file.read_range(0, RANGE_PIECE_LEN * 3).unwrap_err();
+        assert!(matches!(err, FileError::PastEnd));
+    }
 
-        // check file contents
-        let mut buf = Vec::new();
-        file.file.seek(SeekFrom::Start(0)).unwrap();
+    #[test]
+    fn blocks_must_fit_their_piece() {
+        let (mut file, data) = range_file(&[0, 2]);
+        let read =
+            |file: &mut DownloadFile, piece, range| file.get_block(BlockInfo { piece, range });
+
+        // offsets that would run past the end of memory
+        let err = file
+            .process_block(Block::new(1, usize::MAX - 4, &[0; 16]))
+            .unwrap_err();
+        assert!(matches!(err, FileError::InvalidRange(_)));
+        let err = read(&mut file, 0, usize::MAX - 4..usize::MAX).unwrap_err();
+        assert!(matches!(err, FileError::InvalidRange(_)));
+
+        // empty blocks say nothing, and are refused rather than taken as duplicates
+        let err = file.process_block(Block::new(1, 0, &[])).unwrap_err();
+        assert!(matches!(err, FileError::InvalidRange(_)));
+        let err = read(&mut file, 0, 5..5).unwrap_err();
+        assert!(matches!(err, FileError::InvalidRange(_)));
+        assert_eq!(file.piece_state(1), Some(PieceState::Missing));
+
+        // the short final piece ends where the file does
+        let last = RANGE_PIECE_LEN * 2;
+        let tail = data.len() - last;
+        let block = read(&mut file, 2, tail - 24..tail).unwrap();
+        assert_eq!(block, data[data.len() - 24..]);
+        let err = read(&mut file, 2, tail - 24..tail + 1).unwrap_err();
+        assert!(matches!(err, FileError::InvalidRange(_)));
+    }
 
-        file.file.read_to_end(&mut buf).unwrap();
-        assert_eq!(buf[..BLOCK_SIZE * 2], data1);
-        assert_eq!(buf[BLOCK_SIZE * 2..], data2);
+    #[test]
+    fn progress_is_counted_in_bytes_written() {
+        const PIECE_LEN: usize = BLOCK_SIZE * 2;
+        let data = vec![0; PIECE_LEN * 2 + 100];
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            PIECE_LEN,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+
+        // a fresh piece
+        assert_eq!(file.piece_progress(0), Some((0, PIECE_LEN)));
+        assert_eq!(file.piece_progress(3), None);
+        assert_eq!(file.incomplete_pieces().count(), 0);
+
+        // a half-filled piece
+        file.process_block(Block::new(1, BLOCK_SIZE, &data[..BLOCK_SIZE]))
+            .unwrap();
+        assert_eq!(file.piece_progress(1), Some((BLOCK_SIZE, PIECE_LEN)));
+        assert_eq!(
+            file.incomplete_pieces().collect::<Vec<_>>(),
+            [(1, (BLOCK_SIZE, PIECE_LEN))]
+        );
+
+        // the short last piece, part of the way and then all of it
+        assert_eq!(file.piece_progress(2), Some((0, 100)));
+        file.process_block(Block::new(2, 0, &data[..40])).unwrap();
+        assert_eq!(file.piece_progress(2), Some((40, 100)));
+        let outcome = file.process_block(Block::new(2, 40, &data[..60])).unwrap();
+        assert_eq!(outcome, BlockOutcome::PieceComplete);
+        assert_eq!(file.piece_progress(2), Some((100, 100)));
+        assert_eq!(
+            file.incomplete_pieces()
+                .map(|(piece, _)| piece)
+                .collect::<Vec<_>>(),
+            [1]
+        );
     }
 
     #[test]
-    fn file_one_piece_irregular_size_success() {
-        let data = vec![0; 727];
-        let hashes = &[hex!("baa70378f8c072730b9d16869f32a65b7e5d8237")];
-        let temp_file = tempfile::tempfile().unwrap();
+    fn oversized_blocks_are_refused() {
+        let (mut file, data) = range_file(&[0]);
+        file.set_max_block_len(512);
 
-        let mut file = DownloadFile::new_from_file(temp_file, hashes, 727, data.len()).unwrap();
+        let err = file
+            .get_block(BlockInfo {
+                piece: 0,
+                range: 0..513,
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FileError::BlockTooLarge { len: 513, max: 512 }
+        ));
+        assert!(!err.is_fatal());
+
+        // however much of it we would want
+        let piece = &data[RANGE_PIECE_LEN..RANGE_PIECE_LEN * 2];
+        let err = file.process_block(Block::new(1, 0, piece)).unwrap_err();
+        assert!(matches!(err, FileError::BlockTooLarge { len, .. } if len == RANGE_PIECE_LEN));
+        assert_eq!(file.piece_state(1), Some(PieceState::Missing));
+
+        // while blocks up to the limit are fine
+        for offset in [0, 512] {
+            let block = Block::new(1, offset, &piece[offset..offset + 512]);
+            file.process_block(block).unwrap();
+        }
+        assert_eq!(file.piece_state(1), Some(PieceState::Complete));
+        let block = file
+            .get_block(BlockInfo {
+                piece: 1,
+                range: 0..512,
+            })
+            .unwrap();
+        assert_eq!(block, piece[..512]);
+    }
 
-        let block = Block::new(0, 0, &data[..]);
+    #[test]
+    fn mapped_files_end_up_the_same_as_written_ones() {
+        const PIECES: usize = 5;
+        let piece_len = BLOCK_SIZE * 2;
+        let total = piece_len * PIECES - 300;
+        let mut rng = StdRng::seed_from_u64(3);
+        let data: Vec<u8> = (0..total).map(|_| rng.gen()).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(piece_len)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+
+        let mut blocks: Vec<(usize, usize)> = (0..PIECES)
+            .flat_map(|piece| [(piece, 0), (piece, BLOCK_SIZE)])
+            .collect();
+        blocks.shuffle(&mut rng);
+
+        let mut contents = Vec::new();
+        for map in [false, true] {
+            let mut file = DownloadFile::new_from_file(
+                tempfile::tempfile().unwrap(),
+                &hashes,
+                piece_len,
+                total,
+                sha1(),
+            )
+            .unwrap();
+            assert_eq!(file.is_mapped(), cfg!(feature = "mmap"));
+            if map {
+                file.map_into_memory().unwrap();
+                assert!(file.is_mapped());
+            }
+            file.set_cache_pieces(0);
+            file.set_read_cache_bytes(0);
 
-        file.process_block(block).unwrap();
-        assert!(file.pieces[0].is_complete());
+            // a bad block first, so one piece is thrown away and downloaded again
+            let (piece, offset) = blocks[0];
+            file.process_block(Block::new(piece, offset, &[0xaa; BLOCK_SIZE]))
+                .unwrap();
+            let other = BLOCK_SIZE - offset;
+            let start = piece * piece_len + other;
+            let end = (start + BLOCK_SIZE).min(total);
+            let outcome = file
+                .process_block(Block::new(piece, other, &data[start..end]))
+                .unwrap();
+            assert!(matches!(outcome, BlockOutcome::HashMismatch { .. }));
 
-        // check file contents
-        let mut buf = Vec::new();
-        file.file.seek(SeekFrom::Start(0)).unwrap();
+            for &(piece, offset) in &blocks {
+                let start = piece * piece_len + offset;
+                let end = (start + BLOCK_SIZE).min(total);
+                file.process_block(Block::new(piece, offset, &data[start..end]))
+                    .unwrap();
+            }
+            assert!(file.is_complete());
+            assert!(file.verify_all().unwrap().is_empty());
+            assert_eq!(
+                file.read_range(100, total - 200).unwrap(),
+                data[100..total - 100]
+            );
+            let block = file
+                .get_block(BlockInfo {
+                    piece: 3,
+                    range: 10..500,
+                })
+                .unwrap();
+            assert_eq!(block, data[piece_len * 3 + 10..piece_len * 3 + 500]);
 
-        file.file.read_to_end(&mut buf).unwrap();
-        assert_eq!(buf, data);
+            file.flush().unwrap();
+            let mut on_disk = vec![0; total];
+            file.file.read_exact_at(&mut on_disk, 0).unwrap();
+            contents.push(on_disk);
+        }
+        assert_eq!(contents[0], data);
+        assert_eq!(contents[1], data);
     }
 
     #[test]
-    fn file_two_piece_bitmap() {
-        let data1 = vec![0; BLOCK_SIZE * 2];
-        let data2 = vec![1; BLOCK_SIZE * 2];
-        let hashes = &[
-            hex!("5188431849b4613152fd7bdba6a3ff0a4fd6424b"),
-            hex!("d3a26f5cc20679c826302154ccd89edd238cfaca"),
-        ];
+    fn truncated_file_is_fatal() {
         let temp_file = tempfile::tempfile().unwrap();
-
+        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
         let mut file =
-            DownloadFile::new_from_file(temp_file, hashes, BLOCK_SIZE * 2, BLOCK_SIZE * 4).unwrap();
+            DownloadFile::new_from_file(temp_file.try_clone().unwrap(), hashes, 1024, 1024, sha1())
+                .unwrap();
+        file.process_block(Block::new(0, 0, &[0; 1024])).unwrap();
 
-        let (data1_0, data1_1) = data1.split_at(16384);
-        let (data2_0, data2_1) = data2.split_at(16384);
+        // someone truncates the file behind our back
+        temp_file.set_len(10).unwrap();
 
-        let block1_0 = Block::new(0, 0, &data1_0[..]);
-        let block1_1 = Block::new(0, BLOCK_SIZE, &data1_1[..]);
-        let block2_0 = Block::new(1, 0, &data2_0[..]);
-        let block2_1 = Block::new(1, BLOCK_SIZE, &data2_1[..]);
+        let err = file.verify_all().unwrap_err();
+        assert!(matches!(err, FileError::Truncated));
+        assert!(err.is_fatal());
+    }
 
-        file.process_block(block1_0).unwrap();
-        file.process_block(block1_1).unwrap();
-        file.process_block(block2_0).unwrap();
-        assert_eq!(file.bitfield(), &[0x80]);
-        file.process_block(block2_1).unwrap();
-        assert_eq!(file.bitfield(), &[0xc0]);
+    #[test]
+    fn reads_from_before_an_invalidation_are_stale() {
+        let data = vec![0; BLOCK_SIZE];
+        let hashes: [[u8; DIGEST_SIZE]; 2] = [Sha1::digest(&data).into(); 2];
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            BLOCK_SIZE,
+            BLOCK_SIZE * 2,
+            sha1(),
+        )
+        .unwrap();
+        file.process_block(Block::new(0, 0, &data)).unwrap();
+        file.process_block(Block::new(1, 0, &data)).unwrap();
+
+        // an upload of each piece is read, and queued behind other traffic
+        let block = |piece| BlockInfo {
+            piece,
+            range: 0..BLOCK_SIZE,
+        };
+        let queued: Vec<(usize, u64, Vec<u8>)> = (0..2)
+            .map(|piece| {
+                let generation = file.generation(piece).unwrap();
+                (piece, generation, file.get_block(block(piece)).unwrap())
+            })
+            .collect();
 
-        // check file contents
-        let mut buf = Vec::new();
-        file.file.seek(SeekFrom::Start(0)).unwrap();
+        // meanwhile a recheck finds piece 1 corrupted on disk
+        file.file
+            .write_all_at(&[0xff; 16], BLOCK_SIZE as u64)
+            .unwrap();
+        assert_eq!(file.verify_all().unwrap(), vec![1]);
+
+        // so only the read of piece 0 may still go out
+        let sent: Vec<usize> = queued
+            .iter()
+            .filter(|(piece, generation, _)| file.is_current(*piece, *generation))
+            .map(|(piece, _, _)| *piece)
+            .collect();
+        assert_eq!(sent, vec![0]);
+
+        // a late block for the reset piece is written like any other, and the piece comes
+        // back as a new generation that the old read still doesn't match
+        file.process_block(Block::new(1, 0, &data)).unwrap();
+        assert!(file.is_complete());
+        assert_eq!(file.generation(1), Some(1));
+        assert!(!file.is_current(1, queued[1].1));
+        assert!(file.is_current(1, 1));
+        assert!(!file.is_current(2, 0));
+        assert_eq!(file.generation(2), None);
+    }
 
-        file.file.read_to_end(&mut buf).unwrap();
-        assert_eq!(buf[..BLOCK_SIZE * 2], data1);
-        assert_eq!(buf[BLOCK_SIZE * 2..], data2);
+    #[test]
+    fn resuming_keeps_pieces_that_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resume");
+        let data: Vec<u8> = (0..RANGE_PIECE_LEN * 3 - 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(RANGE_PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let resume = || {
+            DownloadFile::new_resume(&path, &hashes, RANGE_PIECE_LEN, data.len(), sha1()).unwrap()
+        };
+
+        // a fresh file has nothing
+        let mut file = resume();
+        assert_eq!(file.left_verified(), data.len());
+
+        // the first and the short last piece make it to disk before a crash
+        file.process_block(Block::new(0, 0, &data[..RANGE_PIECE_LEN]))
+            .unwrap();
+        file.process_block(Block::new(2, 0, &data[RANGE_PIECE_LEN * 2..]))
+            .unwrap();
+        drop(file);
+
+        let file = resume();
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(file.left_verified(), RANGE_PIECE_LEN);
+        assert!(file.get_unfilled(0).unwrap().is_empty());
+        assert_eq!(file.piece_state(1), Some(PieceState::Missing));
+        drop(file);
+
+        // a file cut short loses the pieces it no longer holds in full
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(RANGE_PIECE_LEN as u64 * 2 + 10)
+            .unwrap();
+        let file = resume();
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0]);
+        assert_eq!(file.left_verified(), data.len() - RANGE_PIECE_LEN);
     }
 
     #[test]
-    fn file_get_block_success() {
-        let data = vec![0; 1024];
-        let hashes = &[hex!("60cacbf3d72e1e7834203da608037b1bf83b40e8")];
-        let temp_file = tempfile::tempfile().unwrap();
+    fn disk_thread_orders_reads_after_writes() {
+        const PIECES: usize = 12;
+        const PIECE_LEN: usize = BLOCK_SIZE * 2 + 100;
+
+        let data: Vec<u8> = (0..PIECES * PIECE_LEN - 1000)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            PIECE_LEN,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+        let mut unfilled: Vec<Vec<Range<usize>>> = (0..PIECES)
+            .map(|piece| file.get_unfilled(piece).unwrap().to_vec())
+            .collect();
+
+        let (resp_tx, resp_rx) = channel::unbounded();
+        let (disk, handle) = spawn_disk_thread(file, resp_tx);
+
+        // readers streaming random ranges, which must only ever see verified data
+        let readers: Vec<_> = (0..4)
+            .map(|seed| {
+                let disk = disk.clone();
+                let data = data.clone();
+                thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    let mut seen = 0;
+                    for _ in 0..500 {
+                        let offset = rng.gen_range(0..data.len());
+                        let (reply, rx) = channel::bounded(1);
+                        let len = 3 * BLOCK_SIZE;
+                        disk.send(DiskRequest::ReadRange { offset, len, reply })
+                            .unwrap();
+                        let read = rx.recv().unwrap();
+                        assert_eq!(read, data[offset..offset + read.len()]);
+                        seen += read.len();
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        // every block, in a random order, with a read of each piece as soon as it's complete
+        let mut blocks: Vec<BlockInfo> = unfilled
+            .iter()
+            .enumerate()
+            .flat_map(|(piece, ranges)| {
+                ranges.iter().map(move |range| BlockInfo {
+                    piece,
+                    range: range.clone(),
+                })
+            })
+            .collect();
+        blocks.shuffle(&mut StdRng::seed_from_u64(PIECES as u64));
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        for block in blocks {
+            let start = block.piece * PIECE_LEN;
+            let bytes = &data[start + block.range.start..start + block.range.end];
+            disk.send(DiskRequest::WriteBlock(Block::new(
+                block.piece,
+                block.range.start,
+                bytes,
+            )))
+            .unwrap();
 
-        let mut file = DownloadFile::new_from_file(temp_file, hashes, 1024, data.len()).unwrap();
+            let ranges = &mut unfilled[block.piece];
+            ranges.retain(|range| *range != block.range);
+            if ranges.is_empty() {
+                disk.send(DiskRequest::ReadBlock {
+                    addr,
+                    block: BlockInfo {
+                        piece: block.piece,
+                        range: 0..BLOCK_SIZE,
+                    },
+                    generation: 0,
+                    buf: Vec::new(),
+                })
+                .unwrap();
+            }
+        }
 
-        let block = Block::new(0, 0, &data[..]);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let (reply, rx) = channel::bounded(1);
+        disk.send(DiskRequest::Flush(reply)).unwrap();
+        rx.recv().unwrap().unwrap();
+        drop(disk);
+        handle.join().unwrap();
+
+        // each piece was verified, then read back whole
+        let mut verified = bitvec![0; PIECES];
+        for resp in resp_rx.try_iter() {
+            let Response::Disk(resp) = resp else {
+                panic!("unexpected response {:?}", resp);
+            };
+            match resp {
+                DiskResponse::Verified(piece) => verified.set(piece, true),
+                DiskResponse::Read {
+                    block, data: read, ..
+                } => {
+                    assert!(verified[block.piece]);
+                    let start = block.piece * PIECE_LEN;
+                    assert_eq!(read.unwrap(), data[start..start + BLOCK_SIZE]);
+                }
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+        assert!(verified.all());
+    }
 
-        file.process_block(block).unwrap();
-        assert!(file.pieces[0].is_complete());
+    #[test]
+    fn bad_pieces_never_reach_the_disk() {
+        let (mut file, data) = range_file(&[]);
+        let mut bad = data[..RANGE_PIECE_LEN].to_vec();
+        bad[100] ^= 0xff;
 
-        // check file contents
-        let buf = file
-            .get_block(BlockInfo {
+        file.process_block(Block::new(0, 0, &bad)).unwrap();
+        assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+
+        let mut on_disk = vec![0xaa; RANGE_PIECE_LEN];
+        file.file.read_exact_at(&mut on_disk, 0).unwrap();
+        assert_eq!(on_disk, [0; RANGE_PIECE_LEN]);
+
+        // the good copy is written once it checks out
+        file.process_block(Block::new(0, 0, &data[..RANGE_PIECE_LEN]))
+            .unwrap();
+        file.file.read_exact_at(&mut on_disk, 0).unwrap();
+        assert_eq!(on_disk, data[..RANGE_PIECE_LEN]);
+    }
+
+    #[test]
+    fn resume_files_stand_in_for_hashing() {
+        const PIECE_LEN: usize = BLOCK_SIZE * 2;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("download");
+        let resume_path = dir.path().join("download.resume");
+        let data: Vec<u8> = (0..PIECE_LEN * 4).map(|i| (i % 247) as u8).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let open = |info_hash: &[u8; 20]| {
+            DownloadFile::new_fast_resume(
+                &path,
+                &resume_path,
+                info_hash,
+                &hashes,
+                PIECE_LEN,
+                data.len(),
+                sha1(),
+            )
+            .unwrap()
+        };
+
+        // piece 1 sits in the cache, so only piece 2's first block and piece 0 reach the disk
+        let mut file = open(&[1; 20]);
+        file.set_cache_pieces(1);
+        file.process_block(Block::new(1, 0, &data[PIECE_LEN..PIECE_LEN + BLOCK_SIZE]))
+            .unwrap();
+        let piece_2 = 2 * PIECE_LEN;
+        file.process_block(Block::new(2, 0, &data[piece_2..piece_2 + BLOCK_SIZE]))
+            .unwrap();
+        for offset in [0, BLOCK_SIZE] {
+            let block = &data[offset..offset + BLOCK_SIZE];
+            file.process_block(Block::new(0, offset, block)).unwrap();
+        }
+        file.flush().unwrap();
+        file.save_resume(&resume_path, &[1; 20]).unwrap();
+        drop(file);
+
+        let mut file = open(&[1; 20]);
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0]);
+        assert_eq!(file.piece_state(1), Some(PieceState::Missing));
+        let unfilled = file.get_unfilled(2).unwrap();
+        assert_eq!(unfilled.len(), 1);
+        assert_eq!(unfilled[0], BLOCK_SIZE..PIECE_LEN);
+        assert_eq!(file.left_estimated(), data.len() - PIECE_LEN - BLOCK_SIZE);
+
+        // and the rest of the partial piece is all it takes to finish it
+        let rest = &data[piece_2 + BLOCK_SIZE..piece_2 + PIECE_LEN];
+        file.process_block(Block::new(2, BLOCK_SIZE, rest)).unwrap();
+        assert_eq!(file.piece_state(2), Some(PieceState::Complete));
+        drop(file);
+
+        // a resume file for another torrent is ignored, and hashing finds only whole pieces
+        let file = open(&[2; 20]);
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0, 2]);
+        drop(file);
+
+        let mut bytes = std::fs::read(&resume_path).unwrap();
+        bytes[30] ^= 1;
+        std::fs::write(&resume_path, bytes).unwrap();
+        let file = open(&[1; 20]);
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0, 2]);
+        file.save_resume(&resume_path, &[1; 20]).unwrap();
+        drop(file);
+
+        // as is one that outlived the file's length
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(PIECE_LEN as u64)
+            .unwrap();
+        let file = open(&[1; 20]);
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn resume_files_only_count_what_was_flushed() {
+        const PIECE_LEN: usize = BLOCK_SIZE * 2;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("download");
+        let resume_path = dir.path().join("download.resume");
+        let data: Vec<u8> = (0..PIECE_LEN * 4).map(|i| (i % 241) as u8).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let open = || {
+            let mut file = DownloadFile::new_fast_resume(
+                &path,
+                &resume_path,
+                &[1; 20],
+                &hashes,
+                PIECE_LEN,
+                data.len(),
+                sha1(),
+            )
+            .unwrap();
+            file.set_cache_pieces(0);
+            file
+        };
+        let saved = |file: &DownloadFile| {
+            file.save_resume(&resume_path, &[1; 20]).unwrap();
+            let data = ResumeData::decode(&std::fs::read(&resume_path).unwrap()).unwrap();
+            let partial: Vec<usize> = data.partial.iter().map(|&(piece, _)| piece).collect();
+            (data.verified.iter_ones().collect::<Vec<_>>(), partial)
+        };
+        let piece = |file: &mut DownloadFile, piece: usize| {
+            for offset in [0, BLOCK_SIZE] {
+                let start = piece * PIECE_LEN + offset;
+                let block = Block::new(piece, offset, &data[start..start + BLOCK_SIZE]);
+                file.process_block(block).unwrap();
+            }
+        };
+
+        // nothing reaches the resume file until it has been flushed
+        let mut file = open();
+        piece(&mut file, 0);
+        let start = 2 * PIECE_LEN;
+        file.process_block(Block::new(2, 0, &data[start..start + BLOCK_SIZE]))
+            .unwrap();
+        assert_eq!(file.bitvec().iter_ones().collect::<Vec<_>>(), [0]);
+        assert_eq!(saved(&file), (vec![], vec![]));
+        file.flush().unwrap();
+        assert_eq!(saved(&file), (vec![0], vec![2]));
+
+        // unless every piece is flushed as it's verified
+        file.set_flush_policy(FlushPolicy::OnPieceComplete);
+        piece(&mut file, 1);
+        assert_eq!(saved(&file), (vec![0, 1], vec![2]));
+
+        // or once enough time has passed since the last flush
+        file.set_flush_policy(FlushPolicy::Every(Duration::from_secs(3600)));
+        piece(&mut file, 3);
+        assert_eq!(saved(&file), (vec![0, 1], vec![2]));
+        file.set_flush_policy(FlushPolicy::Every(Duration::ZERO));
+        let rest = &data[start + BLOCK_SIZE..start + PIECE_LEN];
+        file.process_block(Block::new(2, BLOCK_SIZE, rest)).unwrap();
+        assert_eq!(saved(&file), (vec![0, 1, 2, 3], vec![]));
+        drop(file);
+        assert!(open().is_complete());
+
+        assert_eq!("never".parse(), Ok(FlushPolicy::Never));
+        assert_eq!("piece".parse(), Ok(FlushPolicy::OnPieceComplete));
+        assert_eq!(
+            "30s".parse(),
+            Ok(FlushPolicy::Every(Duration::from_secs(30)))
+        );
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn pieces_past_the_cache_go_straight_to_disk() {
+        const PIECE_LEN: usize = BLOCK_SIZE * 2;
+        let data: Vec<u8> = (0..PIECE_LEN * 3).map(|i| (i % 249) as u8).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            PIECE_LEN,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+        file.set_cache_pieces(1);
+
+        // piece 0 takes the only place in the cache, so piece 1 is written as it comes
+        for piece in [0, 1] {
+            let start = piece * PIECE_LEN;
+            file.process_block(Block::new(piece, 0, &data[start..start + BLOCK_SIZE]))
+                .unwrap();
+        }
+        assert_eq!(file.cache.len(), 1);
+        let mut on_disk = vec![0; BLOCK_SIZE];
+        file.file.read_exact_at(&mut on_disk, 0).unwrap();
+        assert_eq!(on_disk, [0; BLOCK_SIZE]);
+        file.file
+            .read_exact_at(&mut on_disk, PIECE_LEN as u64)
+            .unwrap();
+        assert_eq!(on_disk, data[PIECE_LEN..PIECE_LEN + BLOCK_SIZE]);
+
+        // and everything still ends up where it belongs
+        for piece in 0..3 {
+            let start = piece * PIECE_LEN;
+            for offset in [0, BLOCK_SIZE] {
+                let block = &data[start + offset..start + offset + BLOCK_SIZE];
+                file.process_block(Block::new(piece, offset, block))
+                    .unwrap();
+            }
+        }
+        assert!(file.is_complete());
+        assert!(file.cache.is_empty());
+        assert_eq!(file.read_range(0, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn long_pieces_are_never_assembled_in_memory() {
+        const PIECE_LEN: usize = MAX_CACHED_PIECE_LEN + BLOCK_SIZE;
+        let data: Vec<u8> = (0..PIECE_LEN).map(|i| (i % 247) as u8).collect();
+        let hashes = [Sha1::digest(&data).into()];
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            PIECE_LEN,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+
+        // the cache has room, but the piece is hashed on its way to disk instead
+        let mut outcome = BlockOutcome::Duplicate;
+        for offset in (0..PIECE_LEN).step_by(BLOCK_SIZE) {
+            let block = &data[offset..offset + BLOCK_SIZE];
+            outcome = file.process_block(Block::new(0, offset, block)).unwrap();
+            assert!(file.cache.is_empty());
+        }
+        assert_eq!(outcome, BlockOutcome::PieceComplete);
+        assert_eq!(file.read_back, 0);
+    }
+
+    #[test]
+    fn pieces_are_hashed_as_they_arrive() {
+        const PIECE_LEN: usize = BLOCK_SIZE * 3;
+        let data: Vec<u8> = (0..PIECE_LEN * 2).map(|i| (i % 251) as u8).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            PIECE_LEN,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+        file.set_cache_pieces(0);
+        let send = |file: &mut DownloadFile, piece: usize, blocks: &[usize], bad: bool| {
+            let mut result = BlockOutcome::Duplicate;
+            for &b in blocks {
+                let start = piece * PIECE_LEN + b * BLOCK_SIZE;
+                let mut block = data[start..start + BLOCK_SIZE].to_vec();
+                if bad {
+                    block[0] ^= 0xff;
+                }
+                result = file
+                    .process_block(Block::new(piece, b * BLOCK_SIZE, &block))
+                    .unwrap();
+            }
+            result
+        };
+
+        // in order, a bad piece is caught without reading anything back, and starts over
+        assert_eq!(
+            send(&mut file, 0, &[0, 1, 2], true),
+            BlockOutcome::HashMismatch {
                 piece: 0,
-                range: 0..1024,
+                contributors: Vec::new()
+            }
+        );
+        assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+        assert!(file.streams.is_empty());
+        assert_eq!(
+            send(&mut file, 0, &[0, 1, 2], false),
+            BlockOutcome::PieceComplete
+        );
+        assert_eq!(file.read_back_pieces(), 0);
+
+        // out of order, the piece is read back once it's all there
+        assert_eq!(
+            send(&mut file, 1, &[0, 2, 1], true),
+            BlockOutcome::HashMismatch {
+                piece: 1,
+                contributors: Vec::new()
+            }
+        );
+        assert_eq!(file.read_back_pieces(), 1);
+        assert_eq!(
+            send(&mut file, 1, &[1, 0, 2], false),
+            BlockOutcome::PieceComplete
+        );
+        assert_eq!(file.read_back_pieces(), 2);
+        assert!(file.streams.is_empty());
+        assert_eq!(file.read_range(0, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn blocks_are_served_from_recently_used_pieces() {
+        let (mut file, data) = range_file(&[0, 1]);
+        let block = |piece, range| BlockInfo { piece, range };
+        let start = RANGE_PIECE_LEN;
+
+        // a piece that was just completed is already in memory
+        assert_eq!(file.get_block(block(0, 10..20)).unwrap(), data[10..20]);
+        assert_eq!((file.read_cache_hits(), file.read_cache_misses()), (1, 0));
+
+        // otherwise the first read goes to the disk, and the next is the same from memory
+        file.set_read_cache_bytes(0);
+        let from_disk = file.get_block(block(1, 5..50)).unwrap();
+        assert_eq!(from_disk, data[start + 5..start + 50]);
+        file.set_read_cache_bytes(DEFAULT_READ_CACHE_BYTES);
+        assert_eq!(file.get_block(block(1, 5..50)).unwrap(), from_disk);
+        assert_eq!(file.get_block(block(1, 5..50)).unwrap(), from_disk);
+        assert_eq!((file.read_cache_hits(), file.read_cache_misses()), (2, 2));
+
+        // a piece that fails a recheck isn't served from memory either
+        file.file.write_all_at(&[0xff], start as u64).unwrap();
+        assert_eq!(file.verify_all().unwrap(), [1]);
+        assert!(matches!(
+            file.get_block(block(1, 5..50)),
+            Err(FileError::Incomplete(1))
+        ));
+        assert_eq!(file.get_block(block(0, 10..20)).unwrap(), data[10..20]);
+    }
+
+    #[test]
+    fn blocks_can_be_read_into_the_same_buffer_over_and_over() {
+        let (mut file, data) = range_file(&[0, 1, 2]);
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut buf = Vec::with_capacity(RANGE_PIECE_LEN);
+        let at = buf.as_ptr();
+
+        for served in 0..10_000 {
+            // half from disk, half from memory
+            if served % 5_000 == 0 {
+                file.set_read_cache_bytes(if served == 0 {
+                    0
+                } else {
+                    DEFAULT_READ_CACHE_BYTES
+                });
+            }
+            let piece = rng.gen_range(0..3);
+            let piece_len = RANGE_PIECE_LEN.min(data.len() - piece * RANGE_PIECE_LEN);
+            let start = rng.gen_range(0..piece_len);
+            let end = rng.gen_range(start + 1..=piece_len);
+            file.get_block_into(
+                BlockInfo {
+                    piece,
+                    range: start..end,
+                },
+                &mut buf,
+            )
+            .unwrap();
+
+            let offset = piece * RANGE_PIECE_LEN;
+            assert_eq!(buf, data[offset + start..offset + end]);
+            assert_eq!(buf.as_ptr(), at, "block {} reallocated", served);
+        }
+        assert!(file.read_cache_hits() > 4_000);
+
+        // a block that can't be read leaves nothing behind
+        let past = BlockInfo {
+            piece: 3,
+            range: 0..10,
+        };
+        assert!(file.get_block_into(past, &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn handles_can_be_shared_between_threads() {
+        // blocks are written through clones of one handle, which share a cursor, so only
+        // positioned writes land in the right place
+        let piece_len = BLOCK_SIZE * 4;
+        let data: Vec<u8> = (0..piece_len * 4).map(|i| (i % 253) as u8).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(piece_len)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let temp_file = tempfile::tempfile().unwrap();
+
+        let writers: Vec<_> = [[0, 2], [1, 3]]
+            .into_iter()
+            .map(|pieces| {
+                let handle = temp_file.try_clone().unwrap();
+                let (data, hashes) = (data.clone(), hashes.clone());
+                thread::spawn(move || {
+                    let mut file =
+                        DownloadFile::new_from_file(handle, &hashes, piece_len, data.len(), sha1())
+                            .unwrap();
+                    file.set_cache_pieces(0);
+                    file.set_read_cache_bytes(0);
+                    for offset in (0..piece_len).step_by(BLOCK_SIZE) {
+                        for piece in pieces {
+                            let start = piece * piece_len + offset;
+                            let block = Block::new(piece, offset, &data[start..start + BLOCK_SIZE]);
+                            file.process_block(block).unwrap();
+                        }
+                        thread::yield_now();
+                    }
+                    for piece in pieces {
+                        assert_eq!(file.piece_state(piece), Some(PieceState::Complete));
+                        let block = BlockInfo {
+                            piece,
+                            range: BLOCK_SIZE..BLOCK_SIZE * 2,
+                        };
+                        let start = piece * piece_len + BLOCK_SIZE;
+                        assert_eq!(
+                            file.get_block(block).unwrap(),
+                            data[start..start + BLOCK_SIZE]
+                        );
+                    }
+                })
             })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut written = vec![0; data.len()];
+        temp_file.read_exact_at(&mut written, 0).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn preallocation_keeps_what_was_written() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i % 249) as u8).collect();
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(BLOCK_SIZE * 2)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        let mut file = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &hashes,
+            BLOCK_SIZE * 2,
+            data.len(),
+            sha1(),
+        )
+        .unwrap();
+        file.process_block(Block::new(1, 0, &data[BLOCK_SIZE * 2..BLOCK_SIZE * 3]))
             .unwrap();
-        assert_eq!(buf, data);
+        file.process_block(Block::new(1, BLOCK_SIZE, &data[BLOCK_SIZE * 3..]))
+            .unwrap();
+
+        file.preallocate().unwrap();
+        assert!(file.file.metadata().unwrap().blocks() as usize * 512 >= data.len());
+        assert_eq!(file.file.metadata().unwrap().len() as usize, data.len());
+        assert_eq!(
+            file.read_range(BLOCK_SIZE * 2, BLOCK_SIZE * 2).unwrap(),
+            data[BLOCK_SIZE * 2..]
+        );
     }
 
     #[test]
-    fn new_seeding_invariants() {
-        let temp_file = tempfile::NamedTempFile::new().unwrap();
-        let hashes = &[[0u8; DIGEST_SIZE]; 4];
-        let file =
-            DownloadFile::new_seeding(temp_file.path(), hashes, BLOCK_SIZE * 4, BLOCK_SIZE * 16)
-                .unwrap();
+    fn downloads_too_big_for_the_disk_are_refused() {
+        let total_size = 1 << 60;
+        let result = DownloadFile::new_from_file(
+            tempfile::tempfile().unwrap(),
+            &[[0; DIGEST_SIZE]],
+            total_size,
+            total_size,
+            sha1(),
+        );
+        assert!(matches!(
+            result,
+            Err(FileError::NoSpace { needed, .. }) if needed == total_size
+        ));
+    }
 
-        assert!(file.is_complete());
-        assert_eq!(file.bitfield(), &[0b11110000]);
+    #[test]
+    fn a_full_disk_throws_away_what_it_could_not_take() {
+        // every write to /dev/full fails as if the disk were full
+        let full = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/full")
+                .unwrap()
+        };
+        let data = vec![3u8; BLOCK_SIZE * 2];
+        let hashes = [Sha1::digest(&data).into(), [0; DIGEST_SIZE]];
+
+        // written as it arrives, so the first block already fails
+        let mut file =
+            DownloadFile::with_pieces(full(), &hashes, data.len(), data.len() * 2, sha1());
+        file.set_cache_pieces(0);
+        let error = file
+            .process_block(Block::new(0, 0, &data[..BLOCK_SIZE]))
+            .unwrap_err();
+        assert!(
+            matches!(error, FileError::NoSpace { needed, .. } if needed == data.len() * 2),
+            "{:?}",
+            error
+        );
+        assert!(!error.is_fatal());
+        assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+
+        // assembled in memory, so it fails once the piece is whole, and the piece goes with it
+        let mut file =
+            DownloadFile::with_pieces(full(), &hashes, data.len(), data.len() * 2, sha1());
+        file.set_cache_pieces(1);
+        assert_eq!(
+            file.process_block(Block::new(0, 0, &data[..BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::Accepted
+        );
+        assert!(matches!(
+            file.process_block(Block::new(0, BLOCK_SIZE, &data[BLOCK_SIZE..])),
+            Err(FileError::NoSpace { .. })
+        ));
+        assert_eq!(file.piece_state(0), Some(PieceState::Missing));
+        assert!(file.cache.is_empty());
+        assert_eq!(
+            file.process_block(Block::new(0, 0, &data[..BLOCK_SIZE]))
+                .unwrap(),
+            BlockOutcome::Accepted
+        );
+    }
+
+    // A two piece download of `data` into the part file for `target`
+    fn part_download(target: &std::path::Path, data: &[u8]) -> DownloadFile {
+        let hashes: Vec<[u8; DIGEST_SIZE]> = data
+            .chunks(BLOCK_SIZE)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        DownloadFile::new(part_path(target), &hashes, BLOCK_SIZE, data.len(), sha1()).unwrap()
+    }
+
+    #[test]
+    fn complete_downloads_move_to_their_real_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let mut file = part_download(&target, &data);
+        assert!(dir.path().join("data.bin.part").exists());
+
+        file.process_block(Block::new(0, 0, &data[..BLOCK_SIZE]))
+            .unwrap();
+        assert!(matches!(file.finalize(), Err(FileError::Incomplete(1))));
+        assert!(!target.exists());
+
+        file.process_block(Block::new(1, 0, &data[BLOCK_SIZE..]))
+            .unwrap();
+        file.finalize().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+        assert!(!part_path(&target).exists());
+
+        // the moved file is still the one being served, and moving it again does nothing
+        assert_eq!(file.read_range(0, data.len()).unwrap(), data);
+        file.finalize().unwrap();
+        assert!(target.exists());
+    }
+
+    #[test]
+    fn skipped_pieces_dont_hold_up_the_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let mut file = part_download(&target, &data);
+        file.set_piece_priority(0, Priority::Skip).unwrap();
+
+        file.process_block(Block::new(1, 0, &data[BLOCK_SIZE..]))
+            .unwrap();
+        file.finalize().unwrap();
+        assert!(target.exists());
+    }
+
+    #[test]
+    fn finalizing_never_clobbers_an_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("data.bin");
+        let data = vec![7; BLOCK_SIZE];
+        let mut file = part_download(&target, &data);
+        file.process_block(Block::new(0, 0, &data)).unwrap();
+        std::fs::write(&target, b"someone else's").unwrap();
+
+        assert!(matches!(file.finalize(), Err(FileError::Exists(path)) if path == target));
+        assert_eq!(std::fs::read(&target).unwrap(), b"someone else's");
+        assert!(part_path(&target).exists());
     }
 }
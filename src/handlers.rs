@@ -0,0 +1,989 @@
+//! What the main thread does with each message a peer sends.
+//!
+//! Each handler takes only the parts of the session it touches, and talks to the peer through a
+//! [MessageSink], so it can be tested without a session or a peer thread. Dispatching, and
+//! deciding what a violation costs the peer, is left to the session.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bitvec::prelude::*;
+use crossbeam::channel::Sender;
+use thiserror::Error;
+
+use crate::availability::Availability;
+use crate::extension::{self, MetadataMessage};
+use crate::file::{Block, BlockInfo, BlockOutcome, Disk, FileError};
+use crate::log_limiter::PeerWarning;
+use crate::peers::{Message, PeerRequest, QueuedUploads, UploadTicket};
+use crate::piece_set::PieceSet;
+use crate::session::PeerInfo;
+use crate::stats::Stats;
+use crate::timer::{TimerRequest, Token};
+
+/// The peer's thread has exited, so nothing more can be sent to it
+#[derive(Debug, Error)]
+#[error("peer thread has exited")]
+pub struct PeerGone;
+
+/// Somewhere to send messages to a peer
+pub trait MessageSink {
+    fn send_message(&self, msg: Message) -> Result<(), PeerGone>;
+
+    /// Sends a Piece message, which counts as queued until `ticket` is dropped. Sinks that
+    /// don't queue anything are done with it straight away.
+    fn send_upload(&self, msg: Message, ticket: UploadTicket) -> Result<(), PeerGone> {
+        drop(ticket);
+        self.send_message(msg)
+    }
+}
+
+impl MessageSink for Sender<PeerRequest> {
+    fn send_message(&self, msg: Message) -> Result<(), PeerGone> {
+        self.send(PeerRequest::SendMessage(msg))
+            .map_err(|_| PeerGone)
+    }
+
+    fn send_upload(&self, msg: Message, ticket: UploadTicket) -> Result<(), PeerGone> {
+        self.send(PeerRequest::Upload(msg, ticket))
+            .map_err(|_| PeerGone)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HandlerError {
+    /// The peer broke the protocol. The message is what it did, for the log.
+    #[error("{1}")]
+    Violation(PeerWarning, String),
+
+    /// Something went wrong that the peer isn't to blame for
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<FileError> for HandlerError {
+    fn from(e: FileError) -> Self {
+        HandlerError::Other(e.into())
+    }
+}
+
+impl From<PeerGone> for HandlerError {
+    fn from(e: PeerGone) -> Self {
+        HandlerError::Other(e.into())
+    }
+}
+
+pub type Handled<T = ()> = Result<T, HandlerError>;
+
+fn violation<T>(kind: PeerWarning, what: String) -> Handled<T> {
+    Err(HandlerError::Violation(kind, what))
+}
+
+pub fn on_choke(peer: &mut PeerInfo) {
+    peer.peer_choked = true;
+    peer.waiting_since = None;
+}
+
+pub fn on_unchoke(peer: &mut PeerInfo) {
+    peer.peer_choked = false;
+}
+
+/// Only records the interest; whether to unchoke in return is up to the choker
+pub fn on_interested(peer: &mut PeerInfo) {
+    peer.peer_interested = true;
+}
+
+pub fn on_not_interested(peer: &mut PeerInfo) {
+    peer.peer_interested = false;
+}
+
+pub fn on_have(
+    peer: &mut PeerInfo,
+    piece: u32,
+    piece_count: usize,
+    availability: &mut Availability,
+    seeds: &mut usize,
+) -> Handled {
+    // lazy peers may never send a Bitfield, only a stream of these
+    let piece = piece as usize;
+    if piece >= piece_count {
+        return violation(
+            PeerWarning::InvalidHave,
+            format!("sent Have with invalid piece {}", piece),
+        );
+    }
+
+    if peer.has.insert(piece) {
+        availability.add_piece(piece);
+    }
+    peer.update_seed(seeds);
+    Ok(())
+}
+
+/// Replaces everything `peer` had with `bytes`, which must have a bit for each of the
+/// `piece_count` pieces and nothing set past them
+pub fn on_bitfield(
+    peer: &mut PeerInfo,
+    bytes: &[u8],
+    piece_count: usize,
+    availability: &mut Availability,
+    seeds: &mut usize,
+) -> Handled {
+    if bytes.len() != piece_count.div_ceil(8) {
+        return violation(
+            PeerWarning::InvalidBitfield,
+            format!("sent Bitfield with invalid length {}", bytes.len()),
+        );
+    }
+
+    let mut has = BitVec::from_slice(bytes);
+    if has[piece_count..].any() {
+        return violation(
+            PeerWarning::InvalidBitfield,
+            "sent Bitfield with spare bits set".to_owned(),
+        );
+    }
+    has.truncate(piece_count);
+
+    availability.remove(&peer.has);
+    peer.has = PieceSet::from_bitvec(has);
+    availability.add(&peer.has);
+    peer.update_seed(seeds);
+    Ok(())
+}
+
+/// Takes in a block we asked `addr` for, and hands it to the disk thread, returning whether it
+/// was one we still wanted. How the piece fares against its hash is reported by the disk
+/// thread later on.
+pub fn on_piece(
+    peer: &mut PeerInfo,
+    addr: SocketAddr,
+    block: Block,
+    requested: &mut HashMap<Token, (BlockInfo, SocketAddr)>,
+    timer_sender: &Sender<TimerRequest>,
+    file: &mut Disk,
+    stats: &mut Stats,
+) -> Handled<BlockOutcome> {
+    let info = block.info();
+    let len = info.range.len();
+    stats.received += len;
+    stats.download_rate.record(len);
+
+    // A block needn't line up with what we asked for, so long as it overlaps something we
+    // asked this peer for. Only the requests it covers in full are answered; the rest are left
+    // to time out.
+    let asked: Vec<Token> = requested
+        .iter()
+        .filter(|(_, (b, a))| {
+            *a == addr
+                && b.piece == info.piece
+                && b.range.start < info.range.end
+                && info.range.start < b.range.end
+        })
+        .map(|(&token, _)| token)
+        .collect();
+    if asked.is_empty() {
+        // it crossed our Cancel on the wire, which the peer can't help
+        if peer.take_cancelled(&info, Instant::now()) {
+            stats.late += len;
+            return Ok(BlockOutcome::Duplicate);
+        }
+
+        stats.unrequested += len;
+        return violation(
+            PeerWarning::UnrequestedPiece,
+            format!(
+                "sent Piece we did not request (piece={}, offset={}, len={})",
+                info.piece, info.range.start, len
+            ),
+        );
+    }
+
+    let now = Instant::now();
+    for token in asked {
+        let range = &requested[&token].0.range;
+        if range.start < info.range.start || range.end > info.range.end {
+            continue;
+        }
+        let start = range.start;
+        requested.remove(&token);
+
+        // ask the timer thread to terminate this timeout
+        timer_sender
+            .send(TimerRequest::Cancel(token))
+            .expect("Main thread failed to communicate with timer thread!");
+
+        if let Some(sent_at) = peer.sent_at.remove(&(info.piece, start)) {
+            peer.latency.observe(now.saturating_duration_since(sent_at));
+        }
+    }
+
+    // the peer is delivering, so it is no longer snubbing us
+    peer.probation = false;
+    peer.waiting_since = if requested.values().any(|(_, a)| *a == addr) {
+        Some(now)
+    } else {
+        None
+    };
+
+    // process the block
+    match file.write(block) {
+        Ok(outcome) => {
+            // keep statistics
+            peer.uploaded += len;
+            peer.uploaded_recently += len;
+            peer.upload_rate.record(len);
+
+            // another peer got there first, after we asked both
+            if outcome == BlockOutcome::Duplicate {
+                stats.redundant += len;
+            }
+            Ok(outcome)
+        }
+        Err(e) => violation(
+            PeerWarning::BadPiece,
+            format!("sent a Piece we failed to process: {:?}", e),
+        ),
+    }
+}
+
+/// Asks the disk thread to read a block for `addr`, if it is allowed one and we have it.
+/// The block is sent by [on_block_read] once it has been read.
+#[allow(clippy::too_many_arguments)]
+pub fn on_request(
+    peer: &mut PeerInfo,
+    addr: SocketAddr,
+    piece: u32,
+    offset: u32,
+    length: u32,
+    allowance: usize,
+    file: &mut Disk,
+    sink: &dyn MessageSink,
+) -> Handled {
+    // ignore request if we're choking this peer
+    if peer.choked {
+        return violation(
+            PeerWarning::ChokedRequest,
+            "made request while choked".to_owned(),
+        );
+    }
+
+    // Past an upload cap, the peer is choked instead. It may well have asked before it knew,
+    // so that's no violation.
+    if allowance == 0 {
+        peer.choked = true;
+        sink.send_message(Message::Choke)?;
+        return Ok(());
+    }
+
+    let block_info = BlockInfo {
+        piece: piece as usize,
+        range: (offset as usize)..(offset as usize + length as usize),
+    };
+
+    // this can legitimately happen if a recheck invalidated a piece
+    // we previously told the peer we have
+    match file.read(addr, block_info, peer.buffers.take()) {
+        Ok(()) => Ok(()),
+        Err(FileError::BlockTooLarge { len, .. }) => violation(
+            PeerWarning::OversizedRequest,
+            format!("requested an oversized block of {}", len),
+        ),
+        Err(e) => violation(
+            PeerWarning::BadRequest,
+            format!("made Request we cannot serve: {}", e),
+        ),
+    }
+}
+
+/// Sends the peer a block the disk thread read for it, unless it has been choked since it
+/// asked. The block counts towards `queued` until the peer thread has written it, and towards
+/// what we have uploaded only once it has.
+pub fn on_block_read(
+    peer: &mut PeerInfo,
+    block: &BlockInfo,
+    data: Vec<u8>,
+    stats: &mut Stats,
+    queued: &Arc<QueuedUploads>,
+    sink: &dyn MessageSink,
+) -> Handled {
+    if peer.choked {
+        peer.buffers.give_back(data);
+        return Ok(());
+    }
+
+    // the caps go by what we've handed over
+    stats.served += data.len();
+    peer.served += data.len();
+
+    let ticket = queued.ticket(data.len()).returning_to(&peer.buffers);
+    sink.send_upload(
+        Message::Piece(block.piece as u32, block.range.start as u32, data),
+        ticket,
+    )?;
+    Ok(())
+}
+
+/// Handles an extended message, sent with one of the ids our handshake gave out
+pub fn on_extended(
+    peer: &mut PeerInfo,
+    id: u8,
+    payload: &[u8],
+    metadata: &[u8],
+    sink: &dyn MessageSink,
+) -> Handled {
+    match id {
+        extension::HANDSHAKE_ID => match extension::Handshake::decode(payload) {
+            Ok(handshake) => {
+                peer.ut_metadata = handshake.ut_metadata();
+                peer.listen_port = handshake.listen_port();
+            }
+            Err(e) => {
+                return violation(
+                    PeerWarning::MalformedMessage,
+                    format!("sent invalid extension handshake: {}", e),
+                )
+            }
+        },
+        extension::UT_METADATA_ID => match MetadataMessage::decode(payload) {
+            Ok(MetadataMessage::Request(piece)) => {
+                // without its id from the handshake, there's no way to answer
+                if let Some(id) = peer.ut_metadata {
+                    let reply = extension::serve(metadata, piece);
+                    sink.send_message(Message::Extended(id, reply.encode()))?;
+                }
+            }
+            // we never ask anyone for metadata
+            Ok(_) => (),
+            Err(e) => {
+                return violation(
+                    PeerWarning::MalformedMessage,
+                    format!("sent invalid ut_metadata message: {}", e),
+                )
+            }
+        },
+        // extensions we never offered
+        _ => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use crossbeam::channel::Receiver;
+
+    use super::*;
+    use crate::file::{BlockOutcome, DiskResponse, DEFAULT_MAX_BLOCK_LEN};
+    use crate::session::MainState;
+    use crate::test_utils::{main_state, main_state_with_disk, peer_info, settle};
+    use crate::threads::Response;
+
+    const PIECES: usize = 4;
+    const PIECE_LEN: usize = 16;
+
+    // Remembers what would have been sent to the peer
+    #[derive(Default)]
+    struct MockSink(RefCell<Vec<Message>>);
+
+    impl MessageSink for MockSink {
+        fn send_message(&self, msg: Message) -> Result<(), PeerGone> {
+            self.0.borrow_mut().push(msg);
+            Ok(())
+        }
+    }
+
+    // A peer whose thread has already exited
+    struct GoneSink;
+
+    impl MessageSink for GoneSink {
+        fn send_message(&self, _: Message) -> Result<(), PeerGone> {
+            Err(PeerGone)
+        }
+    }
+
+    fn addr() -> SocketAddr {
+        "10.0.0.1:6881".parse().unwrap()
+    }
+
+    fn setup() -> (MainState, Receiver<TimerRequest>, PeerInfo) {
+        let (state, timer_rx, _, peer) = setup_with_disk();
+        (state, timer_rx, peer)
+    }
+
+    fn setup_with_disk() -> (
+        MainState,
+        Receiver<TimerRequest>,
+        Receiver<Response>,
+        PeerInfo,
+    ) {
+        let (state, timer_rx, disk_rx) = main_state_with_disk(PIECES, PIECE_LEN);
+        let (peer, _peer_rx) = peer_info(PIECES);
+        (state, timer_rx, disk_rx, peer)
+    }
+
+    fn assert_violation<T: std::fmt::Debug>(result: Handled<T>, expected: PeerWarning) {
+        match result {
+            Err(HandlerError::Violation(kind, _)) => assert_eq!(kind, expected),
+            other => panic!("expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    // Fills in every piece of the file, so there is something to serve
+    fn complete(state: &mut MainState, disk_rx: &Receiver<Response>) {
+        for piece in 0..PIECES {
+            state
+                .file
+                .write(Block::new(piece, 0, &[0; PIECE_LEN]))
+                .unwrap();
+        }
+        settle(state, disk_rx);
+    }
+
+    // The next block the disk thread read for a peer
+    fn next_read(state: &MainState, disk_rx: &Receiver<Response>) -> (BlockInfo, Vec<u8>) {
+        state.file.flush().unwrap();
+        match disk_rx.try_recv() {
+            Ok(Response::Disk(DiskResponse::Read { block, data, .. })) => (block, data.unwrap()),
+            other => panic!("expected a read, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn choking_and_interest_flip_flags() {
+        let (_state, _timer_rx, mut peer) = setup();
+        peer.waiting_since = Some(Instant::now());
+
+        on_unchoke(&mut peer);
+        assert!(!peer.peer_choked);
+        on_choke(&mut peer);
+        assert!(peer.peer_choked);
+        assert_eq!(peer.waiting_since, None);
+
+        on_interested(&mut peer);
+        assert!(peer.peer_interested);
+        on_not_interested(&mut peer);
+        assert!(!peer.peer_interested);
+    }
+
+    #[test]
+    fn haves_count_towards_availability_once() {
+        let (mut state, _timer_rx, mut peer) = setup();
+        let mut seeds = 0;
+
+        for _ in 0..2 {
+            on_have(&mut peer, 1, PIECES, &mut state.availability, &mut seeds).unwrap();
+        }
+        assert_eq!(state.availability.get(1), 1);
+        assert!(peer.has.get(1));
+
+        for piece in [0, 2, 3] {
+            on_have(
+                &mut peer,
+                piece,
+                PIECES,
+                &mut state.availability,
+                &mut seeds,
+            )
+            .unwrap();
+        }
+        assert!(peer.is_seed);
+        assert_eq!(seeds, 1);
+
+        assert_violation(
+            on_have(
+                &mut peer,
+                PIECES as u32,
+                PIECES,
+                &mut state.availability,
+                &mut seeds,
+            ),
+            PeerWarning::InvalidHave,
+        );
+    }
+
+    #[test]
+    fn bitfields_replace_what_the_peer_had() {
+        let (mut state, _timer_rx, mut peer) = setup();
+        let mut seeds = 0;
+
+        on_bitfield(
+            &mut peer,
+            &[0b1111_0000],
+            PIECES,
+            &mut state.availability,
+            &mut seeds,
+        )
+        .unwrap();
+        assert!(peer.is_seed);
+        assert_eq!(seeds, 1);
+
+        on_bitfield(
+            &mut peer,
+            &[0b0100_0000],
+            PIECES,
+            &mut state.availability,
+            &mut seeds,
+        )
+        .unwrap();
+        assert!(!peer.is_seed);
+        assert_eq!(seeds, 0);
+        assert_eq!(
+            (0..PIECES)
+                .map(|piece| state.availability.get(piece))
+                .collect::<Vec<_>>(),
+            [0, 1, 0, 0]
+        );
+
+        assert_violation(
+            on_bitfield(
+                &mut peer,
+                &[0, 0],
+                PIECES,
+                &mut state.availability,
+                &mut seeds,
+            ),
+            PeerWarning::InvalidBitfield,
+        );
+        assert!(peer.has.get(1));
+    }
+
+    #[test]
+    fn bitfield_length_follows_the_piece_count() {
+        // whole bytes, then a spare byte's worth of bits on top
+        for (piece_count, len) in [(8, 1), (16, 2), (13, 2), (17, 3), (1, 1)] {
+            let (mut state, _timer_rx) = main_state(piece_count, PIECE_LEN);
+            let (mut peer, _peer_rx) = peer_info(piece_count);
+            let mut seeds = 0;
+
+            for wrong in [len - 1, len + 1] {
+                assert_violation(
+                    on_bitfield(
+                        &mut peer,
+                        &vec![0; wrong],
+                        piece_count,
+                        &mut state.availability,
+                        &mut seeds,
+                    ),
+                    PeerWarning::InvalidBitfield,
+                );
+            }
+
+            let mut bytes = vec![0xff; len];
+            let spare = len * 8 - piece_count;
+            bytes[len - 1] = 0xff << spare;
+            on_bitfield(
+                &mut peer,
+                &bytes,
+                piece_count,
+                &mut state.availability,
+                &mut seeds,
+            )
+            .unwrap();
+            assert_eq!(peer.has.len(), piece_count);
+            assert!(peer.is_seed);
+
+            if spare > 0 {
+                bytes[len - 1] |= 1;
+                assert_violation(
+                    on_bitfield(
+                        &mut peer,
+                        &bytes,
+                        piece_count,
+                        &mut state.availability,
+                        &mut seeds,
+                    ),
+                    PeerWarning::InvalidBitfield,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn requested_pieces_are_taken_in() {
+        let (mut state, timer_rx, disk_rx, mut peer) = setup_with_disk();
+        let block = Block::new(2, 0, &[0; PIECE_LEN]);
+        state.requested.insert(7, (block.info(), addr()));
+        peer.probation = true;
+        let sent_at = Instant::now() - Duration::from_secs(2);
+        peer.sent_at.insert((2, 0), sent_at);
+
+        let outcome = on_piece(
+            &mut peer,
+            addr(),
+            block,
+            &mut state.requested,
+            &state.timer_sender,
+            &mut state.file,
+            &mut state.stats,
+        )
+        .unwrap();
+
+        assert_eq!(outcome, BlockOutcome::Accepted);
+        assert!(state.requested.is_empty());
+        assert!(matches!(timer_rx.try_recv(), Ok(TimerRequest::Cancel(7))));
+        assert!(!peer.probation);
+        assert_eq!(peer.waiting_since, None);
+        assert!(peer.sent_at.is_empty());
+        assert!(peer.latency.exceeds(Duration::from_secs(2)));
+
+        // the piece only counts once the disk thread has checked it
+        assert!(!state.file.piece_is_complete(2).unwrap());
+        assert_eq!(state.stats.downloaded, 0);
+        settle(&mut state, &disk_rx);
+        assert!(state.file.piece_is_complete(2).unwrap());
+        assert_eq!(state.stats.downloaded, PIECE_LEN);
+        assert_eq!(peer.uploaded, PIECE_LEN);
+    }
+
+    #[test]
+    fn blocks_answer_the_requests_they_cover() {
+        let (mut state, timer_rx, mut peer) = setup();
+        let request = |piece, range| BlockInfo { piece, range };
+        state.requested.insert(1, (request(0, 0..8), addr()));
+        state.requested.insert(2, (request(0, 8..16), addr()));
+        state.requested.insert(3, (request(1, 0..16), addr()));
+        let mut receive = |state: &mut MainState, block| {
+            on_piece(
+                &mut peer,
+                addr(),
+                block,
+                &mut state.requested,
+                &state.timer_sender,
+                &mut state.file,
+                &mut state.stats,
+            )
+            .unwrap()
+        };
+
+        // one block for both halves of piece 0
+        let outcome = receive(&mut state, Block::new(0, 0, &[0; PIECE_LEN]));
+        assert_eq!(outcome, BlockOutcome::Accepted);
+        let mut cancelled: Vec<Token> = timer_rx
+            .try_iter()
+            .map(|req| match req {
+                TimerRequest::Cancel(token) => token,
+                _ => panic!("expected only Cancels"),
+            })
+            .collect();
+        cancelled.sort();
+        assert_eq!(cancelled, [1, 2]);
+
+        // and the middle of piece 1, which leaves the request for all of it waiting
+        let outcome = receive(&mut state, Block::new(1, 4, &[0; 8]));
+        assert_eq!(outcome, BlockOutcome::Accepted);
+        assert!(timer_rx.try_recv().is_err());
+        assert_eq!(state.requested.keys().collect::<Vec<_>>(), [&3]);
+        assert_eq!(state.file.get_unfilled(1).unwrap(), [0..4, 12..16]);
+    }
+
+    #[test]
+    fn unrequested_and_unusable_pieces_are_violations() {
+        let (mut state, timer_rx, mut peer) = setup();
+
+        let result = on_piece(
+            &mut peer,
+            addr(),
+            Block::new(0, 0, &[0; PIECE_LEN]),
+            &mut state.requested,
+            &state.timer_sender,
+            &mut state.file,
+            &mut state.stats,
+        );
+        assert_violation(result, PeerWarning::UnrequestedPiece);
+        assert_eq!(state.stats.unrequested, PIECE_LEN);
+        assert!(!state.file.piece_is_complete(0).unwrap());
+
+        // we asked for a piece the file doesn't have, so it can't be stored
+        let block = Block::new(PIECES, 0, &[0; PIECE_LEN]);
+        state.requested.insert(1, (block.info(), addr()));
+        let result = on_piece(
+            &mut peer,
+            addr(),
+            block,
+            &mut state.requested,
+            &state.timer_sender,
+            &mut state.file,
+            &mut state.stats,
+        );
+        assert_violation(result, PeerWarning::BadPiece);
+        assert!(matches!(timer_rx.try_recv(), Ok(TimerRequest::Cancel(1))));
+        assert_eq!(peer.uploaded, 0);
+    }
+
+    #[test]
+    fn blocks_that_cross_a_cancel_are_wasted_but_not_violations() {
+        let (mut state, timer_rx, peer) = setup();
+        let block = Block::new(0, 0, &[0; PIECE_LEN]);
+        state.requested.insert(1, (block.info(), addr()));
+        state.peers.insert(addr(), peer);
+
+        // say the peer was snubbing us, so its request went to someone else
+        state.remove_request(1);
+        assert!(matches!(timer_rx.try_recv(), Ok(TimerRequest::Cancel(1))));
+        let mut peer = state.peers.remove(&addr()).unwrap();
+
+        // and the block was already on its way
+        let receive = |state: &mut MainState, peer: &mut PeerInfo| {
+            on_piece(
+                peer,
+                addr(),
+                Block::new(0, 0, &[0; PIECE_LEN]),
+                &mut state.requested,
+                &state.timer_sender,
+                &mut state.file,
+                &mut state.stats,
+            )
+        };
+        let outcome = receive(&mut state, &mut peer).unwrap();
+        assert_eq!(outcome, BlockOutcome::Duplicate);
+        assert_eq!(state.stats.late, PIECE_LEN);
+        assert_eq!(state.stats.unrequested, 0);
+        assert!(!state.file.piece_is_complete(0).unwrap());
+
+        // the Cancel only covers the one copy
+        assert_violation(
+            receive(&mut state, &mut peer),
+            PeerWarning::UnrequestedPiece,
+        );
+
+        // and only for a while
+        let old = Instant::now()
+            .checked_sub(crate::session::LATE_BLOCK_GRACE)
+            .unwrap();
+        peer.cancelled.push((block.info(), old));
+        assert_violation(
+            receive(&mut state, &mut peer),
+            PeerWarning::UnrequestedPiece,
+        );
+        assert_eq!(state.stats.unrequested, 2 * PIECE_LEN);
+    }
+
+    #[test]
+    fn requests_are_served_to_unchoked_peers() {
+        let (mut state, _timer_rx, disk_rx, mut peer) = setup_with_disk();
+        complete(&mut state, &disk_rx);
+        let sink = MockSink::default();
+
+        on_request(
+            &mut peer,
+            addr(),
+            1,
+            4,
+            8,
+            usize::MAX,
+            &mut state.file,
+            &sink,
+        )
+        .unwrap();
+        assert!(sink.0.borrow().is_empty());
+
+        let (block, data) = next_read(&state, &disk_rx);
+        assert_eq!(
+            block,
+            BlockInfo {
+                piece: 1,
+                range: 4..12
+            }
+        );
+        on_block_read(
+            &mut peer,
+            &block,
+            data,
+            &mut state.stats,
+            &state.queued_uploads,
+            &sink,
+        )
+        .unwrap();
+        assert_eq!(*sink.0.borrow(), [Message::Piece(1, 4, vec![0; 8])]);
+        assert_eq!(state.stats.served, 8);
+        assert_eq!(peer.served, 8);
+
+        // it counts as uploaded once the peer thread has written it, not before
+        assert_eq!(state.stats.uploaded, 0);
+        assert_eq!(peer.downloaded, 0);
+
+        // a peer choked while the disk was busy doesn't get the block
+        on_request(
+            &mut peer,
+            addr(),
+            2,
+            0,
+            8,
+            usize::MAX,
+            &mut state.file,
+            &sink,
+        )
+        .unwrap();
+        let (block, data) = next_read(&state, &disk_rx);
+        peer.choked = true;
+        on_block_read(
+            &mut peer,
+            &block,
+            data,
+            &mut state.stats,
+            &state.queued_uploads,
+            &sink,
+        )
+        .unwrap();
+        assert_eq!(sink.0.borrow().len(), 1);
+        assert_eq!(state.stats.served, 8);
+    }
+
+    #[test]
+    fn requests_past_a_cap_get_the_peer_choked() {
+        let (mut state, _timer_rx, disk_rx, mut peer) = setup_with_disk();
+        complete(&mut state, &disk_rx);
+        let sink = MockSink::default();
+
+        on_request(&mut peer, addr(), 1, 4, 8, 0, &mut state.file, &sink).unwrap();
+        assert_eq!(*sink.0.borrow(), [Message::Choke]);
+        assert!(peer.choked);
+
+        // nothing was read for it
+        state.file.flush().unwrap();
+        assert!(disk_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn requests_we_wont_serve_are_violations() {
+        let (mut state, _timer_rx, disk_rx, mut peer) = setup_with_disk();
+        let sink = MockSink::default();
+
+        // we don't have the piece yet
+        let result = on_request(
+            &mut peer,
+            addr(),
+            0,
+            0,
+            8,
+            usize::MAX,
+            &mut state.file,
+            &sink,
+        );
+        assert_violation(result, PeerWarning::BadRequest);
+
+        complete(&mut state, &disk_rx);
+        let result = on_request(
+            &mut peer,
+            addr(),
+            0,
+            0,
+            DEFAULT_MAX_BLOCK_LEN as u32 + 1,
+            usize::MAX,
+            &mut state.file,
+            &sink,
+        );
+        assert_violation(result, PeerWarning::OversizedRequest);
+
+        peer.choked = true;
+        let result = on_request(
+            &mut peer,
+            addr(),
+            0,
+            0,
+            8,
+            usize::MAX,
+            &mut state.file,
+            &sink,
+        );
+        assert_violation(result, PeerWarning::ChokedRequest);
+
+        assert!(sink.0.borrow().is_empty());
+        state.file.flush().unwrap();
+        assert!(disk_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn serving_a_departed_peer_is_an_error() {
+        let (mut state, _timer_rx, mut peer) = setup();
+        let block = BlockInfo {
+            piece: 0,
+            range: 0..8,
+        };
+
+        let result = on_block_read(
+            &mut peer,
+            &block,
+            vec![0; 8],
+            &mut state.stats,
+            &state.queued_uploads,
+            &GoneSink,
+        );
+        assert!(matches!(result, Err(HandlerError::Other(_))));
+    }
+
+    #[test]
+    fn extension_handshakes_and_metadata_requests() {
+        let (_state, _timer_rx, mut peer) = setup();
+        let metadata = vec![7; 100];
+        let sink = MockSink::default();
+        let request = MetadataMessage::Request(0).encode();
+
+        // before the handshake, we don't know which id to answer with
+        on_extended(
+            &mut peer,
+            extension::UT_METADATA_ID,
+            &request,
+            &metadata,
+            &sink,
+        )
+        .unwrap();
+        assert!(sink.0.borrow().is_empty());
+
+        let handshake = extension::Handshake {
+            m: BTreeMap::from([("ut_metadata".to_owned(), 3)]),
+            metadata_size: 0,
+            p: 6881,
+        };
+        on_extended(
+            &mut peer,
+            extension::HANDSHAKE_ID,
+            &handshake.encode(),
+            &metadata,
+            &sink,
+        )
+        .unwrap();
+        assert_eq!(peer.ut_metadata, Some(3));
+        assert_eq!(peer.listen_port, Some(6881));
+
+        on_extended(
+            &mut peer,
+            extension::UT_METADATA_ID,
+            &request,
+            &metadata,
+            &sink,
+        )
+        .unwrap();
+        assert_eq!(
+            *sink.0.borrow(),
+            [Message::Extended(
+                3,
+                extension::serve(&metadata, 0).encode()
+            )]
+        );
+
+        // ids we never handed out are ignored
+        on_extended(&mut peer, 42, b"garbage", &metadata, &sink).unwrap();
+        assert_eq!(sink.0.borrow().len(), 1);
+    }
+
+    #[test]
+    fn malformed_extended_messages_are_violations() {
+        let (_state, _timer_rx, mut peer) = setup();
+        let sink = MockSink::default();
+
+        for id in [extension::HANDSHAKE_ID, extension::UT_METADATA_ID] {
+            assert_violation(
+                on_extended(&mut peer, id, b"not bencode", &[], &sink),
+                PeerWarning::MalformedMessage,
+            );
+        }
+        assert!(sink.0.borrow().is_empty());
+    }
+}
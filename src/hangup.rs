@@ -0,0 +1,232 @@
+//! Telling apart the ways connections end, for debugging swarms
+//!
+//! Peers that drop us mid-handshake, right after our bitfield, while we choke them, or
+//! mid-transfer each point at a different bug, so every disconnect is put down to what was
+//! going on with the peer at the time and to which side closed it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::capture::Direction;
+use crate::reconnect::Disconnect;
+use crate::session::PeerInfo;
+
+/// Which end closed a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Side::Local => "by us",
+            Side::Remote => "by peer",
+        })
+    }
+}
+
+/// How a peer thread saw its connection end
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hangup {
+    pub side: Side,
+    pub handshaken: bool,
+
+    // whether the connection was reset, rather than closed
+    pub reset: bool,
+
+    // the last message sent or received, by kind
+    pub last_message: Option<(Direction, &'static str)>,
+}
+
+impl fmt::Display for Hangup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "closed {}", self.side)?;
+        if self.reset {
+            f.write_str(" with a reset")?;
+        }
+        if !self.handshaken {
+            f.write_str(" during the handshake")?;
+        }
+        match self.last_message {
+            Some((Direction::Sent, kind)) => write!(f, ", last sent {}", kind),
+            Some((Direction::Received, kind)) => write!(f, ", last received {}", kind),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What was going on with a peer when its connection ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Handshaking,
+
+    /// Bitfields may have been exchanged, but nobody has been interested or sent any data
+    PostBitfield,
+
+    Active,
+
+    /// We were choking the peer while it was interested in us
+    ChokedByUs,
+
+    /// We had already decided to drop the peer
+    Closing,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Handshaking => "handshaking",
+            Phase::PostBitfield => "after bitfield",
+            Phase::Active => "active",
+            Phase::ChokedByUs => "choked by us",
+            Phase::Closing => "closing",
+        })
+    }
+}
+
+/// Works out what phase `peer` was in when it was removed for `reason`. A connection without
+/// a `hangup` was given up on by main, rather than its thread.
+pub fn classify(peer: &PeerInfo, hangup: Option<&Hangup>, reason: Disconnect) -> Phase {
+    if hangup.is_some_and(|h| !h.handshaken) {
+        return Phase::Handshaking;
+    }
+
+    match reason {
+        Disconnect::TimedOut | Disconnect::Banned | Disconnect::Culled | Disconnect::Flooded => {
+            Phase::Closing
+        }
+        Disconnect::Died | Disconnect::ConnectFailed => {
+            let quiet = peer.uploaded == 0 && peer.downloaded == 0;
+            if quiet && !peer.interested && !peer.peer_interested {
+                Phase::PostBitfield
+            } else if peer.choked && peer.peer_interested {
+                Phase::ChokedByUs
+            } else {
+                Phase::Active
+            }
+        }
+    }
+}
+
+/// Which side closed a connection removed for `reason`
+pub fn side(hangup: Option<&Hangup>, reason: Disconnect) -> Side {
+    match (hangup, reason) {
+        (Some(hangup), _) => hangup.side,
+        // main only notices a dead connection when it can't hand the thread a message
+        (None, Disconnect::Died) => Side::Remote,
+        (None, _) => Side::Local,
+    }
+}
+
+/// Disconnects counted by phase and side
+#[derive(Clone, Debug, Default)]
+pub struct Disconnects {
+    counts: BTreeMap<(Phase, Side), usize>,
+}
+
+impl Disconnects {
+    pub fn record(&mut self, phase: Phase, side: Side) {
+        *self.counts.entry((phase, side)).or_default() += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+impl fmt::Display for Disconnects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, ((phase, side), count)) in self.counts.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {} {}", count, phase, side)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::Direction;
+    use crate::reconnect::Disconnect;
+    use crate::test_utils::peer_info;
+
+    use super::{classify, side, Disconnects, Hangup, Phase, Side};
+
+    const REMOTE: Hangup = Hangup {
+        side: Side::Remote,
+        handshaken: true,
+        reset: false,
+        last_message: Some((Direction::Sent, "Bitfield")),
+    };
+
+    #[test]
+    fn phases() {
+        let (mut peer, _rx) = peer_info(4);
+        let died = Disconnect::Died;
+
+        let handshaking = Hangup {
+            handshaken: false,
+            ..REMOTE
+        };
+        assert_eq!(
+            classify(&peer, Some(&handshaking), died),
+            Phase::Handshaking
+        );
+        assert_eq!(classify(&peer, Some(&REMOTE), died), Phase::PostBitfield);
+
+        peer.choked = true;
+        peer.peer_interested = true;
+        assert_eq!(classify(&peer, Some(&REMOTE), died), Phase::ChokedByUs);
+
+        peer.choked = false;
+        assert_eq!(classify(&peer, Some(&REMOTE), died), Phase::Active);
+
+        // a quiet peer we were interested in was still doing something
+        let (mut peer, _rx) = peer_info(4);
+        peer.interested = true;
+        assert_eq!(classify(&peer, None, died), Phase::Active);
+
+        for reason in [
+            Disconnect::TimedOut,
+            Disconnect::Banned,
+            Disconnect::Culled,
+            Disconnect::Flooded,
+        ] {
+            assert_eq!(classify(&peer, None, reason), Phase::Closing);
+        }
+    }
+
+    #[test]
+    fn sides() {
+        assert_eq!(side(Some(&REMOTE), Disconnect::Died), Side::Remote);
+        assert_eq!(side(None, Disconnect::Died), Side::Remote);
+        assert_eq!(side(None, Disconnect::Culled), Side::Local);
+    }
+
+    #[test]
+    fn counts_are_listed_by_phase() {
+        let mut disconnects = Disconnects::default();
+        assert!(disconnects.is_empty());
+
+        disconnects.record(Phase::Active, Side::Remote);
+        disconnects.record(Phase::Handshaking, Side::Local);
+        disconnects.record(Phase::Active, Side::Remote);
+        assert_eq!(
+            disconnects.to_string(),
+            "1 handshaking by us, 2 active by peer"
+        );
+        assert_eq!(REMOTE.to_string(), "closed by peer, last sent Bitfield");
+        let reset = Hangup {
+            reset: true,
+            ..REMOTE
+        };
+        assert_eq!(
+            reset.to_string(),
+            "closed by peer with a reset, last sent Bitfield"
+        );
+    }
+}
@@ -0,0 +1,311 @@
+// The v2 hasher isn't used by the session until piece layers are wired up
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::file::FileError;
+use crate::positioned::FileExt;
+
+/// Size of the blocks BitTorrent v2 hashes individually before building a merkle tree
+pub const LEAF_SIZE: usize = 16384;
+
+const SHA256_SIZE: usize = 32;
+
+/// Computes the hash a piece is checked against.
+///
+/// Hashing is incremental: feed the piece through [PieceHasher::update], then take the
+/// result with [PieceHasher::finalize_reset], which also readies the hasher for the next piece.
+pub trait PieceHasher: Debug + Send {
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize_reset(&mut self) -> Vec<u8>;
+
+    /// Another hasher of the same kind, starting from scratch, for hashing pieces side by side
+    fn fresh(&self) -> Box<dyn PieceHasher>;
+
+    /// Hash `length` bytes of `file` starting at `offset`, and compare against `expected`.
+    /// Returns [FileError::Truncated] if the file ends before the piece does.
+    fn verify(
+        &mut self,
+        file: &File,
+        offset: usize,
+        length: usize,
+        expected: &[u8],
+    ) -> Result<bool, FileError> {
+        let mut buf = vec![0u8; 4096];
+
+        let mut done = 0;
+        while done < length {
+            let to_read = buf.len().min(length - done);
+            let bytes_read = file.read_at(&mut buf[..to_read], (offset + done) as u64)?;
+            if bytes_read == 0 {
+                // don't leave a partial piece behind for the next caller
+                self.finalize_reset();
+                return Err(FileError::Truncated);
+            }
+
+            self.update(&buf[..bytes_read]);
+            done += bytes_read;
+        }
+
+        Ok(self.finalize_reset() == expected)
+    }
+}
+
+/// The plain SHA-1 piece hash of BitTorrent v1
+#[derive(Debug, Default)]
+pub struct Sha1PieceHasher(Sha1);
+
+impl PieceHasher for Sha1PieceHasher {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    fn finalize_reset(&mut self) -> Vec<u8> {
+        Digest::finalize_reset(&mut self.0).to_vec()
+    }
+
+    fn fresh(&self) -> Box<dyn PieceHasher> {
+        Box::<Self>::default()
+    }
+}
+
+/// Hashes with another hasher, but leaves nothing that [PieceHasher::verify] reads in the page
+/// cache, for --uncached-verify.
+///
+/// Hashing a whole download at startup or on a recheck otherwise reads every byte of it
+/// through the cache, pushing out everything else the machine had there. Pieces written by
+/// the download itself don't go through [PieceHasher::verify] unless their blocks came out of
+/// order, and those still waiting to be written back to disk aren't dropped anyway.
+#[derive(Debug)]
+pub struct Uncached(pub Box<dyn PieceHasher>);
+
+impl PieceHasher for Uncached {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize_reset(&mut self) -> Vec<u8> {
+        self.0.finalize_reset()
+    }
+
+    fn fresh(&self) -> Box<dyn PieceHasher> {
+        Box::new(Uncached(self.0.fresh()))
+    }
+
+    fn verify(
+        &mut self,
+        file: &File,
+        offset: usize,
+        length: usize,
+        expected: &[u8],
+    ) -> Result<bool, FileError> {
+        let result = self.0.verify(file, offset, length, expected);
+
+        // Only advice, which a filesystem is free to ignore, so failing is no worse than not
+        // asking. Safety: the descriptor stays open for as long as file is borrowed.
+        unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                length as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            );
+        }
+        result
+    }
+}
+
+/// Merkle root over SHA-256 hashes of [LEAF_SIZE] blocks, as used by BitTorrent v2.
+///
+/// The last leaf may be short, and the leaf count is padded up to a power of two with
+/// all-zero hashes. This does not yet pad to the full width of a piece layer, so it is
+/// only correct for pieces that are a power of two leaves long.
+#[derive(Debug, Default)]
+pub struct Sha256MerkleHasher {
+    // hashes of every completed leaf so far
+    leaves: Vec<[u8; SHA256_SIZE]>,
+
+    // data for the leaf currently being filled
+    current: Sha256,
+    current_len: usize,
+}
+
+impl Sha256MerkleHasher {
+    fn finish_leaf(&mut self) {
+        self.leaves.push(self.current.finalize_reset().into());
+        self.current_len = 0;
+    }
+}
+
+impl PieceHasher for Sha256MerkleHasher {
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = data.len().min(LEAF_SIZE - self.current_len);
+            self.current.update(&data[..n]);
+            self.current_len += n;
+            data = &data[n..];
+
+            if self.current_len == LEAF_SIZE {
+                self.finish_leaf();
+            }
+        }
+    }
+
+    fn finalize_reset(&mut self) -> Vec<u8> {
+        if self.current_len > 0 || self.leaves.is_empty() {
+            self.finish_leaf();
+        }
+
+        let mut layer = std::mem::take(&mut self.leaves);
+        layer.resize(layer.len().next_power_of_two(), [0; SHA256_SIZE]);
+
+        while layer.len() > 1 {
+            layer = layer
+                .chunks_exact(2)
+                .map(|pair| {
+                    Sha256::new()
+                        .chain_update(pair[0])
+                        .chain_update(pair[1])
+                        .finalize()
+                        .into()
+                })
+                .collect();
+        }
+
+        layer[0].to_vec()
+    }
+
+    fn fresh(&self) -> Box<dyn PieceHasher> {
+        Box::<Self>::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use hex_literal::hex;
+
+    use crate::file::FileError;
+
+    use super::{PieceHasher, Sha1PieceHasher, Sha256MerkleHasher, Uncached, LEAF_SIZE};
+
+    #[test]
+    fn sha1_matches_known_vector() {
+        let mut hasher = Sha1PieceHasher::default();
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(
+            hasher.finalize_reset(),
+            hex!("a9993e364706816aba3e25717850c26c9cd0d89d")
+        );
+
+        // finalizing starts the next piece from scratch
+        hasher.update(b"abc");
+        assert_eq!(
+            hasher.finalize_reset(),
+            hex!("a9993e364706816aba3e25717850c26c9cd0d89d")
+        );
+    }
+
+    #[test]
+    fn merkle_single_short_leaf_is_plain_sha256() {
+        let mut hasher = Sha256MerkleHasher::default();
+        hasher.update(b"abc");
+        assert_eq!(
+            hasher.finalize_reset(),
+            hex!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+
+    #[test]
+    fn merkle_leaf_boundaries() {
+        let zero_leaf = hex!("4fe7b59af6de3b665b67788cc2f99892ab827efae3a467342b3bb4e3bc8e5bfe");
+
+        let mut hasher = Sha256MerkleHasher::default();
+        hasher.update(&[0; LEAF_SIZE]);
+        assert_eq!(hasher.finalize_reset(), zero_leaf);
+
+        // two leaves, fed in pieces that straddle the boundary
+        let data = vec![0u8; LEAF_SIZE * 2];
+        for chunk in data.chunks(1000) {
+            hasher.update(chunk);
+        }
+        assert_eq!(
+            hasher.finalize_reset(),
+            hex!("c36d0dd6a886e1fce758b6b5c531b703a1f21e8f6453785c390931cf8fa8a76d")
+        );
+    }
+
+    #[test]
+    fn merkle_pads_to_power_of_two() {
+        // two full leaves and a short one, padded out with a zero hash
+        let mut hasher = Sha256MerkleHasher::default();
+        hasher.update(&[0; LEAF_SIZE * 2]);
+        hasher.update(&[1; 100]);
+        assert_eq!(
+            hasher.finalize_reset(),
+            hex!("bad9c0541c6d059c7ebc97fa17177e106004ab2bf87fc8e0955939f7629f0ecb")
+        );
+    }
+
+    #[test]
+    fn verify_reads_from_offset() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"xxabcxx").unwrap();
+
+        let expected = hex!("a9993e364706816aba3e25717850c26c9cd0d89d");
+        let mut hasher = Sha1PieceHasher::default();
+        assert!(hasher.verify(&file, 2, 3, &expected).unwrap());
+        assert!(!hasher.verify(&file, 1, 3, &expected).unwrap());
+        assert!(matches!(
+            hasher.verify(&file, 5, 3, &expected),
+            Err(FileError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn uncached_verify_hashes_the_same() {
+        let mut file = tempfile::tempfile().unwrap();
+        let data: Vec<u8> = (0..LEAF_SIZE * 5).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data).unwrap();
+
+        let mut plain: Box<dyn PieceHasher> = Box::new(Sha1PieceHasher::default());
+        let mut uncached = Uncached(Box::new(Sha1PieceHasher::default()));
+        for (offset, length) in [(0, LEAF_SIZE), (100, LEAF_SIZE * 2), (LEAF_SIZE * 4, 999)] {
+            plain.update(&data[offset..offset + length]);
+            let expected = plain.finalize_reset();
+            assert!(uncached.verify(&file, offset, length, &expected).unwrap());
+            assert!(!uncached
+                .verify(&file, offset + 1, length, &expected)
+                .unwrap());
+            assert_eq!(
+                plain.verify(&file, offset + 1, length, &expected).unwrap(),
+                uncached
+                    .verify(&file, offset + 1, length, &expected)
+                    .unwrap()
+            );
+        }
+        assert!(matches!(
+            uncached.verify(&file, LEAF_SIZE * 5 - 10, 20, &[0; 20]),
+            Err(FileError::Truncated)
+        ));
+
+        // and hashing without reading the file is left alone
+        let mut merkle = Uncached(Box::new(Sha256MerkleHasher::default()));
+        let mut fresh = merkle.fresh();
+        merkle.update(b"abc");
+        fresh.update(b"abc");
+        assert_eq!(merkle.finalize_reset(), fresh.finalize_reset());
+        assert_eq!(
+            merkle.finalize_reset(),
+            Sha256MerkleHasher::default().finalize_reset()
+        );
+    }
+}
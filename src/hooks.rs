@@ -0,0 +1,248 @@
+//! Running the user's commands when the download completes or fails, for scripting
+//!
+//! Commands are fire-and-forget: each is reaped by a thread of its own, and a command that
+//! can't be started or fails is only logged.
+
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::args::Args;
+use crate::stats::Stats;
+
+/// Something a hook can be run for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Complete,
+
+    /// A fatal error, by category
+    Error(&'static str),
+}
+
+/// The commands given with --on-complete and --on-error, and what they are told about the
+/// torrent
+#[derive(Debug)]
+pub struct Hooks {
+    on_complete: Option<String>,
+    on_error: Option<String>,
+    shell: bool,
+
+    name: String,
+    info_hash: [u8; 20],
+    path: PathBuf,
+    started: Instant,
+
+    // completion is only announced once, even if a recheck sends us back to downloading
+    completed: bool,
+}
+
+impl Hooks {
+    pub fn new(args: &Args, name: String, info_hash: [u8; 20], path: PathBuf) -> Self {
+        Self {
+            on_complete: args.on_complete.clone(),
+            on_error: args.on_error.clone(),
+            shell: args.shell,
+            name,
+            info_hash,
+            path,
+            started: Instant::now(),
+            completed: false,
+        }
+    }
+
+    /// Runs the command for `event`, if there is one
+    pub fn fire(&mut self, event: Event, stats: &Stats) {
+        let cmd = match event {
+            Event::Complete if self.completed => return,
+            Event::Complete => {
+                self.completed = true;
+                &self.on_complete
+            }
+            Event::Error(_) => &self.on_error,
+        };
+        let Some(cmd) = cmd else {
+            return;
+        };
+
+        let env = self.env(event, stats, self.started.elapsed());
+        match command(cmd, self.shell).envs(env).spawn() {
+            Ok(child) => reap(child, cmd.clone()),
+            Err(e) => warn!("Failed to run hook {:?}: {}", cmd, e),
+        }
+    }
+
+    // The environment variables describing `event`
+    fn env(&self, event: Event, stats: &Stats, elapsed: Duration) -> Vec<(String, String)> {
+        let info_hash: String = self
+            .info_hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let mut env = vec![
+            ("RITTORRENT_NAME", self.name.clone()),
+            ("RITTORRENT_INFO_HASH", info_hash),
+            ("RITTORRENT_PATH", self.path.display().to_string()),
+            ("RITTORRENT_UPLOADED", stats.uploaded.to_string()),
+            ("RITTORRENT_DOWNLOADED", stats.downloaded.to_string()),
+            ("RITTORRENT_ELAPSED", elapsed.as_secs().to_string()),
+        ];
+        match event {
+            Event::Complete => env.push(("RITTORRENT_EVENT", "complete".to_owned())),
+            Event::Error(category) => {
+                env.push(("RITTORRENT_EVENT", "error".to_owned()));
+                env.push(("RITTORRENT_ERROR", category.to_owned()));
+            }
+        }
+        env.into_iter().map(|(k, v)| (k.to_owned(), v)).collect()
+    }
+}
+
+// With --shell, `cmd` is handed to sh as is. Otherwise it is split on whitespace into a
+// program and its arguments, with no quoting or expansion.
+fn command(cmd: &str, shell: bool) -> Command {
+    if shell {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        return command;
+    }
+
+    let mut words = cmd.split_whitespace();
+    let mut command = Command::new(words.next().unwrap_or_default());
+    command.args(words);
+    command
+}
+
+// Waits for the command on a thread of its own, so it doesn't become a zombie
+fn reap(mut child: Child, cmd: String) {
+    thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => debug!("Hook {:?} finished", cmd),
+        Ok(status) => warn!("Hook {:?} failed: {}", cmd, status),
+        Err(e) => warn!("Failed to wait for hook {:?}: {}", cmd, e),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use clap::Parser;
+
+    use super::{Event, Hooks};
+    use crate::args::Args;
+    use crate::stats::Stats;
+
+    // Waits for the hook to have written its environment to `path`
+    fn read_env(path: &Path) -> HashMap<String, String> {
+        let start = Instant::now();
+        loop {
+            if let Ok(env) = fs::read_to_string(path) {
+                if env.ends_with("done\n") {
+                    return env
+                        .lines()
+                        .filter_map(|line| line.split_once('='))
+                        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                        .collect();
+                }
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "hook never ran");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn hooks(dir: &Path) -> Hooks {
+        let script = dir.join("hook.sh");
+        fs::write(&script, "#!/bin/sh\nenv > \"$1\"\necho done >> \"$1\"\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let complete = format!("{} {}", script.display(), dir.join("complete").display());
+        let error = format!("{} {}", script.display(), dir.join("error").display());
+        let mut args = vec!["rittorrent", "--torrent", "x.torrent"];
+        args.extend(["--on-complete", &complete, "--on-error", &error]);
+        let args = Args::parse_from(args);
+
+        Hooks::new(
+            &args,
+            "Café.txt".to_owned(),
+            [0xab; 20],
+            dir.join("Café.txt"),
+        )
+    }
+
+    #[test]
+    fn completion_is_described_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut hooks = hooks(dir.path());
+        let stats = Stats {
+            uploaded: 12,
+            downloaded: 34,
+            ..Stats::default()
+        };
+
+        hooks.fire(Event::Complete, &stats);
+        let env = read_env(&dir.path().join("complete"));
+        assert_eq!(env["RITTORRENT_EVENT"], "complete");
+        assert_eq!(env["RITTORRENT_NAME"], "Café.txt");
+        assert_eq!(env["RITTORRENT_INFO_HASH"], "ab".repeat(20));
+        assert_eq!(
+            env["RITTORRENT_PATH"],
+            dir.path().join("Café.txt").display().to_string()
+        );
+        assert_eq!(env["RITTORRENT_UPLOADED"], "12");
+        assert_eq!(env["RITTORRENT_DOWNLOADED"], "34");
+        assert_eq!(env["RITTORRENT_ELAPSED"], "0");
+        assert!(!env.contains_key("RITTORRENT_ERROR"));
+
+        // a second completion, after a recheck, doesn't run it again
+        fs::remove_file(dir.path().join("complete")).unwrap();
+        hooks.fire(Event::Complete, &stats);
+        thread::sleep(Duration::from_millis(200));
+        assert!(!dir.path().join("complete").exists());
+    }
+
+    #[test]
+    fn errors_carry_their_category() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut hooks = hooks(dir.path());
+
+        hooks.fire(Event::Error("io"), &Stats::default());
+        let env = read_env(&dir.path().join("error"));
+        assert_eq!(env["RITTORRENT_EVENT"], "error");
+        assert_eq!(env["RITTORRENT_ERROR"], "io");
+    }
+
+    #[test]
+    fn shell_commands_and_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let cmd = format!(
+            "echo RITTORRENT_EVENT=$RITTORRENT_EVENT > {} && echo done >> {}",
+            out.display(),
+            out.display()
+        );
+        let args = Args::parse_from([
+            "rittorrent",
+            "--torrent",
+            "x.torrent",
+            "--shell",
+            "--on-complete",
+            &cmd,
+            "--on-error",
+            "/nonexistent/hook",
+        ]);
+        let mut hooks = Hooks::new(&args, String::new(), [0; 20], dir.path().into());
+
+        // a hook that fails is only logged
+        hooks.fire(Event::Error("io"), &Stats::default());
+
+        hooks.fire(Event::Complete, &Stats::default());
+        assert_eq!(read_env(&out)["RITTORRENT_EVENT"], "complete");
+    }
+}
@@ -1,14 +1,40 @@
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::{collections::HashMap, net::TcpStream};
 
-use anyhow::{anyhow, Result};
 use format_bytes::format_bytes;
 use regex::Regex;
+use thiserror::Error;
 use url::Url;
 use urlencoding::{encode, encode_binary};
 
 const CRLF: &[u8] = b"\r\n";
 
+/// Largest tracker response we accept unless told otherwise
+pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("invalid URL: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+
+    #[error("scheme {0} is not supported")]
+    UnsupportedScheme(String),
+
+    #[error("URL has no host")]
+    NoHost,
+
+    #[error("malformed response: {0}")]
+    MalformedResponse(&'static str),
+
+    #[error("response body is larger than the {0} byte limit")]
+    BodyTooLarge(usize),
+}
+
+type Result<T> = std::result::Result<T, HttpError>;
+
 #[derive(Debug)]
 pub struct Response {
     pub status: u32,
@@ -16,20 +42,73 @@ pub struct Response {
     pub headers: HashMap<String, String>,
 }
 
+impl Response {
+    /// Looks up a header, ignoring the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
 fn strip_leading_whitespace(s: &mut String) {
     // https://stackoverflow.com/a/57063944
     s.retain(|c| !c.is_whitespace());
 }
 
-pub fn http_get(url: &str, parameters: &[(&str, &[u8])]) -> Result<Response> {
+fn parse_status(status: &str) -> Result<u32> {
+    status
+        .parse()
+        .map_err(|_| HttpError::MalformedResponse("invalid status code"))
+}
+
+/// Reads a response body of `len` bytes, or up to end of file if the length isn't known,
+/// failing as soon as it turns out to be larger than `limit`.
+/// The body is read as it arrives, so a huge one is never held in memory, whatever the
+/// server claims its length is.
+pub fn read_body(reader: &mut impl Read, len: Option<usize>, limit: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match len {
+        Some(len) if len > limit => return Err(HttpError::BodyTooLarge(limit)),
+        Some(len) => {
+            reader.take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() < len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        None => {
+            // one byte past the limit is enough to know it was exceeded
+            reader.take(limit as u64 + 1).read_to_end(&mut buf)?;
+            if buf.len() > limit {
+                return Err(HttpError::BodyTooLarge(limit));
+            }
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Sends a GET request, and returns the response if its body is at most `max_body` bytes
+pub fn http_get(url: &str, parameters: &[(&str, &[u8])], max_body: usize) -> Result<Response> {
+    http_request("GET", url, parameters, &[], max_body)
+}
+
+/// Sends a request with any method and extra headers, and returns the response if its body is
+/// at most `max_body` bytes. The response to a HEAD request never has a body, whatever its
+/// Content-Length says.
+pub fn http_request(
+    method: &str,
+    url: &str,
+    parameters: &[(&str, &[u8])],
+    headers: &[(&str, &str)],
+    max_body: usize,
+) -> Result<Response> {
     // First, let's try to parse the provided URL
     let parsed_url = Url::parse(url)?;
     // Is this an http url?
     if parsed_url.scheme() != "http" {
-        return Err(anyhow!(
-            "http_get: scheme {} is not valid",
-            parsed_url.scheme()
-        ));
+        return Err(HttpError::UnsupportedScheme(parsed_url.scheme().to_owned()));
     }
 
     // Next, let's try to connect to the remote
@@ -42,7 +121,7 @@ pub fn http_get(url: &str, parameters: &[(&str, &[u8])]) -> Result<Response> {
 
     // Send the HTTP request itself
     let path = parsed_url.path().as_bytes();
-    let mut request = format_bytes!(b"GET {}", path);
+    let mut request = format_bytes!(b"{} {}", method.as_bytes(), path);
     // Add the query parameters
     let mut is_first = true;
     for (query, value) in parameters {
@@ -61,7 +140,10 @@ pub fn http_get(url: &str, parameters: &[(&str, &[u8])]) -> Result<Response> {
     if let Some(host) = parsed_url.host() {
         request_headers.insert(String::from("Host"), host.to_string());
     } else {
-        return Err(anyhow!("http_get: url has no host!"));
+        return Err(HttpError::NoHost);
+    }
+    for (name, value) in headers {
+        request_headers.insert((*name).to_owned(), (*value).to_owned());
     }
     for (name, value) in request_headers {
         writer.write_all(&format_bytes!(b"{}: {}", name.as_bytes(), value.as_bytes()))?;
@@ -76,22 +158,22 @@ pub fn http_get(url: &str, parameters: &[(&str, &[u8])]) -> Result<Response> {
     let mut status_code: Option<u32> = None;
     let mut response_length: Option<usize> = None;
 
-    let re_1_1: Regex = Regex::new(r"^HTTP/1.1 (\d{3})")?;
-    let re_1_0: Regex = Regex::new(r"^HTTP/1.0 (\d{3})")?;
+    let re_1_1: Regex = Regex::new(r"^HTTP/1.1 (\d{3})").expect("invalid status line regex");
+    let re_1_0: Regex = Regex::new(r"^HTTP/1.0 (\d{3})").expect("invalid status line regex");
     for line in reader.by_ref().lines() {
         let line = line?;
 
         // Look for line with status code (HTTP 1.1)
         if let Some(captures) = re_1_1.captures(&line) {
             if let Some(status) = captures.get(1) {
-                status_code = Some(status.as_str().parse()?);
+                status_code = Some(parse_status(status.as_str())?);
             }
         }
 
         // Look for line with status code (HTTP 1.0)
         if let Some(captures) = re_1_0.captures(&line) {
             if let Some(status) = captures.get(1) {
-                status_code = Some(status.as_str().parse()?);
+                status_code = Some(parse_status(status.as_str())?);
             }
         }
 
@@ -113,48 +195,113 @@ pub fn http_get(url: &str, parameters: &[(&str, &[u8])]) -> Result<Response> {
     }
 
     if let Some(len) = response_headers.get("Content-Length") {
-        response_length = Some(len.parse()?);
+        response_length = Some(
+            len.parse()
+                .map_err(|_| HttpError::MalformedResponse("invalid Content-Length"))?,
+        );
     }
 
     // Receive the rest of the response and return
     if let Some(status) = status_code {
-        if let Some(len) = response_length {
-            let mut buf = vec![0u8; len];
-
-            reader.read_exact(&mut buf)?;
-
-            Ok(Response {
-                status: status,
-                content: buf,
-                headers: response_headers,
-            })
+        let content = if method == "HEAD" {
+            Vec::new()
         } else {
-            let mut buf = Vec::new();
+            read_body(&mut reader, response_length, max_body)?
+        };
 
-            reader.read_to_end(&mut buf)?;
-
-            Ok(Response {
-                status,
-                content: buf,
-                headers: response_headers,
-            })
-        }
+        Ok(Response {
+            status,
+            content,
+            headers: response_headers,
+        })
     } else if !response_headers.contains_key("Content-Length") {
-        Err(anyhow!(
-            "http_get: Did not receive Content-Length in HTTP response!"
-        ))
+        Err(HttpError::MalformedResponse("no Content-Length"))
     } else if status_code.is_none() {
-        Err(anyhow!(
-            "http_get: Did not receive status code in HTTP response!"
-        ))
+        Err(HttpError::MalformedResponse("no status code"))
     } else {
-        Err(anyhow!("http_get: Unknown error"))
+        Err(HttpError::MalformedResponse("unknown error"))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::{http_get, http_request, HttpError};
+
+    // Serves one request with `head` followed by `body_len` bytes of body, written a bit at a
+    // time like a slow or hostile server would. Returns the URL to fetch.
+    fn stub_server(head: &'static str, body_len: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // closing with the request unread would reset the connection
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            for line in reader.by_ref().lines() {
+                if line.unwrap().is_empty() {
+                    break;
+                }
+            }
+
+            stream.write_all(head.as_bytes()).unwrap();
+            let chunk = [b'x'; 4096];
+            let mut sent = 0;
+            while sent < body_len {
+                let n = chunk.len().min(body_len - sent);
+                // the client hangs up once it has seen enough
+                if stream.write_all(&chunk[..n]).is_err() {
+                    return;
+                }
+                sent += n;
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused() {
+        const LIMIT: usize = 64 * 1024;
+
+        // no Content-Length, so the body runs until the server closes the connection
+        let url = stub_server("HTTP/1.1 200 OK\r\n\r\n", 16 * LIMIT);
+        let err = http_get(&url, &[], LIMIT).unwrap_err();
+        assert!(matches!(err, HttpError::BodyTooLarge(LIMIT)));
+
+        // a Content-Length over the limit is refused before reading any of it
+        let url = stub_server(
+            "HTTP/1.1 200 OK\r\nContent-Length: 1000000000000\r\n\r\n",
+            LIMIT,
+        );
+        let err = http_get(&url, &[], LIMIT).unwrap_err();
+        assert!(matches!(err, HttpError::BodyTooLarge(LIMIT)));
+
+        // right at the limit is fine either way
+        let url = stub_server("HTTP/1.1 200 OK\r\n\r\n", LIMIT);
+        assert_eq!(http_get(&url, &[], LIMIT).unwrap().content.len(), LIMIT);
+        let url = stub_server("HTTP/1.1 200 OK\r\nContent-Length: 65536\r\n\r\n", LIMIT);
+        assert_eq!(http_get(&url, &[], LIMIT).unwrap().content.len(), LIMIT);
+    }
+
+    #[test]
+    fn short_body_is_an_error() {
+        let url = stub_server("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n", 10);
+        let err = http_get(&url, &[], 1000).unwrap_err();
+        assert!(matches!(err, HttpError::Io(_)));
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let url = stub_server("HTTP/1.1 200 OK\r\nContent-Length: 100000\r\n\r\n", 0);
+        let response = http_request("HEAD", &url, &[], &[("Range", "bytes=0-0")], 0).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-length"), Some("100000"));
+        assert!(response.content.is_empty());
+    }
 
     #[test]
     fn http_get_1() {
@@ -163,6 +310,7 @@ mod tests {
         let resp = super::http_get(
             "http://128.8.126.63:21212/announce",
             &[("query1", "value1".as_bytes())],
+            super::DEFAULT_MAX_BODY,
         )
         .unwrap();
         println!("Response: {}", String::from_utf8(resp.content).unwrap());
@@ -0,0 +1,160 @@
+//! Recovering pieces from another copy of the data, for repairs and cross-seeding
+//!
+//! The copy may have a different name, be partly corrupt, or be the wrong length. Each of its
+//! pieces is fed to the download file as if a peer had sent it, so only those that match their
+//! hash are kept, and the next run downloads whatever is left.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::file::{self, DownloadFile};
+use crate::hash::Sha1PieceHasher;
+use crate::torrent::MetaInfo;
+
+/// Imports `from` into the download of the torrent at `torrent` in `output_dir`, and prints
+/// how it went. Torrents with pieces longer than `max_piece_length` are refused.
+pub fn run(torrent: &Path, from: &Path, output_dir: &Path, max_piece_length: usize) -> Result<()> {
+    let metainfo = MetaInfo::from_file(torrent)?;
+    metainfo.validate(max_piece_length)?;
+    let path = file::part_path(output_dir.join(metainfo.name().file_name));
+    let source_len = fs::metadata(from)?.len() as usize;
+    if source_len != metainfo.info.length {
+        warn!(
+            "{} is {} bytes, but the torrent is {}, so only the pieces they share are tried",
+            from.display(),
+            source_len,
+            metainfo.info.length
+        );
+    }
+
+    let mut file = DownloadFile::new_resume(
+        &path,
+        &metainfo.piece_hashes(),
+        metainfo.info.piece_length,
+        metainfo.info.length,
+        Box::new(Sha1PieceHasher::default()),
+    )?;
+    let imported = file.import_from(from)?;
+
+    println!("{}: {}", path.display(), imported);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use sha1::{Digest, Sha1};
+
+    use crate::file::{DownloadFile, Imported};
+    use crate::hash::Sha1PieceHasher;
+
+    const PIECE_LEN: usize = 32 * 1024;
+
+    // Random data of `len` bytes, with the hash of each of its pieces
+    fn data(len: usize) -> (Vec<u8>, Vec<[u8; 20]>) {
+        let mut data = vec![0; len];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        let hashes = data
+            .chunks(PIECE_LEN)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        (data, hashes)
+    }
+
+    #[test]
+    fn good_pieces_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let len = 6 * PIECE_LEN + 100;
+        let (data, hashes) = data(len);
+
+        // a copy with pieces 1 and 4 corrupted
+        let mut copy = data.clone();
+        copy[PIECE_LEN + 7] ^= 1;
+        copy[4 * PIECE_LEN] ^= 1;
+        fs::write(dir.path().join("copy"), &copy).unwrap();
+
+        // and a download that already has piece 2
+        let target = dir.path().join("target");
+        let mut existing = vec![0; 3 * PIECE_LEN];
+        existing[2 * PIECE_LEN..].copy_from_slice(&data[2 * PIECE_LEN..3 * PIECE_LEN]);
+        fs::write(&target, existing).unwrap();
+
+        let hasher = Box::new(Sha1PieceHasher::default());
+        let mut file = DownloadFile::new_resume(&target, &hashes, PIECE_LEN, len, hasher).unwrap();
+        let copy = dir.path().join("copy");
+        let imported = file.import_from(&copy).unwrap();
+        assert_eq!(
+            imported,
+            Imported {
+                imported: 4,
+                present: 1,
+                rejected: 2,
+                missing: 0,
+                bytes_copied: 3 * PIECE_LEN + 100,
+            }
+        );
+
+        // the corrupt pieces are left to be downloaded, and never reach the disk
+        let ones: Vec<usize> = file.bitvec().iter_ones().collect();
+        assert_eq!(ones, [0, 2, 3, 5, 6]);
+        let written = fs::read(&target).unwrap();
+        assert_eq!(written[..PIECE_LEN], data[..PIECE_LEN]);
+        assert_eq!(written[5 * PIECE_LEN..], data[5 * PIECE_LEN..]);
+        assert!(written[PIECE_LEN..2 * PIECE_LEN].iter().all(|&b| b == 0));
+
+        // doing it again finds nothing new
+        let imported = file.import_from(&copy).unwrap();
+        assert_eq!((imported.imported, imported.present), (0, 5));
+    }
+
+    #[test]
+    fn short_sources_give_up_the_pieces_they_cover() {
+        let dir = tempfile::tempdir().unwrap();
+        let len = 4 * PIECE_LEN;
+        let (data, hashes) = data(len);
+
+        // ends partway through piece 2
+        fs::write(dir.path().join("copy"), &data[..2 * PIECE_LEN + 10]).unwrap();
+
+        let target = dir.path().join("target");
+        let hasher = Box::new(Sha1PieceHasher::default());
+        let mut file = DownloadFile::new_resume(&target, &hashes, PIECE_LEN, len, hasher).unwrap();
+        let copy = dir.path().join("copy");
+        let imported = file.import_from(&copy).unwrap();
+        assert_eq!(
+            imported,
+            Imported {
+                imported: 2,
+                present: 0,
+                rejected: 0,
+                missing: 2,
+                bytes_copied: 2 * PIECE_LEN,
+            }
+        );
+        assert_eq!(file.left_verified(), 2 * PIECE_LEN);
+    }
+
+    #[test]
+    fn long_sources_are_cut_to_the_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let len = 3 * PIECE_LEN + 10;
+        let (data, hashes) = data(len);
+
+        let mut copy = data.clone();
+        copy.extend([0xff; 1000]);
+        fs::write(dir.path().join("copy"), &copy).unwrap();
+
+        let target = dir.path().join("target");
+        let hasher = Box::new(Sha1PieceHasher::default());
+        let mut file = DownloadFile::new_resume(&target, &hashes, PIECE_LEN, len, hasher).unwrap();
+        let imported = file.import_from(dir.path().join("copy")).unwrap();
+        assert_eq!((imported.imported, imported.bytes_copied), (4, len));
+        assert!(file.is_complete());
+        assert_eq!(fs::read(&target).unwrap(), data);
+    }
+}
@@ -0,0 +1,112 @@
+//! What the last session for a torrent left in the state dir
+//!
+//! Each session writes down the port it listens on and the peer id it announces with, and
+//! marks them stopped when it shuts down cleanly. The next session listens on the same port
+//! if it can, since trackers and peers keep hold of our old address for a while. If the last
+//! session died without telling trackers it was stopping, they still list it, so a Stopped
+//! announce is sent for it ahead of our own Started.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::resume;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastRun {
+    /// The port listened on, or 0 if none was
+    pub port: u16,
+    pub peer_id: [u8; 20],
+
+    /// Whether the session was still going, as far as anyone could tell. One that shut down
+    /// cleanly has set this to false.
+    pub running: bool,
+}
+
+/// Where the record of the last session for the torrent with `info_hash` goes in `state_dir`
+pub fn path(state_dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    resume::path(state_dir, info_hash).with_extension("run")
+}
+
+impl LastRun {
+    /// Reads the record at `path`, if there is a usable one
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        serde_json::from_slice(&bytes)
+            .inspect_err(|e| warn!("Ignoring {}, which is corrupt: {}", path.display(), e))
+            .ok()
+    }
+
+    /// Writes the record to `path`, by way of a temporary file so a crash part way through
+    /// doesn't leave half of one
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("run.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Records a session starting on `port` with `peer_id` at `path`, returning the last one if it
+/// never shut down cleanly
+pub fn start(path: &Path, port: u16, peer_id: [u8; 20]) -> Option<LastRun> {
+    let stale = LastRun::load(path).filter(|last| last.running);
+    let run = LastRun {
+        port,
+        peer_id,
+        running: true,
+    };
+    if let Err(e) = run.save(path) {
+        warn!("Failed to save {}: {}", path.display(), e);
+    }
+    stale
+}
+
+/// Marks the session recorded at `path` as having shut down cleanly
+pub fn stop(path: &Path) {
+    let Some(mut run) = LastRun::load(path) else {
+        return;
+    };
+    run.running = false;
+    if let Err(e) = run.save(path) {
+        warn!("Failed to save {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path, start, stop, LastRun};
+
+    #[test]
+    fn sessions_that_never_stopped_are_handed_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = path(dir.path(), &[0xab; 20]);
+        assert!(path.ends_with(format!("{}.run", "ab".repeat(20))));
+
+        // nothing to go on the first time
+        assert_eq!(start(&path, 6881, [1; 20]), None);
+
+        // the session that was killed is still running as far as its record says
+        let stale = start(&path, 6882, [2; 20]).unwrap();
+        assert_eq!(stale.port, 6881);
+        assert_eq!(stale.peer_id, [1; 20]);
+
+        // and one that shut down cleanly isn't
+        stop(&path);
+        assert_eq!(LastRun::load(&path).map(|run| run.running), Some(false));
+        assert_eq!(start(&path, 6883, [3; 20]), None);
+        assert_eq!(LastRun::load(&path).map(|run| run.port), Some(6883));
+
+        std::fs::write(&path, b"{\"port\": 6").unwrap();
+        assert_eq!(LastRun::load(&path), None);
+    }
+}
@@ -0,0 +1,54 @@
+//! How long peers take to deliver a block once we've asked for it
+
+use std::time::Duration;
+
+/// Requests at once for a peer whose blocks take longer to arrive than the request timeout.
+/// One is enough to show whether it has got any faster, without a whole pipeline timing out.
+pub const PROBE_PIPELINE_DEPTH: usize = 1;
+
+/// A moving average of the time between requesting a block and receiving it.
+/// Recent samples count for more, so a peer that speeds up is noticed after a block or two.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Latency {
+    average: Option<Duration>,
+}
+
+impl Latency {
+    pub fn observe(&mut self, sample: Duration) {
+        self.average = Some(match self.average {
+            None => sample,
+            Some(average) => (average * 3 + sample) / 4,
+        });
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        self.average
+    }
+
+    /// Whether blocks take `budget` or longer to arrive, on average
+    pub fn exceeds(&self, budget: Duration) -> bool {
+        self.average.is_some_and(|average| average >= budget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Latency;
+
+    #[test]
+    fn recent_samples_count_for_more() {
+        let budget = Duration::from_secs(12);
+        let mut latency = Latency::default();
+        assert!(!latency.exceeds(budget));
+
+        latency.observe(Duration::from_secs(12));
+        assert!(latency.exceeds(budget));
+
+        // one quick delivery is enough to bring it back under
+        latency.observe(Duration::from_secs(2));
+        assert_eq!(latency.average(), Some(Duration::from_millis(9500)));
+        assert!(!latency.exceeds(budget));
+    }
+}
@@ -0,0 +1,76 @@
+//! A moderately functional BitTorrent client written in Rust
+//!
+//! Besides the client itself, a few of its parts are meant to be reused by other tools, and
+//! follow semver:
+//!
+//! - [compact] encodes peer lists the way trackers, PEX and the DHT do
+//! - [events] reports what happens in a [session::Session] to whoever subscribes
+//! - [mod@file] verifies and assembles pieces on disk, with the hashes in [hash]
+//! - [peers::Message] encodes and decodes the peer wire protocol
+//! - [torrent] parses metainfo files
+//! - [tracker] announces to HTTP trackers
+//!
+//! Everything else public here exists to serve the binary, and may change at any time.
+
+mod address_watch;
+mod announce;
+mod availability;
+pub mod args;
+mod bencode;
+mod caps;
+pub mod capture;
+mod choke;
+pub mod compact;
+mod connections;
+pub mod control;
+mod cooldown;
+mod crash;
+mod dial_queue;
+mod disk_full;
+mod encoding;
+pub mod events;
+mod extension;
+pub mod file;
+pub mod hash;
+mod handlers;
+mod hangup;
+mod helpers;
+mod hooks;
+pub mod import;
+mod last_run;
+mod latency;
+mod http;
+mod log_limiter;
+mod mapped;
+mod misbehavior;
+mod peer_key;
+pub mod peers;
+mod piece_cache;
+mod piece_set;
+mod portcheck;
+mod positioned;
+mod probation;
+mod progress;
+mod rate;
+pub mod reader;
+mod reconnect;
+pub mod resume;
+mod rng;
+pub mod scheduler;
+pub mod selftest;
+pub mod session;
+pub mod shutdown;
+mod stall;
+mod stats;
+mod strategy;
+mod stream;
+#[cfg(test)]
+mod test_utils;
+mod threads;
+mod timer;
+pub mod torrent;
+pub mod tracker;
+pub mod tracker_sim;
+mod transport;
+mod units;
+mod webseed;
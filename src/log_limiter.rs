@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How many warnings of each kind a peer gets logged before we start suppressing them
+const LOGGED_PER_KIND: u32 = 5;
+
+/// How often to summarize the warnings we suppressed
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Kinds of peer misbehaviour we warn about
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerWarning {
+    InvalidHave,
+    InvalidBitfield,
+    UnrequestedPiece,
+    BadPiece,
+    ChokedRequest,
+    BadRequest,
+    OversizedRequest,
+    MalformedMessage,
+    MessageFlood,
+    HashFailed,
+}
+
+impl fmt::Display for PeerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PeerWarning::InvalidHave => "invalid-Have",
+            PeerWarning::InvalidBitfield => "invalid-Bitfield",
+            PeerWarning::UnrequestedPiece => "unrequested-Piece",
+            PeerWarning::BadPiece => "bad-Piece",
+            PeerWarning::ChokedRequest => "choked-Request",
+            PeerWarning::BadRequest => "bad-Request",
+            PeerWarning::OversizedRequest => "oversized-Request",
+            PeerWarning::MalformedMessage => "malformed-message",
+            PeerWarning::MessageFlood => "message-flood",
+            PeerWarning::HashFailed => "hash-failure",
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    // every occurrence, logged or not
+    total: u32,
+
+    // occurrences not logged since the last summary
+    suppressed: u32,
+}
+
+/// Keeps a broken peer from flooding the log with the same warning.
+///
+/// The first few warnings of each kind from a peer are logged as usual. After that they are
+/// only counted, and [LogLimiter::summarize] reports the count once every [SUMMARY_INTERVAL].
+#[derive(Debug)]
+pub struct LogLimiter {
+    counters: HashMap<(SocketAddr, PeerWarning), Counter>,
+    last_summary: Instant,
+}
+
+impl LogLimiter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            counters: HashMap::new(),
+            last_summary: now,
+        }
+    }
+
+    /// Records a warning. Returns whether the caller should go ahead and log it.
+    pub fn allow(&mut self, addr: SocketAddr, kind: PeerWarning) -> bool {
+        let counter = self.counters.entry((addr, kind)).or_default();
+        counter.total += 1;
+
+        if counter.total > LOGGED_PER_KIND {
+            counter.suppressed += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Number of warnings of this kind recorded for a peer, logged or not
+    #[cfg(test)]
+    pub fn count(&self, addr: SocketAddr, kind: PeerWarning) -> u32 {
+        self.counters.get(&(addr, kind)).map_or(0, |c| c.total)
+    }
+
+    /// Once every [SUMMARY_INTERVAL], returns one line for each kind of warning that was
+    /// suppressed since the last summary.
+    /// Peers that have gone quiet are forgotten, so their warnings are logged again if they
+    /// start back up.
+    pub fn summarize(&mut self, now: Instant) -> Vec<String> {
+        if now.saturating_duration_since(self.last_summary) < SUMMARY_INTERVAL {
+            return Vec::new();
+        }
+        self.last_summary = now;
+
+        let mut lines = Vec::new();
+        self.counters.retain(|&(addr, kind), counter| {
+            if counter.suppressed == 0 {
+                return false;
+            }
+
+            lines.push(format!(
+                "suppressed {} further {} warnings from {}",
+                counter.suppressed, kind, addr
+            ));
+            counter.suppressed = 0;
+            true
+        });
+
+        lines
+    }
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    use super::{LogLimiter, PeerWarning, LOGGED_PER_KIND, SUMMARY_INTERVAL};
+
+    #[test]
+    fn suppresses_after_first_few() {
+        let mut limiter = LogLimiter::new(Instant::now());
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let other: SocketAddr = "5.6.7.8:6881".parse().unwrap();
+
+        let logged = (0..100)
+            .filter(|_| limiter.allow(addr, PeerWarning::InvalidHave))
+            .count();
+        assert_eq!(logged as u32, LOGGED_PER_KIND);
+        assert_eq!(limiter.count(addr, PeerWarning::InvalidHave), 100);
+
+        // other kinds and other peers have their own allowance
+        assert!(limiter.allow(addr, PeerWarning::UnrequestedPiece));
+        assert!(limiter.allow(other, PeerWarning::InvalidHave));
+    }
+
+    #[test]
+    fn summary_once_per_interval() {
+        let start = Instant::now();
+        let mut limiter = LogLimiter::new(start);
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+
+        for _ in 0..LOGGED_PER_KIND + 412 {
+            limiter.allow(addr, PeerWarning::InvalidHave);
+        }
+
+        // too early
+        assert!(limiter.summarize(start + SUMMARY_INTERVAL / 2).is_empty());
+
+        assert_eq!(
+            limiter.summarize(start + SUMMARY_INTERVAL),
+            vec!["suppressed 412 further invalid-Have warnings from 1.2.3.4:6881"]
+        );
+
+        // nothing new to report, and the quiet peer is forgotten
+        assert!(limiter.summarize(start + SUMMARY_INTERVAL * 2).is_empty());
+        assert!(limiter.allow(addr, PeerWarning::InvalidHave));
+    }
+}
@@ -0,0 +1,150 @@
+//! A file mapped into memory read-write, for --mmap
+//!
+//! Blocks are copied straight into the map and pieces hashed where they lie, without a system
+//! call for every read and write. The kernel writes the pages back in its own time, unless
+//! told to with [Mapped::flush].
+
+use std::fs::File;
+use std::io;
+use std::ops::{Deref, DerefMut, Range};
+use std::os::fd::AsRawFd;
+use std::ptr::NonNull;
+use std::slice;
+
+/// Longest file that may be mapped. A 32-bit address space has nowhere near enough room for
+/// the files people download, so anything past 1GiB is refused there rather than failing
+/// somewhere inside mmap.
+pub const MAX_MAP_LEN: usize = if usize::BITS < 64 {
+    1 << 30
+} else {
+    isize::MAX as usize
+};
+
+/// The first `len` bytes of a file, mapped shared so that writes reach the file
+#[derive(Debug)]
+pub struct Mapped {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Safety: the map is plain memory owned by this struct, only written through &mut self
+unsafe impl Send for Mapped {}
+unsafe impl Sync for Mapped {}
+
+impl Mapped {
+    /// Maps the first `len` bytes of `file`, which must be open for reading and writing, and
+    /// at least that long. Anything that shrinks the file while it is mapped gets the process
+    /// killed with SIGBUS the next time the lost part is touched.
+    pub fn new(file: &File, len: usize) -> io::Result<Self> {
+        if len == 0 || len > MAX_MAP_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't map {} bytes", len),
+            ));
+        }
+
+        // Safety: the kernel picks the address, and the descriptor only needs to be open for
+        // the call itself
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).expect("mmap returned null"),
+            len,
+        })
+    }
+
+    /// Writes the pages holding `range` back to the file, returning once they are written
+    pub fn flush_range(&self, range: Range<usize>) -> io::Result<()> {
+        assert!(
+            range.end <= self.len,
+            "{:?} is past the end of the map",
+            range
+        );
+
+        // msync wants an address on a page boundary
+        let page = page_size();
+        let start = range.start - range.start % page;
+        let len = range.end - start;
+
+        // Safety: the range lies within the map
+        let ret = unsafe { libc::msync(self.ptr.as_ptr().add(start).cast(), len, libc::MS_SYNC) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Writes every page back to the file, returning once they are written
+    pub fn flush(&self) -> io::Result<()> {
+        self.flush_range(0..self.len)
+    }
+}
+
+fn page_size() -> usize {
+    // Safety: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size).unwrap_or(4096)
+}
+
+impl Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: the map is `len` bytes long and lives as long as self
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for Mapped {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safety: as above, and &mut self makes this the only reference into the map
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        // Safety: nothing can borrow from the map past this point
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::Mapped;
+
+    #[test]
+    fn writes_reach_the_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(10_000).unwrap();
+
+        let mut mapped = Mapped::new(&file, 10_000).unwrap();
+        mapped[5000..5004].copy_from_slice(b"data");
+        mapped.flush_range(5000..5004).unwrap();
+        mapped.flush().unwrap();
+        drop(mapped);
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(&contents[5000..5004], b"data");
+        assert!(contents[..5000].iter().all(|&b| b == 0));
+
+        assert!(Mapped::new(&file, 0).is_err());
+    }
+}
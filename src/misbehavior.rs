@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::cooldown::Cooldowns;
+use crate::log_limiter::PeerWarning;
+use crate::peer_key::PeerKey;
+use crate::rate::RateWindow;
+use crate::session::PeerInfo;
+
+/// Score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How long a peer stays banned for protocol violations.
+/// Kept short, since buggy clients end up here as well as abusive ones.
+pub const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// How many pieces that failed their hash check a peer may send part of before it is banned.
+/// Unlike the score, these never decay: a peer keeps sending bad data, however slowly.
+pub const HASH_FAILURE_STRIKES: u32 = 3;
+
+/// Points taken off every peer's score per second, so occasional glitches don't add up
+const DECAY_PER_SEC: u32 = 1;
+
+/// A peer that sends more than this many times the cap on a kind of message in a second is
+/// disconnected, rather than only having the excess ignored
+pub const FLOOD_DISCONNECT_FACTOR: usize = 4;
+
+/// How much a violation counts towards [BAN_THRESHOLD].
+/// Some warnings can be our own fault, or a race with the peer, so they don't count at all.
+pub fn weight(kind: PeerWarning) -> u32 {
+    match kind {
+        PeerWarning::InvalidHave => 10,
+        PeerWarning::InvalidBitfield => 50,
+        PeerWarning::UnrequestedPiece => 5,
+        PeerWarning::OversizedRequest => 25,
+        PeerWarning::MalformedMessage => 20,
+
+        // counted once for each second the peer spends over a cap
+        PeerWarning::MessageFlood => 20,
+
+        // counted against every peer that sent a block of the piece, since any of them may
+        // have been the one with bad data
+        PeerWarning::HashFailed => 20,
+
+        // a Choke can cross the peer's Request on the wire
+        PeerWarning::ChokedRequest => 0,
+
+        // a recheck can take back pieces we told the peer we have
+        PeerWarning::BadRequest => 0,
+
+        // we asked for this block, so it isn't the peer's fault it doesn't fit
+        PeerWarning::BadPiece => 0,
+    }
+}
+
+/// Peers we refuse to talk to for a while
+#[derive(Debug)]
+pub struct Bans {
+    hosts: Cooldowns<IpAddr>,
+
+    // when scores were last decayed
+    last_decay: Instant,
+}
+
+impl Bans {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            hosts: Cooldowns::default(),
+            last_decay: now,
+        }
+    }
+
+    /// Bans every port on the peer's host, since reconnecting from a new port is trivial
+    pub fn ban(&mut self, key: &PeerKey, now: Instant) {
+        self.hosts.start(key.ip(), now, BAN_DURATION);
+    }
+
+    pub fn is_banned(&self, key: &PeerKey, now: Instant) -> bool {
+        self.hosts.is_active(&key.ip(), now)
+    }
+
+    /// Lowers every peer's score for the time that has passed since the last call,
+    /// and forgets bans that have run out
+    pub fn decay<'a>(&mut self, peers: impl IntoIterator<Item = &'a mut PeerInfo>, now: Instant) {
+        let secs = now.saturating_duration_since(self.last_decay).as_secs();
+        if secs == 0 {
+            return;
+        }
+        self.last_decay += Duration::from_secs(secs);
+
+        let points = DECAY_PER_SEC.saturating_mul(secs.try_into().unwrap_or(u32::MAX));
+        for peer_info in peers {
+            peer_info.misbehavior = peer_info.misbehavior.saturating_sub(points);
+        }
+
+        self.hosts.expire(now);
+    }
+}
+
+impl Default for Bans {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+/// What to do about a message, given how many of its kind the peer sent in the last second
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flood {
+    Within,
+
+    /// Over the cap, so the message is ignored
+    Over,
+
+    /// Far enough over the cap that the peer is disconnected
+    Disconnect,
+}
+
+impl Flood {
+    /// Judges `count` messages of a kind in a second, against a cap of `cap` a second
+    pub fn of(count: usize, cap: usize) -> Self {
+        if count <= cap {
+            Flood::Within
+        } else if count <= cap.saturating_mul(FLOOD_DISCONNECT_FACTOR) {
+            Flood::Over
+        } else {
+            Flood::Disconnect
+        }
+    }
+}
+
+/// How many messages of each kind a peer has sent us lately
+#[derive(Clone, Debug, Default)]
+pub struct MessageRates {
+    windows: HashMap<&'static str, RateWindow>,
+}
+
+impl MessageRates {
+    /// Counts a message of type `kind` arriving at `now`. Returns how many of that type have
+    /// arrived in the current second, this one included.
+    pub fn record(&mut self, kind: &'static str, now: Instant) -> usize {
+        let window = self
+            .windows
+            .entry(kind)
+            .or_insert_with(|| RateWindow::new(now));
+        window.advance(now);
+        window.record(1);
+        window.current()
+    }
+
+    /// Messages of type `kind` a second, averaged over the last few seconds
+    pub fn rate(&self, kind: &str) -> f64 {
+        self.windows.get(kind).map_or(0.0, RateWindow::rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Bans, Flood, MessageRates, BAN_DURATION};
+    use crate::peer_key::PeerKey;
+    use crate::test_utils::peer_info;
+
+    #[test]
+    fn ban_covers_every_port_and_expires() {
+        let now = Instant::now();
+        let mut bans = Bans::new(now);
+        let key = PeerKey::dialed("1.2.3.4:6881".parse().unwrap());
+
+        // including the port the host connects to us from
+        bans.ban(&key, now);
+        let inbound = PeerKey::inbound("1.2.3.4:51413".parse().unwrap(), None);
+        assert!(bans.is_banned(&inbound, now));
+        assert!(!bans.is_banned(&PeerKey::dialed("1.2.3.5:6881".parse().unwrap()), now));
+        assert!(!bans.is_banned(&key, now + BAN_DURATION));
+
+        // and the other way around
+        bans.ban(&inbound, now);
+        assert!(bans.is_banned(&key, now));
+    }
+
+    #[test]
+    fn scores_decay_with_time() {
+        let now = Instant::now();
+        let mut bans = Bans::new(now);
+        let (mut peer, _rx) = peer_info(1);
+        peer.misbehavior = 50;
+
+        // less than a second doesn't count yet
+        bans.decay([&mut peer], now + Duration::from_millis(500));
+        assert_eq!(peer.misbehavior, 50);
+
+        bans.decay([&mut peer], now + Duration::from_secs(20));
+        assert_eq!(peer.misbehavior, 30);
+
+        bans.decay([&mut peer], now + Duration::from_secs(1000));
+        assert_eq!(peer.misbehavior, 0);
+    }
+
+    #[test]
+    fn floods_are_judged_per_kind_and_second() {
+        let now = Instant::now();
+        let mut rates = MessageRates::default();
+
+        for count in 1..=10 {
+            assert_eq!(rates.record("Have", now), count);
+        }
+        assert_eq!(rates.record("Request", now), 1);
+        assert_eq!(Flood::of(10, 10), Flood::Within);
+        assert_eq!(Flood::of(11, 10), Flood::Over);
+        assert_eq!(Flood::of(40, 10), Flood::Over);
+        assert_eq!(Flood::of(41, 10), Flood::Disconnect);
+
+        // the count starts over every second, and the average catches up
+        assert_eq!(rates.record("Have", now + Duration::from_secs(1)), 1);
+        assert_eq!(rates.rate("Have"), 10.0 / 9.0);
+        assert_eq!(rates.rate("Piece"), 0.0);
+    }
+}
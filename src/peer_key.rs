@@ -0,0 +1,82 @@
+//! Telling connections to the same peer apart
+//!
+//! A peer we dial is known by the address it listens on, which is what trackers hand out. A peer
+//! that dials us shows up from whatever port its side picked, which says nothing about where it
+//! listens, so until it tells us its port in its extension handshake all we know is its host.
+//! Anything that outlives a connection, like reconnect backoff and bans, goes by [PeerKey] so
+//! both views of one peer end up in the same place.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Who a peer is, as far as we can tell
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerKey {
+    /// Where the peer listens for connections
+    Endpoint(SocketAddr),
+
+    /// The host of a peer that connected to us, when we don't know where it listens
+    Host(IpAddr),
+}
+
+impl PeerKey {
+    /// A peer we dialed at `addr`, or could, such as one a tracker gave us
+    pub fn dialed(addr: SocketAddr) -> Self {
+        PeerKey::Endpoint(addr)
+    }
+
+    /// A peer that connected to us from `remote`, which listens on `listen_port` if it said.
+    /// Local peers are told apart by the port they came from, so several clients on one
+    /// machine can be tested against each other.
+    pub fn inbound(remote: SocketAddr, listen_port: Option<u16>) -> Self {
+        match listen_port {
+            Some(port) => PeerKey::Endpoint(SocketAddr::new(remote.ip(), port)),
+            None if remote.ip().is_loopback() => PeerKey::Endpoint(remote),
+            None => PeerKey::Host(remote.ip()),
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            PeerKey::Endpoint(addr) => addr.ip(),
+            PeerKey::Host(ip) => *ip,
+        }
+    }
+
+    /// Whether the two could be the same peer. A host could be any peer on it.
+    pub fn same_peer(&self, other: &PeerKey) -> bool {
+        match (self, other) {
+            (PeerKey::Endpoint(a), PeerKey::Endpoint(b)) => a == b,
+            _ => self.ip() == other.ip(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::PeerKey;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn inbound_peers_are_their_host_until_they_say_where_they_listen() {
+        let advertised = PeerKey::dialed(addr("10.0.0.1:6881"));
+        let ephemeral = PeerKey::inbound(addr("10.0.0.1:51234"), None);
+        assert_eq!(ephemeral, PeerKey::Host("10.0.0.1".parse().unwrap()));
+        assert!(ephemeral.same_peer(&advertised));
+        assert!(advertised.same_peer(&ephemeral));
+        assert!(!ephemeral.same_peer(&PeerKey::dialed(addr("10.0.0.2:6881"))));
+
+        let told = PeerKey::inbound(addr("10.0.0.1:51234"), Some(6881));
+        assert_eq!(told, advertised);
+        assert!(!told.same_peer(&PeerKey::dialed(addr("10.0.0.1:6882"))));
+
+        // local clients keep to their own ports
+        let local = PeerKey::inbound(addr("127.0.0.1:51234"), None);
+        assert!(!local.same_peer(&PeerKey::dialed(addr("127.0.0.1:6881"))));
+        assert_eq!(local.ip(), addr("127.0.0.1:1").ip());
+    }
+}
@@ -1,20 +1,89 @@
-use anyhow::{anyhow, Result};
-use crossbeam::channel::{self, Select, Sender};
-use log::{error, warn};
+//! The peer wire protocol, and the threads that speak it to each connected peer.
+//!
+//! Only [Message] and its encoding are public. The threads are an implementation detail of
+//! the client.
+
+use crossbeam::channel::{self, Receiver, Select, Sender};
+use log::{debug, warn};
 use std::{
-    io::{self, BufReader, BufWriter, Read, Write},
+    io::{self, BufReader, Read, Write},
     net::{SocketAddr, TcpStream},
+    ops::AddAssign,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use thiserror::Error;
 
-use crate::args::{METAINFO, PEER_ID};
-use crate::threads::Response;
+use crate::capture::{Capture, Direction};
+use crate::connections::HandshakeSlot;
+use crate::hangup::{Hangup, Side};
+use crate::shutdown;
+use crate::threads::{Response, PEER_SEND_TIMEOUT};
+use crate::transport::Transport;
 
 const PROTO_IDENTIFIER: &str = "BitTorrent protocol";
 
 const TCP_READ_TIMEOUT: Duration = Duration::from_secs(5);
 
+// how long a single write may block before the peer counts as congested
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// how often to try a congested peer again, if nothing new is sent to it in the meantime
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long a congested peer may go without taking anything we write before we give up on it
+const WRITE_STALL_LIMIT: Duration = Duration::from_secs(60);
+
+// how long a peer gets to send the whole handshake, however slowly it trickles in
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// most buffers of written blocks kept for each peer to read the next ones into
+const SPARE_BLOCK_BUFFERS: usize = 16;
+
+// reserved handshake bytes advertising the extension protocol (BEP 10)
+const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+
+// how many Piece messages can pile up before they go out, in as few writes as possible
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+// length of the handshake each side sends, all of it protocol overhead
+const HANDSHAKE_LEN: usize = 49 + PROTO_IDENTIFIER.len();
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PeerError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("received invalid {0} message")]
+    InvalidMessage(&'static str),
+
+    #[error("received unsupported message type {0}")]
+    UnsupportedMessage(u8),
+
+    #[error("main thread channel is full or gone")]
+    MainChannel,
+
+    #[error("handshake for info_hash {} that we aren't serving", hex(.0))]
+    UnknownInfoHash([u8; 20]),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl PeerError {
+    /// Whether this is just a read timing out, which happens whenever the peer is quiet
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, PeerError::Io(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+    }
+}
+
+type Result<T> = std::result::Result<T, PeerError>;
+
 #[derive(Copy, Clone)]
 enum MessageType {
     Choke = 0,
@@ -26,9 +95,29 @@ enum MessageType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
 }
 
-#[derive(Debug, PartialEq)]
+/// A message of the peer wire protocol, as described in BEP 3.
+///
+/// Messages are written with [Message::write_to] and read back with [Message::recv]:
+///
+/// ```
+/// use std::io::BufReader;
+/// use rittorrent::peers::Message;
+///
+/// let mut wire = Vec::new();
+/// Message::Request(3, 16384, 16384).write_to(&mut wire).unwrap();
+/// Message::Keepalive.write_to(&mut wire).unwrap();
+///
+/// let mut reader = BufReader::new(&wire[..]);
+/// assert_eq!(Message::recv(&mut reader).unwrap(), Message::Request(3, 16384, 16384));
+/// assert_eq!(Message::recv(&mut reader).unwrap(), Message::Keepalive);
+/// ```
+///
+/// Extension messages may be added, so matches on this need a wildcard arm.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum Message {
     Keepalive,
     Choke,
@@ -37,24 +126,377 @@ pub enum Message {
     NotInterested,
     Have(u32),
     Bitfield(Vec<u8>),
+
+    /// Piece index, offset within the piece, and length
     Request(u32, u32, u32),
+
+    /// Piece index, offset within the piece, and the block itself
     Piece(u32, u32, Vec<u8>),
+
+    /// Piece index, offset within the piece, and length of a request being withdrawn
     Cancel(u32, u32, u32),
+
+    /// Extension protocol message (BEP 10): the extended message id, and its payload
+    Extended(u8, Vec<u8>),
+}
+
+/// Bytes a message takes up on the wire, split into piece data and everything else
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// Blocks of the torrent, carried in Piece messages
+    pub payload: usize,
+
+    /// Length prefixes, message ids, and every other message in full
+    pub protocol: usize,
+}
+
+impl Traffic {
+    fn protocol(bytes: usize) -> Self {
+        Self {
+            payload: 0,
+            protocol: bytes,
+        }
+    }
+}
+
+impl AddAssign for Traffic {
+    fn add_assign(&mut self, other: Self) {
+        self.payload += other.payload;
+        self.protocol += other.protocol;
+    }
+}
+
+/// Running totals of the [Traffic] sent and received over some connections.
+/// Peer threads count into it as they go, and main reads it.
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounter {
+    sent_payload: AtomicUsize,
+    sent_protocol: AtomicUsize,
+    received_payload: AtomicUsize,
+    received_protocol: AtomicUsize,
+}
+
+impl TrafficCounter {
+    pub fn add_sent(&self, traffic: Traffic) {
+        self.sent_payload
+            .fetch_add(traffic.payload, Ordering::Relaxed);
+        self.sent_protocol
+            .fetch_add(traffic.protocol, Ordering::Relaxed);
+    }
+
+    fn add_received(&self, traffic: Traffic) {
+        self.received_payload
+            .fetch_add(traffic.payload, Ordering::Relaxed);
+        self.received_protocol
+            .fetch_add(traffic.protocol, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> Traffic {
+        Traffic {
+            payload: self.sent_payload.load(Ordering::Relaxed),
+            protocol: self.sent_protocol.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn received(&self) -> Traffic {
+        Traffic {
+            payload: self.received_payload.load(Ordering::Relaxed),
+            protocol: self.received_protocol.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Piece data handed to peer threads that hasn't been written to their sockets yet, across
+/// every peer. Each [UploadTicket] counts towards it until it is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct QueuedUploads {
+    bytes: AtomicUsize,
+}
+
+impl QueuedUploads {
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Counts `len` bytes as queued until the ticket is dropped
+    pub fn ticket(self: &Arc<Self>, len: usize) -> UploadTicket {
+        self.bytes.fetch_add(len, Ordering::Relaxed);
+        UploadTicket {
+            queue: Arc::clone(self),
+            len,
+            buffers: None,
+        }
+    }
 }
 
+/// Buffers one peer's blocks are read into. The peer thread hands back the data of each Piece
+/// message once it has been copied out to be written, and the next block read for the peer
+/// goes into it, so a peer that is downloading steadily from us costs no allocations per block.
+#[derive(Clone, Debug)]
+pub(crate) struct BlockBuffers {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl Default for BlockBuffers {
+    fn default() -> Self {
+        let (tx, rx) = channel::bounded(SPARE_BLOCK_BUFFERS);
+        Self { tx, rx }
+    }
+}
+
+impl BlockBuffers {
+    /// A buffer to read a block into, which is a new one if none are spare
+    pub fn take(&self) -> Vec<u8> {
+        self.rx.try_recv().unwrap_or_default()
+    }
+
+    /// Keeps `buf` for the next block, unless there are plenty spare already
+    pub fn give_back(&self, buf: Vec<u8>) {
+        let _ = self.tx.try_send(buf);
+    }
+}
+
+/// Travels with a queued Piece message, and is dropped by the peer thread once the message has
+/// been written. A message that is thrown away unsent takes its ticket with it.
 #[derive(Debug)]
-pub enum PeerRequest {
+pub(crate) struct UploadTicket {
+    queue: Arc<QueuedUploads>,
+    len: usize,
+
+    // where the message's data goes once it has been copied out
+    buffers: Option<BlockBuffers>,
+}
+
+impl UploadTicket {
+    /// Hands the message's data back to `buffers` once it has been copied out
+    pub fn returning_to(mut self, buffers: &BlockBuffers) -> Self {
+        self.buffers = Some(buffers.clone());
+        self
+    }
+}
+
+impl Drop for UploadTicket {
+    fn drop(&mut self) {
+        self.queue.bytes.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PeerRequest {
     SendMessage(Message),
+
+    // several messages that go out together, with a single flush
+    SendBatch(Vec<Message>),
+
+    // a Piece message, counted towards the queued uploads until it is written
+    Upload(Message, UploadTicket),
+}
+
+impl PeerRequest {
+    fn into_messages(self) -> (Vec<Message>, Option<UploadTicket>) {
+        match self {
+            PeerRequest::SendMessage(msg) => (vec![msg], None),
+            PeerRequest::SendBatch(msgs) => (msgs, None),
+            PeerRequest::Upload(msg, ticket) => (vec![msg], Some(ticket)),
+        }
+    }
 }
 
 #[derive(Debug)]
-pub enum PeerResponse {
+pub(crate) enum PeerResponse {
     MessageReceived(SocketAddr, Message),
     Heartbeat,
+
+    // the peer sent a message we could not parse, naming its type
+    InvalidMessage(SocketAddr, &'static str),
+
+    // the peer's handshake says it speaks the extension protocol
+    SupportsExtensions(SocketAddr),
+
+    // the peer has stopped taking what we write, or has taken all of it again
+    Congested(SocketAddr),
+    Drained(SocketAddr),
+
+    // the peer thread is giving up on this peer, and how the connection ended
+    Death(SocketAddr, Hangup),
+}
+
+/// Messages encoded for the peer that haven't all been written yet. Writing into it never
+/// fails; only [Outbox::drain] and flushing touch the socket, and a write that times out
+/// partway leaves the rest here, partial frame and all, to pick up where it left off.
+struct Outbox<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+
+    // how much of `buf` has been written
+    written: usize,
+
+    // Piece messages in `buf`, which count as queued until all of it has been written
+    tickets: Vec<UploadTicket>,
+
+    // piece data in `buf`, which counts as sent once all of it has been written
+    payload: usize,
+
+    // where the traffic we write is counted
+    counters: Vec<Arc<TrafficCounter>>,
+}
+
+impl<W: Write> Outbox<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            written: 0,
+            tickets: Vec::new(),
+            payload: 0,
+            counters: Vec::new(),
+        }
+    }
+
+    // Counts what is written into `counters`
+    fn counting_into(mut self, counters: Vec<Arc<TrafficCounter>>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    // Counts a message just put in `buf`. Its overhead counts straight away, but piece data
+    // only once it has gone out, so that blocks lost with the connection aren't counted.
+    fn count(&mut self, traffic: Traffic) {
+        let overhead = Traffic::protocol(traffic.protocol);
+        self.counters.iter().for_each(|c| c.add_sent(overhead));
+        self.payload += traffic.payload;
+    }
+
+    // Bytes not yet written
+    fn pending(&self) -> usize {
+        self.buf.len() - self.written
+    }
+
+    // Writes out everything pending, without flushing `inner`
+    fn drain(&mut self) -> io::Result<()> {
+        while self.written < self.buf.len() {
+            match self.inner.write(&self.buf[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let payload = Traffic {
+            payload: self.payload,
+            protocol: 0,
+        };
+        self.counters.iter().for_each(|c| c.add_sent(payload));
+        self.buf.clear();
+        self.written = 0;
+        self.payload = 0;
+        self.tickets.clear();
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Outbox<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+// What changed about a peer's congestion
+#[derive(Debug, PartialEq, Eq)]
+enum Congestion {
+    Started,
+    Cleared,
+    GaveUp,
+}
+
+// When the peer stopped taking what we write, while it hasn't taken it since
+#[derive(Debug, Default)]
+struct Stall {
+    since: Option<Instant>,
+}
+
+impl Stall {
+    fn is_stalled(&self) -> bool {
+        self.since.is_some()
+    }
+
+    // A write timed out at `now`. After `limit` of nothing but timeouts, we give up.
+    fn timed_out(&mut self, now: Instant, limit: Duration) -> Option<Congestion> {
+        match self.since {
+            None => {
+                self.since = Some(now);
+                Some(Congestion::Started)
+            }
+            Some(since) if now.saturating_duration_since(since) >= limit => {
+                Some(Congestion::GaveUp)
+            }
+            Some(_) => None,
+        }
+    }
+
+    // Everything we had for the peer has been written
+    fn drained(&mut self) -> Option<Congestion> {
+        self.since.take().map(|_| Congestion::Cleared)
+    }
 }
 
 impl Message {
-    fn send(&self, writer: &mut BufWriter<impl Write>) -> Result<()> {
+    /// The message's type, for logs
+    pub fn kind(&self) -> &'static str {
+        use Message::*;
+        match self {
+            Keepalive => "Keepalive",
+            Choke => "Choke",
+            Unchoke => "Unchoke",
+            Interested => "Interested",
+            NotInterested => "NotInterested",
+            Have(_) => "Have",
+            Bitfield(_) => "Bitfield",
+            Request(..) => "Request",
+            Piece(..) => "Piece",
+            Cancel(..) => "Cancel",
+            Extended(..) => "Extended",
+        }
+    }
+
+    /// How many bytes the message takes up on the wire, and how many of those are payload
+    pub fn traffic(&self) -> Traffic {
+        use Message::*;
+        let body = match self {
+            Keepalive => 0,
+            Choke | Unchoke | Interested | NotInterested => 1,
+            Have(_) => 5,
+            Bitfield(bytes) => 1 + bytes.len(),
+            Request(..) | Cancel(..) => 13,
+            Piece(_, _, data) => 9 + data.len(),
+            Extended(_, payload) => 2 + payload.len(),
+        };
+        let payload = match self {
+            Piece(_, _, data) => data.len(),
+            _ => 0,
+        };
+
+        Traffic {
+            payload,
+            protocol: 4 + body - payload,
+        }
+    }
+
+    /// Serializes the message into `writer` without flushing it.
+    /// Returns what was written, as counted by [Message::traffic].
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<Traffic> {
         let mut buf: Vec<u8> = Vec::new();
 
         use Message::*;
@@ -98,17 +540,27 @@ impl Message {
                 buf.extend(&(*begin as u32).to_be_bytes());
                 buf.extend(&(*len as u32).to_be_bytes());
             }
+            Extended(id, payload) => {
+                buf.extend(&[MessageType::Extended as u8, *id]);
+                buf.extend(payload);
+            }
         }
 
         // actually send the message
         writer.write_all(&(buf.len() as u32).to_be_bytes())?;
         writer.write_all(&buf)?;
-        writer.flush()?;
 
-        Ok(())
+        Ok(self.traffic())
+    }
+
+    // Bulk data can sit in the write buffer until the outgoing queue is drained;
+    // everything else should reach the peer right away
+    fn is_bulk(&self) -> bool {
+        matches!(self, Message::Piece(..))
     }
 
-    fn recv(reader: &mut BufReader<impl Read>) -> Result<Self> {
+    /// Reads one length-prefixed message from `reader`
+    pub fn recv(reader: &mut impl Read) -> Result<Self> {
         // Receive length first
         let mut length_buf = [0u8; 4];
         reader.read_exact(&mut length_buf)?;
@@ -144,7 +596,7 @@ impl Message {
 
                 Ok(Self::Have(idx))
             } else {
-                Err(anyhow!("Received invalid Have message"))
+                Err(PeerError::InvalidMessage("Have"))
             }
         } else if message_type == MessageType::Bitfield as u8 {
             Ok(Self::Bitfield(buf))
@@ -156,7 +608,7 @@ impl Message {
 
                 Ok(Self::Request(idx, begin, len))
             } else {
-                Err(anyhow!("Received invalid Request message"))
+                Err(PeerError::InvalidMessage("Request"))
             }
         } else if message_type == MessageType::Piece as u8 {
             if buf.len() >= 8 {
@@ -166,7 +618,7 @@ impl Message {
 
                 Ok(Self::Piece(idx, begin, piece))
             } else {
-                Err(anyhow!("Received invalid Piece message"))
+                Err(PeerError::InvalidMessage("Piece"))
             }
         } else if message_type == MessageType::Cancel as u8 {
             if buf.len() == 12 {
@@ -176,150 +628,566 @@ impl Message {
 
                 Ok(Self::Cancel(idx, begin, len))
             } else {
-                Err(anyhow!("Received invalid Cancel message"))
+                Err(PeerError::InvalidMessage("Cancel"))
+            }
+        } else if message_type == MessageType::Extended as u8 {
+            match buf.split_first() {
+                Some((&id, payload)) => Ok(Self::Extended(id, payload.to_vec())),
+                None => Err(PeerError::InvalidMessage("Extended")),
             }
         } else {
-            Err(anyhow!("Received unsupported message type"))
+            Err(PeerError::UnsupportedMessage(message_type))
         }
     }
 }
 
-fn do_handshake(
-    reader: &mut BufReader<impl Read>,
-    writer: &mut BufWriter<impl Write>,
-) -> Result<()> {
-    const HEADER_LEN: usize = 49 + PROTO_IDENTIFIER.len();
+/// Writes `first` along with every other message already queued for this peer,
+/// flushing after latency-sensitive messages and once at the end of the batch.
+/// A [PeerRequest::SendBatch] is flushed once, after its last message.
+/// Returns the kind of the last message written. Whatever a write that timed out didn't get
+/// to stays in `writer`, ahead of anything sent next.
+fn send_queued(
+    first: PeerRequest,
+    rx: &Receiver<PeerRequest>,
+    writer: &mut Outbox<impl Write>,
+    capture: Option<&Capture>,
+) -> Result<Option<&'static str>> {
+    let mut req = first;
+    let mut unflushed = false;
+    let mut last = None;
+
+    loop {
+        let batch = matches!(req, PeerRequest::SendBatch(_));
+        let (msgs, ticket) = req.into_messages();
+        let buffers = ticket.as_ref().and_then(|ticket| ticket.buffers.clone());
+        writer.tickets.extend(ticket);
+        for msg in msgs {
+            if let Some(capture) = capture {
+                record(capture, Direction::Sent, &msg);
+            }
+            let traffic = msg.write_to(writer)?;
+            writer.count(traffic);
+            last = Some(msg.kind());
+            unflushed = batch || msg.is_bulk();
+
+            // the data is in the outbox now, so the next block read can go in its buffer
+            if let (Message::Piece(_, _, data), Some(buffers)) = (msg, &buffers) {
+                buffers.give_back(data);
+            }
+            if !unflushed {
+                writer.flush()?;
+            } else if writer.pending() >= WRITE_BUFFER_SIZE {
+                writer.drain()?;
+            }
+        }
+        if batch {
+            writer.flush()?;
+            unflushed = false;
+        }
+
+        match rx.try_recv() {
+            Ok(next) => req = next,
+            Err(_) => break,
+        }
+    }
+
+    // the last message may still be sitting in the buffer
+    if unflushed {
+        writer.flush()?;
+    }
+
+    Ok(last)
+}
+
+// A capture is only a debugging aid, so failing to write one shouldn't affect the connection
+fn record(capture: &Capture, direction: Direction, msg: &Message) {
+    if let Err(e) = capture.record(direction, msg) {
+        debug!("Failed to capture message: {}", e);
+    }
+}
+
+// Tells main the connection is over, if it will still listen. The hangups channel has no
+// bound, so this gets through however backed up main is, and main can't be left holding
+// requests for a peer that is gone.
+fn hang_up(hangups: &Sender<Response>, addr: SocketAddr, hangup: Hangup) {
+    let _ = hangups.send(Response::Peer(PeerResponse::Death(addr, hangup)));
+}
+
+// Which side a failed read or write means closed the connection. Giving up on a slow peer,
+// or on what it sent, is our doing.
+fn side_of(e: &PeerError) -> Side {
+    match e {
+        PeerError::Io(_) if !e.is_timeout() => Side::Remote,
+        _ => Side::Local,
+    }
+}
+
+// Whether the connection was reset, which is how a peer looks to go away when our own address
+// changes under us
+fn was_reset(e: &PeerError) -> bool {
+    matches!(e, PeerError::Io(e) if e.kind() == io::ErrorKind::ConnectionReset)
+}
+
+// Pass a response on to the main thread, giving up if it stays backed up for too long
+fn forward(sender: &Sender<Response>, resp: PeerResponse, timeout: Duration) -> Result<()> {
+    sender
+        .send_timeout(Response::Peer(resp), timeout)
+        .map_err(|_| PeerError::MainChannel)
+}
+
+/// Fills `buf` from `reader`, failing with [io::ErrorKind::TimedOut] if that isn't done by
+/// `deadline`.
+fn read_exact_by(
+    reader: &mut BufReader<impl Transport>,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        // every read gets less time, so the peer can't stretch things out a byte at a time
+        reader.get_ref().set_read_timeout(Some(remaining))?;
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            // a read that timed out (WouldBlock on Unix) means the deadline has passed,
+            // which the next time round the loop reports
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::Interrupted
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
 
-    // First, let's send our end of the handshake
+fn send_handshake(writer: &mut impl Write, info_hash: &[u8], peer_id: &[u8]) -> Result<()> {
     writer.write_all(&[PROTO_IDENTIFIER.len() as u8])?; // pstrlen
     writer.write_all(PROTO_IDENTIFIER.as_bytes())?; // pstr
-    writer.write_all(&[0u8; 8])?; // reserved
-    writer.write_all(&METAINFO.info_hash())?; // info_hash
-    writer.write_all(&*PEER_ID)?; // peer_id
+    writer.write_all(&RESERVED)?; // reserved
+    writer.write_all(info_hash)?; // info_hash
+    writer.write_all(peer_id)?; // peer_id
     writer.flush()?;
+    Ok(())
+}
 
-    // Next, let's receive the other end of the handshake
-    let mut buf = [0u8; HEADER_LEN];
-    reader.read_exact(&mut buf)?;
+// Reads the peer's handshake, making sure it is for our torrent.
+// Returns whether it supports the extension protocol.
+fn recv_handshake(
+    reader: &mut BufReader<impl Transport>,
+    info_hash: &[u8],
+    deadline: Instant,
+) -> Result<bool> {
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    read_exact_by(reader, &mut buf, deadline)?;
 
-    Ok(())
+    let (reserved, rest) = buf[1 + PROTO_IDENTIFIER.len()..].split_at(RESERVED.len());
+    let theirs: [u8; 20] = rest[..20].try_into().unwrap();
+    if theirs != info_hash {
+        return Err(PeerError::UnknownInfoHash(theirs));
+    }
+    Ok(reserved[5] & 0x10 != 0)
+}
+
+// Swaps handshakes with the peer. Returns whether it supports the extension protocol.
+// A peer that connected to us goes first, so one asking for a torrent we aren't serving is
+// hung up on without learning anything about us. When we connect, we go first.
+fn do_handshake(
+    reader: &mut BufReader<impl Transport>,
+    writer: &mut impl Write,
+    info_hash: &[u8],
+    peer_id: &[u8],
+    incoming: bool,
+    deadline: Instant,
+) -> Result<bool> {
+    if incoming {
+        let extensions = recv_handshake(reader, info_hash, deadline)?;
+        send_handshake(writer, info_hash, peer_id)?;
+        Ok(extensions)
+    } else {
+        send_handshake(writer, info_hash, peer_id)?;
+        recv_handshake(reader, info_hash, deadline)
+    }
+}
+
+/// Spawns the thread that talks to `peer`, returning the channel main sends it requests on.
+/// What the peer says goes to main on `sender`, except for the thread hanging up, which goes
+/// on `hangups` so it isn't lost when `sender` is full.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_peer_thread(
+    peer: TcpStream,
+    addr: SocketAddr,
+    sender: Sender<Response>,
+    hangups: Sender<Response>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    handshake: Option<HandshakeSlot>,
+    capture_dir: Option<PathBuf>,
+    counters: Vec<Arc<TrafficCounter>>,
+) -> Sender<PeerRequest> {
+    spawn_peer_thread_over(
+        peer,
+        addr,
+        sender,
+        hangups,
+        info_hash,
+        peer_id,
+        handshake,
+        capture_dir,
+        counters,
+    )
 }
 
-pub fn spawn_peer_thread(peer: TcpStream, sender: Sender<Response>) -> Sender<PeerRequest> {
+/// Like [spawn_peer_thread], over any [Transport]. The peer is known to main as `addr`, as
+/// there may be no address to get from the transport itself.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_peer_thread_over<T: Transport>(
+    peer: T,
+    addr: SocketAddr,
+    sender: Sender<Response>,
+    hangups: Sender<Response>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    handshake: Option<HandshakeSlot>,
+    capture_dir: Option<PathBuf>,
+    counters: Vec<Arc<TrafficCounter>>,
+) -> Sender<PeerRequest> {
     let (tx, rx) = channel::unbounded();
-    let addr = peer.peer_addr().expect("TcpStream not connected to peer!");
 
     thread::spawn(move || {
-        // set timeout for tcp stream
-        peer.set_read_timeout(Some(TCP_READ_TIMEOUT))
-            .expect("Failed to set read timeout on TcpStream");
+        run_peer(
+            &peer,
+            addr,
+            rx,
+            sender,
+            &hangups,
+            info_hash,
+            peer_id,
+            handshake,
+            capture_dir,
+            counters,
+        );
 
-        let mut writer = BufWriter::new(peer.try_clone().expect("Failed to clone TcpStream"));
-        let mut reader = BufReader::new(peer.try_clone().expect("Failed to clone TcpStream"));
+        // wakes the receiver thread, and lets the remote know we're done with it
+        let _ = peer.shutdown();
+    });
+
+    tx
+}
+
+// Body of the peer thread. Returns when either side hangs up.
+#[allow(clippy::too_many_arguments)]
+fn run_peer(
+    peer: &impl Transport,
+    addr: SocketAddr,
+    rx: Receiver<PeerRequest>,
+    sender: Sender<Response>,
+    hangups: &Sender<Response>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    handshake: Option<HandshakeSlot>,
+    capture_dir: Option<PathBuf>,
+    counters: Vec<Arc<TrafficCounter>>,
+) {
+    let mut writer = Outbox::new(peer.try_clone().expect("Failed to clone peer connection"))
+        .counting_into(counters.clone());
+    let mut reader = BufReader::new(peer.try_clone().expect("Failed to clone peer connection"));
 
-        // do the handshake
-        if let Err(e) = do_handshake(&mut reader, &mut writer) {
+    // do the handshake. Only incoming connections hold a slot.
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let incoming = handshake.is_some();
+    let handshaken = do_handshake(
+        &mut reader,
+        &mut writer,
+        &info_hash,
+        &peer_id,
+        incoming,
+        deadline,
+    );
+    let extensions = match handshaken {
+        Ok(extensions) => {
+            drop(handshake);
+            for counter in &counters {
+                counter.add_sent(Traffic::protocol(HANDSHAKE_LEN));
+                counter.add_received(Traffic::protocol(HANDSHAKE_LEN));
+            }
+            extensions
+        }
+        Err(PeerError::UnknownInfoHash(theirs)) => {
+            debug!(
+                "Peer {:?} wants info_hash {}, which we aren't serving",
+                addr,
+                hex(&theirs)
+            );
+            if let Some(slot) = handshake {
+                slot.unknown_info_hash();
+            }
+            let hangup = Hangup {
+                side: Side::Local,
+                handshaken: false,
+                reset: false,
+                last_message: None,
+            };
+            hang_up(hangups, addr, hangup);
+            return;
+        }
+        Err(e) => {
+            if e.is_timeout() {
+                if let Some(slot) = handshake {
+                    slot.expire();
+                }
+            }
             eprintln!("Failed to perform handshake: {:?}", e);
+            let hangup = Hangup {
+                side: side_of(&e),
+                handshaken: false,
+                reset: was_reset(&e),
+                last_message: None,
+            };
+            hang_up(hangups, addr, hangup);
             return;
         }
+    };
 
-        // create receiving thread
-        let (s, r) = channel::unbounded();
-        thread::spawn(move || loop {
-            match Message::recv(&mut reader) {
-                Ok(msg) => {
-                    // send message back to main thread
-                    if s.send(PeerResponse::MessageReceived(addr, msg)).is_err() {
-                        eprintln!("Received thread failed to send response to peer thread");
-                        return;
-                    }
+    let mut hangup = Hangup {
+        side: Side::Local,
+        handshaken: true,
+        reset: false,
+        last_message: None,
+    };
+
+    // main sends the extension handshake, since it knows what we have to offer
+    if extensions {
+        if let Err(e) = forward(
+            &sender,
+            PeerResponse::SupportsExtensions(addr),
+            PEER_SEND_TIMEOUT,
+        ) {
+            warn!("Dropping peer {:?}: {}", addr, e);
+            hang_up(hangups, addr, hangup);
+            return;
+        }
+    }
+
+    // only connections that got past the handshake are worth capturing
+    let capture = capture_dir.and_then(|dir| match Capture::create(&dir, addr) {
+        Ok(capture) => Some(Arc::new(capture)),
+        Err(e) => {
+            warn!("Failed to start capture for {:?}: {}", addr, e);
+            None
+        }
+    });
+
+    // set timeouts for the connection, so neither a quiet peer nor one that stops reading can
+    // hold this thread up for long
+    peer.set_read_timeout(Some(TCP_READ_TIMEOUT))
+        .expect("Failed to set read timeout on peer connection");
+    peer.set_write_timeout(Some(TCP_WRITE_TIMEOUT))
+        .expect("Failed to set write timeout on peer connection");
+
+    // create receiving thread
+    let (s, r) = channel::unbounded();
+    let recv_capture = capture.clone();
+    let recv_counters = counters.clone();
+    let remote_hangup = Hangup {
+        side: Side::Remote,
+        ..hangup
+    };
+    thread::spawn(move || loop {
+        match Message::recv(&mut reader) {
+            Ok(msg) => {
+                if let Some(capture) = &recv_capture {
+                    record(capture, Direction::Received, &msg);
                 }
-                Err(e) => {
-                    match e.downcast::<io::Error>() {
-                        Ok(t) => {
-                            // timeout; just continue
-                            if t.kind() != io::ErrorKind::WouldBlock {
-                                warn!("Received thread encountered I/O error: {}", t);
-                                return;
-                            }
+
+                // every message has exactly one encoding, so this is what was read
+                let traffic = msg.traffic();
+                recv_counters.iter().for_each(|c| c.add_received(traffic));
+
+                // send message back to main thread
+                if s.send(PeerResponse::MessageReceived(addr, msg)).is_err() {
+                    eprintln!("Received thread failed to send response to peer thread");
+                    return;
+                }
+            }
+            Err(e) => {
+                match e {
+                    // timeout; just continue
+                    e if e.is_timeout() => {
+                        if shutdown::requested() {
+                            return;
                         }
-                        Err(e) => {
-                            // unrecoverable error
-                            println!("Receiver thread encountered unknown error: {}", e);
+                    }
+                    PeerError::InvalidMessage(kind) => {
+                        // the whole message was consumed, so we can carry on reading
+                        if s.send(PeerResponse::InvalidMessage(addr, kind)).is_err() {
+                            eprintln!("Received thread failed to send response to peer thread");
                             return;
                         }
+                        continue;
+                    }
+                    PeerError::Io(e) => {
+                        warn!("Received thread encountered I/O error: {}", e);
+                        let hangup = Hangup {
+                            reset: e.kind() == io::ErrorKind::ConnectionReset,
+                            ..remote_hangup
+                        };
+                        let _ = s.send(PeerResponse::Death(addr, hangup));
+                        return;
+                    }
+                    e => {
+                        // unrecoverable error
+                        eprintln!("Receiver thread encountered unknown error: {}", e);
+                        let _ = s.send(PeerResponse::Death(addr, hangup));
+                        return;
                     }
+                }
 
-                    // send heartbeat to peer thread
-                    s.send(PeerResponse::Heartbeat)
-                        .expect("Receiver thread failed to send heartbeat to peer thread");
+                // send heartbeat to peer thread, which may have exited in the meantime
+                if s.send(PeerResponse::Heartbeat).is_err() {
+                    return;
                 }
             }
-        });
+        }
+    });
+
+    let mut sel = Select::new();
+    let main_thread_oper = sel.recv(&rx);
+    let recv_thread_oper = sel.recv(&r);
+    let mut stall = Stall::default();
+
+    loop {
+        // a congested peer is tried again every so often, in case it is reading again
+        let oper = match stall.is_stalled() {
+            true => sel.select_timeout(WRITE_RETRY_INTERVAL).ok(),
+            false => Some(sel.select()),
+        };
+        let written = match oper {
+            None => writer.flush().map(|()| None).map_err(PeerError::from),
+            Some(oper) if oper.index() == main_thread_oper => {
+                let Ok(req) = oper.recv(&rx) else {
+                    // main dropped this peer, or is shutting down
+                    debug!("Main thread hung up on peer {:?}", addr);
+                    return;
+                };
 
-        let mut sel = Select::new();
-        let main_thread_oper = sel.recv(&rx);
-        let recv_thread_oper = sel.recv(&r);
+                // send the message (and anything queued behind it) to the remote
+                send_queued(req, &rx, &mut writer, capture.as_deref())
+            }
+            Some(oper) if oper.index() == recv_thread_oper => {
+                let Ok(resp) = oper.recv(&r) else {
+                    eprintln!("Peer thread failed to read from receiver thread channel");
+                    return;
+                };
+
+                // the receiver thread is done, which main hears about with what we know
+                if let PeerResponse::Death(_, theirs) = resp {
+                    hangup.side = theirs.side;
+                    hangup.reset = theirs.reset;
+                    hang_up(hangups, addr, hangup);
+                    return;
+                }
 
-        loop {
-            let oper = sel.select();
-            match oper.index() {
-                i if i == main_thread_oper => {
-                    let Ok(req) = oper.recv(&rx) else {
-                        error!("Peer thread failed to read from receiver thread channel");
+                // forward the message back to the main thread
+                if let PeerResponse::MessageReceived(_, msg) = &resp {
+                    hangup.last_message = Some((Direction::Received, msg.kind()));
+                }
+                if matches!(
+                    resp,
+                    PeerResponse::MessageReceived(..) | PeerResponse::InvalidMessage(..)
+                ) {
+                    if let Err(e) = forward(&sender, resp, PEER_SEND_TIMEOUT) {
+                        warn!("Dropping peer {:?}: {}", addr, e);
+                        hangup.side = Side::Local;
+                        hang_up(hangups, addr, hangup);
                         return;
-                    };
-
-                    use PeerRequest::*;
-                    match req {
-                        SendMessage(msg) => {
-                            // send the message to the remote
-                            if let Err(e) = msg.send(&mut writer) {
-                                println!("Peer thread failed to send message to remote: {}", e);
-                                return;
-                            }
-                        }
                     }
                 }
-                i if i == recv_thread_oper => {
-                    let Ok(resp) = oper.recv(&r) else {
-                        eprintln!("Peer thread failed to read from receiver thread channel");
-                        return;
-                    };
+                continue;
+            }
+            _ => unreachable!(),
+        };
 
-                    // forward the message back to the main thread
-                    if let PeerResponse::MessageReceived(_, _) = resp {
-                        sender
-                            .send(Response::Peer(resp))
-                            .expect("Peer thread failed to write to channel");
-                    }
+        // a write that timed out leaves the peer congested rather than gone, unless it stays
+        // that way for too long
+        let change = match written {
+            Ok(kind) => {
+                if let Some(kind) = kind {
+                    hangup.last_message = Some((Direction::Sent, kind));
+                }
+                match writer.pending() {
+                    0 => stall.drained(),
+                    _ => None,
                 }
-                _ => unreachable!(),
             }
+            Err(e) if e.is_timeout() => stall.timed_out(Instant::now(), WRITE_STALL_LIMIT),
+            Err(e) => {
+                eprintln!("Peer thread failed to send message to remote: {}", e);
+                hangup.side = side_of(&e);
+                hangup.reset = was_reset(&e);
+                hang_up(hangups, addr, hangup);
+                return;
+            }
+        };
+        let resp = match change {
+            None => continue,
+            Some(Congestion::Started) => PeerResponse::Congested(addr),
+            Some(Congestion::Cleared) => PeerResponse::Drained(addr),
+            Some(Congestion::GaveUp) => {
+                warn!("Dropping peer {:?}: it stopped reading what we send", addr);
+                hangup.side = Side::Local;
+                hang_up(hangups, addr, hangup);
+                return;
+            }
+        };
+        if let Err(e) = forward(&sender, resp, PEER_SEND_TIMEOUT) {
+            warn!("Dropping peer {:?}: {}", addr, e);
+            hangup.side = Side::Local;
+            hang_up(hangups, addr, hangup);
+            return;
         }
-    });
-
-    tx
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use std::{
-        io::{BufReader, BufWriter},
-        sync::mpsc,
+        io::{self, BufReader, BufWriter, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        os::unix::net::UnixStream,
+        sync::{mpsc, Arc},
         thread,
+        time::{Duration, Instant},
     };
 
+    use crossbeam::channel;
     use pipe;
 
-    use super::Message;
+    use super::{
+        forward, read_exact_by, send_queued, spawn_peer_thread_over, BlockBuffers, Congestion,
+        Message, Outbox, PeerError, PeerRequest, PeerResponse, QueuedUploads, Stall, Traffic,
+        TrafficCounter, HANDSHAKE_LEN, PROTO_IDENTIFIER, RESERVED, SPARE_BLOCK_BUFFERS,
+    };
+    use crate::capture::Direction;
+    use crate::connections::HandshakeLimiter;
+    use crate::hangup::Side;
+    use crate::threads::{Response, PEER_SEND_TIMEOUT};
 
     use Message::*;
 
     #[test]
     fn peer_msg_test() {
-        let test_messages: [Message; 10] = [
+        let test_messages: [Message; 11] = [
             Keepalive,
             Choke,
             Unchoke,
@@ -332,6 +1200,7 @@ mod tests {
             Request(123, 456, 789),
             Piece(5810134, 215970, vec![204, 10, 0]),
             Cancel(789, 456, 123),
+            Extended(1, b"d8:msg_typei0e5:piecei0ee".to_vec()),
         ];
         let num_messages = test_messages.len();
 
@@ -351,7 +1220,8 @@ mod tests {
 
         for msg in test_messages {
             // send the message
-            msg.send(&mut writer).unwrap();
+            msg.write_to(&mut writer).unwrap();
+            writer.flush().unwrap();
 
             // what did the second thread receive?
             let received = rx.recv().unwrap();
@@ -360,4 +1230,447 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    // Collects everything written to it and counts flushes
+    #[derive(Default)]
+    struct CountingWriter {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_queued_flushes_once_per_batch() {
+        let block = || Piece(1, 0, vec![7; 16384]);
+        let (tx, rx) = channel::unbounded();
+        for msg in [block(), block(), Have(3), block(), block()] {
+            tx.send(PeerRequest::SendMessage(msg)).unwrap();
+        }
+
+        let mut writer = Outbox::new(CountingWriter::default());
+        send_queued(PeerRequest::SendMessage(block()), &rx, &mut writer, None).unwrap();
+
+        // once for the Have, and once at the end of the batch
+        let inner = writer.into_inner();
+        assert_eq!(inner.flushes, 2);
+        assert!(rx.is_empty());
+
+        // everything arrives intact and in order
+        let mut reader = BufReader::new(&inner.data[..]);
+        let received: Vec<Message> = (0..6)
+            .map(|_| Message::recv(&mut reader).unwrap())
+            .collect();
+        assert_eq!(
+            received,
+            [block(), block(), block(), Have(3), block(), block()]
+        );
+    }
+
+    #[test]
+    fn queued_pieces_count_until_written() {
+        let queued = Arc::new(QueuedUploads::default());
+        let (tx, rx) = channel::unbounded();
+        for _ in 0..3 {
+            let ticket = queued.ticket(16384);
+            tx.send(PeerRequest::Upload(Piece(1, 0, vec![7; 16384]), ticket))
+                .unwrap();
+        }
+        assert_eq!(queued.bytes(), 3 * 16384);
+
+        let first = rx.recv().unwrap();
+        let mut writer = Outbox::new(CountingWriter::default());
+        send_queued(first, &rx, &mut writer, None).unwrap();
+        assert_eq!(queued.bytes(), 0);
+
+        // and pieces that never go out don't count either
+        tx.send(PeerRequest::Upload(Have(1), queued.ticket(10)))
+            .unwrap();
+        drop(rx);
+        assert_eq!(queued.bytes(), 0);
+    }
+
+    #[test]
+    fn written_pieces_hand_their_buffers_back() {
+        let queued = Arc::new(QueuedUploads::default());
+        let buffers = BlockBuffers::default();
+        let (tx, rx) = channel::unbounded();
+
+        let mut data = buffers.take();
+        data.extend([7; 16384]);
+        let at = data.as_ptr();
+        let ticket = queued.ticket(16384).returning_to(&buffers);
+        tx.send(PeerRequest::Upload(Piece(1, 0, data), ticket))
+            .unwrap();
+
+        // data with nowhere to go back to is dropped as usual
+        tx.send(PeerRequest::Upload(
+            Piece(2, 0, vec![8; 16384]),
+            queued.ticket(16384),
+        ))
+        .unwrap();
+
+        let first = rx.recv().unwrap();
+        let mut writer = Outbox::new(CountingWriter::default());
+        send_queued(first, &rx, &mut writer, None).unwrap();
+        let spare = buffers.take();
+        assert_eq!(spare.as_ptr(), at);
+        assert_eq!(buffers.take().capacity(), 0);
+
+        // and only so many are kept
+        for _ in 0..SPARE_BLOCK_BUFFERS * 2 {
+            buffers.give_back(vec![0; 10]);
+        }
+        assert_eq!(buffers.rx.len(), SPARE_BLOCK_BUFFERS);
+    }
+
+    #[test]
+    fn send_queued_flushes_control_messages_immediately() {
+        let (_tx, rx) = channel::unbounded();
+
+        let mut writer = Outbox::new(CountingWriter::default());
+        send_queued(PeerRequest::SendMessage(Unchoke), &rx, &mut writer, None).unwrap();
+
+        let inner = writer.into_inner();
+        assert_eq!(inner.flushes, 1);
+        assert_eq!(inner.data, [0, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn send_queued_flushes_a_batch_once() {
+        let (tx, rx) = channel::unbounded();
+        tx.send(PeerRequest::SendMessage(Unchoke)).unwrap();
+
+        let haves = (0..100).map(Have).collect();
+        let mut writer = Outbox::new(CountingWriter::default());
+        send_queued(PeerRequest::SendBatch(haves), &rx, &mut writer, None).unwrap();
+
+        // once for the batch, and once for the Unchoke queued behind it
+        let inner = writer.into_inner();
+        assert_eq!(inner.flushes, 2);
+        assert_eq!(inner.data.len(), 100 * 9 + 5);
+    }
+
+    #[test]
+    fn stuck_writes_time_out_and_pick_up_where_they_left_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        stream
+            .set_write_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        // the remote isn't reading, so sooner or later the socket buffers fill up
+        let (_tx, rx) = channel::unbounded();
+        let queued = Arc::new(QueuedUploads::default());
+        let counter = Arc::new(TrafficCounter::default());
+        let mut writer = Outbox::new(stream).counting_into(vec![counter.clone()]);
+        let mut sent = 0;
+        let err = loop {
+            assert!(sent < 10_000, "writes never blocked");
+            let piece = Piece(sent, 0, vec![sent as u8; 16384]);
+            let req = PeerRequest::Upload(piece, queued.ticket(16384));
+            let start = Instant::now();
+            let result = send_queued(req, &rx, &mut writer, None);
+            assert!(start.elapsed() < Duration::from_secs(1));
+            sent += 1;
+            if let Err(e) = result {
+                break e;
+            }
+        };
+        assert!(err.is_timeout());
+        assert!(writer.pending() > 0);
+        assert!(queued.bytes() > 0);
+
+        // blocks still waiting to go out aren't counted as sent
+        assert!(counter.sent().payload < sent as usize * 16384);
+
+        // anything sent meanwhile waits its turn, without blocking for long either
+        let start = Instant::now();
+        let err = send_queued(PeerRequest::SendMessage(Choke), &rx, &mut writer, None);
+        assert!(err.is_err_and(|e| e.is_timeout()));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // once the remote reads again, everything arrives whole and in order
+        let reader = thread::spawn(move || {
+            let mut reader = BufReader::new(remote);
+            (0..=sent)
+                .map(|_| Message::recv(&mut reader).unwrap())
+                .collect::<Vec<_>>()
+        });
+        while let Err(e) = writer.flush() {
+            assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        }
+        assert_eq!(writer.pending(), 0);
+        assert_eq!(counter.sent().payload, sent as usize * 16384);
+        assert_eq!(queued.bytes(), 0);
+
+        let received = reader.join().unwrap();
+        for (i, msg) in received[..sent as usize].iter().enumerate() {
+            assert_eq!(*msg, Piece(i as u32, 0, vec![i as u8; 16384]));
+        }
+        assert_eq!(received[sent as usize], Choke);
+    }
+
+    #[test]
+    fn stalls_are_reported_once_and_given_up_on_eventually() {
+        let limit = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut stall = Stall::default();
+        assert_eq!(stall.drained(), None);
+
+        assert_eq!(stall.timed_out(start, limit), Some(Congestion::Started));
+        assert!(stall.is_stalled());
+        assert_eq!(stall.timed_out(start + limit / 2, limit), None);
+        assert_eq!(
+            stall.timed_out(start + limit, limit),
+            Some(Congestion::GaveUp)
+        );
+
+        // catching up starts the clock over
+        assert_eq!(stall.drained(), Some(Congestion::Cleared));
+        assert!(!stall.is_stalled());
+        let later = start + limit * 2;
+        assert_eq!(stall.timed_out(later, limit), Some(Congestion::Started));
+    }
+
+    #[test]
+    fn traffic_splits_piece_data_from_overhead() {
+        let script = [
+            Bitfield(vec![0xff; 3]),
+            Interested,
+            Unchoke,
+            Request(0, 0, 16384),
+            Piece(0, 0, vec![1; 16384]),
+            Have(0),
+            Keepalive,
+            Extended(0, b"de".to_vec()),
+        ];
+        let (tx, rx) = channel::unbounded();
+        for msg in &script[1..] {
+            tx.send(PeerRequest::SendMessage(msg.clone())).unwrap();
+        }
+
+        let counters = [Arc::new(TrafficCounter::default()), Arc::default()];
+        let mut writer = Outbox::new(CountingWriter::default()).counting_into(counters.to_vec());
+        let first = PeerRequest::SendMessage(script[0].clone());
+        send_queued(first, &rx, &mut writer, None).unwrap();
+
+        // 8 for the bitfield, 5 each for Interested and Unchoke, 17 for the Request, 13 of
+        // Piece header, 9 for the Have, 4 for the Keepalive and 8 for the Extended message
+        let expected = Traffic {
+            payload: 16384,
+            protocol: 8 + 5 + 5 + 17 + 13 + 9 + 4 + 8,
+        };
+        for counter in &counters {
+            assert_eq!(counter.sent(), expected);
+            assert_eq!(counter.received(), Traffic::default());
+        }
+
+        // and it's what actually went on the wire, as the reading side counts it
+        let inner = writer.into_inner();
+        assert_eq!(inner.data.len(), expected.payload + expected.protocol);
+        let mut reader = BufReader::new(&inner.data[..]);
+        let mut read = Traffic::default();
+        for _ in &script {
+            read += Message::recv(&mut reader).unwrap().traffic();
+        }
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn forward_gives_up_on_full_channel() {
+        let (tx, rx) = channel::bounded(2);
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let timeout = Duration::from_millis(50);
+
+        forward(&tx, PeerResponse::MessageReceived(addr, Choke), timeout).unwrap();
+        forward(&tx, PeerResponse::MessageReceived(addr, Choke), timeout).unwrap();
+
+        // main isn't draining, so the peer thread must not block forever
+        let start = Instant::now();
+        assert!(forward(&tx, PeerResponse::MessageReceived(addr, Choke), timeout).is_err());
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(rx.len(), 2);
+    }
+
+    // A reader that always times out, like a TcpStream with a read timeout and a quiet peer
+    struct QuietReader;
+
+    impl io::Read for QuietReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn recv_timeout_is_not_an_error() {
+        let err = Message::recv(&mut BufReader::new(QuietReader)).unwrap_err();
+        assert!(matches!(err, PeerError::Io(_)));
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn recv_reports_bad_messages() {
+        // a Have with only three bytes of index
+        let data: &[u8] = &[0, 0, 0, 4, 4, 0, 0, 1];
+        let err = Message::recv(&mut BufReader::new(data)).unwrap_err();
+        assert!(matches!(err, PeerError::InvalidMessage("Have")));
+        assert!(!err.is_timeout());
+
+        let data: &[u8] = &[0, 0, 0, 1, 42];
+        let err = Message::recv(&mut BufReader::new(data)).unwrap_err();
+        assert!(matches!(err, PeerError::UnsupportedMessage(42)));
+
+        // the peer hung up
+        let data: &[u8] = &[0, 0];
+        let err = Message::recv(&mut BufReader::new(data)).unwrap_err();
+        assert!(matches!(err, PeerError::Io(_)));
+        assert!(!err.is_timeout());
+    }
+
+    #[test]
+    fn handshake_deadline_covers_trickling_peer() {
+        let (mut remote, local) = UnixStream::pair().unwrap();
+
+        // one byte every 50ms would keep a per-read timeout happy forever
+        thread::spawn(move || {
+            for _ in 0..100 {
+                if remote.write_all(&[0]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let start = Instant::now();
+        let mut buf = [0u8; 68];
+        let err = read_exact_by(
+            &mut BufReader::new(local),
+            &mut buf,
+            start + Duration::from_millis(300),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn incoming_handshakes_for_other_torrents_are_hung_up_on() {
+        const OURS: [u8; 20] = [1; 20];
+
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let limiter = HandshakeLimiter::new(2);
+        let (tx, rx) = channel::unbounded();
+        let death = || loop {
+            match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Response::Peer(PeerResponse::Death(addr, hangup)) => return (addr, hangup),
+                _ => continue,
+            }
+        };
+        let connect = |info_hash: [u8; 20]| {
+            let (mut remote, local) = UnixStream::pair().unwrap();
+            let slot = limiter.try_acquire();
+            let sender = spawn_peer_thread_over(
+                local,
+                addr,
+                tx.clone(),
+                tx.clone(),
+                OURS,
+                [0; 20],
+                slot,
+                None,
+                Vec::new(),
+            );
+
+            let mut handshake = vec![PROTO_IDENTIFIER.len() as u8];
+            handshake.extend(PROTO_IDENTIFIER.as_bytes());
+            handshake.extend(RESERVED);
+            handshake.extend(info_hash);
+            handshake.extend([2; 20]);
+            remote.write_all(&handshake).unwrap();
+            remote
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            (remote, sender)
+        };
+
+        // someone else's torrent: closed before we send a thing
+        let (mut remote, _sender) = connect([9; 20]);
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+        assert_eq!(limiter.unknown_info_hash(), 1);
+        let (who, hangup) = death();
+        assert_eq!(who, addr);
+        assert_eq!((hangup.side, hangup.handshaken), (Side::Local, false));
+
+        // ours gets our handshake back
+        let (mut remote, _sender) = connect(OURS);
+        let mut handshake = [0; HANDSHAKE_LEN];
+        remote.read_exact(&mut handshake).unwrap();
+        assert_eq!(handshake[28..48], OURS);
+        assert_eq!(limiter.unknown_info_hash(), 1);
+
+        // and main hears who hung up, and what was said last
+        Message::Interested.write_to(&mut remote).unwrap();
+        drop(remote);
+        let (_, hangup) = death();
+        assert_eq!((hangup.side, hangup.handshaken), (Side::Remote, true));
+        assert_eq!(
+            hangup.last_message,
+            Some((Direction::Received, "Interested"))
+        );
+    }
+
+    #[test]
+    fn hanging_up_gets_through_a_full_main_channel() {
+        const OURS: [u8; 20] = [1; 20];
+
+        // main is backed up, with no room for anything the peer says
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (tx, rx) = channel::bounded(1);
+        tx.send(Response::ConnectFailed(addr)).unwrap();
+        let (hangups, hangup_rx) = channel::unbounded();
+
+        let (mut remote, local) = UnixStream::pair().unwrap();
+        let _sender = spawn_peer_thread_over(
+            local,
+            addr,
+            tx,
+            hangups,
+            OURS,
+            [0; 20],
+            None,
+            None,
+            Vec::new(),
+        );
+        remote.read_exact(&mut [0; HANDSHAKE_LEN]).unwrap();
+        let mut handshake = vec![PROTO_IDENTIFIER.len() as u8];
+        handshake.extend(PROTO_IDENTIFIER.as_bytes());
+        handshake.extend([0; 8]);
+        handshake.extend(OURS);
+        handshake.extend([2; 20]);
+        remote.write_all(&handshake).unwrap();
+        Message::Interested.write_to(&mut remote).unwrap();
+
+        // the peer is given up on once forwarding times out, and main still hears of it
+        let resp = hangup_rx.recv_timeout(PEER_SEND_TIMEOUT * 2).unwrap();
+        let Response::Peer(PeerResponse::Death(who, hangup)) = resp else {
+            panic!("expected a hangup, got {:?}", resp);
+        };
+        assert_eq!(who, addr);
+        assert_eq!((hangup.side, hangup.handshaken), (Side::Local, true));
+        assert_eq!(rx.len(), 1);
+    }
 }
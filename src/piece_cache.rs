@@ -0,0 +1,135 @@
+//! Keeping recently used pieces in memory, so peers asking for blocks of the same hot piece
+//! don't each cost a trip to the disk
+
+use std::collections::{HashMap, VecDeque};
+
+/// Whole pieces, up to a total size, dropping the least recently used first
+#[derive(Debug)]
+pub struct PieceCache {
+    capacity: usize,
+    used: usize,
+    pieces: HashMap<usize, Vec<u8>>,
+
+    // least recently used first
+    order: VecDeque<usize>,
+
+    hits: usize,
+    misses: usize,
+}
+
+impl PieceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            pieces: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Changes how many bytes may be held, dropping pieces until they fit
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink_to(capacity);
+    }
+
+    /// Whether a piece of `len` bytes would be kept at all
+    pub fn fits(&self, len: usize) -> bool {
+        len <= self.capacity
+    }
+
+    /// The data of `piece`, counted as a hit or a miss
+    pub fn get(&mut self, piece: usize) -> Option<&[u8]> {
+        if !self.pieces.contains_key(&piece) {
+            self.misses += 1;
+            return None;
+        }
+
+        self.hits += 1;
+        self.touch(piece);
+        self.pieces.get(&piece).map(Vec::as_slice)
+    }
+
+    /// Keeps `data` as the contents of `piece`, unless it is too big to
+    pub fn insert(&mut self, piece: usize, data: Vec<u8>) {
+        if !self.fits(data.len()) {
+            return;
+        }
+
+        self.remove(piece);
+        self.shrink_to(self.capacity - data.len());
+        self.used += data.len();
+        self.pieces.insert(piece, data);
+        self.order.push_back(piece);
+    }
+
+    /// Forgets `piece`, whose data is no longer good
+    pub fn remove(&mut self, piece: usize) {
+        if let Some(data) = self.pieces.remove(&piece) {
+            self.used -= data.len();
+            self.order.retain(|&p| p != piece);
+        }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    // Moves `piece` to the most recently used end
+    fn touch(&mut self, piece: usize) {
+        if let Some(i) = self.order.iter().position(|&p| p == piece) {
+            self.order.remove(i);
+            self.order.push_back(piece);
+        }
+    }
+
+    // Drops the least recently used pieces until at most `target` bytes are held
+    fn shrink_to(&mut self, target: usize) {
+        while self.used > target {
+            let Some(piece) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(data) = self.pieces.remove(&piece) {
+                self.used -= data.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PieceCache;
+
+    #[test]
+    fn least_recently_used_pieces_go_first() {
+        let mut cache = PieceCache::new(30);
+        cache.insert(0, vec![0; 10]);
+        cache.insert(1, vec![1; 10]);
+        cache.insert(2, vec![2; 10]);
+
+        // using piece 0 makes piece 1 the one to go
+        assert_eq!(cache.get(0), Some(&[0; 10][..]));
+        cache.insert(3, vec![3; 10]);
+        assert_eq!(cache.get(1), None);
+        assert!(cache.get(2).is_some());
+        assert!(cache.get(3).is_some());
+        assert_eq!((cache.hits(), cache.misses()), (3, 1));
+
+        // too big to keep at all, which leaves the rest alone
+        cache.insert(4, vec![4; 31]);
+        assert_eq!(cache.get(4), None);
+        assert!(cache.get(0).is_some());
+
+        cache.remove(0);
+        assert_eq!(cache.get(0), None);
+        cache.set_capacity(10);
+        assert_eq!(cache.get(2), None);
+        assert!(cache.get(3).is_some());
+    }
+}
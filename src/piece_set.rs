@@ -0,0 +1,199 @@
+//! Which pieces a peer has, kept small for the peers that have all of them or none
+//!
+//! With hundreds of thousands of pieces a bitfield per peer adds up, yet most peers in a mature
+//! swarm are seeds and most new ones have nothing. Only peers with some of the pieces get a
+//! bitfield of their own.
+
+use bitvec::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pieces {
+    None,
+    All,
+    Some(BitVec<u8, Msb0>),
+}
+
+/// A set of the pieces of a torrent with `len` pieces
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PieceSet {
+    len: usize,
+    pieces: Pieces,
+
+    // how many pieces are in the set, so telling seeds apart doesn't take a scan
+    count: usize,
+}
+
+impl PieceSet {
+    /// None of `len` pieces
+    pub fn none(len: usize) -> Self {
+        Self {
+            len,
+            pieces: Pieces::None,
+            count: 0,
+        }
+    }
+
+    /// All `len` pieces
+    pub fn all(len: usize) -> Self {
+        Self {
+            len,
+            pieces: Pieces::All,
+            count: len,
+        }
+    }
+
+    /// The pieces set in `bits`, one bit per piece
+    pub fn from_bitvec(bits: BitVec<u8, Msb0>) -> Self {
+        let len = bits.len();
+        let count = bits.count_ones();
+        if count == 0 {
+            Self::none(len)
+        } else if count == len {
+            Self::all(len)
+        } else {
+            Self {
+                len,
+                pieces: Pieces::Some(bits),
+                count,
+            }
+        }
+    }
+
+    /// How many pieces the torrent has, in the set or not
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// How many pieces are in the set
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.count == self.len
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether `piece` is in the set. Pieces past the end never are.
+    pub fn get(&self, piece: usize) -> bool {
+        match &self.pieces {
+            _ if piece >= self.len => false,
+            Pieces::None => false,
+            Pieces::All => true,
+            Pieces::Some(bits) => bits[piece],
+        }
+    }
+
+    /// Adds `piece` to the set, returning whether it wasn't there already
+    ///
+    /// # Panics
+    ///
+    /// If `piece` is past the end
+    pub fn insert(&mut self, piece: usize) -> bool {
+        assert!(piece < self.len, "piece {} is out of range", piece);
+        if self.pieces == Pieces::None {
+            self.pieces = Pieces::Some(bitvec![u8, Msb0; 0; self.len]);
+        }
+        match &mut self.pieces {
+            Pieces::Some(bits) if !bits[piece] => bits.set(piece, true),
+            _ => return false,
+        }
+
+        self.count += 1;
+        if self.is_all() {
+            self.pieces = Pieces::All;
+        }
+        true
+    }
+
+    /// The pieces in the set, in order
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        let (all, some) = match &self.pieces {
+            Pieces::None => (0..0, None),
+            Pieces::All => (0..self.len, None),
+            Pieces::Some(bits) => (0..0, Some(bits.iter_ones())),
+        };
+        all.chain(some.into_iter().flatten())
+    }
+
+    /// Whether the set has any piece that `ours` doesn't
+    pub fn has_any_missing_from(&self, ours: &BitSlice<u8, Msb0>) -> bool {
+        match &self.pieces {
+            Pieces::None => false,
+            Pieces::All => ours.not_all(),
+            Pieces::Some(bits) => bits.iter_ones().any(|piece| !ours[piece]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::prelude::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{PieceSet, Pieces};
+
+    // Checks every question `set` answers against the plain bitfield it stands for
+    fn assert_matches(set: &PieceSet, bits: &BitVec<u8, Msb0>, ours: &BitVec<u8, Msb0>) {
+        assert_eq!(set.len(), bits.len());
+        assert_eq!(set.count(), bits.count_ones());
+        assert_eq!(set.is_all(), bits.all());
+        assert_eq!(set.is_empty(), bits.not_any());
+        for piece in 0..bits.len() + 2 {
+            assert_eq!(set.get(piece), bits.get(piece).is_some_and(|bit| *bit));
+        }
+        assert!(set.iter_ones().eq(bits.iter_ones()));
+        assert_eq!(
+            set.has_any_missing_from(ours),
+            bits.iter().zip(ours).any(|(p, s)| *p && !*s)
+        );
+    }
+
+    #[test]
+    fn matches_a_plain_bitfield() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for len in [1, 7, 8, 9, 100] {
+            let ours: BitVec<u8, Msb0> = (0..len).map(|_| rng.gen_bool(0.5)).collect();
+            let everything = bitvec![u8, Msb0; 1; len];
+
+            // peers that fill in one Have at a time, in a random order
+            let mut set = PieceSet::none(len);
+            let mut bits = bitvec![u8, Msb0; 0; len];
+            assert_matches(&set, &bits, &ours);
+            for _ in 0..len * 3 {
+                let piece = rng.gen_range(0..len);
+                assert_eq!(set.insert(piece), !bits.replace(piece, true));
+                assert_matches(&set, &bits, &ours);
+                assert_matches(&set, &bits, &everything);
+            }
+
+            // and peers that send a whole bitfield
+            for density in [0.0, 0.3, 0.9, 1.0] {
+                let bits: BitVec<u8, Msb0> = (0..len).map(|_| rng.gen_bool(density)).collect();
+                let set = PieceSet::from_bitvec(bits.clone());
+                assert_matches(&set, &bits, &ours);
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_and_empty_peers_have_no_bitfield() {
+        let bits = bitvec![u8, Msb0; 1; 500_000];
+        assert_eq!(PieceSet::from_bitvec(bits).pieces, Pieces::All);
+        let bits = bitvec![u8, Msb0; 0; 500_000];
+        assert_eq!(PieceSet::from_bitvec(bits).pieces, Pieces::None);
+
+        // filling in the last piece lets the bitfield go
+        let mut set = PieceSet::none(3);
+        set.insert(0);
+        set.insert(2);
+        assert!(matches!(set.pieces, Pieces::Some(_)));
+        set.insert(1);
+        assert_eq!(set, PieceSet::all(3));
+    }
+}
@@ -0,0 +1,169 @@
+//! Checking whether peers can connect to the port we listen on
+//!
+//! Outgoing connections come from whatever source port the OS picks, so the listen port is
+//! the only one that needs to be reachable. The check is best-effort: plenty of networks
+//! can't tell either way, which comes out as [Reachability::Unknown].
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Sender};
+use log::debug;
+
+use crate::http::http_get;
+use crate::threads::Response;
+
+/// How long a check may take before we give up on hearing back
+pub const PORT_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A check service has no business sending more than a word
+const MAX_REPLY: usize = 1024;
+
+/// What the check made of the listen port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    Open,
+    Closed,
+    Unknown,
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reachability::Open => "appears open",
+            Reachability::Closed => "appears closed",
+            Reachability::Unknown => "unknown",
+        })
+    }
+}
+
+/// How to go about checking
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortCheck {
+    /// Ask a web service, which is sent `?port=<port>` and answers `open` or `closed`
+    Url(String),
+
+    /// Connect to our own external address, which only gets through routers that support
+    /// hairpinning
+    Loopback(SocketAddr),
+}
+
+/// Picks a way to check `port`, preferring a check service if there is one.
+/// Returns [None] if there's no service and we don't know our external address.
+pub fn choose(url: Option<&str>, external_ip: Option<IpAddr>, port: u16) -> Option<PortCheck> {
+    match (url, external_ip) {
+        (Some(url), _) => Some(PortCheck::Url(url.to_owned())),
+        (None, Some(ip)) => Some(PortCheck::Loopback(SocketAddr::new(ip, port))),
+        (None, None) => None,
+    }
+}
+
+/// What connecting to the port says about it
+pub fn classify_connect<T>(result: &io::Result<T>) -> Reachability {
+    match result {
+        Ok(_) => Reachability::Open,
+        // something at that address answered, and nobody was listening
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Reachability::Closed,
+        // a router that doesn't hairpin looks just like a firewall dropping us
+        Err(_) => Reachability::Unknown,
+    }
+}
+
+/// What a check service's reply says about the port
+pub fn classify_reply(status: u32, body: &[u8]) -> Reachability {
+    if status != 200 {
+        return Reachability::Unknown;
+    }
+
+    match String::from_utf8_lossy(body)
+        .trim()
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "open" => Reachability::Open,
+        "closed" => Reachability::Closed,
+        _ => Reachability::Unknown,
+    }
+}
+
+fn run(check: &PortCheck, port: u16) -> Reachability {
+    match check {
+        PortCheck::Loopback(addr) => {
+            classify_connect(&TcpStream::connect_timeout(addr, PORT_CHECK_TIMEOUT))
+        }
+        PortCheck::Url(url) => {
+            let port = port.to_string();
+            match http_get(url, &[("port", port.as_bytes())], MAX_REPLY) {
+                Ok(reply) => classify_reply(reply.status, &reply.content),
+                Err(e) => {
+                    debug!("Port check with {} failed: {}", url, e);
+                    Reachability::Unknown
+                }
+            }
+        }
+    }
+}
+
+/// Checks `port` on a thread of its own, and sends main the outcome as a
+/// [Response::PortCheck]. A check still going after [PORT_CHECK_TIMEOUT] is reported as
+/// unknown, and left to finish by itself.
+pub fn spawn_port_check(sender: Sender<Response>, check: PortCheck, port: u16) {
+    thread::spawn(move || {
+        let (tx, rx) = channel::bounded(1);
+        thread::spawn(move || {
+            let _ = tx.send(run(&check, port));
+        });
+
+        let reachability = rx
+            .recv_timeout(PORT_CHECK_TIMEOUT)
+            .unwrap_or(Reachability::Unknown);
+        let _ = sender.send(Response::PortCheck(reachability));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::{choose, classify_connect, classify_reply, PortCheck, Reachability};
+
+    #[test]
+    fn connect_outcomes() {
+        let ok: io::Result<()> = Ok(());
+        assert_eq!(classify_connect(&ok), Reachability::Open);
+
+        let refused: io::Result<()> = Err(io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(classify_connect(&refused), Reachability::Closed);
+
+        for kind in [io::ErrorKind::TimedOut, io::ErrorKind::HostUnreachable] {
+            let failed: io::Result<()> = Err(kind.into());
+            assert_eq!(classify_connect(&failed), Reachability::Unknown);
+        }
+    }
+
+    #[test]
+    fn service_replies() {
+        assert_eq!(classify_reply(200, b"open\n"), Reachability::Open);
+        assert_eq!(classify_reply(200, b" Closed"), Reachability::Closed);
+        assert_eq!(classify_reply(200, b"<html>"), Reachability::Unknown);
+        assert_eq!(classify_reply(500, b"open"), Reachability::Unknown);
+    }
+
+    #[test]
+    fn service_is_preferred_to_loopback() {
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(
+            choose(Some("http://check.example/"), Some(ip), 6881),
+            Some(PortCheck::Url("http://check.example/".to_owned()))
+        );
+        assert_eq!(
+            choose(None, Some(ip), 6881),
+            Some(PortCheck::Loopback(SocketAddr::new(ip, 6881)))
+        );
+        assert_eq!(choose(None, None, 6881), None);
+    }
+}
@@ -0,0 +1,44 @@
+//! Reading and writing a file at an offset, without touching the cursor its handles share
+//!
+//! Unix has this built in, so any number of threads can read and write through clones of one
+//! handle. Elsewhere it is emulated by seeking before each read or write, which only works
+//! while a single thread uses the file at a time.
+
+#[cfg(unix)]
+pub use std::os::unix::fs::FileExt;
+
+#[cfg(not(unix))]
+pub use fallback::FileExt;
+
+#[cfg(not(unix))]
+mod fallback {
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    /// The part of `std::os::unix::fs::FileExt` we use
+    pub trait FileExt {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+        fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    }
+
+    impl FileExt for File {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let mut file = self;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read(buf)
+        }
+
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let mut file = self;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(buf)
+        }
+
+        fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let mut file = self;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(buf)
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::session::MainState;
+use crate::units::format_duration;
+
+/// Number of outstanding requests we allow a peer on probation
+pub const PROBATION_PIPELINE_DEPTH: usize = 1;
+
+/// Finds peers that have unchoked us but have not delivered a single block in `timeout`
+/// despite having outstanding requests, and puts them on probation.
+///
+/// All outstanding requests to such a peer are dropped immediately so the strategy can hand
+/// them to somebody else, rather than waiting for each request to time out individually.
+/// Returns the addresses of the peers that were put on probation.
+pub fn check_snubbed(state: &mut MainState, now: Instant, timeout: Duration) -> Vec<SocketAddr> {
+    let mut snubbed = Vec::new();
+    let holding: HashSet<SocketAddr> = state.requested.values().map(|&(_, addr)| addr).collect();
+
+    for (&addr, peer_info) in state.peers.iter_mut() {
+        if peer_info.peer_choked || peer_info.probation {
+            continue;
+        }
+
+        // its requests were cancelled or handed to someone else, so it owes us nothing, and
+        // the wait starts over once we ask it for more
+        if !holding.contains(&addr) {
+            peer_info.waiting_since = None;
+            continue;
+        }
+
+        let Some(since) = peer_info.waiting_since else {
+            continue;
+        };
+
+        if now.saturating_duration_since(since) >= timeout {
+            info!(
+                "Peer {:?} has not delivered anything in {}, putting it on probation",
+                addr,
+                format_duration(timeout)
+            );
+            peer_info.probation = true;
+            peer_info.waiting_since = None;
+            snubbed.push(addr);
+        }
+    }
+
+    // reassign everything the snubbed peers were holding on to, telling them not to bother
+    let tokens: Vec<_> = state
+        .requested
+        .iter()
+        .filter(|(_, (_, addr))| snubbed.contains(addr))
+        .map(|(&token, _)| token)
+        .collect();
+    for token in tokens {
+        state.remove_request(token);
+    }
+
+    state.stats.probation_events += snubbed.len();
+    snubbed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::file::BlockInfo;
+    use crate::peers::{Message, PeerRequest};
+    use crate::test_utils::{main_state, peer_info};
+    use crate::timer::TimerRequest;
+
+    use super::check_snubbed;
+
+    const TIMEOUT: Duration = Duration::from_secs(6);
+
+    #[test]
+    fn unresponsive_peer_is_put_on_probation() {
+        let (mut state, timer_rx) = main_state(4, 16384);
+        let (mut slow, slow_rx) = peer_info(4);
+        let (fast, fast_rx) = peer_info(4);
+        let slow_addr = "10.0.0.1:6881".parse().unwrap();
+        let fast_addr = "10.0.0.2:6881".parse().unwrap();
+
+        let start = Instant::now();
+        slow.peer_choked = false;
+        slow.waiting_since = Some(start);
+        state.peers.insert(slow_addr, slow);
+        state.peers.insert(fast_addr, fast);
+
+        for (id, piece) in [(1, 0), (2, 1), (3, 2)] {
+            let block = BlockInfo {
+                piece,
+                range: 0..16384,
+            };
+            state.requested.insert(id, (block, slow_addr));
+        }
+        state.requested.insert(
+            4,
+            (
+                BlockInfo {
+                    piece: 3,
+                    range: 0..16384,
+                },
+                fast_addr,
+            ),
+        );
+
+        // not long enough yet
+        assert!(check_snubbed(&mut state, start + TIMEOUT / 2, TIMEOUT).is_empty());
+        assert_eq!(state.requested.len(), 4);
+
+        // as soon as the deadline passes, every block is released at once
+        let snubbed = check_snubbed(&mut state, start + TIMEOUT, TIMEOUT);
+        assert_eq!(snubbed, vec![slow_addr]);
+        assert_eq!(state.requested.len(), 1);
+        assert!(state.requested.values().all(|(_, a)| *a == fast_addr));
+        assert!(state.peers[&slow_addr].probation);
+        assert_eq!(state.stats.probation_events, 1);
+
+        // and the corresponding timers are cancelled
+        let cancelled = timer_rx
+            .try_iter()
+            .filter(|r| matches!(r, TimerRequest::Cancel(_)))
+            .count();
+        assert_eq!(cancelled, 3);
+
+        // and the slow peer is told it needn't send them after all
+        let mut cancels: Vec<(u32, u32, u32)> = slow_rx
+            .try_iter()
+            .map(|req| match req {
+                PeerRequest::SendMessage(Message::Cancel(piece, begin, len)) => (piece, begin, len),
+                req => panic!("unexpected {:?}", req),
+            })
+            .collect();
+        cancels.sort();
+        assert_eq!(cancels, vec![(0, 0, 16384), (1, 0, 16384), (2, 0, 16384)]);
+        assert!(fast_rx.is_empty());
+
+        // a peer already on probation doesn't count twice
+        assert!(check_snubbed(&mut state, start + TIMEOUT * 2, TIMEOUT).is_empty());
+        assert_eq!(state.stats.probation_events, 1);
+    }
+
+    #[test]
+    fn peer_without_requests_is_not_snubbing() {
+        let (mut state, _timer_rx) = main_state(4, 16384);
+        let (mut peer, _rx) = peer_info(4);
+        let addr = "10.0.0.1:6881".parse().unwrap();
+
+        // left over from requests that have since gone to someone else
+        let start = Instant::now();
+        peer.peer_choked = false;
+        peer.waiting_since = Some(start);
+        state.peers.insert(addr, peer);
+
+        assert!(check_snubbed(&mut state, start + TIMEOUT * 2, TIMEOUT).is_empty());
+        assert_eq!(state.peers[&addr].waiting_since, None);
+        assert!(!state.peers[&addr].probation);
+    }
+
+    #[test]
+    fn choked_peer_is_not_snubbing() {
+        let (mut state, _timer_rx) = main_state(4, 16384);
+        let (mut peer, _rx) = peer_info(4);
+        let addr = "10.0.0.1:6881".parse().unwrap();
+
+        let start = Instant::now();
+        peer.peer_choked = true;
+        peer.waiting_since = Some(start);
+        state.peers.insert(addr, peer);
+
+        assert!(check_snubbed(&mut state, start + TIMEOUT * 2, TIMEOUT).is_empty());
+    }
+}
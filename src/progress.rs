@@ -0,0 +1,206 @@
+//! Progress as JSON lines on stdout, for tools that wrap the client
+//!
+//! With --progress-json, a snapshot of the session is written every --progress-interval, along
+//! with a line for each thing that happens that a wrapper might show. Every line is one object
+//! with a `type` and a `ts` in milliseconds since the epoch. Logs go to stderr, so stdout holds
+//! nothing else.
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::crash;
+use crate::session::{MainState, SessionPhase};
+
+/// Everything that goes on a line, which is the whole of the format
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Line {
+    Progress {
+        downloaded: usize,
+        uploaded: usize,
+
+        // counting blocks written to pieces that aren't verified yet, unlike what trackers hear
+        left: usize,
+
+        // bytes per second of piece data, over the last few seconds
+        rate_down: f64,
+        rate_up: f64,
+
+        // seconds until `left` is down to nothing at `rate_down`, if we're getting anywhere
+        eta: Option<u64>,
+
+        peers: usize,
+        pieces_complete: usize,
+        pieces_total: usize,
+        state: SessionPhase,
+    },
+    PieceComplete {
+        piece: usize,
+    },
+    PeerConnected {
+        peer: SocketAddr,
+    },
+    PeerDisconnected {
+        peer: SocketAddr,
+    },
+    Announce {
+        tracker: String,
+
+        // how many peers the tracker gave us, or why it failed
+        peers: Option<usize>,
+        error: Option<String>,
+    },
+    Complete,
+}
+
+impl Line {
+    /// Where the session has got to
+    pub fn progress(state: &MainState) -> Self {
+        let left = state.file.left_estimated();
+        let rate_down = state.stats.download_rate.rate();
+        Line::Progress {
+            downloaded: state.stats.downloaded,
+            uploaded: state.stats.uploaded,
+            left,
+            rate_down,
+            rate_up: state.stats.upload_rate.rate(),
+            eta: eta(left, rate_down),
+            peers: state.peers.len(),
+            pieces_complete: state.file.bitvec().count_ones(),
+            pieces_total: state.piece_count,
+            state: state.phase,
+        }
+    }
+}
+
+// Seconds to download `left` bytes at `rate` bytes per second
+fn eta(left: usize, rate: f64) -> Option<u64> {
+    match left {
+        0 => Some(0),
+        _ if rate >= 1.0 => Some((left as f64 / rate).ceil() as u64),
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+struct Stamped<'a> {
+    ts: u64,
+    #[serde(flatten)]
+    line: &'a Line,
+}
+
+/// Where lines go, if anywhere
+#[derive(Default)]
+pub struct ProgressOutput {
+    out: Option<Box<dyn Write + Send>>,
+}
+
+impl ProgressOutput {
+    pub fn stdout() -> Self {
+        Self::to(io::stdout())
+    }
+
+    pub fn to(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Some(Box::new(out)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.out.is_some()
+    }
+
+    /// Writes `line` out whole, straight away. Once a write fails, likely because whatever
+    /// was reading has gone, nothing more is written.
+    pub fn emit(&mut self, line: Line) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+
+        let stamped = Stamped {
+            ts: crash::now_ms(),
+            line: &line,
+        };
+        let written = serde_json::to_writer(&mut *out, &stamped)
+            .map_err(io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+            .and_then(|()| out.flush());
+        if let Err(e) = written {
+            warn!("Failed to write progress, so no more will be: {}", e);
+            self.out = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::thread;
+
+    use serde_json::Value;
+
+    use super::{eta, Line, ProgressOutput};
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lines_are_tagged_and_stamped() {
+        let (read, write) = pipe::pipe();
+
+        // the pipe has no buffer, so each write waits for the reader
+        let reader = thread::spawn(move || {
+            BufReader::new(read)
+                .lines()
+                .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                .collect::<Vec<Value>>()
+        });
+
+        let mut output = ProgressOutput::to(write);
+        output.emit(Line::PieceComplete { piece: 3 });
+        output.emit(Line::Announce {
+            tracker: "http://t/announce".to_owned(),
+            peers: None,
+            error: Some("refused".to_owned()),
+        });
+        drop(output);
+
+        let lines = reader.join().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["type"], "piece_complete");
+        assert_eq!(lines[0]["piece"], 3);
+        assert!(lines[0]["ts"].is_u64());
+        assert_eq!(lines[1]["type"], "announce");
+        assert!(lines[1]["peers"].is_null());
+        assert_eq!(lines[1]["error"], "refused");
+    }
+
+    #[test]
+    fn eta_needs_a_rate_to_go_by() {
+        assert_eq!(eta(0, 0.0), Some(0));
+        assert_eq!(eta(1000, 0.0), None);
+        assert_eq!(eta(1000, 300.0), Some(4));
+        assert_eq!(eta(1000, 1000.0), Some(1));
+    }
+
+    #[test]
+    fn output_stops_once_nobody_is_reading() {
+        let mut output = ProgressOutput::to(Broken);
+        output.emit(Line::Complete);
+        assert!(!output.is_enabled());
+        output.emit(Line::Complete);
+        assert!(!ProgressOutput::default().is_enabled());
+    }
+}
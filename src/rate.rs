@@ -0,0 +1,214 @@
+use std::time::{Duration, Instant};
+
+/// Length of each slot in a [RateWindow]
+const SLOT_LEN: Duration = Duration::from_secs(1);
+
+/// Number of slots, and so seconds, a [RateWindow] averages over
+const SLOTS: usize = 10;
+
+/// Counts bytes over a sliding window of one-second slots to give a transfer rate.
+///
+/// The window is advanced by however much time has actually passed, so a tick that arrives
+/// late (or several that arrive at once) doesn't skew the rate.
+#[derive(Debug, Clone)]
+pub struct RateWindow {
+    slots: [usize; SLOTS],
+
+    // index of the slot currently being filled
+    current: usize,
+
+    // start of the current slot
+    slot_start: Instant,
+}
+
+impl RateWindow {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            slots: [0; SLOTS],
+            current: 0,
+            slot_start: now,
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        self.slots[self.current] += bytes;
+    }
+
+    /// What has been recorded in the slot being filled, so over the last second or less
+    pub fn current(&self) -> usize {
+        self.slots[self.current]
+    }
+
+    /// Moves the window forward to `now`, clearing any slots that have gone by.
+    /// Times before the start of the current slot are ignored.
+    pub fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.slot_start);
+        let slots = (elapsed.as_nanos() / SLOT_LEN.as_nanos()) as u32;
+        if slots == 0 {
+            return;
+        }
+
+        // after a long stall every slot is stale, so there's no point going round more than once
+        for _ in 0..(slots as usize).min(SLOTS) {
+            self.current = (self.current + 1) % SLOTS;
+            self.slots[self.current] = 0;
+        }
+        self.slot_start += SLOT_LEN * slots;
+    }
+
+    /// Average bytes per second over the completed slots of the window
+    pub fn rate(&self) -> f64 {
+        let total: usize = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != self.current)
+            .map(|(_, bytes)| bytes)
+            .sum();
+
+        total as f64 / ((SLOTS - 1) as f64 * SLOT_LEN.as_secs_f64())
+    }
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+/// Hands out up to `rate` tokens per second, with up to `burst` saved up for later
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a bucket that starts out full
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    /// Takes a token if one is available.
+    /// Otherwise returns how long until one will be.
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = self.last.max(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateWindow, TokenBucket, SLOTS};
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    // one second's worth of traffic at `rate` bytes per second, followed by a tick
+    fn second(window: &mut RateWindow, now: &mut Instant, rate: usize) {
+        window.record(rate);
+        *now += SECOND;
+        window.advance(*now);
+    }
+
+    #[test]
+    fn steady_rate() {
+        let mut now = Instant::now();
+        let mut window = RateWindow::new(now);
+
+        for _ in 0..SLOTS * 2 {
+            second(&mut window, &mut now, 1000);
+        }
+        assert_eq!(window.rate(), 1000.0);
+    }
+
+    #[test]
+    fn bunched_ticks_do_not_advance_window() {
+        let mut now = Instant::now();
+        let mut window = RateWindow::new(now);
+
+        for _ in 0..SLOTS {
+            second(&mut window, &mut now, 1000);
+        }
+
+        // a backlog of ticks delivered all at once mustn't clear slots that are still current
+        for _ in 0..5 {
+            window.advance(now);
+        }
+        assert_eq!(window.rate(), 1000.0);
+    }
+
+    #[test]
+    fn recovers_after_stall() {
+        let mut now = Instant::now();
+        let mut window = RateWindow::new(now);
+
+        for _ in 0..SLOTS {
+            second(&mut window, &mut now, 1000);
+        }
+
+        // nothing for ten seconds, and only one late tick to show for it
+        now += SECOND * 10;
+        window.advance(now);
+        assert_eq!(window.rate(), 0.0);
+
+        // the rate climbs back as traffic resumes
+        for _ in 0..SLOTS / 2 {
+            second(&mut window, &mut now, 1800);
+        }
+        assert_eq!(window.rate(), 1000.0);
+        for _ in 0..SLOTS {
+            second(&mut window, &mut now, 1800);
+        }
+        assert_eq!(window.rate(), 1800.0);
+    }
+
+    #[test]
+    fn partial_seconds_accumulate() {
+        let mut now = Instant::now();
+        let mut window = RateWindow::new(now);
+
+        // ticks that land just short of a second still advance eventually
+        for _ in 0..4 {
+            now += Duration::from_millis(600);
+            window.advance(now);
+        }
+        assert_eq!(window.current, 2);
+    }
+
+    #[test]
+    fn token_bucket_limits_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 5.0, now);
+
+        // the burst goes through at once, and then we have to wait
+        for _ in 0..5 {
+            assert!(bucket.take(now).is_ok());
+        }
+        let wait = bucket.take(now).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(100));
+
+        assert!(bucket.take(now + wait).is_ok());
+
+        // a long idle spell only saves up a burst's worth
+        let later = now + SECOND * 60;
+        let taken = (0..100).filter(|_| bucket.take(later).is_ok()).count();
+        assert_eq!(taken, 5);
+    }
+}
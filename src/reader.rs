@@ -0,0 +1,138 @@
+//! Reading a download in order while it is still coming in, such as to pipe it to a media
+//! player
+//!
+//! A [PrefixReader] only ever serves the start of the file up to the first piece that isn't
+//! verified. Once it has caught up, reads fail with [io::ErrorKind::WouldBlock] until more
+//! pieces are verified, which [PrefixReader::wait] waits for.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::positioned::FileExt;
+
+/// How many bytes at the start of a file are verified, shared between the file and its
+/// readers
+#[derive(Debug, Default)]
+pub(crate) struct Prefix {
+    len: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl Prefix {
+    /// Sets the verified length to `len`, waking readers if it changed. It only shrinks when
+    /// a piece turns out bad on a recheck.
+    pub fn set(&self, len: usize) {
+        let mut current = self.len.lock().unwrap();
+        if *current != len {
+            *current = len;
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Reads a download from the start, as far as it is verified
+///
+/// ```
+/// use std::io::{ErrorKind, Read};
+///
+/// use rittorrent::file::{Block, DownloadFile};
+/// use rittorrent::hash::Sha1PieceHasher;
+/// use sha1::{Digest, Sha1};
+///
+/// let data = vec![7u8; 32768];
+/// let hashes: Vec<[u8; 20]> = data.chunks(16384).map(|p| Sha1::digest(p).into()).collect();
+/// let mut file = DownloadFile::new_from_file(
+///     tempfile::tempfile()?,
+///     &hashes,
+///     16384,
+///     data.len(),
+///     Box::new(Sha1PieceHasher::default()),
+/// )?;
+/// let mut reader = file.reader()?;
+///
+/// let mut buf = [0; 1000];
+/// assert_eq!(reader.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+///
+/// file.process_block(Block::new(0, 0, &data[..16384]))?;
+/// assert_eq!(reader.read(&mut buf)?, 1000);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct PrefixReader {
+    file: File,
+    prefix: Arc<Prefix>,
+    position: usize,
+    total_size: usize,
+}
+
+impl PrefixReader {
+    pub(crate) fn new(file: File, prefix: Arc<Prefix>, total_size: usize) -> Self {
+        Self {
+            file,
+            prefix,
+            position: 0,
+            total_size,
+        }
+    }
+
+    /// How far into the file the next read starts
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Bytes that can be read now without blocking
+    pub fn available(&self) -> usize {
+        self.prefix
+            .len
+            .lock()
+            .unwrap()
+            .saturating_sub(self.position)
+    }
+
+    /// Whether everything has been read
+    pub fn is_done(&self) -> bool {
+        self.position >= self.total_size
+    }
+
+    /// Blocks until there is something to read, or nothing more to come
+    pub fn wait(&self) {
+        let len = self.prefix.len.lock().unwrap();
+        let _len = self
+            .prefix
+            .changed
+            .wait_while(len, |len| *len <= self.position && !self.is_done())
+            .unwrap();
+    }
+
+    /// Like [PrefixReader::wait], giving up after `timeout`. Returns whether there is
+    /// something to read, or nothing more to come.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let len = self.prefix.len.lock().unwrap();
+        let (_len, result) = self
+            .prefix
+            .changed
+            .wait_timeout_while(len, timeout, |len| *len <= self.position && !self.is_done())
+            .unwrap();
+        !result.timed_out()
+    }
+}
+
+impl Read for PrefixReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.is_done() {
+            return Ok(0);
+        }
+
+        let available = self.available();
+        if available == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = buf.len().min(available);
+        let read = self.file.read_at(&mut buf[..len], self.position as u64)?;
+        self.position += read;
+        Ok(read)
+    }
+}
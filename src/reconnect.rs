@@ -0,0 +1,228 @@
+//! Holding off on redialing peers that keep dropping us, so the same few addresses in every
+//! tracker response don't eat our connection slots in a reconnect storm
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::cooldown::Cooldowns;
+use crate::latency::Latency;
+use crate::peer_key::PeerKey;
+
+/// How long to wait before redialing a peer after its first disconnect.
+/// Doubles with every disconnect after that.
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// A peer that stays away this long gets a clean slate
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Why we lost a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The connection failed, or the peer closed it
+    Died,
+    /// The peer sat on a request for too long
+    TimedOut,
+    /// We couldn't connect in the first place
+    ConnectFailed,
+    /// The peer misbehaved until it was banned
+    Banned,
+    /// We dropped a perfectly good peer to make room for others
+    Culled,
+    /// The peer sent cheap messages far faster than we are willing to handle them
+    Flooded,
+}
+
+/// The last disconnect from a peer, and how many there have been
+#[derive(Clone, Debug, PartialEq)]
+pub struct History {
+    pub last: Instant,
+    pub reason: Disconnect,
+    pub count: u32,
+
+    // how slow the peer was by the end, to carry over if it reconnects
+    pub latency: Latency,
+}
+
+/// Disconnect history for every peer we've lost recently
+#[derive(Debug, Default)]
+pub struct Reconnects {
+    history: HashMap<PeerKey, History>,
+    cooldowns: Cooldowns<PeerKey>,
+}
+
+impl Reconnects {
+    /// Records that we lost `key`, and returns how long to wait before dialing it again.
+    /// Peers we culled for capacity did nothing wrong, so they may be redialed straight away.
+    /// Losing a peer we only know the host of holds off on every port of that host.
+    pub fn record(&mut self, key: PeerKey, reason: Disconnect, now: Instant) -> Duration {
+        let history = self.history.entry(key).or_insert(History {
+            last: now,
+            reason,
+            count: 0,
+            latency: Latency::default(),
+        });
+        history.last = now;
+        history.reason = reason;
+
+        if reason == Disconnect::Culled {
+            self.cooldowns.clear(&key);
+            return Duration::ZERO;
+        }
+
+        history.count += 1;
+        let delay = backoff(history.count);
+        self.cooldowns.start(key, now, delay);
+        delay
+    }
+
+    /// Whether `key` may be dialed, or we are still holding off on it or its whole host
+    pub fn may_dial(&self, key: &PeerKey, now: Instant) -> bool {
+        !self.cooldowns.is_active(key, now)
+            && !self.cooldowns.is_active(&PeerKey::Host(key.ip()), now)
+    }
+
+    /// Remembers how slow `key` was by the time we lost it
+    pub fn set_latency(&mut self, key: &PeerKey, latency: Latency) {
+        if let Some(history) = self.history.get_mut(key) {
+            history.latency = latency;
+        }
+    }
+
+    /// How slow `key` was last time we were connected, if we remember
+    pub fn latency(&self, key: &PeerKey) -> Latency {
+        self.history
+            .get(key)
+            .map_or_else(Latency::default, |history| history.latency)
+    }
+
+    pub fn history(&self, key: &PeerKey) -> Option<&History> {
+        self.history.get(key)
+    }
+
+    /// Lets every peer be dialed again straight away, and counts its next disconnect as its
+    /// first. For when we were the ones to drop off, so nobody is to blame for having gone.
+    pub fn clear_backoff(&mut self) {
+        self.cooldowns = Cooldowns::default();
+        for history in self.history.values_mut() {
+            history.count = 0;
+        }
+    }
+
+    /// Forgets peers we haven't lost in a long while, so the history stays bounded
+    pub fn expire(&mut self, now: Instant) {
+        self.cooldowns.expire(now);
+        self.history
+            .retain(|_, history| now.saturating_duration_since(history.last) < FORGET_AFTER);
+    }
+}
+
+fn backoff(count: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1 << count.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Disconnect, Reconnects, BACKOFF_MAX, FORGET_AFTER};
+    use crate::latency::Latency;
+    use crate::peer_key::PeerKey;
+
+    fn key() -> PeerKey {
+        PeerKey::dialed("10.0.0.1:6881".parse().unwrap())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_limit() {
+        let now = Instant::now();
+        let mut reconnects = Reconnects::default();
+
+        let delays: Vec<u64> = (0..8)
+            .map(|_| reconnects.record(key(), Disconnect::Died, now).as_secs())
+            .collect();
+        assert_eq!(delays, [30, 60, 120, 240, 480, 960, 1800, 1800]);
+
+        let history = reconnects.history(&key()).unwrap();
+        assert_eq!((history.reason, history.count), (Disconnect::Died, 8));
+
+        assert!(!reconnects.may_dial(&key(), now + BACKOFF_MAX - Duration::from_secs(1)));
+        assert!(reconnects.may_dial(&key(), now + BACKOFF_MAX));
+        assert!(reconnects.may_dial(&PeerKey::dialed("10.0.0.1:6882".parse().unwrap()), now));
+    }
+
+    #[test]
+    fn culled_peers_may_be_redialed_at_once() {
+        let now = Instant::now();
+        let mut reconnects = Reconnects::default();
+
+        reconnects.record(key(), Disconnect::TimedOut, now);
+        assert!(!reconnects.may_dial(&key(), now));
+
+        assert_eq!(
+            reconnects.record(key(), Disconnect::Culled, now),
+            Duration::ZERO
+        );
+        assert!(reconnects.may_dial(&key(), now));
+
+        // culling doesn't count against the peer next time
+        let delay = reconnects.record(key(), Disconnect::Died, now);
+        assert_eq!(delay, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn old_history_is_forgotten() {
+        let now = Instant::now();
+        let mut reconnects = Reconnects::default();
+        reconnects.record(key(), Disconnect::ConnectFailed, now);
+
+        reconnects.expire(now + FORGET_AFTER / 2);
+        assert!(reconnects.history(&key()).is_some());
+
+        reconnects.expire(now + FORGET_AFTER);
+        assert!(reconnects.history(&key()).is_none());
+        assert_eq!(
+            reconnects.record(key(), Disconnect::Died, now + FORGET_AFTER),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn latency_outlives_the_connection() {
+        let now = Instant::now();
+        let mut reconnects = Reconnects::default();
+        let mut latency = Latency::default();
+        latency.observe(Duration::from_secs(20));
+
+        // nothing to remember it against until the peer is lost
+        reconnects.set_latency(&key(), latency);
+        assert_eq!(reconnects.latency(&key()), Latency::default());
+
+        reconnects.record(key(), Disconnect::TimedOut, now);
+        reconnects.set_latency(&key(), latency);
+        assert_eq!(reconnects.latency(&key()), latency);
+
+        reconnects.expire(now + FORGET_AFTER);
+        assert_eq!(reconnects.latency(&key()), Latency::default());
+    }
+
+    #[test]
+    fn losing_a_peer_known_by_its_host_holds_off_on_the_whole_host() {
+        let now = Instant::now();
+        let mut reconnects = Reconnects::default();
+        let inbound = PeerKey::inbound("10.0.0.1:51234".parse().unwrap(), None);
+
+        reconnects.record(inbound, Disconnect::Died, now);
+        assert!(!reconnects.may_dial(&key(), now));
+        assert!(!reconnects.may_dial(&PeerKey::dialed("10.0.0.1:7000".parse().unwrap()), now));
+        assert!(reconnects.may_dial(&PeerKey::dialed("10.0.0.2:6881".parse().unwrap()), now));
+
+        // a peer that said where it listens only holds off on that
+        let told = PeerKey::inbound("10.0.0.2:51234".parse().unwrap(), Some(6881));
+        reconnects.record(told, Disconnect::Died, now);
+        assert!(!reconnects.may_dial(&PeerKey::dialed("10.0.0.2:6881".parse().unwrap()), now));
+        assert!(reconnects.may_dial(&PeerKey::dialed("10.0.0.2:7000".parse().unwrap()), now));
+    }
+}
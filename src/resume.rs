@@ -0,0 +1,216 @@
+//! The fast-resume file, which saves hashing every piece again each time a download restarts
+//!
+//! It holds which pieces have been verified and which blocks of the rest are still missing,
+//! tied to the torrent's info hash and the file's length. Everything is big endian:
+//!
+//! - the magic `rtresume` and a u32 version
+//! - the 20 byte info hash, the file's length as a u64, and the piece count as a u32
+//! - the bitfield of verified pieces, one bit per piece, padded to a whole byte
+//! - a u32 count of partial pieces, each a u32 index, a u32 count of the byte ranges within
+//!   the piece still to be downloaded, and those ranges as u32 start and end pairs
+//! - the SHA-1 of everything before it, so a file cut short or scribbled over is noticed
+//!
+//! Pieces that are neither verified nor partial are missing entirely.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use bitvec::prelude::*;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"rtresume";
+const VERSION: u32 = 1;
+const CHECKSUM_LEN: usize = 20;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ResumeError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("not a resume file")]
+    NotResume,
+
+    #[error("unsupported resume file version {0}")]
+    Version(u32),
+
+    #[error("resume file is corrupt")]
+    Corrupt,
+
+    #[error("resume file is for another torrent")]
+    WrongTorrent,
+
+    #[error("resume file doesn't match the download: {0}")]
+    Mismatch(&'static str),
+}
+
+type Result<T> = std::result::Result<T, ResumeError>;
+
+/// What a resume file says about a download
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    pub total_size: usize,
+    pub verified: BitVec<u8, Msb0>,
+
+    /// The ranges still missing from each partly downloaded piece
+    pub partial: Vec<(usize, Vec<Range<usize>>)>,
+}
+
+/// Where the resume file for the torrent with `info_hash` goes in `state_dir`
+pub fn path(state_dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+    state_dir.join(format!("{}.resume", hex))
+}
+
+impl ResumeData {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_be_bytes());
+        bytes.extend(self.info_hash);
+        bytes.extend((self.total_size as u64).to_be_bytes());
+        bytes.extend((self.verified.len() as u32).to_be_bytes());
+        bytes.extend(self.verified.as_raw_slice());
+
+        bytes.extend((self.partial.len() as u32).to_be_bytes());
+        for (piece, unfilled) in &self.partial {
+            bytes.extend((*piece as u32).to_be_bytes());
+            bytes.extend((unfilled.len() as u32).to_be_bytes());
+            for range in unfilled {
+                bytes.extend((range.start as u32).to_be_bytes());
+                bytes.extend((range.end as u32).to_be_bytes());
+            }
+        }
+
+        let checksum = Sha1::digest(&bytes);
+        bytes.extend(checksum);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(ResumeError::NotResume);
+        }
+        let Some(body_len) = bytes.len().checked_sub(CHECKSUM_LEN) else {
+            return Err(ResumeError::Corrupt);
+        };
+        let (body, checksum) = bytes.split_at(body_len);
+        if Sha1::digest(body)[..] != checksum[..] {
+            return Err(ResumeError::Corrupt);
+        }
+
+        let mut reader = Reader(&body[MAGIC.len()..]);
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(ResumeError::Version(version));
+        }
+        let info_hash = reader.take(20)?.try_into().unwrap();
+        let total_size = u64::from_be_bytes(reader.take(8)?.try_into().unwrap()) as usize;
+
+        let piece_count = reader.u32()? as usize;
+        let mut verified = BitVec::from_slice(reader.take(piece_count.div_ceil(8))?);
+        verified.truncate(piece_count);
+
+        let mut partial = Vec::new();
+        for _ in 0..reader.u32()? {
+            let piece = reader.u32()? as usize;
+            let mut unfilled = Vec::new();
+            for _ in 0..reader.u32()? {
+                unfilled.push(reader.u32()? as usize..reader.u32()? as usize);
+            }
+            partial.push((piece, unfilled));
+        }
+        if !reader.0.is_empty() {
+            return Err(ResumeError::Corrupt);
+        }
+
+        Ok(Self {
+            info_hash,
+            total_size,
+            verified,
+            partial,
+        })
+    }
+}
+
+// Takes fields off the front of a resume file, which the checksum has already vouched for
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(ResumeError::Corrupt);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bitvec::prelude::*;
+
+    use super::{path, ResumeData, ResumeError};
+
+    fn data() -> ResumeData {
+        ResumeData {
+            info_hash: [7; 20],
+            total_size: 10 * 32768 + 5,
+            verified: bitvec![u8, Msb0; 1, 0, 1, 1, 0, 0, 0, 0, 0, 1, 1],
+            partial: vec![
+                (1, vec![0..100, 200..300]),
+                (4, vec![16384..32768, 0..16384]),
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = data();
+        assert_eq!(ResumeData::decode(&data.encode()).unwrap(), data);
+
+        let empty = ResumeData {
+            verified: BitVec::new(),
+            partial: Vec::new(),
+            ..data
+        };
+        assert_eq!(ResumeData::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn damage_is_noticed() {
+        let bytes = data().encode();
+
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 1;
+        assert!(matches!(
+            ResumeData::decode(&flipped),
+            Err(ResumeError::Corrupt)
+        ));
+        assert!(matches!(
+            ResumeData::decode(&bytes[..bytes.len() - 1]),
+            Err(ResumeError::Corrupt)
+        ));
+        assert!(matches!(
+            ResumeData::decode(b"d8:announce"),
+            Err(ResumeError::NotResume)
+        ));
+    }
+
+    #[test]
+    fn named_after_the_info_hash() {
+        let path = path(Path::new("/state"), &[0xab; 20]);
+        assert_eq!(
+            path.to_str().unwrap(),
+            format!("/state/{}.resume", "ab".repeat(20))
+        );
+    }
+}
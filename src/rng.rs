@@ -0,0 +1,63 @@
+//! Randomness for a session, which can be seeded so that runs are reproducible
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Hands out a generator for each thing in the session that makes random decisions.
+///
+/// Without a seed every generator is seeded from the OS. With one, each generator is derived
+/// from the seed and the name of its purpose, so the same purpose always gets the same stream,
+/// however many other generators were handed out before it.
+#[derive(Clone, Copy, Debug)]
+pub struct RngSource {
+    seed: Option<u64>,
+}
+
+impl RngSource {
+    pub fn new(seed: Option<u64>) -> Self {
+        Self { seed }
+    }
+
+    pub fn derive(&self, purpose: &str) -> StdRng {
+        let Some(seed) = self.seed else {
+            return StdRng::from_entropy();
+        };
+
+        // FNV-1a over the purpose, starting from the seed
+        let mut hash = seed ^ 0xcbf29ce484222325;
+        for byte in purpose.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        StdRng::seed_from_u64(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::RngSource;
+
+    #[test]
+    fn seeded_generators_are_reproducible() {
+        let draw = |source: RngSource, purpose| -> Vec<u64> {
+            let mut rng = source.derive(purpose);
+            (0..4).map(|_| rng.gen()).collect()
+        };
+
+        let seeded = RngSource::new(Some(42));
+        assert_eq!(
+            draw(seeded, "strategy"),
+            draw(RngSource::new(Some(42)), "strategy")
+        );
+        assert_ne!(draw(seeded, "strategy"), draw(seeded, "timers"));
+        assert_ne!(
+            draw(seeded, "strategy"),
+            draw(RngSource::new(Some(43)), "strategy")
+        );
+
+        let unseeded = RngSource::new(None);
+        assert_ne!(draw(unseeded, "strategy"), draw(unseeded, "strategy"));
+    }
+}
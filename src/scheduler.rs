@@ -0,0 +1,583 @@
+//! Sharing one process's connections and dials between several torrents
+//!
+//! Each torrent given with --torrent runs as a [Session] of its own, and the scheduler divides
+//! --max-connections and the --dial-rate tokens between them by their --torrent-weight. A
+//! torrent that doesn't want its whole share, having nobody left to dial, leaves the rest to
+//! the others. Sessions keep a [Demand] up to date every tick, and every
+//! [REBALANCE_INTERVAL] the scheduler reads it and hands each session a new [Allocation].
+//!
+//! A torrent is paused for as long as a file named `<name>.paused` is in the --state-dir, and
+//! gets nothing until it is removed.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use crossbeam::channel::{self, Sender};
+use log::{error, info, warn};
+
+use crate::args::Args;
+use crate::control;
+use crate::session::Session;
+use crate::threads::Response;
+use crate::torrent::MetaInfo;
+
+/// How often allocations are worked out again from what the sessions want
+pub const REBALANCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Weight of a torrent not given one with --torrent-weight
+const DEFAULT_WEIGHT: u32 = 1;
+
+/// Parses a --torrent-weight of the form `name=3`
+pub fn parse_weight(s: &str) -> Result<(String, u32), String> {
+    let (name, weight) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{:?} isn't of the form NAME=WEIGHT", s))?;
+    if name.is_empty() {
+        return Err(format!("{:?} doesn't name a torrent", s));
+    }
+    match weight.parse() {
+        Ok(weight) if weight > 0 => Ok((name.to_owned(), weight)),
+        _ => Err(format!("{:?} isn't a whole number above 0", weight)),
+    }
+}
+
+/// Name a torrent goes by in --torrent-weight and its pause file: its torrent file's name,
+/// without `.torrent`
+pub fn torrent_name(torrent: &str) -> String {
+    let path = Path::new(torrent);
+    path.file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// File whose presence pauses the torrent called `name`
+pub fn pause_path(state_dir: &Path, name: &str) -> PathBuf {
+    state_dir.join(format!("{}.paused", name))
+}
+
+/// What a session could make use of, kept up to date by the session and read by the scheduler
+#[derive(Debug, Default)]
+pub struct Demand {
+    peers: AtomicUsize,
+    queued_dials: AtomicUsize,
+
+    // incoming connections refused for want of room since the scheduler last looked
+    turned_away: AtomicUsize,
+}
+
+impl Demand {
+    /// Records how many peers the session has, and how many more are waiting to be dialed
+    pub fn update(&self, peers: usize, queued_dials: usize) {
+        self.peers.store(peers, Ordering::Relaxed);
+        self.queued_dials.store(queued_dials, Ordering::Relaxed);
+    }
+
+    /// Records an incoming connection refused for the session having no room for it
+    pub fn turn_away(&self) {
+        self.turned_away.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// What the session wants as of now, which starts the count of turned away connections
+    /// over
+    pub fn take(&self) -> Wants {
+        let queued_dials = self.queued_dials.load(Ordering::Relaxed);
+        Wants {
+            connections: self.peers.load(Ordering::Relaxed)
+                + queued_dials
+                + self.turned_away.swap(0, Ordering::Relaxed),
+            dials: queued_dials,
+        }
+    }
+}
+
+/// How much of each budget a session could use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Wants {
+    pub connections: usize,
+    pub dials: usize,
+}
+
+/// A session's share of the budgets, until the next rebalance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// Most peers the session may be connected to
+    pub connections: usize,
+
+    /// Most peers the session may dial a second
+    pub dial_rate: usize,
+
+    /// Whether the torrent is paused, which is why it has nothing
+    pub paused: bool,
+}
+
+/// Splits `total` between claims of `(weight, wants)`, in proportion to their weights but
+/// giving none more than it wants while others want more. Whatever nobody wants is split
+/// by weight too, so a torrent has room to grow before the next rebalance. Claims with no
+/// weight get nothing.
+pub fn divide(total: usize, claims: &[(u32, usize)]) -> Vec<usize> {
+    let mut shares = vec![0; claims.len()];
+    let mut left = total;
+
+    // claims wanting less than their fair share get what they want, which leaves more for
+    // the rest, until everyone left wants more than their share
+    let mut open: Vec<usize> = (0..claims.len())
+        .filter(|&i| claims[i].0 > 0 && claims[i].1 > 0)
+        .collect();
+    loop {
+        let weight: u64 = open.iter().map(|&i| claims[i].0 as u64).sum();
+        let (satisfied, wanting): (Vec<usize>, Vec<usize>) = open.iter().partition(|&&i| {
+            let (w, wants) = claims[i];
+            wants as u64 * weight <= left as u64 * w as u64
+        });
+        if satisfied.is_empty() {
+            break;
+        }
+        for i in satisfied {
+            shares[i] = claims[i].1;
+            left -= claims[i].1;
+        }
+        open = wanting;
+    }
+    if open.is_empty() {
+        open = (0..claims.len()).filter(|&i| claims[i].0 > 0).collect();
+    }
+
+    // the rest goes by weight, with what rounding leaves over going to whoever it was
+    // rounded away from most
+    let weight: u64 = open.iter().map(|&i| claims[i].0 as u64).sum();
+    if weight == 0 {
+        return shares;
+    }
+    let mut given = 0;
+    let mut remainders = Vec::new();
+    for &i in &open {
+        let exact = left as u64 * claims[i].0 as u64;
+        shares[i] += (exact / weight) as usize;
+        given += (exact / weight) as usize;
+        remainders.push((exact % weight, i));
+    }
+    remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for &(_, i) in remainders.iter().take(left - given) {
+        shares[i] += 1;
+    }
+    shares
+}
+
+#[derive(Debug)]
+struct Torrent {
+    name: String,
+    weight: u32,
+    paused: bool,
+    finished: bool,
+}
+
+/// Divides the budgets between torrents by weight and by what they want
+#[derive(Debug)]
+pub struct Scheduler {
+    torrents: Vec<Torrent>,
+    max_connections: usize,
+    dial_rate: usize,
+}
+
+impl Scheduler {
+    /// Schedules the torrents `names`, with weights from `weights` and the rest weighing
+    /// [DEFAULT_WEIGHT]. Weights for torrents not in `names` are refused.
+    pub fn new(
+        names: Vec<String>,
+        weights: &[(String, u32)],
+        max_connections: usize,
+        dial_rate: usize,
+    ) -> Result<Self> {
+        let mut seen = HashSet::new();
+        if let Some(name) = names.iter().find(|name| !seen.insert(name.as_str())) {
+            bail!(
+                "Two torrents are called {}, so they can't be told apart; rename one",
+                name
+            );
+        }
+        if let Some((name, _)) = weights
+            .iter()
+            .find(|(name, _)| !seen.contains(name.as_str()))
+        {
+            bail!(
+                "--torrent-weight names {}, which isn't a torrent given",
+                name
+            );
+        }
+
+        let torrents = names
+            .into_iter()
+            .map(|name| Torrent {
+                weight: weights
+                    .iter()
+                    .rev()
+                    .find(|(n, _)| *n == name)
+                    .map_or(DEFAULT_WEIGHT, |&(_, weight)| weight),
+                name,
+                paused: false,
+                finished: false,
+            })
+            .collect();
+        Ok(Self {
+            torrents,
+            max_connections,
+            dial_rate,
+        })
+    }
+
+    pub fn name(&self, i: usize) -> &str {
+        &self.torrents[i].name
+    }
+
+    /// Pauses or resumes torrent `i`. Returns whether that changed anything.
+    pub fn set_paused(&mut self, i: usize, paused: bool) -> bool {
+        let was = std::mem::replace(&mut self.torrents[i].paused, paused);
+        was != paused
+    }
+
+    /// Stops counting torrent `i`, whose session is over
+    pub fn finish(&mut self, i: usize) {
+        self.torrents[i].finished = true;
+    }
+
+    /// Works out every torrent's allocation from what each wants, in the same order
+    pub fn rebalance(&self, wants: &[Wants]) -> Vec<Allocation> {
+        let weights: Vec<u32> = self
+            .torrents
+            .iter()
+            .map(|t| if t.paused || t.finished { 0 } else { t.weight })
+            .collect();
+        let claims = |want: fn(&Wants) -> usize| -> Vec<(u32, usize)> {
+            weights
+                .iter()
+                .copied()
+                .zip(wants.iter().map(want))
+                .collect()
+        };
+        let connections = divide(self.max_connections, &claims(|w| w.connections));
+        let dial_rates = divide(self.dial_rate, &claims(|w| w.dials));
+
+        self.torrents
+            .iter()
+            .zip(connections.into_iter().zip(dial_rates))
+            .map(|(t, (connections, dial_rate))| Allocation {
+                connections,
+                dial_rate,
+                paused: t.paused,
+            })
+            .collect()
+    }
+}
+
+/// Runs every torrent in `args`, each as a session of its own, sharing the budgets between
+/// them until all of them are done. Fails with the first session to fail, once the rest are
+/// done too.
+pub fn run(args: Args) -> Result<()> {
+    let names: Vec<String> = args.torrent.iter().map(|t| torrent_name(t)).collect();
+    let mut scheduler = Scheduler::new(
+        names,
+        &args.torrent_weights,
+        args.max_connections,
+        args.dial_rate as usize,
+    )?;
+
+    let mut sessions = Vec::new();
+    let mut demands = Vec::new();
+    let mut senders: Vec<Sender<Response>> = Vec::new();
+    for (i, torrent) in args.torrent.iter().enumerate() {
+        let mut session_args = args.clone();
+        session_args.torrent = vec![torrent.clone()];
+        // only one session can listen on the port asked for
+        if i > 0 {
+            session_args.port = None;
+        }
+
+        let metainfo = MetaInfo::from_file(torrent)?;
+        let mut session = Session::new(session_args, metainfo)
+            .with_context(|| format!("Failed to start {}", torrent))?;
+        let demand = Arc::new(Demand::default());
+        senders.push(session.share(demand.clone()));
+        demands.push(demand);
+        sessions.push(session);
+    }
+
+    // needs to happen before any other threads are spawned
+    control::spawn_signal_thread(senders.clone())?;
+
+    let (done_tx, done_rx) = channel::unbounded();
+    for (i, session) in sessions.into_iter().enumerate() {
+        let done_tx = done_tx.clone();
+        thread::spawn(move || {
+            let _ = done_tx.send((i, session.run()));
+        });
+    }
+
+    let mut running = senders.len();
+    let mut failed = None;
+    let ticker = channel::tick(REBALANCE_INTERVAL);
+    rebalance(&mut scheduler, &args, &demands, &senders);
+    while running > 0 {
+        channel::select! {
+            recv(ticker) -> _ => rebalance(&mut scheduler, &args, &demands, &senders),
+            recv(done_rx) -> done => {
+                let (i, result) = done.expect("Every session thread went away");
+                running -= 1;
+                scheduler.finish(i);
+                match result {
+                    Ok(()) => info!("{} is done", scheduler.name(i)),
+                    Err(e) => {
+                        error!("{} failed: {:?}", scheduler.name(i), e);
+                        let name = scheduler.name(i).to_owned();
+                        failed.get_or_insert(e.context(name));
+                    }
+                }
+            },
+        }
+    }
+
+    failed.map_or(Ok(()), Err)
+}
+
+// Picks up pause files, and hands every session its share of what they want now
+fn rebalance(
+    scheduler: &mut Scheduler,
+    args: &Args,
+    demands: &[Arc<Demand>],
+    senders: &[Sender<Response>],
+) {
+    for i in 0..senders.len() {
+        let paused = pause_path(args.state_dir(), scheduler.name(i)).exists();
+        if scheduler.set_paused(i, paused) {
+            let what = if paused { "Pausing" } else { "Resuming" };
+            info!("{} {}", what, scheduler.name(i));
+        }
+    }
+
+    let wants: Vec<Wants> = demands.iter().map(|d| d.take()).collect();
+    for (i, allocation) in scheduler.rebalance(&wants).into_iter().enumerate() {
+        // a session too backed up to take this gets the next one instead
+        if let Err(channel::TrySendError::Full(_)) =
+            senders[i].try_send(Response::Schedule(allocation))
+        {
+            warn!(
+                "{} is too busy to be given its share, trying again later",
+                scheduler.name(i)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{divide, parse_weight, torrent_name, Allocation, Demand, Scheduler, Wants};
+
+    const MAX_CONNECTIONS: usize = 40;
+    const DIAL_RATE: usize = 8;
+
+    // A torrent going along with its allocations the way a session does: it culls peers over
+    // its share, and dials as many as its dial rate allows towards it
+    #[derive(Debug)]
+    struct SimTorrent {
+        peers: usize,
+        queued: usize,
+    }
+
+    impl SimTorrent {
+        fn wants(&self) -> Wants {
+            Wants {
+                connections: self.peers + self.queued,
+                dials: self.queued,
+            }
+        }
+
+        fn follow(&mut self, allocation: Allocation) {
+            self.peers = self.peers.min(allocation.connections);
+            let room = allocation.connections - self.peers;
+            let dials = allocation.dial_rate.min(self.queued).min(room);
+            self.peers += dials;
+            self.queued -= dials;
+        }
+    }
+
+    fn scheduler(weights: &[(&str, u32)]) -> Scheduler {
+        Scheduler::new(
+            vec!["a".to_owned(), "b".to_owned()],
+            &weights
+                .iter()
+                .map(|&(name, weight)| (name.to_owned(), weight))
+                .collect::<Vec<_>>(),
+            MAX_CONNECTIONS,
+            DIAL_RATE,
+        )
+        .unwrap()
+    }
+
+    fn round(scheduler: &Scheduler, torrents: &mut [SimTorrent]) -> Vec<Allocation> {
+        let wants: Vec<Wants> = torrents.iter().map(SimTorrent::wants).collect();
+        let allocations = scheduler.rebalance(&wants);
+        for (torrent, &allocation) in torrents.iter_mut().zip(&allocations) {
+            torrent.follow(allocation);
+        }
+        allocations
+    }
+
+    #[test]
+    fn weighted_torrents_converge_on_their_shares() {
+        let scheduler = scheduler(&[("b", 3)]);
+
+        // a hogs every connection to begin with, and both have plenty more to dial
+        let mut torrents = [
+            SimTorrent {
+                peers: MAX_CONNECTIONS,
+                queued: 1000,
+            },
+            SimTorrent {
+                peers: 0,
+                queued: 1000,
+            },
+        ];
+
+        let mut allocations = Vec::new();
+        for _ in 0..10 {
+            allocations = round(&scheduler, &mut torrents);
+            assert!(torrents.iter().map(|t| t.peers).sum::<usize>() <= MAX_CONNECTIONS);
+        }
+
+        assert_eq!(
+            torrents.iter().map(|t| t.peers).collect::<Vec<_>>(),
+            [10, 30]
+        );
+        assert_eq!(
+            allocations.iter().map(|a| a.dial_rate).collect::<Vec<_>>(),
+            [2, 6]
+        );
+    }
+
+    #[test]
+    fn unwanted_share_goes_to_the_rest() {
+        let scheduler = scheduler(&[("a", 3)]);
+        let allocations = scheduler.rebalance(&[
+            Wants {
+                connections: 5,
+                dials: 0,
+            },
+            Wants {
+                connections: 100,
+                dials: 100,
+            },
+        ]);
+
+        assert_eq!(allocations[0].connections, 5);
+        assert_eq!(allocations[1].connections, MAX_CONNECTIONS - 5);
+        assert_eq!(allocations[0].dial_rate, 0);
+        assert_eq!(allocations[1].dial_rate, DIAL_RATE);
+    }
+
+    #[test]
+    fn paused_torrents_get_nothing_until_resumed() {
+        let mut scheduler = scheduler(&[("b", 3)]);
+        let mut torrents = [
+            SimTorrent {
+                peers: 10,
+                queued: 1000,
+            },
+            SimTorrent {
+                peers: 30,
+                queued: 1000,
+            },
+        ];
+
+        assert!(scheduler.set_paused(1, true));
+        assert!(!scheduler.set_paused(1, true));
+        let allocations = round(&scheduler, &mut torrents);
+        assert!(allocations[1].paused);
+        assert_eq!(allocations[1].connections, 0);
+        assert_eq!(allocations[1].dial_rate, 0);
+        assert_eq!(torrents[1].peers, 0);
+        for _ in 0..10 {
+            round(&scheduler, &mut torrents);
+        }
+        assert_eq!(torrents[0].peers, MAX_CONNECTIONS);
+
+        assert!(scheduler.set_paused(1, false));
+        for _ in 0..10 {
+            round(&scheduler, &mut torrents);
+        }
+        assert_eq!(
+            torrents.iter().map(|t| t.peers).collect::<Vec<_>>(),
+            [10, 30]
+        );
+    }
+
+    #[test]
+    fn finished_torrents_leave_everything_to_the_rest() {
+        let mut scheduler = scheduler(&[]);
+        scheduler.finish(0);
+        let wants = Wants {
+            connections: 100,
+            dials: 100,
+        };
+        let allocations = scheduler.rebalance(&[wants, wants]);
+        assert_eq!(allocations[0].connections, 0);
+        assert_eq!(allocations[1].connections, MAX_CONNECTIONS);
+    }
+
+    #[test]
+    fn division_adds_up_without_overshooting_wants() {
+        // contended, so nobody gets more than they want, and it all gets handed out
+        let shares = divide(10, &[(1, 100), (1, 100), (1, 100)]);
+        assert_eq!(shares.iter().sum::<usize>(), 10);
+        assert!(shares.iter().all(|&s| s == 3 || s == 4));
+
+        let shares = divide(10, &[(1, 2), (2, 100), (0, 100)]);
+        assert_eq!(shares, [2, 8, 0]);
+
+        // what nobody wants is spread by weight anyway
+        let shares = divide(10, &[(1, 1), (4, 1)]);
+        assert_eq!(shares, [3, 7]);
+
+        assert_eq!(divide(10, &[(0, 5)]), [0]);
+        assert_eq!(divide(0, &[(1, 5)]), [0]);
+    }
+
+    #[test]
+    fn turned_away_connections_count_once() {
+        let demand = Demand::default();
+        demand.update(3, 2);
+        demand.turn_away();
+        assert_eq!(
+            demand.take(),
+            Wants {
+                connections: 6,
+                dials: 2
+            }
+        );
+        assert_eq!(demand.take().connections, 5);
+    }
+
+    #[test]
+    fn weights_and_names() {
+        assert_eq!(parse_weight("ubuntu=3"), Ok(("ubuntu".to_owned(), 3)));
+        assert!(parse_weight("ubuntu").is_err());
+        assert!(parse_weight("=3").is_err());
+        assert!(parse_weight("ubuntu=0").is_err());
+        assert!(parse_weight("ubuntu=1.5").is_err());
+
+        assert_eq!(torrent_name("some/dir/ubuntu.torrent"), "ubuntu");
+        assert_eq!(torrent_name("ubuntu"), "ubuntu");
+    }
+
+    #[test]
+    fn weights_must_name_a_torrent() {
+        let names = || vec!["a".to_owned(), "b".to_owned()];
+        assert!(Scheduler::new(names(), &[("c".to_owned(), 2)], 10, 5).is_err());
+        assert!(Scheduler::new(vec!["a".to_owned(), "a".to_owned()], &[], 10, 5).is_err());
+        assert!(Scheduler::new(names(), &[("a".to_owned(), 2)], 10, 5).is_ok());
+    }
+}
@@ -0,0 +1,160 @@
+//! End-to-end check that runs a seeding and a leeching session against each other on localhost
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use clap::Parser;
+use crossbeam::channel::{self, RecvTimeoutError};
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+
+use crate::args::Args;
+use crate::session::Session;
+use crate::torrent::{Info, MetaInfo};
+
+const FILE_NAME: &str = "selftest.bin";
+
+// deliberately not a multiple of the piece length, so the last piece is short
+const FILE_LEN: usize = 1024 * 1024 + 1234;
+const PIECE_LEN: usize = 32 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs the self test, printing PASS or FAIL.
+/// Returns an error if the test failed.
+pub fn run() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("rittorrent-selftest-{}", process::id()));
+    let start = Instant::now();
+
+    let result = run_in(&dir, &[]);
+    let elapsed = start.elapsed();
+    let _ = fs::remove_dir_all(&dir);
+
+    match result {
+        Ok(()) => {
+            println!(
+                "PASS: transferred {} bytes in {:.2}s",
+                FILE_LEN,
+                elapsed.as_secs_f64()
+            );
+            Ok(())
+        }
+        Err(e) => {
+            println!("FAIL after {:.2}s: {:#}", elapsed.as_secs_f64(), e);
+            Err(e)
+        }
+    }
+}
+
+// Downloads from a seeder in `dir`, with `leech_args` added to the leecher's command line
+fn run_in(dir: &Path, leech_args: &[&str]) -> Result<()> {
+    let seed_dir = dir.join("seed");
+    let leech_dir = dir.join("leech");
+    fs::create_dir_all(&seed_dir)?;
+    fs::create_dir_all(&leech_dir)?;
+
+    // random payload for the seeder to serve
+    let mut data = vec![0u8; FILE_LEN];
+    rand::thread_rng().fill_bytes(&mut data);
+    fs::write(seed_dir.join(FILE_NAME), &data)?;
+
+    let metainfo = metainfo(&data);
+
+    let seeder = Session::new(
+        session_args(&seed_dir, &["--seed-existing"]),
+        metainfo.clone(),
+    )?;
+    let seeder_addr = format!("127.0.0.1:{}", seeder.local_addr()?.port());
+
+    // the seeder never finishes on its own; it goes away with the process
+    thread::spawn(move || {
+        if let Err(e) = seeder.run() {
+            eprintln!("Seeding session failed: {:?}", e);
+        }
+    });
+
+    let mut extra = vec!["--add-peer", &seeder_addr];
+    extra.extend_from_slice(leech_args);
+    let leecher = Session::new(session_args(&leech_dir, &extra), metainfo)?;
+
+    let (done_tx, done_rx) = channel::bounded(1);
+    thread::spawn(move || {
+        let _ = done_tx.send(leecher.run());
+    });
+
+    match done_rx.recv_timeout(TIMEOUT) {
+        Ok(result) => result?,
+        Err(RecvTimeoutError::Timeout) => {
+            bail!("download did not complete within {}s", TIMEOUT.as_secs())
+        }
+        Err(RecvTimeoutError::Disconnected) => bail!("leeching session panicked"),
+    }
+
+    let downloaded = fs::read(leech_dir.join(FILE_NAME))?;
+    if downloaded.len() != data.len() {
+        bail!(
+            "downloaded file is {} bytes, expected {}",
+            downloaded.len(),
+            data.len()
+        );
+    }
+    if let Some(offset) = downloaded.iter().zip(&data).position(|(a, b)| a != b) {
+        return Err(anyhow!("downloaded file differs at byte {}", offset));
+    }
+
+    Ok(())
+}
+
+fn session_args(dir: &Path, extra: &[&str]) -> Args {
+    let mut argv = vec![
+        "rittorrent",
+        "--torrent",
+        FILE_NAME,
+        "--skip-announce",
+        "--port",
+        "0",
+        "--output-dir",
+        dir.to_str().unwrap(),
+    ];
+    argv.extend_from_slice(extra);
+
+    Args::parse_from(argv)
+}
+
+fn metainfo(data: &[u8]) -> MetaInfo<'static> {
+    let pieces = data
+        .chunks(PIECE_LEN)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    MetaInfo {
+        announce: "http://127.0.0.1/announce".to_owned(),
+        announce_list: Vec::new(),
+        encoding: String::new(),
+        info: Info {
+            piece_length: PIECE_LEN,
+            pieces,
+            name: FILE_NAME.into(),
+            length: data.len(),
+            remaining: HashMap::new(),
+        },
+        nodes: None,
+        remaining: HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_in;
+
+    #[test]
+    fn downloads_without_listening() {
+        let dir = tempfile::tempdir().unwrap();
+        run_in(dir.path(), &["--no-listen"]).unwrap();
+    }
+}
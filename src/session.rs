@@ -0,0 +1,4701 @@
+use log::{debug, error, info, trace, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::mem;
+use std::net::TcpListener;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bitvec::prelude::*;
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::Serialize;
+
+use crate::address_watch::AddressWatch;
+use crate::announce::{self, AnnounceMode, Trackers};
+use crate::args::Args;
+use crate::availability::Availability;
+use crate::caps::{self, Stop};
+use crate::choke;
+use crate::connections::{self, ConnectionData, HandshakeLimiter};
+use crate::control::{self, ControlCommand};
+use crate::crash;
+use crate::dial_queue::{Candidate, DialQueue};
+use crate::disk_full::DiskFull;
+use crate::events::{EventKind, Events, Subscription};
+use crate::extension;
+use crate::file::{self, Block, BlockInfo, Disk, DiskResponse, DownloadFile, FileError, FileMap};
+use crate::handlers::{self, HandlerError};
+use crate::hangup::{self, Hangup};
+use crate::hash::{PieceHasher, Sha1PieceHasher, Uncached};
+use crate::hooks::{self, Hooks};
+use crate::last_run::{self, LastRun};
+use crate::latency::Latency;
+use crate::log_limiter::{LogLimiter, PeerWarning};
+use crate::misbehavior::{self, Bans, Flood, MessageRates};
+use crate::peer_key::PeerKey;
+use crate::peers;
+use crate::peers::{
+    spawn_peer_thread, BlockBuffers, Message, PeerRequest, PeerResponse, QueuedUploads,
+    TrafficCounter,
+};
+use crate::piece_set::PieceSet;
+use crate::portcheck;
+use crate::probation;
+use crate::progress::{Line, ProgressOutput};
+use crate::rate::RateWindow;
+use crate::reconnect::{Disconnect, Reconnects};
+use crate::resume;
+use crate::rng::RngSource;
+use crate::scheduler::{Allocation, Demand};
+use crate::shutdown::ListenerGuard;
+use crate::stall::{self, StallWatch};
+use crate::stats::Stats;
+use crate::strategy;
+use crate::stream;
+use crate::threads::{Response, MAIN_CHANNEL_CAPACITY};
+use crate::timer::{self, spawn_timer_thread, TimerInfo, TimerRequest};
+use crate::torrent::MetaInfo;
+use crate::tracker::{self, request, TrackerRequest};
+use crate::units;
+use crate::webseed::{self, WebSeeds};
+
+pub(crate) const DIGEST_SIZE: usize = 20;
+const PEER_ID_LEN: usize = 20;
+
+/// How long a block may still arrive after we cancelled its request, without the peer being
+/// blamed for sending something we didn't ask for
+pub(crate) const LATE_BLOCK_GRACE: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub(crate) struct PeerInfo {
+    // channel to send to this peer
+    pub sender: Sender<PeerRequest>,
+
+    // basic state
+    pub choked: bool,
+    pub interested: bool,
+    pub peer_choked: bool,
+    pub peer_interested: bool,
+
+    // which pieces does this peer have?
+    pub has: PieceSet,
+
+    // does this peer have every piece? Kept in step with MainState::seeds
+    pub is_seed: bool,
+
+    // statistics (and their distributions). What it downloaded from us is as of the last tick.
+    pub uploaded: usize,
+    pub downloaded: usize,
+
+    // payload bytes handed to its thread to send, whether or not they got written
+    pub served: usize,
+
+    // "recent" statistics
+    pub uploaded_recently: usize,
+    pub downloaded_recently: usize,
+
+    // how fast the peer is uploading to us, and downloading from us
+    pub upload_rate: RateWindow,
+    pub download_rate: RateWindow,
+
+    // choke rounds in a row this peer has been interested but left choked
+    pub choked_rounds: u32,
+
+    // when we started waiting on this peer to deliver a requested block
+    pub waiting_since: Option<Instant>,
+
+    // has this peer been caught ignoring our requests?
+    pub probation: bool,
+
+    // protocol violations, weighted and decaying over time; too many gets the peer banned
+    pub misbehavior: u32,
+
+    // pieces the peer sent part of that then failed their hash check
+    pub hash_strikes: u32,
+
+    // has the peer stopped taking what we write to it?
+    pub congested: bool,
+
+    // Whether our Bitfield has gone out, which strict clients only take once and before
+    // anything else, and how many of our pieces the peer has been told about since
+    pub sent_bitfield: bool,
+    pub pieces_told: usize,
+
+    // how many of the pieces waiting to be announced were in our Bitfield already
+    pub bitfield_covers: usize,
+
+    // how many messages of each kind the peer has sent lately, to catch it flooding us
+    pub message_rates: MessageRates,
+
+    // extended message id the peer wants ut_metadata messages sent with, if it speaks it
+    pub ut_metadata: Option<u8>,
+
+    // whether the peer dialed us, and the port it listens on if it said in its extension
+    // handshake, which together tell who it is beyond the address it connected from
+    pub incoming: bool,
+    pub listen_port: Option<u16>,
+
+    // bytes exchanged with this peer, as counted by its thread
+    pub traffic: Arc<TrafficCounter>,
+
+    // what blocks read for the peer go into, handed back by its thread once written
+    pub buffers: BlockBuffers,
+
+    // how long the peer takes to deliver a block, and when we sent each request it holds,
+    // by piece and offset
+    pub latency: Latency,
+    pub sent_at: HashMap<(usize, usize), Instant>,
+
+    // requests we cancelled lately, and when, whose blocks may have crossed the Cancel
+    pub cancelled: Vec<(BlockInfo, Instant)>,
+}
+
+impl PeerInfo {
+    // Consumes a new connection, creates a new peer thread
+    fn new(data: ConnectionData, sender: Sender<Response>, state: &MainState) -> Self {
+        let traffic = Arc::new(TrafficCounter::default());
+        let incoming = data.handshake.is_some();
+        let key = peer_key(data.addr, incoming, None);
+        Self {
+            sender: spawn_peer_thread(
+                data.peer,
+                data.addr,
+                sender,
+                state.hangup_sender.clone(),
+                state.info_hash,
+                state.peer_id,
+                data.handshake,
+                state.args.capture_dir.clone(),
+                vec![traffic.clone(), state.traffic.clone()],
+            ),
+            choked: true,
+            interested: false,
+            peer_choked: true,
+            peer_interested: false,
+            has: PieceSet::none(state.piece_count),
+            is_seed: false,
+            uploaded: 0,
+            downloaded: 0,
+            served: 0,
+            uploaded_recently: 0,
+            downloaded_recently: 0,
+            upload_rate: RateWindow::default(),
+            download_rate: RateWindow::default(),
+            choked_rounds: 0,
+            waiting_since: None,
+            probation: false,
+            misbehavior: 0,
+            hash_strikes: 0,
+            congested: false,
+            sent_bitfield: false,
+            pieces_told: 0,
+            bitfield_covers: 0,
+            message_rates: MessageRates::default(),
+            ut_metadata: None,
+            incoming,
+            listen_port: None,
+            traffic,
+            buffers: BlockBuffers::default(),
+            // a peer that was too slow last time starts out on probes
+            latency: state.reconnects.latency(&key),
+            sent_at: HashMap::new(),
+            cancelled: Vec::new(),
+        }
+    }
+
+    // Call after `has` changes, to keep `is_seed` and the count of connected seeds up to date
+    pub fn update_seed(&mut self, seeds: &mut usize) {
+        let is_seed = self.has.is_all();
+        match (self.is_seed, is_seed) {
+            (false, true) => *seeds += 1,
+            (true, false) => *seeds -= 1,
+            _ => (),
+        }
+        self.is_seed = is_seed;
+    }
+
+    // Remembers that we cancelled our request for `block`, for [LATE_BLOCK_GRACE]
+    pub fn record_cancel(&mut self, block: BlockInfo, now: Instant) {
+        self.cancelled
+            .retain(|(_, at)| now.saturating_duration_since(*at) < LATE_BLOCK_GRACE);
+        self.cancelled.push((block, now));
+    }
+
+    // Whether `info` is a block we cancelled lately, which a Cancel only asks the peer not to
+    // send. The cancelled requests it covers in full are forgotten, like answered requests.
+    pub fn take_cancelled(&mut self, info: &BlockInfo, now: Instant) -> bool {
+        self.cancelled
+            .retain(|(_, at)| now.saturating_duration_since(*at) < LATE_BLOCK_GRACE);
+        let overlaps = self.cancelled.iter().any(|(b, _)| {
+            b.piece == info.piece
+                && b.range.start < info.range.end
+                && info.range.start < b.range.end
+        });
+        self.cancelled.retain(|(b, _)| {
+            b.piece != info.piece
+                || b.range.start < info.range.start
+                || b.range.end > info.range.end
+        });
+        overlaps
+    }
+
+    // Who the peer connected from `addr` is, for anything that outlives the connection
+    pub fn key(&self, addr: SocketAddr) -> PeerKey {
+        peer_key(addr, self.incoming, self.listen_port)
+    }
+}
+
+// Who a peer is: where we dialed it, or what we know of one that dialed us
+fn peer_key(addr: SocketAddr, incoming: bool, listen_port: Option<u16>) -> PeerKey {
+    if incoming {
+        PeerKey::inbound(addr, listen_port)
+    } else {
+        PeerKey::dialed(addr)
+    }
+}
+
+/// Whether we still have pieces to download, which decides who we prefer to upload to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SessionPhase {
+    Leeching,
+    Seeding,
+}
+
+impl SessionPhase {
+    fn of(file: &FileMap) -> Self {
+        if file.is_complete() {
+            SessionPhase::Seeding
+        } else {
+            SessionPhase::Leeching
+        }
+    }
+}
+
+pub(crate) struct MainState {
+    pub args: Args,
+    pub info_hash: [u8; DIGEST_SIZE],
+    pub peer_id: [u8; PEER_ID_LEN],
+
+    // from the metainfo, so it doesn't depend on the length of any bitfield
+    pub piece_count: usize,
+
+    // the port we are actually listening on
+    pub port: u16,
+
+    // whether a --check-port check has been started
+    pub port_check_started: bool,
+
+    // the --on-complete and --on-error commands
+    pub hooks: Hooks,
+
+    // the torrent's url-list, probed before any of it is trusted
+    pub web_seeds: WebSeeds,
+
+    pub peers: HashMap<SocketAddr, PeerInfo>,
+
+    // how many of `peers` have every piece
+    pub seeds: usize,
+
+    // how many connected peers have each piece
+    pub availability: Availability,
+
+    // how many peers get a regular unchoke, as of the last choke round
+    pub upload_slots: usize,
+
+    pub phase: SessionPhase,
+
+    // the bencoded info dictionary, served to peers over ut_metadata
+    pub metadata: Vec<u8>,
+
+    // bytes exchanged with every peer, as counted by the peer threads
+    pub traffic: Arc<TrafficCounter>,
+
+    // pieces we completed but haven't sent Have for yet
+    pub unannounced: Vec<usize>,
+
+    // pieces completed since the resume file was last saved
+    pub unsaved_pieces: usize,
+
+    // piece data waiting to be written to peers, and the requests put off until there is less
+    // of it
+    pub queued_uploads: Arc<QueuedUploads>,
+    pub deferred_uploads: VecDeque<(SocketAddr, u32, u32, u32)>,
+
+    // the disk thread, and what it has told us of the file
+    pub file: Disk,
+    pub timer_sender: Sender<TimerRequest>,
+
+    // where peer threads say they've hung up, which unlike the main channel is never full
+    pub hangup_sender: Sender<Response>,
+    pub requested: HashMap<timer::Token, (file::BlockInfo, SocketAddr)>,
+
+    pub trackers: Trackers,
+    pub stats: Stats,
+
+    // file offset a streaming client last read from
+    pub stream_position: Option<usize>,
+
+    // peers whose pieces (or ours) changed since we last decided whether we're interested
+    pub interest_dirty: HashSet<SocketAddr>,
+
+    // keeps misbehaving peers from flooding the log
+    pub log_limiter: LogLimiter,
+
+    // peers we won't talk to for having misbehaved
+    pub bans: Bans,
+
+    // why we lost peers recently, and when we may dial them again
+    pub reconnects: Reconnects,
+
+    // peers from trackers waiting to be dialed, a few every second
+    pub dial_queue: DialQueue,
+
+    // when a scheduler shares connections between torrents, what we tell it we want and our
+    // share as of its last rebalance
+    pub demand: Option<Arc<Demand>>,
+    pub allocation: Option<Allocation>,
+
+    // whether we've had enough peers since last running low, so the next time we do is a
+    // reason to announce early
+    pub above_low_water: bool,
+
+    // the last session, if it died without telling trackers it stopped
+    pub stale_run: Option<LastRun>,
+
+    // signs that our own address changed under us
+    pub address_watch: AddressWatch,
+
+    // which of --max-download-bytes and --max-upload-bytes we have hit, as of the last check
+    pub caps_reached: caps::Reached,
+
+    // set once a write fails for the disk being full, until space is freed
+    pub disk_full: Option<DiskFull>,
+
+    // what happened lately, for the crash report
+    pub events: Events,
+
+    // --progress-json lines, if they were asked for
+    pub progress: ProgressOutput,
+
+    // source of timer tokens and choking decisions
+    pub rng: StdRng,
+
+    // where tests keep their resume and last run files, removed along with the state
+    #[cfg(test)]
+    pub _scratch_dir: Option<tempfile::TempDir>,
+}
+
+impl MainState {
+    /// Payload bytes written out to peers this session, as of the last tick
+    pub fn uploaded(&self) -> usize {
+        self.stats.uploaded
+    }
+
+    /// Payload bytes we have downloaded and verified this session
+    pub fn downloaded(&self) -> usize {
+        self.stats.downloaded
+    }
+
+    /// Most peers to be connected to: --max-connections, or our share of it when a scheduler
+    /// shares it between torrents
+    pub fn max_connections(&self) -> usize {
+        self.allocation
+            .map_or(self.args.max_connections, |a| a.connections)
+    }
+
+    /// Most peers to dial a second, which is shared like [MainState::max_connections]
+    pub fn dial_rate(&self) -> usize {
+        self.allocation
+            .map_or(self.args.dial_rate as usize, |a| a.dial_rate)
+    }
+
+    /// Gives up on an outstanding request, cancelling its timeout. If the peer it went to is
+    /// still connected, it is sent a Cancel so it doesn't upload a block we no longer expect.
+    /// Returns the block and the peer it was requested from.
+    pub fn remove_request(&mut self, token: timer::Token) -> Option<(BlockInfo, SocketAddr)> {
+        let (block, addr) = self.requested.remove(&token)?;
+        self.timer_sender
+            .send(TimerRequest::Cancel(token))
+            .expect("Main thread failed to communicate with timer thread!");
+
+        if let Some(peer_info) = self.peers.get_mut(&addr) {
+            // we gave up on it, so the block took at least this long
+            if let Some(sent_at) = peer_info.sent_at.remove(&(block.piece, block.range.start)) {
+                peer_info.latency.observe(sent_at.elapsed());
+            }
+
+            let msg = Message::Cancel(
+                block.piece as u32,
+                block.range.start as u32,
+                block.range.len() as u32,
+            );
+
+            // a dead peer thread is noticed the next time there's something that matters to send
+            let _ = peer_info.sender.send(PeerRequest::SendMessage(msg));
+            peer_info.record_cancel(block.clone(), Instant::now());
+        }
+
+        Some((block, addr))
+    }
+}
+
+// Send an announce to the given tracker.
+// If the tracker has not heard from us yet, this is upgraded to a Started event.
+fn announce(
+    state: &mut MainState,
+    tracker_sender: &Sender<TrackerRequest>,
+    url: &str,
+    event: Option<request::Event>,
+) {
+    let started = state.trackers.get(url).is_some_and(|t| t.started);
+    let event = match event {
+        None if !started => Some(request::Event::Started),
+        event => event,
+    };
+    if matches!(event, Some(request::Event::Started)) {
+        state.trackers.mark_started(url);
+    }
+    state.trackers.mark_announced(url, Instant::now());
+
+    // a session that died before saying it stopped is stopped for it
+    let stale = match (event, &state.stale_run) {
+        (Some(request::Event::Started), Some(stale)) => Some(request::Request {
+            info_hash: state.info_hash,
+            peer_id: stale.peer_id,
+            my_port: stale.port,
+            uploaded: 0,
+            downloaded: 0,
+            left: state.file.left_verified(),
+            event: Some(request::Event::Stopped),
+        }),
+        _ => None,
+    };
+
+    let tracker_req = TrackerRequest {
+        url: url.to_owned(),
+        request: request::Request {
+            info_hash: state.info_hash,
+            peer_id: state.peer_id,
+            my_port: state.port,
+            uploaded: state.uploaded(),
+            downloaded: state.downloaded(),
+            left: state.file.left_verified(),
+            event,
+        },
+        stale,
+    };
+    tracker_sender
+        .send(tracker_req)
+        .expect("Failed to send request to tracker thread");
+}
+
+fn schedule_announce(state: &MainState, url: &str, delay: Duration) {
+    let Some(tracker) = state.trackers.get(url) else {
+        return;
+    };
+
+    let timer_req = TimerRequest::Timer(TimerInfo {
+        timer_len: delay,
+        id: tracker.timer_id,
+        repeat: false,
+    });
+    state
+        .timer_sender
+        .send(timer_req)
+        .expect("Main thread failed to communicate with timer thread!");
+}
+
+// Forget about a peer, dropping any requests it was holding on to.
+// Session totals live in MainState::stats, so they are unaffected.
+// How long before we dial it again depends on why it went.
+fn remove_peer(state: &mut MainState, addr: SocketAddr, reason: Disconnect) {
+    remove_hung_up_peer(state, addr, reason, None);
+}
+
+// Removes a peer, along with how its thread saw the connection end if it was the one to give up
+fn remove_hung_up_peer(
+    state: &mut MainState,
+    addr: SocketAddr,
+    reason: Disconnect,
+    hangup: Option<&Hangup>,
+) {
+    let Some(mut peer_info) = state.peers.remove(&addr) else {
+        return;
+    };
+    update_peer_traffic(&mut peer_info);
+    state
+        .events
+        .record(EventKind::PeerRemoved, Some(addr), None);
+    state.progress.emit(Line::PeerDisconnected { peer: addr });
+    state.deferred_uploads.retain(|&(a, ..)| a != addr);
+    let phase = hangup::classify(&peer_info, hangup, reason);
+    let side = hangup::side(hangup, reason);
+    state.stats.disconnects.record(phase, side);
+    let key = peer_info.key(addr);
+    let delay = state.reconnects.record(key, reason, Instant::now());
+    state.reconnects.set_latency(&key, peer_info.latency);
+    let times = state.reconnects.history(&key).map_or(0, |h| h.count);
+    debug!(
+        "Lost peer {:?} ({:?} {} {}, {} times lately), not redialing {:?} for {}",
+        addr,
+        reason,
+        phase,
+        side,
+        times,
+        key,
+        units::format_duration(delay)
+    );
+    let (sent, received) = (peer_info.traffic.sent(), peer_info.traffic.received());
+    debug!(
+        " --> sent {} of payload and {} of overhead, received {} and {}",
+        units::format_size(sent.payload),
+        units::format_size(sent.protocol),
+        units::format_size(received.payload),
+        units::format_size(received.protocol)
+    );
+    if let Some(average) = peer_info.latency.average() {
+        debug!(
+            " --> took {} per block on average",
+            units::format_duration(average)
+        );
+    }
+    if peer_info.is_seed {
+        state.seeds -= 1;
+    }
+    state.availability.remove(&peer_info.has);
+
+    // the peer is already gone, so nobody is sent a Cancel
+    let tokens: Vec<timer::Token> = state
+        .requested
+        .iter()
+        .filter(|(_, (_, a))| *a == addr)
+        .map(|(&token, _)| token)
+        .collect();
+    for token in tokens {
+        state.remove_request(token);
+    }
+}
+
+// Takes on a new connection, incoming or outgoing, unless it's one we don't want.
+// A connection that has already died is dropped here rather than taking the session with it.
+fn accept_connection(state: &mut MainState, data: ConnectionData, tx: &Sender<Response>) {
+    let addr = data.addr;
+    let key = peer_key(addr, data.handshake.is_some(), None);
+    debug!("New connection with {:?}", addr);
+
+    // decide before a peer thread is spawned, so a rejected connection is just closed
+    if let Some(reason) = rejection(state, addr, &key) {
+        debug!("Dropping connection with {:?}: {}", addr, reason);
+        return;
+    }
+
+    // nothing else can be sent to the peer before it is in `state.peers`, so the Bitfield
+    // goes first
+    let mut peer_info = PeerInfo::new(data, tx.clone(), state);
+    if !send_bitfield(state, &mut peer_info) {
+        debug!("Connection with {:?} died during setup, dropping it", addr);
+        return;
+    }
+
+    // the peer stays choked until it is interested and there is a slot for it
+    state.peers.insert(addr, peer_info);
+    state.events.record(EventKind::PeerAdded, Some(addr), None);
+    state.progress.emit(Line::PeerConnected { peer: addr });
+}
+
+// Sends a new peer our Bitfield. Pieces still waiting to be announced are in it already, so
+// the peer won't be sent a Have for them as well.
+// Returns false if the peer thread has gone away.
+fn send_bitfield(state: &MainState, peer_info: &mut PeerInfo) -> bool {
+    debug_assert!(!peer_info.sent_bitfield, "Bitfield sent twice");
+    peer_info.sent_bitfield = true;
+    peer_info.pieces_told = state.file.bitvec().count_ones();
+    peer_info.bitfield_covers = state.unannounced.len();
+
+    let bytes = state.file.bitfield().to_vec();
+    let msg = PeerRequest::SendMessage(Message::Bitfield(bytes));
+    peer_info.sender.send(msg).is_ok()
+}
+
+// Whether we're connected to anyone who could be `key`
+fn is_connected(peers: &HashMap<SocketAddr, PeerInfo>, key: &PeerKey) -> bool {
+    peers
+        .iter()
+        .any(|(&addr, peer_info)| peer_info.key(addr).same_peer(key))
+}
+
+// Takes the queued peers to dial now, as many as this round and our connection limit have
+// room for. Peers that failed or dropped us lately go after the rest, and those we're
+// connected to, have banned, or are holding off on are dropped from the queue.
+fn next_dials(state: &mut MainState) -> Vec<SocketAddr> {
+    let now = Instant::now();
+    let (peers, bans, reconnects) = (&state.peers, &state.bans, &state.reconnects);
+    let judge = |addr| {
+        let key = PeerKey::dialed(addr);
+        if is_connected(peers, &key) || bans.is_banned(&key, now) || !reconnects.may_dial(&key, now)
+        {
+            Candidate::Skip
+        } else if reconnects.history(&key).is_some() {
+            Candidate::Failed
+        } else {
+            Candidate::Fresh
+        }
+    };
+
+    let limit = state.dial_rate();
+    let max_connections = state.max_connections();
+    let mut dials = Vec::new();
+    while peers.len() + dials.len() < max_connections {
+        let Some(addr) = state.dial_queue.next(limit, judge) else {
+            break;
+        };
+        dials.push(addr);
+    }
+    state.stats.dial_queue = state.dial_queue.len();
+    dials
+}
+
+fn dial_queued(state: &mut MainState, tx: &Sender<Response>) {
+    for addr in next_dials(state) {
+        connections::async_connect(tx.clone(), addr);
+    }
+}
+
+// Why we won't take on a connection with `addr`, which is `key`, if we won't
+fn rejection(state: &MainState, addr: SocketAddr, key: &PeerKey) -> Option<&'static str> {
+    // Don't accept connection from peer we're connected to!
+    if state.peers.contains_key(&addr) || is_connected(&state.peers, key) {
+        return Some("already connected");
+    }
+
+    // Nor from the same host on another port. Local clients are left alone, so several can
+    // be tested against each other.
+    if !addr.ip().is_loopback() && state.peers.keys().any(|a| a.ip() == addr.ip()) {
+        return Some("already connected to that host");
+    }
+
+    if state.peers.len() >= state.max_connections() {
+        // the scheduler hears there was a peer we had no room for
+        if let Some(demand) = &state.demand {
+            demand.turn_away();
+        }
+        return Some("too many connections");
+    }
+
+    if state.bans.is_banned(key, Instant::now()) {
+        return Some("banned");
+    }
+
+    None
+}
+
+// Notices when we finish downloading, or a recheck sends us back to it. The choke criteria
+// flip with the phase, so the unchoked set is re-evaluated straight away rather than at the
+// next round. A finished download is moved to its real name before anyone hears about it.
+//
+// Trackers count us as a seed from the `left` of our last announce, so losing a piece tells
+// them straight away. It's only a change of `left`, with no event; nothing was completed or
+// stopped.
+fn check_phase(state: &mut MainState, tracker_sender: &Sender<TrackerRequest>) -> Result<()> {
+    let phase = SessionPhase::of(&state.file);
+    if phase == state.phase {
+        return Ok(());
+    }
+
+    info!("Now {:?}", phase);
+    state.phase = phase;
+    if phase == SessionPhase::Seeding {
+        state
+            .file
+            .finalize()
+            .context("Failed to move the finished download into place")?;
+        state.hooks.fire(hooks::Event::Complete, &state.stats);
+        state.progress.emit(Line::Complete);
+        state.events.record(EventKind::Completed, None, None);
+    } else {
+        let urls: Vec<String> = state.trackers.started().map(|t| t.url.clone()).collect();
+        for url in urls {
+            announce(state, tracker_sender, &url, None);
+        }
+    }
+    for addr in choke::choke_round(state) {
+        warn!("Peer {:?} appears to have died, removing it", addr);
+        remove_peer(state, addr, Disconnect::Died);
+    }
+    Ok(())
+}
+
+// Handles a successful announce: schedules the next one, and queues the peers it gave us
+// along with those the other trackers last did
+fn on_announced(
+    state: &mut MainState,
+    tx: &Sender<Response>,
+    url: String,
+    mut data: tracker::response::Response,
+) {
+    debug!("main thread received response from {} {:#?}", url, data);
+    state.events.record_detail(
+        EventKind::Announced,
+        format!("{}: {} peers", url, data.peers.len()),
+    );
+    state.progress.emit(Line::Announce {
+        tracker: url.clone(),
+        peers: Some(data.peers.len()),
+        error: None,
+    });
+
+    // Create a timer for the next request
+    let (interval, min_interval) = (data.interval(), data.min_interval());
+    let peers = mem::take(&mut data.peers);
+    let Some(delay) = state
+        .trackers
+        .on_success(&url, peers, interval, min_interval)
+    else {
+        warn!("Received response from unknown tracker {}", url);
+        return;
+    };
+    state.stats.trackers = state.trackers.statuses();
+    if let Some(min_interval) = min_interval {
+        debug!(
+            "{} asks for at least {:?} between announces",
+            url, min_interval
+        );
+    }
+    debug!("Next announce to {} in {:?}", url, delay);
+    schedule_announce(state, &url, delay);
+    debug!("Tracker status: {:?}", state.trackers);
+
+    if let Some(ip) = data.external_ip() {
+        state.address_watch.external_ip(ip);
+    }
+
+    if state.args.check_port && !state.args.no_listen && !state.port_check_started {
+        start_port_check(state, data.external_ip(), tx);
+    }
+
+    // the peers wait their turn, though the first few are dialed straight away
+    for p in state.trackers.peers() {
+        let addr = (&p.ip[..], p.port).to_socket_addrs().ok();
+        let Some(addr) = addr.and_then(|mut addrs| addrs.next()) else {
+            debug!("Skipping tracker peer {:?}, as it doesn't resolve", p);
+            continue;
+        };
+        state.dial_queue.push(addr);
+    }
+    dial_queued(state, tx);
+}
+
+// Announces early once we've lost most of our peers and have nobody left to dial, rather than
+// sitting idle until the next regular announce. That happens once each time the peer count
+// drops below the low-water mark. The pending announce is cancelled, and the response
+// schedules the next one as usual.
+fn check_peer_pool(state: &mut MainState, tracker_sender: &Sender<TrackerRequest>) {
+    let low_water = (state.max_connections() as f64 * announce::LOW_WATER).ceil() as usize;
+    if state.peers.len() >= low_water {
+        state.above_low_water = true;
+        return;
+    }
+    if !state.above_low_water || !state.dial_queue.is_empty() {
+        return;
+    }
+    state.above_low_water = false;
+
+    let urls = state.trackers.early_announce(Instant::now());
+    if urls.is_empty() {
+        debug!(
+            "Down to {} peers, but no tracker can be announced to early",
+            state.peers.len()
+        );
+        return;
+    }
+    info!(
+        "Down to {} peers with nobody left to dial, announcing early to {}",
+        state.peers.len(),
+        urls.join(", ")
+    );
+    announce_early(state, tracker_sender, urls);
+}
+
+// Acts on our own address having changed. Trackers still list the old one, and the peers we
+// lost over it are held off on as if they had dropped us, so we announce again and forget
+// the backoff. A --check-port check is made again with the next tracker response.
+fn check_address(state: &mut MainState, tracker_sender: &Sender<TrackerRequest>) {
+    let Some(change) = state.address_watch.take_change() else {
+        return;
+    };
+    state.reconnects.clear_backoff();
+    state.port_check_started = false;
+
+    let urls = state.trackers.early_announce(Instant::now());
+    if urls.is_empty() {
+        info!(
+            "Our address seems to have changed, as {}, but no tracker can be announced to early",
+            change
+        );
+        return;
+    }
+    info!(
+        "Our address seems to have changed, as {}; announcing again to {}",
+        change,
+        urls.join(", ")
+    );
+    announce_early(state, tracker_sender, urls);
+}
+
+// Announces to `urls` now, in place of their scheduled announces
+fn announce_early(
+    state: &mut MainState,
+    tracker_sender: &Sender<TrackerRequest>,
+    urls: Vec<String>,
+) {
+    for url in urls {
+        if let Some(tracker) = state.trackers.get(&url) {
+            state
+                .timer_sender
+                .send(TimerRequest::Cancel(tracker.timer_id))
+                .expect("Main thread failed to communicate with timer thread!");
+        }
+        announce(state, tracker_sender, &url, None);
+    }
+}
+
+// Notices when a session cap is reached. Peers hear that we're no longer interested, or get
+// choked, straight away rather than whenever they next change something.
+fn check_caps(state: &mut MainState) {
+    let reached = caps::reached(&state.args, &state.stats);
+    let was = std::mem::replace(&mut state.caps_reached, reached);
+
+    if reached.download && !was.download {
+        info!(
+            "Reached the download cap after {}, no longer requesting",
+            units::format_size(state.stats.received)
+        );
+        state.interest_dirty.extend(state.peers.keys());
+    }
+
+    if reached.upload && !was.upload {
+        info!(
+            "Reached the upload cap after {}, choking every peer",
+            units::format_size(state.stats.uploaded)
+        );
+        for addr in choke::choke_round(state) {
+            warn!("Peer {:?} appears to have died, removing it", addr);
+            remove_peer(state, addr, Disconnect::Died);
+        }
+    }
+}
+
+// Keep only the `keep` peers that have uploaded the most to us recently.
+// While we're still downloading, a seed is kept even if it has been slow, since a seed is the
+// only peer guaranteed to have the pieces nobody else does.
+fn cull_peers(state: &mut MainState, keep: usize) {
+    let mut s: Vec<SocketAddr> = state.peers.keys().copied().collect();
+    s.sort_unstable_by(|&addr1, &addr2| {
+        let peer_info1 = state.peers.get(&addr1).unwrap();
+        let peer_info2 = state.peers.get(&addr2).unwrap();
+
+        peer_info2
+            .uploaded_recently
+            .cmp(&peer_info1.uploaded_recently)
+    });
+
+    let n = keep.min(s.len());
+    let keeps_seed = s[..n].iter().any(|addr| state.peers[addr].is_seed);
+    if n > 0 && !keeps_seed && !state.file.is_complete() {
+        if let Some(i) = s[n..].iter().position(|addr| state.peers[addr].is_seed) {
+            s.swap(n - 1, n + i);
+        }
+    }
+
+    for addr in s.drain(n..) {
+        remove_peer(state, addr, Disconnect::Culled);
+    }
+
+    // reset uploaded/downloaded recently
+    for (_, peer_info) in state.peers.iter_mut() {
+        peer_info.uploaded_recently = 0;
+        peer_info.downloaded_recently = 0;
+    }
+}
+
+// Takes on our share of the connections and dials from the scheduler. Peers beyond it are
+// culled straight away, and a paused torrent keeps none.
+fn apply_allocation(state: &mut MainState, allocation: Allocation) {
+    let was = state.allocation.replace(allocation);
+    match (was.is_some_and(|a| a.paused), allocation.paused) {
+        (false, true) => info!("Paused, so dropping every peer"),
+        (true, false) => info!("Resumed"),
+        _ if was != Some(allocation) => debug!(
+            "Scheduled {} connections and {} dials a second",
+            allocation.connections, allocation.dial_rate
+        ),
+        _ => (),
+    }
+
+    if state.peers.len() > allocation.connections {
+        cull_peers(state, allocation.connections);
+    }
+}
+
+// Tell peers about every piece we completed since the last flush, in one batch per peer.
+// Seeds already have everything, so they are skipped without looking at their pieces, and so
+// are peers we've told about every piece already.
+fn flush_haves(state: &mut MainState) {
+    if state.unannounced.is_empty() {
+        return;
+    }
+    let pieces = std::mem::take(&mut state.unannounced);
+    trace!("Sending Have for pieces {:?}", pieces);
+
+    let mut dead = Vec::new();
+    for (&addr, peer_info) in &mut state.peers {
+        let covered = std::mem::take(&mut peer_info.bitfield_covers);
+        if peer_info.is_seed || peer_info.pieces_told >= state.piece_count {
+            continue;
+        }
+        debug_assert!(peer_info.sent_bitfield, "Have sent before the Bitfield");
+
+        // don't send to peers who already have the piece, or heard about it in our Bitfield
+        let haves: Vec<Message> = pieces[covered..]
+            .iter()
+            .filter(|&&piece| !peer_info.has.get(piece))
+            .map(|&piece| Message::Have(piece as u32))
+            .collect();
+        if haves.is_empty() {
+            continue;
+        }
+        peer_info.pieces_told = (peer_info.pieces_told + haves.len()).min(state.piece_count);
+
+        if peer_info
+            .sender
+            .send(PeerRequest::SendBatch(haves))
+            .is_err()
+        {
+            dead.push(addr);
+        }
+    }
+
+    for addr in dead {
+        warn!("Peer {:?} appears to have died, removing it", addr);
+        remove_peer(state, addr, Disconnect::Died);
+    }
+}
+
+// Past the download cap, or with the disk full, there is nothing we want from anyone, whatever
+// they have
+fn rescan_interest(
+    my_has: &BitVec<u8, Msb0>,
+    capped: bool,
+    peer_info: &mut PeerInfo,
+    addr: SocketAddr,
+) -> Result<()> {
+    let interested = !capped && peer_info.has.has_any_missing_from(my_has);
+    if interested != peer_info.interested {
+        peer_info.interested = interested;
+
+        // Tell the peer about this change
+        let msg = PeerRequest::SendMessage(if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        });
+        trace!(
+            "Interest state for peer {:?} changed to {:?}",
+            addr,
+            interested
+        );
+        peer_info.sender.send(msg)?;
+    }
+
+    Ok(())
+}
+
+// Recompute interest for every peer marked dirty since the last flush.
+// A burst of Haves from one peer costs at most one Interested, and a single rescan while main
+// is too backed up to refill pipelines.
+fn flush_interest(state: &mut MainState) {
+    for addr in std::mem::take(&mut state.interest_dirty) {
+        // the peer may have gone away in the meantime
+        let Some(peer_info) = state.peers.get_mut(&addr) else {
+            continue;
+        };
+
+        let capped = state.caps_reached.download || state.disk_full.is_some();
+        if let Err(e) = rescan_interest(state.file.bitvec(), capped, peer_info, addr) {
+            warn!("Failed to update interest for peer {:?}: {:?}", addr, e);
+        }
+    }
+}
+
+// Count a protocol violation against the peer.
+// Returns whether it should be logged, which stops being the case once the peer keeps at it.
+fn report(
+    log_limiter: &mut LogLimiter,
+    peer_info: &mut PeerInfo,
+    addr: SocketAddr,
+    kind: PeerWarning,
+) -> bool {
+    peer_info.misbehavior += misbehavior::weight(kind);
+    log_limiter.allow(addr, kind)
+}
+
+// Disconnect and ban the peer if it has racked up too many violations, or sent part of too
+// many bad pieces
+fn ban_if_misbehaving(state: &mut MainState, addr: SocketAddr) {
+    let Some(peer_info) = state.peers.get(&addr) else {
+        return;
+    };
+
+    if peer_info.misbehavior >= misbehavior::BAN_THRESHOLD {
+        warn!(
+            "Peer {:?} reached misbehavior score {}, banning it",
+            addr, peer_info.misbehavior
+        );
+    } else if peer_info.hash_strikes >= misbehavior::HASH_FAILURE_STRIKES {
+        warn!(
+            "Peer {:?} sent part of {} pieces that failed their hash check, banning it",
+            addr, peer_info.hash_strikes
+        );
+    } else {
+        return;
+    }
+    state.bans.ban(&peer_info.key(addr), Instant::now());
+    state.stats.misbehavior_bans += 1;
+    remove_peer(state, addr, Disconnect::Banned);
+}
+
+// Counts `msg` towards the peer's message rates. Returns whether to handle it, which stops
+// being the case while the peer sends more of a kind that is cheap to send but not to handle
+// than its cap allows. A peer far over the cap is disconnected.
+fn admit(state: &mut MainState, addr: SocketAddr, msg: &Message) -> bool {
+    // handle_message deals with peers we don't know
+    let Some(peer_info) = state.peers.get_mut(&addr) else {
+        return true;
+    };
+
+    let count = peer_info.message_rates.record(msg.kind(), Instant::now());
+    let cap = match msg {
+        Message::Have(_) => state.args.max_have_rate,
+        Message::Request(..) => state.args.max_request_rate,
+        _ => return true,
+    };
+    match Flood::of(count, cap) {
+        Flood::Within => true,
+        Flood::Over => {
+            // only the first message over the cap each second counts against the peer
+            if count == cap + 1
+                && report(
+                    &mut state.log_limiter,
+                    peer_info,
+                    addr,
+                    PeerWarning::MessageFlood,
+                )
+            {
+                warn!(
+                    "Peer {:?} sent over {} {} messages in a second, ignoring the rest",
+                    addr,
+                    cap,
+                    msg.kind()
+                );
+            }
+            state.stats.flood_ignored += 1;
+            false
+        }
+        Flood::Disconnect => {
+            warn!(
+                "Peer {:?} sent over {} {} messages in a second ({:.0} a second before that), disconnecting",
+                addr,
+                cap * misbehavior::FLOOD_DISCONNECT_FACTOR,
+                msg.kind(),
+                peer_info.message_rates.rate(msg.kind())
+            );
+            state.stats.flood_disconnects += 1;
+            remove_peer(state, addr, Disconnect::Flooded);
+            false
+        }
+    }
+}
+
+fn handle_peer_response(state: &mut MainState, resp: PeerResponse) -> Result<()> {
+    match resp {
+        PeerResponse::MessageReceived(addr, msg) => {
+            if !admit(state, addr, &msg) {
+                ban_if_misbehaving(state, addr);
+                return Ok(());
+            }
+            let result = handle_message(state, addr, msg);
+            ban_if_misbehaving(state, addr);
+            result
+        }
+        PeerResponse::InvalidMessage(addr, kind) => {
+            if let Some(peer_info) = state.peers.get_mut(&addr) {
+                if report(
+                    &mut state.log_limiter,
+                    peer_info,
+                    addr,
+                    PeerWarning::MalformedMessage,
+                ) {
+                    warn!("Peer {:?} sent malformed {} message", addr, kind);
+                }
+            }
+            ban_if_misbehaving(state, addr);
+            Ok(())
+        }
+        PeerResponse::SupportsExtensions(addr) => {
+            if let Some(peer_info) = state.peers.get(&addr) {
+                let handshake = extension::Handshake::ours(&state.metadata);
+                let msg = Message::Extended(extension::HANDSHAKE_ID, handshake.encode());
+                if peer_info
+                    .sender
+                    .send(PeerRequest::SendMessage(msg))
+                    .is_err()
+                {
+                    remove_peer(state, addr, Disconnect::Died);
+                }
+            }
+            Ok(())
+        }
+        PeerResponse::Congested(addr) => {
+            info!("Peer {:?} isn't reading what we send, choking it", addr);
+            if !choke::set_congested(state, addr, true) {
+                remove_peer(state, addr, Disconnect::Died);
+            }
+            Ok(())
+        }
+        PeerResponse::Drained(addr) => {
+            info!("Peer {:?} has caught up with what we sent", addr);
+            choke::set_congested(state, addr, false);
+            Ok(())
+        }
+        PeerResponse::Death(addr, hangup) => {
+            warn!(
+                "Peer thread for {:?} gave up ({}), removing peer",
+                addr, hangup
+            );
+            remove_hung_up_peer(state, addr, Disconnect::Died, Some(&hangup));
+            if hangup.reset {
+                state
+                    .address_watch
+                    .peer_reset(Instant::now(), state.peers.len());
+            }
+            Ok(())
+        }
+        _ => {
+            warn!("handle_peer_response(): received unhandled response type");
+            Ok(())
+        }
+    }
+}
+
+fn handle_message(state: &mut MainState, addr: SocketAddr, msg: Message) -> Result<()> {
+    let Some(peer_info) = state.peers.get_mut(&addr) else {
+        bail!("Main thread has no context for peer {:?}", addr);
+    };
+    let sink = peer_info.sender.clone();
+
+    use peers::Message::*;
+    let handled = match msg {
+        Choke => {
+            info!("Peer {:?} has choked us", addr);
+            handlers::on_choke(peer_info);
+            Ok(())
+        }
+        Unchoke => {
+            info!("Peer {:?} has unchoked us", addr);
+            handlers::on_unchoke(peer_info);
+            Ok(())
+        }
+        Interested => {
+            info!("Peer {:?} is interested in us", addr);
+            handlers::on_interested(peer_info);
+
+            if !choke::unchoke_if_free(state, addr) {
+                remove_peer(state, addr, Disconnect::Died);
+            }
+            Ok(())
+        }
+        NotInterested => {
+            handlers::on_not_interested(peer_info);
+            Ok(())
+        }
+        Have(piece) => handlers::on_have(
+            peer_info,
+            piece,
+            state.piece_count,
+            &mut state.availability,
+            &mut state.seeds,
+        )
+        .inspect(|_| {
+            state.interest_dirty.insert(addr);
+        }),
+        Bitfield(bytes) => handlers::on_bitfield(
+            peer_info,
+            &bytes,
+            state.piece_count,
+            &mut state.availability,
+            &mut state.seeds,
+        )
+        .inspect(|_| {
+            state.interest_dirty.insert(addr);
+        }),
+        Piece(piece, offset, data) => {
+            let block = Block::new(piece as usize, offset as usize, &data).sent_by(addr);
+            let handled = handlers::on_piece(
+                peer_info,
+                addr,
+                block,
+                &mut state.requested,
+                &state.timer_sender,
+                &mut state.file,
+                &mut state.stats,
+            );
+            // we may have just run out of things to want from this peer
+            if handled.is_ok() {
+                state.interest_dirty.insert(addr);
+            }
+
+            handled.map(|_| ())
+        }
+        Request(piece, offset, length)
+            if upload_backlog(&state.queued_uploads, &state.file)
+                >= state.args.max_queued_upload_bytes =>
+        {
+            debug!(
+                " --> deferring request from {:?}: {} {}+{}",
+                addr, piece, offset, length
+            );
+            state
+                .deferred_uploads
+                .push_back((addr, piece, offset, length));
+            Ok(())
+        }
+        Request(piece, offset, length) => {
+            info!(
+                " --> request from {:?}: {} {}+{}",
+                addr, piece, offset, length
+            );
+            let allowance = caps::upload_allowance(&state.args, &state.stats, peer_info);
+            handlers::on_request(
+                peer_info,
+                addr,
+                piece,
+                offset,
+                length,
+                allowance,
+                &mut state.file,
+                &sink,
+            )
+        }
+        Extended(id, payload) => {
+            handlers::on_extended(peer_info, id, &payload, &state.metadata, &sink)
+        }
+
+        // nothing of ours is queued on the peer's side to cancel
+        Cancel(_, _, _) => Ok(()),
+
+        // ignore keepalives for now (we do our own timeouts)
+        Keepalive => Ok(()),
+    };
+
+    match handled {
+        Ok(()) => Ok(()),
+        Err(HandlerError::Violation(kind, what)) => {
+            if let Some(peer_info) = state.peers.get_mut(&addr) {
+                if report(&mut state.log_limiter, peer_info, addr, kind) {
+                    warn!("Peer {:?} {}", addr, what);
+                }
+            }
+            Ok(())
+        }
+        Err(HandlerError::Other(e)) => Err(e),
+    }
+}
+
+// Handles the disk thread's report on something main asked of it
+pub(crate) fn handle_disk_response(state: &mut MainState, resp: DiskResponse) -> Result<()> {
+    match resp {
+        DiskResponse::Verified(piece) => {
+            // only count data towards what we've downloaded once it is verified
+            state.stats.downloaded += state.file.verified(piece);
+            state
+                .events
+                .record(EventKind::PieceVerified, None, Some(piece));
+            state.progress.emit(Line::PieceComplete { piece });
+
+            // Peers hear about it once the current burst of messages has been handled, along
+            // with any other pieces completed in it
+            if !state.unannounced.contains(&piece) {
+                state.unannounced.push(piece);
+            }
+
+            state.unsaved_pieces += 1;
+            if state.unsaved_pieces == state.args.resume_every {
+                save_resume(state);
+            }
+
+            // we may have just run out of things to want from peers that have it
+            for (&addr, peer_info) in &state.peers {
+                if peer_info.has.get(piece) {
+                    state.interest_dirty.insert(addr);
+                }
+            }
+            Ok(())
+        }
+        DiskResponse::HashFailed {
+            piece,
+            contributors,
+        } => {
+            debug!("Piece {} failed its hash check", piece);
+            state.file.reset(piece);
+            state
+                .events
+                .record(EventKind::PieceFailed, None, Some(piece));
+
+            // the peers that are still around are all suspects
+            for addr in contributors {
+                let Some(peer_info) = state.peers.get_mut(&addr) else {
+                    continue;
+                };
+                peer_info.hash_strikes += 1;
+                if report(
+                    &mut state.log_limiter,
+                    peer_info,
+                    addr,
+                    PeerWarning::HashFailed,
+                ) {
+                    warn!(
+                        "Peer {:?} sent part of piece {}, which failed its hash check",
+                        addr, piece
+                    );
+                }
+                ban_if_misbehaving(state, addr);
+            }
+            Ok(())
+        }
+        DiskResponse::Read {
+            addr,
+            block,
+            generation,
+            data,
+        } => {
+            state.file.read_done(&block);
+            let data = match data {
+                Ok(data) => data,
+                Err(e) if e.is_fatal() => return Err(e.into()),
+                // a recheck may have reset the piece since the peer asked
+                Err(e) => {
+                    debug!("Not sending {:?} to {:?}: {}", block, addr, e);
+                    return Ok(());
+                }
+            };
+
+            // or reset it after the read, in which case what was read may be what was wrong
+            if !state.file.is_current(block.piece, generation) {
+                debug!(
+                    "Not sending {:?} to {:?}, as its piece was reset since it was read",
+                    block, addr
+                );
+                return Ok(());
+            }
+
+            let Some(peer_info) = state.peers.get_mut(&addr) else {
+                return Ok(());
+            };
+            let sink = peer_info.sender.clone();
+            let queued = &state.queued_uploads;
+            if handlers::on_block_read(peer_info, &block, data, &mut state.stats, queued, &sink)
+                .is_err()
+            {
+                remove_peer(state, addr, Disconnect::Died);
+            }
+            Ok(())
+        }
+        DiskResponse::Rechecked(invalidated) => rechecked(state, invalidated?),
+        DiskResponse::FlushFailed(pieces) => {
+            unflushed(state, &pieces);
+            Ok(())
+        }
+        DiskResponse::NoSpace { piece, error } => {
+            state.file.reset(piece);
+            if state.disk_full.is_none() {
+                warn!("{}; no longer requesting until some is freed", error);
+                state.disk_full = Some(DiskFull::new(Instant::now()));
+                state.interest_dirty.extend(state.peers.keys());
+            }
+            Ok(())
+        }
+        DiskResponse::FreeSpace(free) => {
+            space_probed(state, free);
+            Ok(())
+        }
+        DiskResponse::WriteFailed(e) => Err(e.into()),
+    }
+}
+
+// Carries on downloading once the disk has room for a whole piece again, or waits longer
+// before checking again
+fn space_probed(state: &mut MainState, free: Result<usize, FileError>) {
+    let Some(disk_full) = &mut state.disk_full else {
+        return;
+    };
+    let needed = state.file.piece_range(0).map_or(0, |range| range.len());
+    match free {
+        Ok(free) if free >= needed => {
+            info!(
+                "{} free on disk again, resuming the download",
+                units::format_size(free)
+            );
+            state.disk_full = None;
+            state.interest_dirty.extend(state.peers.keys());
+        }
+        Ok(free) => {
+            debug!("Disk still full, with {} free", units::format_size(free));
+            disk_full.still_full(Instant::now());
+        }
+        Err(e) => {
+            warn!("Failed to check for free disk space: {}", e);
+            disk_full.still_full(Instant::now());
+        }
+    }
+}
+
+// Piece data on its way to peers: read but not yet written, or still being read
+fn upload_backlog(queued: &QueuedUploads, file: &Disk) -> usize {
+    queued.bytes() + file.reading()
+}
+
+// How many pieces are partway done, and the few furthest along, e.g. "12, 371 at 80%, 5 at 50%"
+fn pieces_in_progress(file: &FileMap, furthest: usize) -> Option<String> {
+    let mut partial: Vec<(usize, usize)> = file
+        .incomplete_pieces()
+        .map(|(piece, (filled, total))| (piece, filled * 100 / total))
+        .collect();
+    if partial.is_empty() {
+        return None;
+    }
+
+    partial.sort_by_key(|&(piece, percent)| (std::cmp::Reverse(percent), piece));
+    let shown: Vec<String> = partial
+        .iter()
+        .take(furthest)
+        .map(|(piece, percent)| format!("{} at {}%", piece, percent))
+        .collect();
+    Some(format!("{}, {}", partial.len(), shown.join(", ")))
+}
+
+// Serves the requests put off by --max-queued-upload-bytes, for as long as there is room
+fn resume_uploads(state: &mut MainState) {
+    while upload_backlog(&state.queued_uploads, &state.file) < state.args.max_queued_upload_bytes {
+        let Some((addr, piece, offset, length)) = state.deferred_uploads.pop_front() else {
+            return;
+        };
+
+        // a peer choked since it asked isn't owed anything, nor to blame for asking
+        if state.peers.get(&addr).is_none_or(|p| p.choked) {
+            continue;
+        }
+        // already counted towards the peer's Request rate when it first arrived
+        let request = Message::Request(piece, offset, length);
+        if let Err(e) = handle_message(state, addr, request) {
+            debug!("Failed to serve deferred request from {:?}: {:?}", addr, e);
+        }
+    }
+}
+
+// Sends as many requests as the strategy picks. Interest is settled first, so that every peer
+// we have something to ask of hears Interested before its first Request.
+fn refill_pipelines(state: &mut MainState, rng: &mut impl Rng) {
+    flush_interest(state);
+
+    let requests = strategy::pick_blocks(state, rng);
+    for (block, addr) in requests {
+        let Some(peer_info) = state.peers.get_mut(&addr) else {
+            continue;
+        };
+
+        let now = Instant::now();
+        if peer_info.waiting_since.is_none() {
+            peer_info.waiting_since = Some(now);
+        }
+        peer_info
+            .sent_at
+            .insert((block.piece, block.range.start), now);
+
+        // Try to send the request to the peer, which must already know we're interested
+        debug_assert!(
+            peer_info.interested,
+            "requesting {:?} from {:?}, which we aren't interested in",
+            block, addr
+        );
+        let msg = PeerRequest::SendMessage(Message::Request(
+            block.piece as u32,
+            block.range.start as u32,
+            (block.range.end - block.range.start) as u32,
+        ));
+        if peer_info.sender.send(msg).is_err() {
+            warn!(
+                "Main: peer {:?} appears to have died. Removing from peer context map...",
+                addr
+            );
+            remove_peer(state, addr, Disconnect::Died);
+            continue;
+        }
+
+        // Associate a timer with the request
+        let id: u64 = state.rng.gen();
+        let timer_req = TimerRequest::Timer(TimerInfo {
+            timer_len: state.args.request_timeout,
+            id,
+            repeat: false,
+        });
+        state
+            .timer_sender
+            .send(timer_req)
+            .expect("Main thread failed to communicate with timer thread!");
+
+        // Add to the requests queue
+        state.requested.insert(id, (block, addr));
+    }
+}
+
+fn rechecked(state: &mut MainState, invalidated: Vec<usize>) -> Result<()> {
+    for &piece in &invalidated {
+        state.file.reset(piece);
+    }
+
+    if invalidated.is_empty() {
+        info!("Recheck complete: all pieces verified");
+        return Ok(());
+    }
+
+    warn!(
+        "Recheck invalidated {} piece(s): {:?}",
+        invalidated.len(),
+        invalidated
+    );
+
+    // We can't take back Haves we already sent, but peers that have the
+    // invalidated pieces may have become interesting again
+    state.interest_dirty.extend(state.peers.keys());
+
+    Ok(())
+}
+
+// Waits for the disk thread to flush the file. Returns false if that failed, in which case the
+// pieces it threw away are downloaded again.
+fn flush(state: &mut MainState) -> Result<bool> {
+    match state.file.flush() {
+        Ok(()) => Ok(true),
+        Err(FileError::FlushFailed { pieces, source }) => {
+            warn!(
+                "Failed to flush the file to disk, so {} piece(s) will be downloaded again: {}",
+                pieces.len(),
+                source
+            );
+            unflushed(state, &pieces);
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Resets the pieces the disk thread threw away when a flush failed
+fn unflushed(state: &mut MainState, pieces: &[usize]) {
+    for &piece in pieces {
+        state.file.reset(piece);
+        state
+            .events
+            .record(EventKind::PieceFailed, None, Some(piece));
+    }
+
+    // as with a recheck, Haves already sent stay sent
+    state.interest_dirty.extend(state.peers.keys());
+}
+
+/// Whether an error means the session can't go on, as opposed to a problem with one peer
+fn is_fatal(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<FileError>()
+        .is_some_and(FileError::is_fatal)
+}
+
+// What --on-error is told about a fatal error
+fn error_category(e: &anyhow::Error) -> &'static str {
+    match e.downcast_ref::<FileError>() {
+        Some(FileError::Io(_)) => "io",
+        Some(FileError::Truncated) => "truncated",
+        _ => "other",
+    }
+}
+
+// Bring the upload and protocol overhead totals up to date with what the peer threads have
+// counted, crediting the difference to the rates
+fn update_traffic(stats: &mut Stats, traffic: &TrafficCounter) {
+    let uploaded = traffic.sent().payload;
+    stats.upload_rate.record(uploaded - stats.uploaded);
+    stats.uploaded = uploaded;
+
+    let sent = traffic.sent().protocol;
+    let received = traffic.received().protocol;
+    stats
+        .protocol_upload_rate
+        .record(sent - stats.protocol_sent);
+    stats
+        .protocol_download_rate
+        .record(received - stats.protocol_received);
+    stats.protocol_sent = sent;
+    stats.protocol_received = received;
+}
+
+// Catches up on the piece data the peer's thread has written out to it since the last tick
+fn update_peer_traffic(peer_info: &mut PeerInfo) {
+    let downloaded = peer_info.traffic.sent().payload;
+    let written = downloaded - peer_info.downloaded;
+    peer_info.downloaded = downloaded;
+    peer_info.downloaded_recently += written;
+    peer_info.download_rate.record(written);
+}
+
+// Waits for the next thing for the main thread to handle. Peers hanging up go first, so the
+// requests they held are handed out again without waiting behind a backlog.
+fn next_response(hangups: &Receiver<Response>, rx: &Receiver<Response>) -> Option<Response> {
+    if let Ok(resp) = hangups.try_recv() {
+        return Some(resp);
+    }
+    channel::select! {
+        recv(hangups) -> resp => resp.ok(),
+        recv(rx) -> resp => resp.ok(),
+    }
+}
+
+/// Record how many events are waiting on the main thread.
+/// Returns true if we are over the soft limit and should skip work that can wait.
+fn record_channel_depth(state: &mut MainState, depth: usize) -> bool {
+    state.stats.channel_depth = depth;
+    state.stats.max_channel_depth = state.stats.max_channel_depth.max(depth);
+
+    depth > state.args.channel_soft_limit
+}
+
+// Has the disk thread save the resume file, once it has written everything sent so far
+fn save_resume(state: &mut MainState) {
+    let path = resume::path(state.args.state_dir(), &state.info_hash);
+    state.file.save_resume(path, state.info_hash);
+    state.unsaved_pieces = 0;
+}
+
+// Checks whether our listen port is reachable, if we have a way to. Without a check service,
+// that waits for a tracker to tell us our external address.
+fn start_port_check(state: &mut MainState, external_ip: Option<IpAddr>, tx: &Sender<Response>) {
+    let url = state.args.port_check_url.as_deref();
+    let Some(check) = portcheck::choose(url, external_ip, state.port) else {
+        debug!("Can't check the listen port until a tracker tells us our external IP");
+        return;
+    };
+
+    debug!("Checking listen port {} with {:?}", state.port, check);
+    portcheck::spawn_port_check(tx.clone(), check, state.port);
+    state.port_check_started = true;
+}
+
+// The trackers to announce to, from the torrent and the command line
+fn tracker_tiers(args: &Args, metainfo: &MetaInfo) -> Result<Vec<Vec<String>>> {
+    if metainfo.is_private() && !args.trackers.is_empty() && !args.force {
+        bail!(
+            "The torrent is private, so its peers are only meant to be shared with its own \
+             trackers. Pass --force to add trackers anyway"
+        );
+    }
+
+    Ok(announce::add_tiers(
+        metainfo.tiers(),
+        &args.trackers,
+        args.replace_trackers,
+    ))
+}
+
+/// A single torrent being downloaded and/or seeded
+pub struct Session {
+    args: Args,
+    metainfo: MetaInfo<'static>,
+    tiers: Vec<Vec<String>>,
+
+    // None with --no-listen
+    listener: Option<TcpListener>,
+    rngs: RngSource,
+
+    // this is how each thread will communicate back with main thread
+    tx: Sender<Response>,
+    rx: Receiver<Response>,
+
+    // handed to the main thread, along with everyone subscribed to it
+    events: Events,
+
+    // what we want of the connections a scheduler shares between torrents, if there is one
+    demand: Option<Arc<Demand>>,
+}
+
+impl Session {
+    /// Sets up a session, binding its listening socket unless told not to listen.
+    /// No threads are spawned until [Session::run] is called.
+    pub fn new(args: Args, metainfo: MetaInfo<'static>) -> Result<Self> {
+        metainfo.validate(args.max_piece_size)?;
+        let tiers = tracker_tiers(&args, &metainfo)?;
+        for (i, tier) in tiers.iter().enumerate() {
+            info!("Tracker tier {}: {}", i, tier.join(", "));
+        }
+
+        let rngs = RngSource::new(args.seed_rng);
+        let listener = if args.no_listen {
+            info!("Not listening for peers, so only outgoing connections will be made");
+            if args.check_port {
+                warn!("Ignoring --check-port, as there is no listen port to check");
+            }
+            None
+        } else {
+            // the last session's port keeps us where trackers and peers last saw us, if it's free
+            let last_port = LastRun::load(&last_run::path(args.state_dir(), &metainfo.info_hash()))
+                .map(|last| last.port)
+                .filter(|&port| port != 0 && args.port.is_none());
+            let reused = last_port.and_then(|port| match TcpListener::bind(("0.0.0.0", port)) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    info!("Port {} from the last session isn't free: {}", port, e);
+                    None
+                }
+            });
+            let listener = match reused {
+                Some(listener) => listener,
+                None => {
+                    let port = args
+                        .port
+                        .unwrap_or_else(|| rngs.derive("port").gen_range(1025..65535));
+                    TcpListener::bind(("0.0.0.0", port))?
+                }
+            };
+            info!(
+                "Listening for peers on port {}. Outgoing connections use ephemeral source ports",
+                listener.local_addr()?.port()
+            );
+            Some(listener)
+        };
+        let (tx, rx) = channel::bounded(MAIN_CHANNEL_CAPACITY);
+
+        Ok(Self {
+            args,
+            metainfo,
+            tiers,
+            listener,
+            rngs,
+            tx,
+            rx,
+            events: Events::default(),
+            demand: None,
+        })
+    }
+
+    /// Subscribes to what happens in the session once it runs, such as pieces completing,
+    /// peers coming and going, and announces. Up to `capacity` events wait to be taken, past
+    /// which the oldest are dropped, so a subscriber that falls behind never holds the session
+    /// up. See [crate::events].
+    pub fn subscribe(&mut self, capacity: usize) -> Subscription {
+        self.events.subscribe(capacity)
+    }
+
+    /// Address we are accepting peer connections on, which is an error with --no-listen
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let listener = self.listener.as_ref().context("Not listening for peers")?;
+        Ok(listener.local_addr()?)
+    }
+
+    /// Has the session share connections with other torrents: it keeps `demand` up to date
+    /// with what it wants, and takes its allocations from the returned sender.
+    /// See [crate::scheduler].
+    pub(crate) fn share(&mut self, demand: Arc<Demand>) -> Sender<Response> {
+        self.demand = Some(demand);
+        self.tx.clone()
+    }
+
+    /// Starts the thread that turns control signals into commands for this session.
+    /// This must be called before any other threads are spawned, so that only that thread
+    /// receives the signals.
+    pub fn spawn_signal_thread(&self) -> Result<()> {
+        control::spawn_signal_thread(vec![self.tx.clone()])
+    }
+
+    /// Runs the session until the download completes (or forever, when seeding)
+    pub fn run(self) -> Result<()> {
+        let Session {
+            args,
+            metainfo,
+            tiers,
+            listener,
+            rngs,
+            tx,
+            rx,
+            events,
+            demand,
+        } = self;
+
+        let (tracker_sender, _) =
+            tracker::spawn_tracker_thread(tx.clone(), args.max_tracker_response);
+
+        // timer thread to handle block timeouts and periodic game theory
+        let (timer_sender, _) = spawn_timer_thread(tx.clone());
+
+        // peer threads that hang up say so here, so main hears even when it's backed up
+        let (hangup_sender, hangup_rx) = channel::unbounded();
+
+        // create main thread state
+        let hashes = metainfo.piece_hashes();
+        let name = metainfo.name();
+        let path = args.output_dir.join(&name.file_name);
+        if !args.seed_existing {
+            // a finished download would have nowhere to go
+            if path.exists() {
+                bail!(
+                    "{} already exists; pass --seed-existing to seed it",
+                    path.display()
+                );
+            }
+            fs::create_dir_all(&args.output_dir)
+                .with_context(|| format!("Failed to create {}", args.output_dir.display()))?;
+        }
+        let mut peer_id = [0u8; PEER_ID_LEN];
+        rngs.derive("peer_id").fill_bytes(&mut peer_id);
+
+        let hasher = || -> Box<dyn PieceHasher> {
+            let sha1 = Box::new(Sha1PieceHasher::default());
+            if args.uncached_verify {
+                Box::new(Uncached(sha1))
+            } else {
+                sha1
+            }
+        };
+        let mut file = if args.read_only {
+            DownloadFile::new_seeding_read_only(
+                &path,
+                &hashes,
+                metainfo.info.piece_length,
+                metainfo.info.length,
+                hasher(),
+                args.verify_existing,
+            )?
+        } else if args.seed_existing {
+            DownloadFile::new_seeding(
+                &path,
+                &hashes,
+                metainfo.info.piece_length,
+                metainfo.info.length,
+                hasher(),
+                args.verify_existing,
+            )?
+        } else {
+            DownloadFile::new_fast_resume(
+                file::part_path(&path),
+                &resume::path(args.state_dir(), &metainfo.info_hash()),
+                &metainfo.info_hash(),
+                &hashes,
+                metainfo.info.piece_length,
+                metainfo.info.length,
+                hasher(),
+            )?
+        };
+        if args.mmap {
+            file.map_into_memory()?;
+        }
+        if let Some(source) = args.import_from.as_ref().filter(|_| !args.seed_existing) {
+            let imported = file.import_from(source)?;
+            info!("From {}: {}", source.display(), imported);
+        }
+        if args.preallocate {
+            file.preallocate()?;
+        }
+        file.set_cache_pieces(args.write_cache_pieces);
+        file.set_read_cache_bytes(args.read_cache_bytes);
+        file.set_max_block_len(args.max_block_size);
+        file.set_flush_policy(args.flush);
+        let mut state = MainState {
+            info_hash: metainfo.info_hash(),
+            peer_id,
+            piece_count: metainfo.piece_count(),
+            // announced to trackers, where 0 says there is nothing to connect to
+            port: match &listener {
+                Some(listener) => listener.local_addr()?.port(),
+                None => 0,
+            },
+            port_check_started: false,
+            web_seeds: WebSeeds::new(
+                metainfo
+                    .web_seeds()
+                    .iter()
+                    .map(|url| webseed::file_url(url, &name.display))
+                    .collect(),
+                metainfo.info.length,
+            ),
+            hooks: Hooks::new(&args, name.display, metainfo.info_hash(), path),
+
+            queued_uploads: Arc::default(),
+            deferred_uploads: VecDeque::new(),
+
+            // Map from SocketAddr->PeerInfo. Also serves as "list" of peers
+            peers: HashMap::new(),
+            seeds: 0,
+            availability: Availability::new(hashes.len()),
+            upload_slots: args.min_upload_slots,
+            phase: SessionPhase::Leeching,
+            metadata: metainfo.info_bytes(),
+            traffic: Arc::default(),
+            unannounced: Vec::new(),
+            unsaved_pieces: 0,
+
+            // File I/O subsystem context, which the disk thread takes over
+            file: Disk::spawn(file, tx.clone()),
+
+            timer_sender,
+            hangup_sender,
+
+            // queue of outgoing requests we are awaiting
+            requested: HashMap::new(),
+
+            // every tracker we know about, and when to next announce to it
+            trackers: Trackers::new(
+                tiers,
+                AnnounceMode::from_flags(args.announce_all_tiers, args.announce_all_trackers),
+                &mut rngs.derive("trackers"),
+            ),
+
+            // session-wide counters
+            stats: Stats {
+                download_cap: args.max_download_bytes,
+                upload_cap: args.max_upload_bytes,
+                web_seeds: metainfo.web_seeds().len(),
+                outgoing_only: args.no_listen,
+                ..Stats::default()
+            },
+            caps_reached: caps::Reached::default(),
+            disk_full: None,
+            events,
+            progress: if args.progress_json {
+                ProgressOutput::stdout()
+            } else {
+                ProgressOutput::default()
+            },
+
+            stream_position: None,
+
+            interest_dirty: HashSet::new(),
+
+            log_limiter: LogLimiter::default(),
+
+            bans: Bans::default(),
+            reconnects: Reconnects::default(),
+            dial_queue: DialQueue::default(),
+            demand,
+            allocation: None,
+            above_low_water: false,
+            stale_run: None,
+            address_watch: AddressWatch::default(),
+
+            rng: rngs.derive("session"),
+            #[cfg(test)]
+            _scratch_dir: None,
+
+            args,
+        };
+        state.phase = SessionPhase::of(&state.file);
+        state.stats.trackers = state.trackers.statuses();
+        let resumed = state.file.bitvec().count_ones();
+        if resumed > 0 && !state.args.seed_existing {
+            info!(
+                "Resuming with {} of {} pieces already on disk",
+                resumed, state.piece_count
+            );
+        } else if state.file.is_read_only() && resumed < state.piece_count {
+            warn!(
+                "Only {} of {} pieces of the file to seed match their hashes, and the file is \
+                 read-only, so the rest are skipped",
+                resumed, state.piece_count
+            );
+        } else if state.args.seed_existing && resumed < state.piece_count {
+            warn!(
+                "Only {} of {} pieces of the file to seed match their hashes, downloading the rest",
+                resumed, state.piece_count
+            );
+        }
+
+        let run_path = last_run::path(state.args.state_dir(), &state.info_hash);
+        state.stale_run = last_run::start(&run_path, state.port, state.peer_id);
+        if let Some(stale) = &state.stale_run {
+            info!(
+                "The last session, on port {}, didn't shut down cleanly, so trackers will be told \
+                 it stopped",
+                stale.port
+            );
+        }
+
+        // send initial starting request(s)
+        if !state.args.skip_announce {
+            let urls: Vec<String> = state
+                .trackers
+                .active()
+                .iter()
+                .map(|t| t.url.clone())
+                .collect();
+            for url in urls {
+                announce(&mut state, &tracker_sender, &url, None);
+            }
+        }
+
+        // Start listening. The guards wake the accept threads up when we return or panic,
+        // and dropping `state` on the way out disconnects every peer thread.
+        let mut _listener_guard = None;
+        let handshakes = HandshakeLimiter::new(state.args.max_handshaking);
+        if let Some(listener) = listener {
+            _listener_guard = Some(ListenerGuard::new(&listener)?);
+            connections::spawn_accept_thread(
+                listener,
+                tx.clone(),
+                handshakes.clone(),
+                state.args.accept_rate,
+            );
+        }
+
+        let mut _stream_guard = None;
+        if let Some(port) = state.args.stream_port {
+            let stream_listener = TcpListener::bind(("127.0.0.1", port))?;
+            info!(
+                "Streaming file at http://{}/file",
+                stream_listener.local_addr()?
+            );
+            _stream_guard = Some(ListenerGuard::new(&stream_listener)?);
+            stream::spawn_stream_thread(stream_listener, metainfo.info.length, tx.clone());
+        }
+
+        // periodically drop our worst peers
+        let cull_timer_id: u64 = state.rng.gen();
+        state
+            .timer_sender
+            .send(TimerRequest::Timer(TimerInfo {
+                timer_len: announce::ANNOUNCE_INTERVAL,
+                id: cull_timer_id,
+                repeat: true,
+            }))
+            .expect("Main thread failed to communicate with timer thread!");
+
+        // periodically decide who we upload to
+        let choke_timer_id: u64 = state.rng.gen();
+        state
+            .timer_sender
+            .send(TimerRequest::Timer(TimerInfo {
+                timer_len: choke::CHOKE_INTERVAL,
+                id: choke_timer_id,
+                repeat: true,
+            }))
+            .expect("Main thread failed to communicate with timer thread!");
+
+        // housekeeping that needs to happen every second
+        let tick_timer_id: u64 = state.rng.gen();
+        state
+            .timer_sender
+            .send(TimerRequest::Timer(TimerInfo {
+                timer_len: Duration::from_secs(1),
+                id: tick_timer_id,
+                repeat: true,
+            }))
+            .expect("Main thread failed to communicate with timer thread!");
+
+        // every so often, check whether the download has got stuck
+        let stall_timer_id: u64 = state.rng.gen();
+        state
+            .timer_sender
+            .send(TimerRequest::Timer(TimerInfo {
+                timer_len: stall::STALL_CHECK_INTERVAL,
+                id: stall_timer_id,
+                repeat: true,
+            }))
+            .expect("Main thread failed to communicate with timer thread!");
+        let mut stall_watch = StallWatch::new(state.downloaded(), Instant::now());
+
+        // --progress-json snapshots
+        let progress_timer_id: u64 = state.rng.gen();
+        if state.progress.is_enabled() {
+            state
+                .timer_sender
+                .send(TimerRequest::Timer(TimerInfo {
+                    timer_len: state.args.progress_interval,
+                    id: progress_timer_id,
+                    repeat: true,
+                }))
+                .expect("Main thread failed to communicate with timer thread!");
+        }
+
+        // Completed announces, held back a little once the download finishes. The session
+        // carries on as usual until the timer fires, rather than leaving peers waiting.
+        let completion_timer_id: u64 = state.rng.gen();
+        let mut completion_scheduled = false;
+        let mut completion_due = false;
+
+        // peers are asked for blocks in a shuffled order
+        let mut strategy_rng = rngs.derive("strategy");
+
+        // Add single peer (if provided)
+        if let Some(peer) = &state.args.add_peer {
+            let addr = peer.to_socket_addrs().unwrap().next().unwrap();
+            connections::async_connect(tx.clone(), addr);
+        }
+
+        // DHT bootstrap nodes are often peers of the torrent as well
+        if state.args.connect_nodes && !metainfo.is_private() {
+            for (host, port) in metainfo.nodes() {
+                match (host.as_str(), *port).to_socket_addrs() {
+                    Ok(mut addrs) => {
+                        if let Some(addr) = addrs.next() {
+                            connections::async_connect(tx.clone(), addr);
+                        }
+                    }
+                    Err(e) => warn!("Failed to resolve DHT node {}: {}", host, e),
+                }
+            }
+        }
+
+        // Main loop. A panic in it leaves a crash report behind on its way out.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<()> {
+            while let Some(resp) = next_response(&hangup_rx, &rx) {
+                let overloaded = record_channel_depth(&mut state, rx.len());
+
+                match resp {
+                    Response::Connection(data) => accept_connection(&mut state, data, &tx),
+                    Response::ConnectFailed(addr) => {
+                        let delay = state.reconnects.record(
+                            PeerKey::dialed(addr),
+                            Disconnect::ConnectFailed,
+                            Instant::now(),
+                        );
+                        debug!(
+                            "Not redialing {:?} for {}",
+                            addr,
+                            units::format_duration(delay)
+                        );
+                    }
+                    Response::Peer(data) => {
+                        if let Err(e) = handle_peer_response(&mut state, data) {
+                            if is_fatal(&e) {
+                                error!("Giving up on download: {:?}", e);
+                                state
+                                    .hooks
+                                    .fire(hooks::Event::Error(error_category(&e)), &state.stats);
+                                crash::dump(&state, error_category(&e));
+                                return Err(e);
+                            }
+                            error!("Failed to handle peer response: {:?}", e);
+                        }
+                    }
+                    Response::Control(ControlCommand::Recheck) => {
+                        info!("Rechecking all pieces...");
+                        state.file.recheck();
+                    }
+                    Response::Disk(data) => {
+                        if let Err(e) = handle_disk_response(&mut state, data) {
+                            if is_fatal(&e) {
+                                error!("Giving up on download: {:?}", e);
+                                state
+                                    .hooks
+                                    .fire(hooks::Event::Error(error_category(&e)), &state.stats);
+                                crash::dump(&state, error_category(&e));
+                                return Err(e);
+                            }
+                            error!("Failed to handle disk response: {:?}", e);
+                        }
+                    }
+                    Response::Stream(read) => stream::serve_read(&mut state, read),
+                    Response::Schedule(allocation) => apply_allocation(&mut state, allocation),
+                    Response::PortCheck(reachability) => {
+                        info!("Listen port {} {}", state.port, reachability);
+                        state.stats.port = Some(reachability);
+                    }
+                    Response::WebSeed(url, result) => {
+                        state.web_seeds.record(&url, result, Instant::now());
+                        state.stats.usable_web_seeds = state.web_seeds.usable().count();
+                    }
+                    Response::Tracker(url, Ok(data)) => {
+                        on_announced(&mut state, &tx, url, data);
+                    }
+                    Response::Tracker(url, Err(e)) => {
+                        error!("tracker {} failed with error: {:?}", url, e);
+                        state
+                            .events
+                            .record_detail(EventKind::AnnounceFailed, format!("{}: {}", url, e));
+                        state.progress.emit(Line::Announce {
+                            tracker: url.clone(),
+                            peers: None,
+                            error: Some(e.to_string()),
+                        });
+
+                        let failure =
+                            state
+                                .trackers
+                                .on_failure(&url, e.to_string(), e.is_permanent());
+                        state.stats.trackers = state.trackers.statuses();
+                        if let Some((next, delay)) = failure {
+                            if delay.is_zero() {
+                                announce(&mut state, &tracker_sender, &next, None);
+                            } else {
+                                schedule_announce(&state, &next, delay);
+                            }
+                        }
+                    }
+                    Response::Timer(data) if state.trackers.by_timer(data.id).is_some() => {
+                        // send periodic tracker request
+                        let url = state.trackers.by_timer(data.id).unwrap().url.clone();
+                        announce(&mut state, &tracker_sender, &url, None);
+                    }
+                    Response::Timer(data) if { data.id == tick_timer_id } => {
+                        trace!("Main channel depth: {}", state.stats.channel_depth);
+
+                        // ticks can arrive late or bunched up, so go by the actual time
+                        let now = Instant::now();
+                        update_traffic(&mut state.stats, &state.traffic);
+                        state.stats.queued_upload_bytes =
+                            upload_backlog(&state.queued_uploads, &state.file);
+                        state.stats.upload_rate.advance(now);
+                        state.stats.download_rate.advance(now);
+                        state.stats.protocol_upload_rate.advance(now);
+                        state.stats.protocol_download_rate.advance(now);
+                        debug!(
+                            "Rates: up {} ({} overhead), down {} ({} overhead)",
+                            units::format_rate(state.stats.upload_rate.rate()),
+                            units::format_rate(state.stats.protocol_upload_rate.rate()),
+                            units::format_rate(state.stats.download_rate.rate()),
+                            units::format_rate(state.stats.protocol_download_rate.rate())
+                        );
+
+                        if let Some(pieces) = pieces_in_progress(&state.file, 5) {
+                            debug!("Pieces in progress: {}", pieces);
+                        }
+
+                        // in case the channel never drains long enough for the usual flush
+                        flush_haves(&mut state);
+                        flush_interest(&mut state);
+
+                        for line in state.log_limiter.summarize(now) {
+                            warn!("{}", line);
+                        }
+
+                        for peer_info in state.peers.values_mut() {
+                            update_peer_traffic(peer_info);
+                            peer_info.upload_rate.advance(now);
+                            peer_info.download_rate.advance(now);
+                        }
+
+                        state.dial_queue.new_round();
+                        dial_queued(&mut state, &tx);
+                        if let Some(demand) = &state.demand {
+                            demand.update(state.peers.len(), state.dial_queue.len());
+                        }
+
+                        state.bans.decay(state.peers.values_mut(), now);
+                        state.web_seeds.probe_due(&tx, now);
+                        if let Some(disk_full) = &mut state.disk_full {
+                            if disk_full.probe_due(now) {
+                                state.file.probe_space();
+                            }
+                        }
+                        state.reconnects.expire(now);
+                        state.stats.seeds = state.seeds;
+                        state.stats.partial_peers = state.peers.len() - state.seeds;
+                        state.stats.distributed_copies =
+                            state.availability.distributed_copies(state.file.bitvec());
+                        debug!(
+                            "Distributed copies: {:.3}, pieces by number of copies: {:?}",
+                            state.stats.distributed_copies,
+                            state.availability.histogram(state.file.bitvec())
+                        );
+                        state.stats.handshaking = handshakes.in_progress();
+                        state.stats.handshakes_rejected = handshakes.rejected();
+                        state.stats.handshakes_expired = handshakes.expired();
+                        state.stats.unknown_infohash_connections = handshakes.unknown_info_hash();
+                        state.stats.worst_misbehavior = state
+                            .peers
+                            .values()
+                            .map(|p| p.misbehavior)
+                            .max()
+                            .unwrap_or(0);
+
+                        let timeout = state.args.snub_timeout;
+                        probation::check_snubbed(&mut state, Instant::now(), timeout);
+                    }
+                    Response::Timer(data) if { data.id == choke_timer_id } => {
+                        for addr in choke::choke_round(&mut state) {
+                            warn!("Peer {:?} appears to have died, removing it", addr);
+                            remove_peer(&mut state, addr, Disconnect::Died);
+                        }
+                    }
+                    Response::Timer(data) if { data.id == stall_timer_id } => {
+                        // a finished download isn't stuck, however long it goes without a piece
+                        let downloaded = match state.phase {
+                            SessionPhase::Leeching => state.downloaded(),
+                            SessionPhase::Seeding => usize::MAX,
+                        };
+                        let window = state.args.stall_window;
+                        if stall_watch.check(downloaded, Instant::now(), window) {
+                            let diagnosis = stall::diagnose(&state);
+                            warn!(
+                                "No progress in {}: {}",
+                                units::format_duration(window),
+                                diagnosis
+                            );
+                            state.stats.stalled = Some(diagnosis.reason);
+                        } else if !stall_watch.is_stalled() {
+                            state.stats.stalled = None;
+                        }
+                    }
+                    Response::Timer(data) if { data.id == completion_timer_id } => {
+                        completion_due = true;
+                    }
+                    Response::Timer(data) if { data.id == progress_timer_id } => {
+                        let line = Line::progress(&state);
+                        state.progress.emit(line);
+                    }
+                    Response::Timer(data) if { data.id == cull_timer_id } => {
+                        // this can wait until the backlog clears
+                        if overloaded {
+                            continue;
+                        }
+
+                        // keep top n peers
+                        let keep = state.max_connections() / 2;
+                        cull_peers(&mut state, keep);
+                    }
+                    Response::Timer(data) => {
+                        if let Some(&(_, addr)) = state.requested.get(&data.id) {
+                            debug!("Timeout occurred for peer {:?}", addr);
+
+                            // remove from requested queue
+                            state.requested.remove(&data.id);
+
+                            // should the peer come back, it starts out on probes
+                            let timeout = state.args.request_timeout;
+                            if let Some(peer_info) = state.peers.get_mut(&addr) {
+                                peer_info.latency.observe(timeout);
+                            }
+
+                            // actually remove the peer
+                            remove_peer(&mut state, addr, Disconnect::TimedOut);
+                        } else {
+                            warn!("Weird race condition thing?");
+                        }
+                    }
+                }
+
+                // wait for a burst of messages to be handled before announcing pieces and
+                // deciding on interest
+                if rx.is_empty() {
+                    flush_haves(&mut state);
+                    flush_interest(&mut state);
+                }
+                resume_uploads(&mut state);
+
+                check_phase(&mut state, &tracker_sender)?;
+                check_address(&mut state, &tracker_sender);
+                check_peer_pool(&mut state, &tracker_sender);
+                check_caps(&mut state);
+
+                if let Some(stop) = caps::should_stop(&state) {
+                    let event = match stop {
+                        Stop::Complete => {
+                            // trackers count us as a seed from here on, so the data had
+                            // better be on disk first
+                            if !flush(&mut state)? {
+                                continue;
+                            }
+
+                            // Everyone who grabbed a new release finishes it at about the
+                            // same time, so completions are spread out
+                            if !completion_due && state.trackers.started().next().is_some() {
+                                if !completion_scheduled {
+                                    completion_scheduled = true;
+                                    let delay = state.trackers.event_delay();
+                                    debug!("Announcing completion in {:?}", delay);
+                                    state
+                                        .timer_sender
+                                        .send(TimerRequest::Timer(TimerInfo {
+                                            timer_len: delay,
+                                            id: completion_timer_id,
+                                            repeat: false,
+                                        }))
+                                        .expect(
+                                            "Main thread failed to communicate with timer thread!",
+                                        );
+                                }
+                                continue;
+                            }
+                            info!("File download complete!");
+                            request::Event::Completed
+                        }
+                        Stop::DownloadCap => {
+                            info!("Download cap reached, exiting");
+                            request::Event::Stopped
+                        }
+                        Stop::UploadCap => {
+                            info!("Upload cap reached, exiting");
+                            request::Event::Stopped
+                        }
+                    };
+                    update_traffic(&mut state.stats, &state.traffic);
+                    info!("Session summary: {}", state.stats);
+                    let line = Line::progress(&state);
+                    state.progress.emit(line);
+
+                    // Tell every tracker that knows about us that we're done
+                    let urls: Vec<String> =
+                        state.trackers.started().map(|t| t.url.clone()).collect();
+                    for url in urls {
+                        announce(&mut state, &tracker_sender, &url, Some(event));
+                    }
+
+                    save_resume(&mut state);
+                    flush(&mut state)?;
+                    last_run::stop(&run_path);
+                    return Ok(());
+                }
+
+                // catching up on the backlog matters more than keeping pipelines full,
+                // and the next event will refill them anyway
+                if overloaded {
+                    state.stats.shed_passes += 1;
+                    continue;
+                }
+
+                // after handling event, refill pipelines
+                refill_pipelines(&mut state, &mut strategy_rng);
+            }
+
+            debug!("Exited from main loop");
+            save_resume(&mut state);
+            flush(&mut state)?;
+            last_run::stop(&run_path);
+
+            Ok(())
+        }));
+        match result {
+            Ok(Err(e)) => {
+                state
+                    .events
+                    .record_detail(EventKind::Fatal, format!("{:#}", e));
+                Err(e)
+            }
+            Ok(Ok(())) => Ok(()),
+            Err(panic) => {
+                state
+                    .events
+                    .record_detail(EventKind::Fatal, "panic".to_owned());
+                crash::dump(&state, "panic");
+                panic::resume_unwind(panic)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bitvec::prelude::*;
+    use clap::Parser;
+    use crossbeam::channel;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use rand::{rngs::StdRng, SeedableRng};
+    use sha1::{Digest, Sha1};
+
+    use crate::announce::{AnnounceMode, TrackerStatus, Trackers};
+    use crate::args::Args;
+    use crate::caps;
+    use crate::connections::ConnectionData;
+    use crate::events::EventKind;
+    use crate::extension::{self, Handshake, MetadataMessage, METADATA_PIECE_LEN};
+    use crate::file::{
+        Block, BlockInfo, DiskResponse, FileError, PieceState, DEFAULT_MAX_BLOCK_LEN,
+    };
+    use crate::last_run::{self, LastRun};
+    use crate::peer_key::PeerKey;
+    use crate::peers::{Message, PeerResponse};
+    use crate::piece_set::PieceSet;
+    use crate::progress::{Line, ProgressOutput};
+    use crate::scheduler::{Allocation, Demand};
+    use crate::stall::{self, StallReason};
+    use crate::test_utils::{
+        download_path, insert_peer, main_state, main_state_with_disk, peer_info, settle,
+    };
+    use crate::threads::Response;
+    use crate::timer::TimerRequest;
+    use crate::torrent::MetaInfo;
+    use crate::tracker::{request, response};
+    use crate::tracker_sim::{SimConfig, TrackerSim};
+
+    use super::{
+        accept_connection, announce, apply_allocation, check_address, check_caps, check_peer_pool,
+        check_phase, cull_peers, error_category, flush_haves, flush_interest, handle_disk_response,
+        handle_peer_response, is_connected, is_fatal, next_dials, on_announced,
+        record_channel_depth, refill_pipelines, rejection, remove_peer, resume_uploads,
+        start_port_check, tracker_tiers, update_peer_traffic, update_traffic, upload_backlog,
+        SessionPhase,
+    };
+    use crate::capture::Direction;
+    use crate::hangup::{Hangup, Side};
+    use crate::log_limiter::PeerWarning;
+    use crate::misbehavior::{self, BAN_THRESHOLD};
+    use crate::peers::PeerRequest;
+    use crate::portcheck::Reachability;
+    use crate::reconnect::Disconnect;
+    use crate::strategy::pick_blocks;
+
+    const PIECE_LEN: usize = 16384;
+
+    fn receive(state: &mut super::MainState, addr: SocketAddr, msg: Message) {
+        handle_peer_response(state, PeerResponse::MessageReceived(addr, msg)).unwrap();
+    }
+
+    // Does what the thread of the peer at `addr` does as it writes out the blocks queued for
+    // it, then ticks
+    fn write_uploads(
+        state: &mut super::MainState,
+        addr: SocketAddr,
+        peer_rx: &crossbeam::channel::Receiver<PeerRequest>,
+    ) {
+        for req in peer_rx.try_iter() {
+            if let PeerRequest::Upload(msg, _ticket) = req {
+                state.peers[&addr].traffic.add_sent(msg.traffic());
+                state.traffic.add_sent(msg.traffic());
+            }
+        }
+        update_traffic(&mut state.stats, &state.traffic);
+    }
+
+    #[test]
+    fn announce_totals_survive_peer_removal() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let (mut peer, peer_rx) = peer_info(2);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        peer.peer_choked = false;
+        peer.has = PieceSet::all(peer.has.len());
+        insert_peer(&mut state, addr, peer);
+
+        // we download piece 0 from the peer
+        let block = BlockInfo {
+            piece: 0,
+            range: 0..PIECE_LEN,
+        };
+        state.requested.insert(1, (block, addr));
+        receive(&mut state, addr, Message::Piece(0, 0, vec![0; PIECE_LEN]));
+        settle(&mut state, &disk_rx);
+
+        // and it downloads piece 0 back from us
+        receive(&mut state, addr, Message::Request(0, 0, 1024));
+        settle(&mut state, &disk_rx);
+        write_uploads(&mut state, addr, &peer_rx);
+
+        assert_eq!(state.downloaded(), PIECE_LEN);
+        assert_eq!(state.uploaded(), 1024);
+
+        // losing the peer must not make our totals go backwards
+        remove_peer(&mut state, addr, Disconnect::Died);
+        assert_eq!(state.downloaded(), PIECE_LEN);
+        assert_eq!(state.uploaded(), 1024);
+    }
+
+    #[test]
+    fn only_blocks_written_out_count_as_uploaded() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(1, PIECE_LEN);
+        state.file.write(Block::new(0, 0, &[0; PIECE_LEN])).unwrap();
+        settle(&mut state, &disk_rx);
+        let (mut peer, peer_rx) = peer_info(1);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        peer.choked = false;
+        peer.peer_interested = true;
+        insert_peer(&mut state, addr, peer);
+
+        // two blocks are handed to the peer thread, which gets only the first one out
+        receive(&mut state, addr, Message::Request(0, 0, 1024));
+        receive(&mut state, addr, Message::Request(0, 1024, 1024));
+        settle(&mut state, &disk_rx);
+        let uploads: Vec<PeerRequest> = peer_rx.try_iter().collect();
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(state.stats.served, 2048);
+        assert_eq!(state.uploaded(), 0);
+
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let mut uploads = uploads.into_iter();
+        tx.send(uploads.next().unwrap()).unwrap();
+        write_uploads(&mut state, addr, &rx);
+        update_peer_traffic(state.peers.get_mut(&addr).unwrap());
+        assert_eq!(state.uploaded(), 1024);
+        assert_eq!(state.stats.upload_rate.current(), 1024);
+        assert_eq!(state.peers[&addr].downloaded, 1024);
+
+        // and the other goes down with the connection
+        drop(uploads);
+        remove_peer(&mut state, addr, Disconnect::Died);
+        update_traffic(&mut state.stats, &state.traffic);
+        assert_eq!(state.uploaded(), 1024);
+        assert_eq!(state.queued_uploads.bytes(), 0);
+    }
+
+    #[test]
+    fn haves_skip_seeds_and_go_out_in_batches() {
+        const PIECES: usize = 100;
+        const SEEDS: usize = 50;
+        const LEECHERS: usize = 5;
+
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(PIECES, 16);
+        let mut seeds = Vec::new();
+        for i in 0..SEEDS {
+            let (mut peer, peer_rx) = peer_info(PIECES);
+            peer.peer_choked = false;
+            peer.has = PieceSet::all(peer.has.len());
+            insert_peer(
+                &mut state,
+                format!("10.0.0.{}:6881", i).parse().unwrap(),
+                peer,
+            );
+            seeds.push(peer_rx);
+        }
+        let mut leechers = Vec::new();
+        for i in 0..LEECHERS {
+            // each leecher already has the even pieces
+            let (mut peer, peer_rx) = peer_info(PIECES);
+            for piece in (0..PIECES).step_by(2) {
+                peer.has.insert(piece);
+            }
+            insert_peer(
+                &mut state,
+                format!("10.0.1.{}:6881", i).parse().unwrap(),
+                peer,
+            );
+            leechers.push(peer_rx);
+        }
+        assert_eq!(state.seeds, SEEDS);
+
+        // every piece arrives from some seed within one burst
+        let seed_addrs: Vec<SocketAddr> = state
+            .peers
+            .iter()
+            .filter(|(_, p)| p.is_seed)
+            .map(|(&a, _)| a)
+            .collect();
+        for piece in 0..PIECES {
+            let addr = seed_addrs[piece % SEEDS];
+            let block = BlockInfo {
+                piece,
+                range: 0..16,
+            };
+            state.requested.insert(piece as u64, (block, addr));
+            receive(
+                &mut state,
+                addr,
+                Message::Piece(piece as u32, 0, vec![0; 16]),
+            );
+        }
+        settle(&mut state, &disk_rx);
+        assert!(state.file.is_complete());
+        flush_haves(&mut state);
+
+        // seeds hear nothing, and each leecher gets one batch of the pieces it lacks
+        assert!(seeds.iter().all(|rx| rx.is_empty()));
+        let mut messages = 0;
+        for rx in &leechers {
+            let requests: Vec<PeerRequest> = rx.try_iter().collect();
+            assert_eq!(requests.len(), 1);
+            let PeerRequest::SendBatch(haves) = &requests[0] else {
+                panic!("expected a batch, got {:?}", requests[0]);
+            };
+            let expected: Vec<Message> = (1..PIECES as u32).step_by(2).map(Message::Have).collect();
+            assert_eq!(haves, &expected);
+            messages += haves.len();
+        }
+
+        // rather than a Have per piece to every peer, one at a time
+        assert_eq!(messages, LEECHERS * PIECES / 2);
+        assert!(messages * 20 < PIECES * (SEEDS + LEECHERS));
+    }
+
+    #[test]
+    fn abandoned_requests_are_cancelled_with_the_peer() {
+        let (mut state, timer_rx) = main_state(2, PIECE_LEN);
+        let (peer, peer_rx) = peer_info(2);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        insert_peer(&mut state, addr, peer);
+        for (token, piece) in [(1, 0), (2, 1)] {
+            let block = BlockInfo {
+                piece,
+                range: 1024..2048,
+            };
+            state.requested.insert(token, (block, addr));
+        }
+
+        // giving up on a request tells the peer exactly which block not to send
+        let (block, from) = state.remove_request(1).unwrap();
+        assert_eq!((block.piece, from), (0, addr));
+        assert!(matches!(
+            peer_rx.try_recv(),
+            Ok(PeerRequest::SendMessage(Message::Cancel(0, 1024, 1024)))
+        ));
+        assert!(matches!(timer_rx.try_recv(), Ok(TimerRequest::Cancel(1))));
+        assert!(state.remove_request(1).is_none());
+
+        // but a peer that is being dropped isn't sent anything
+        remove_peer(&mut state, addr, Disconnect::Died);
+        assert!(state.requested.is_empty());
+        assert!(matches!(timer_rx.try_recv(), Ok(TimerRequest::Cancel(2))));
+        assert!(peer_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn culling_keeps_totals_and_best_peers() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let good: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let bad: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [good, bad] {
+            let (mut peer, peer_rx) = peer_info(2);
+            peer.peer_choked = false;
+            peer.has = PieceSet::all(peer.has.len());
+            insert_peer(&mut state, addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        // the good peer sends us a full piece, the bad one only has a request outstanding
+        state.requested.insert(
+            1,
+            (
+                BlockInfo {
+                    piece: 0,
+                    range: 0..PIECE_LEN,
+                },
+                good,
+            ),
+        );
+        state.requested.insert(
+            2,
+            (
+                BlockInfo {
+                    piece: 1,
+                    range: 0..PIECE_LEN,
+                },
+                bad,
+            ),
+        );
+        receive(&mut state, good, Message::Piece(0, 0, vec![0; PIECE_LEN]));
+        settle(&mut state, &disk_rx);
+        receive(&mut state, bad, Message::Request(0, 0, 1024));
+        settle(&mut state, &disk_rx);
+        write_uploads(&mut state, bad, &receivers[1]);
+
+        cull_peers(&mut state, 1);
+
+        assert!(state.peers.contains_key(&good));
+        assert!(!state.peers.contains_key(&bad));
+        assert!(state.requested.is_empty());
+        assert_eq!(state.downloaded(), PIECE_LEN);
+        assert_eq!(state.uploaded(), 1024);
+    }
+
+    #[test]
+    fn seeds_are_counted_as_they_complete() {
+        // not a multiple of 8, so the Bitfield has spare bits
+        let (mut state, _timer_rx) = main_state(10, PIECE_LEN);
+        let full: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let trickle: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let partial: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [full, trickle, partial] {
+            let (peer, peer_rx) = peer_info(10);
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        receive(&mut state, full, Message::Bitfield(vec![0xff, 0xc0]));
+        receive(&mut state, partial, Message::Bitfield(vec![0xff, 0x80]));
+        assert_eq!(state.seeds, 1);
+
+        for piece in 0..10 {
+            assert!(!state.peers[&trickle].is_seed);
+            receive(&mut state, trickle, Message::Have(piece));
+        }
+        assert!(state.peers[&trickle].is_seed);
+        assert!(!state.peers[&partial].is_seed);
+        assert_eq!(state.seeds, 2);
+
+        remove_peer(&mut state, full, Disconnect::Died);
+        assert_eq!(state.seeds, 1);
+    }
+
+    #[test]
+    fn availability_follows_peer_messages() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (peer, _peer_rx) = peer_info(2);
+        state.peers.insert(addr, peer);
+        let counts = |state: &super::MainState| [0, 1].map(|p| state.availability.get(p));
+
+        receive(&mut state, addr, Message::Bitfield(vec![0x80]));
+        assert_eq!(counts(&state), [1, 0]);
+
+        // a repeated Have doesn't count twice
+        receive(&mut state, addr, Message::Have(1));
+        receive(&mut state, addr, Message::Have(1));
+        assert_eq!(counts(&state), [1, 1]);
+        assert_eq!(
+            state.availability.distributed_copies(state.file.bitvec()),
+            1.0
+        );
+
+        remove_peer(&mut state, addr, Disconnect::Died);
+        assert_eq!(counts(&state), [0, 0]);
+    }
+
+    #[test]
+    fn culling_keeps_a_seed_while_downloading() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let fast: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let seed: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let slow: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [fast, seed, slow] {
+            let (peer, peer_rx) = peer_info(2);
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+        }
+        receive(&mut state, seed, Message::Bitfield(vec![0xc0]));
+        state.peers.get_mut(&fast).unwrap().uploaded_recently = 2000;
+        state.peers.get_mut(&slow).unwrap().uploaded_recently = 1000;
+
+        cull_peers(&mut state, 2);
+
+        assert!(state.peers.contains_key(&fast));
+        assert!(state.peers.contains_key(&seed));
+        assert!(!state.peers.contains_key(&slow));
+        assert_eq!(state.seeds, 1);
+    }
+
+    #[test]
+    fn finishing_the_download_re_ranks_peers_straight_away() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(1, PIECE_LEN);
+        state.args.min_upload_slots = 1;
+        state.args.max_upload_slots = 1;
+        state.upload_slots = 1;
+
+        // the uploader has the regular slot while we leech, the others wait
+        let uploader: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let downloader: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let waiting: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [uploader, downloader, waiting] {
+            let (mut peer, peer_rx) = peer_info(1);
+            peer.peer_interested = true;
+            peer.choked = addr != uploader;
+            if addr == uploader {
+                peer.upload_rate.record(100_000);
+                peer.upload_rate
+                    .advance(Instant::now() + Duration::from_secs(1));
+            } else if addr == downloader {
+                peer.download_rate.record(100_000);
+                peer.download_rate
+                    .advance(Instant::now() + Duration::from_secs(1));
+            } else {
+                peer.choked_rounds = 3;
+            }
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        let block = BlockInfo {
+            piece: 0,
+            range: 0..PIECE_LEN,
+        };
+        state.requested.insert(1, (block, uploader));
+        receive(
+            &mut state,
+            uploader,
+            Message::Piece(0, 0, vec![0; PIECE_LEN]),
+        );
+        assert_eq!(state.phase, SessionPhase::Leeching);
+        settle(&mut state, &disk_rx);
+
+        check_phase(&mut state, &channel::unbounded().0).unwrap();
+
+        assert_eq!(state.phase, SessionPhase::Seeding);
+        assert!(!state.peers[&downloader].choked);
+        assert!(!state.peers[&waiting].choked);
+        assert!(state.peers[&uploader].choked);
+    }
+
+    #[test]
+    fn seeds_that_lose_a_piece_say_so() {
+        const URL: &str = "http://tracker.example/announce";
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        state.args.seed_existing = true;
+        state.trackers = Trackers::new(
+            vec![vec![URL.to_owned()]],
+            AnnounceMode::Tiered,
+            &mut StdRng::seed_from_u64(0),
+        );
+        state.trackers.mark_started(URL);
+        let (tracker_tx, tracker_rx) = channel::unbounded();
+
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (peer, _peer_rx) = peer_info(2);
+        state.peers.insert(addr, peer);
+        let download = |state: &mut super::MainState, piece: u32| {
+            let block = BlockInfo {
+                piece: piece as usize,
+                range: 0..PIECE_LEN,
+            };
+            state.requested.insert(piece as u64, (block, addr));
+            receive(state, addr, Message::Piece(piece, 0, vec![0; PIECE_LEN]));
+            settle(state, &disk_rx);
+            check_phase(state, &tracker_tx).unwrap();
+        };
+        download(&mut state, 0);
+        download(&mut state, 1);
+        assert_eq!(state.phase, SessionPhase::Seeding);
+        assert_eq!(state.file.left_verified(), 0);
+
+        // a recheck finds piece 1 bad, as far as main can tell
+        handle_disk_response(&mut state, DiskResponse::Rechecked(Ok(vec![1]))).unwrap();
+        check_phase(&mut state, &tracker_tx).unwrap();
+        assert_eq!(state.phase, SessionPhase::Leeching);
+        let req = tracker_rx.try_recv().unwrap();
+        assert_eq!(req.request.left, PIECE_LEN);
+        assert!(req.request.event.is_none());
+
+        // getting it back only makes us a seed again, which is no completion
+        handle_disk_response(&mut state, DiskResponse::Verified(1)).unwrap();
+        check_phase(&mut state, &tracker_tx).unwrap();
+        assert_eq!(state.phase, SessionPhase::Seeding);
+        assert_eq!(state.file.left_verified(), 0);
+        assert!(caps::should_stop(&state).is_none());
+        assert!(tracker_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn unclean_restarts_stop_the_last_session_before_starting() {
+        const URL: &str = "http://tracker.example/announce";
+        let dir = tempfile::tempdir().unwrap();
+        let run_path = last_run::path(dir.path(), &[0; 20]);
+        LastRun {
+            port: 6881,
+            peer_id: [1; 20],
+            running: true,
+        }
+        .save(&run_path)
+        .unwrap();
+
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        state.port = 6882;
+        state.peer_id = [2; 20];
+        state.trackers = Trackers::new(
+            vec![vec![URL.to_owned()]],
+            AnnounceMode::Tiered,
+            &mut StdRng::seed_from_u64(0),
+        );
+        state.stale_run = last_run::start(&run_path, state.port, state.peer_id);
+        let (tracker_tx, tracker_rx) = channel::unbounded();
+
+        announce(&mut state, &tracker_tx, URL, None);
+        let req = tracker_rx.try_recv().unwrap();
+        assert!(matches!(req.request.event, Some(request::Event::Started)));
+        assert_eq!(req.request.my_port, 6882);
+        let stale = req.stale.unwrap();
+        assert!(matches!(stale.event, Some(request::Event::Stopped)));
+        assert_eq!((stale.my_port, stale.peer_id), (6881, [1; 20]));
+
+        // only the first announce needs it
+        announce(&mut state, &tracker_tx, URL, None);
+        assert!(tracker_rx.try_recv().unwrap().stale.is_none());
+
+        // and a session that stops cleanly leaves nothing for the next one to clean up
+        last_run::stop(&run_path);
+        assert!(last_run::start(&run_path, 6882, [3; 20]).is_none());
+    }
+
+    #[test]
+    fn a_change_of_address_announces_again_and_forgets_backoff() {
+        const URL: &str = "http://tracker.example/announce";
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        state.trackers = Trackers::new(
+            vec![vec![URL.to_owned()]],
+            AnnounceMode::Tiered,
+            &mut StdRng::seed_from_u64(0),
+        );
+        state.trackers.mark_started(URL);
+        let (tracker_tx, tracker_rx) = channel::unbounded();
+        let (tx, _rx) = channel::unbounded();
+
+        // a tracker that says where it sees us
+        let respond = |state: &mut super::MainState, ip: [u8; 4]| {
+            let mut body = b"d11:external ip4:".to_vec();
+            body.extend(ip);
+            body.extend(b"8:intervali1800e5:peers0:e");
+            let data: response::Response = bendy::serde::from_bytes(&body).unwrap();
+            on_announced(state, &tx, URL.to_owned(), data);
+            check_address(state, &tracker_tx);
+            tracker_rx.try_iter().count()
+        };
+
+        let now = Instant::now();
+        let key = PeerKey::dialed("10.0.0.1:6881".parse().unwrap());
+        state.reconnects.record(key, Disconnect::Died, now);
+        state.port_check_started = true;
+        assert_eq!(respond(&mut state, [203, 0, 113, 7]), 0);
+        assert_eq!(respond(&mut state, [203, 0, 113, 7]), 0);
+        assert!(!state.reconnects.may_dial(&key, now));
+
+        // the line reconnected with a new address
+        assert_eq!(respond(&mut state, [198, 51, 100, 2]), 1);
+        assert!(state.reconnects.may_dial(&key, now));
+        assert_eq!(state.reconnects.history(&key).unwrap().count, 0);
+        assert!(!state.port_check_started);
+
+        // every peer reset at once looks the same
+        assert_eq!(respond(&mut state, [198, 51, 100, 2]), 0);
+        state.reconnects.record(key, Disconnect::Died, now);
+        let hangup = Hangup {
+            side: Side::Remote,
+            handshaken: true,
+            reset: true,
+            last_message: None,
+        };
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|i| SocketAddr::from(([10, 0, 1, i], 6881)))
+            .collect();
+        for &addr in &addrs {
+            let (peer, _peer_rx) = peer_info(2);
+            state.peers.insert(addr, peer);
+        }
+        for &addr in &addrs {
+            handle_peer_response(&mut state, PeerResponse::Death(addr, hangup)).unwrap();
+        }
+        check_address(&mut state, &tracker_tx);
+        assert_eq!(tracker_rx.try_iter().count(), 1);
+        assert!(state.reconnects.may_dial(&key, now));
+    }
+
+    #[test]
+    fn losing_most_peers_announces_early_once_each_time() {
+        const URL: &str = "http://tracker.example/announce";
+        let (mut state, timer_rx) = main_state(2, PIECE_LEN);
+        state.args.max_connections = 10;
+        state.trackers = Trackers::new(
+            vec![vec![URL.to_owned()]],
+            AnnounceMode::Tiered,
+            &mut StdRng::seed_from_u64(0),
+        );
+        state.trackers.mark_started(URL);
+        state.trackers.on_success(URL, Vec::new(), None, None);
+        let timer_id = state.trackers.get(URL).unwrap().timer_id;
+        let (tracker_tx, tracker_rx) = channel::unbounded();
+
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|i| SocketAddr::from(([10, 0, 0, i], 6881)))
+            .collect();
+        let hangup = Hangup {
+            side: Side::Remote,
+            handshaken: true,
+            reset: false,
+            last_message: None,
+        };
+
+        // peers connect, then every one of them goes away
+        let episode = |state: &mut super::MainState| {
+            let mut peer_rxs = Vec::new();
+            for &addr in &addrs {
+                let (peer, peer_rx) = peer_info(2);
+                state.peers.insert(addr, peer);
+                peer_rxs.push(peer_rx);
+                check_peer_pool(state, &tracker_tx);
+            }
+            for &addr in &addrs {
+                handle_peer_response(state, PeerResponse::Death(addr, hangup)).unwrap();
+                check_peer_pool(state, &tracker_tx);
+            }
+            tracker_rx.try_iter().count()
+        };
+
+        // one early announce, in place of the one that was scheduled
+        assert_eq!(episode(&mut state), 1);
+        assert!(timer_rx
+            .try_iter()
+            .any(|req| matches!(req, TimerRequest::Cancel(id) if id == timer_id)));
+
+        // none while the last one is waiting on an answer
+        assert_eq!(episode(&mut state), 0);
+
+        // and none while there are peers left to dial, until they've all been tried
+        state.trackers.on_success(URL, Vec::new(), None, None);
+        state.dial_queue.push("10.0.0.9:6881".parse().unwrap());
+        assert_eq!(episode(&mut state), 0);
+        state.dial_queue = Default::default();
+        check_peer_pool(&mut state, &tracker_tx);
+        assert_eq!(tracker_rx.try_iter().count(), 1);
+        check_peer_pool(&mut state, &tracker_tx);
+        assert_eq!(tracker_rx.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn subscribers_follow_a_download() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let events = state.events.subscribe(16);
+        let (tx, _rx) = channel::unbounded();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let data = ConnectionData {
+            peer: stream,
+            addr,
+            handshake: None,
+        };
+        accept_connection(&mut state, data, &tx);
+
+        // the first copy of piece 0 is bad
+        for (piece, fill) in [(0, 1), (0, 0), (1, 0)] {
+            let block = BlockInfo {
+                piece,
+                range: 0..PIECE_LEN,
+            };
+            state.requested.insert(piece as u64, (block, addr));
+            let msg = Message::Piece(piece as u32, 0, vec![fill; PIECE_LEN]);
+            receive(&mut state, addr, msg);
+            settle(&mut state, &disk_rx);
+            check_phase(&mut state, &channel::unbounded().0).unwrap();
+        }
+        remove_peer(&mut state, addr, Disconnect::Died);
+
+        let seen: Vec<(EventKind, Option<SocketAddr>, Option<usize>)> = events
+            .try_iter()
+            .map(|e| (e.kind, e.peer, e.piece))
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                (EventKind::PeerAdded, Some(addr), None),
+                (EventKind::PieceFailed, None, Some(0)),
+                (EventKind::PieceVerified, None, Some(0)),
+                (EventKind::PieceVerified, None, Some(1)),
+                (EventKind::Completed, None, None),
+                (EventKind::PeerRemoved, Some(addr), None),
+            ]
+        );
+        assert_eq!(events.dropped(), 0);
+
+        // and the crash report gets the same
+        assert_eq!(state.events.iter().count(), seen.len());
+    }
+
+    #[test]
+    fn progress_lines_follow_the_session() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let (read, write) = pipe::pipe();
+
+        // the pipe has no buffer, so each line waits for the reader
+        let reader = thread::spawn(move || {
+            BufReader::new(read)
+                .lines()
+                .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                .collect::<Vec<serde_json::Value>>()
+        });
+        state.progress = ProgressOutput::to(write);
+        let (tx, _rx) = channel::unbounded();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let data = ConnectionData {
+            peer: stream,
+            addr,
+            handshake: None,
+        };
+        accept_connection(&mut state, data, &tx);
+
+        for piece in 0..2 {
+            let block = BlockInfo {
+                piece,
+                range: 0..PIECE_LEN,
+            };
+            state.requested.insert(piece as u64, (block, addr));
+            let msg = Message::Piece(piece as u32, 0, vec![0; PIECE_LEN]);
+            receive(&mut state, addr, msg);
+            settle(&mut state, &disk_rx);
+            if piece == 0 {
+                let line = Line::progress(&state);
+                state.progress.emit(line);
+            }
+            check_phase(&mut state, &channel::unbounded().0).unwrap();
+        }
+        remove_peer(&mut state, addr, Disconnect::Died);
+        state.progress = ProgressOutput::default();
+
+        let lines = reader.join().unwrap();
+        let types: Vec<&str> = lines.iter().map(|l| l["type"].as_str().unwrap()).collect();
+        assert_eq!(
+            types,
+            [
+                "peer_connected",
+                "piece_complete",
+                "progress",
+                "piece_complete",
+                "complete",
+                "peer_disconnected"
+            ]
+        );
+        assert!(lines.iter().all(|l| l["ts"].is_u64()));
+        assert_eq!(lines[0]["peer"], addr.to_string());
+        assert_eq!(lines[1]["piece"], 0);
+
+        let progress = &lines[2];
+        assert_eq!(progress["downloaded"], PIECE_LEN);
+        assert_eq!(progress["uploaded"], 0);
+        assert_eq!(progress["left"], PIECE_LEN);
+        assert_eq!(progress["rate_down"], 0.0);
+        assert_eq!(progress["rate_up"], 0.0);
+        assert!(progress["eta"].is_null());
+        assert_eq!(progress["peers"], 1);
+        assert_eq!(progress["pieces_complete"], 1);
+        assert_eq!(progress["pieces_total"], 2);
+        assert_eq!(progress["state"], "leeching");
+    }
+
+    #[test]
+    fn strict_clients_get_one_bitfield_and_no_redundant_haves() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(4, PIECE_LEN);
+        let (tx, _rx) = channel::unbounded();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // where the pieces come from, which is a seed and so never hears about them
+        let source: SocketAddr = "10.0.0.9:6881".parse().unwrap();
+        let (mut peer, _source_rx) = peer_info(4);
+        peer.has = PieceSet::all(4);
+        insert_peer(&mut state, source, peer);
+        let complete = |state: &mut super::MainState, piece: usize| {
+            let block = BlockInfo {
+                piece,
+                range: 0..PIECE_LEN,
+            };
+            state.requested.insert(piece as u64, (block, source));
+            let msg = Message::Piece(piece as u32, 0, vec![0; PIECE_LEN]);
+            receive(state, source, msg);
+            settle(state, &disk_rx);
+        };
+
+        // Connects a client and trades handshakes with it, leaving it to read what comes next
+        let connect = |state: &mut super::MainState| -> BufReader<TcpStream> {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, addr) = listener.accept().unwrap();
+            let data = ConnectionData {
+                peer: stream,
+                addr,
+                handshake: None,
+            };
+            accept_connection(state, data, &tx);
+
+            let mut handshake = vec![19];
+            handshake.extend_from_slice(b"BitTorrent protocol");
+            handshake.extend_from_slice(&[0; 8]);
+            handshake.extend_from_slice(&state.info_hash);
+            handshake.extend_from_slice(&[1; 20]);
+            client.write_all(&handshake).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            let mut theirs = [0; 68];
+            client.read_exact(&mut theirs).unwrap();
+            BufReader::new(client)
+        };
+        let received = |client: &mut BufReader<TcpStream>| -> Vec<Message> {
+            let mut msgs = Vec::new();
+            while let Ok(msg) = Message::recv(client) {
+                msgs.push(msg);
+            }
+            msgs
+        };
+
+        // piece 1 is complete but not yet announced when the first client connects
+        complete(&mut state, 0);
+        flush_haves(&mut state);
+        complete(&mut state, 1);
+        let mut first = connect(&mut state);
+        flush_haves(&mut state);
+
+        // and the second connects once everything is, with the last two not yet announced
+        complete(&mut state, 2);
+        complete(&mut state, 3);
+        let mut second = connect(&mut state);
+        flush_haves(&mut state);
+
+        // a piece found bad and downloaded again is news to nobody
+        state.unannounced.push(3);
+        flush_haves(&mut state);
+
+        assert_eq!(
+            received(&mut first),
+            [
+                Message::Bitfield(vec![0b1100_0000]),
+                Message::Have(2),
+                Message::Have(3)
+            ]
+        );
+        assert_eq!(
+            received(&mut second),
+            [Message::Bitfield(vec![0b1111_0000])]
+        );
+        for peer_info in state.peers.values() {
+            assert!(peer_info.sent_bitfield);
+            assert_eq!(peer_info.bitfield_covers, 0);
+        }
+    }
+
+    #[test]
+    fn connection_reset_before_setup_is_survived() {
+        let (mut state, _timer_rx) = main_state(1, PIECE_LEN);
+        let (tx, _rx) = channel::unbounded();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, addr) = listener.accept().unwrap();
+
+        // closing with unread data makes the client reset the connection
+        stream.write_all(b"unread").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        drop(client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stream.peer_addr().is_ok() {
+            assert!(Instant::now() < deadline, "connection was never reset");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        accept_connection(
+            &mut state,
+            ConnectionData {
+                peer: stream,
+                addr,
+                handshake: None,
+            },
+            &tx,
+        );
+
+        // and the next connection is handled as normal
+        client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        accept_connection(
+            &mut state,
+            ConnectionData {
+                peer: stream,
+                addr,
+                handshake: None,
+            },
+            &tx,
+        );
+        assert!(state.peers.contains_key(&addr));
+
+        // the new peer thread starts with the handshake
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 19);
+    }
+
+    #[test]
+    fn rejected_connections_never_get_a_peer_thread() {
+        let (mut state, _timer_rx) = main_state(1, PIECE_LEN);
+        state.args.max_connections = 2;
+        let (tx, _rx) = channel::unbounded();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // Hands `accept_connection` a real connection claiming to be from `addr`, and returns
+        // the first byte the client side sees, or None if it is just closed
+        let connect = |state: &mut super::MainState, addr: &str| -> Option<u8> {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let data = ConnectionData {
+                peer: stream,
+                addr: addr.parse().unwrap(),
+                handshake: None,
+            };
+            accept_connection(state, data, &tx);
+
+            let mut buf = [0u8; 1];
+            match client.read(&mut buf).unwrap() {
+                0 => None,
+                _ => Some(buf[0]),
+            }
+        };
+
+        // the first connection starts a peer thread, which sends its handshake
+        assert_eq!(connect(&mut state, "10.0.0.1:6881"), Some(19));
+
+        // the same address again, or the same host on another port, are closed unanswered
+        assert_eq!(connect(&mut state, "10.0.0.1:6881"), None);
+        assert_eq!(connect(&mut state, "10.0.0.1:7000"), None);
+        assert_eq!(state.peers.len(), 1);
+
+        // as is one more connection than we're allowed
+        assert_eq!(connect(&mut state, "10.0.0.2:6881"), Some(19));
+        assert_eq!(connect(&mut state, "10.0.0.3:6881"), None);
+        assert_eq!(state.peers.len(), 2);
+    }
+
+    #[test]
+    fn scheduled_shares_limit_peers_and_dials() {
+        let (mut state, _timer_rx) = main_state(1, PIECE_LEN);
+        let demand = Arc::new(Demand::default());
+        state.demand = Some(demand.clone());
+        for i in 1..=4 {
+            let (peer, _) = peer_info(1);
+            insert_peer(
+                &mut state,
+                format!("10.0.0.{}:6881", i).parse().unwrap(),
+                peer,
+            );
+        }
+        for i in 1..=5 {
+            state
+                .dial_queue
+                .push(format!("10.0.1.{}:6881", i).parse().unwrap());
+        }
+
+        // a smaller share culls the peers over it, and leaves no room for more
+        let share = |connections, dial_rate, paused| Allocation {
+            connections,
+            dial_rate,
+            paused,
+        };
+        apply_allocation(&mut state, share(2, 1, false));
+        assert_eq!(state.peers.len(), 2);
+        assert!(next_dials(&mut state).is_empty());
+        let addr: SocketAddr = "10.0.2.1:6881".parse().unwrap();
+        assert_eq!(
+            rejection(&state, addr, &PeerKey::dialed(addr)),
+            Some("too many connections")
+        );
+        // which the scheduler hears of
+        assert_eq!(demand.take().connections, 1);
+
+        // a bigger one is grown into at the dial rate it comes with
+        apply_allocation(&mut state, share(4, 1, false));
+        assert_eq!(next_dials(&mut state).len(), 1);
+        state.dial_queue.new_round();
+
+        // and a paused torrent keeps nobody
+        apply_allocation(&mut state, share(0, 0, true));
+        assert!(state.peers.is_empty());
+        assert!(next_dials(&mut state).is_empty());
+    }
+
+    #[test]
+    fn downloaded_excludes_unverified_and_unrequested_data() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let (peer, _peer_rx) = peer_info(2);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+
+        // data we never asked for doesn't count
+        receive(&mut state, addr, Message::Piece(1, 0, vec![0; PIECE_LEN]));
+
+        // and neither does data that fails the hash check
+        let block = Block::new(0, 0, &[1; PIECE_LEN]);
+        state.requested.insert(1, (block.info(), addr));
+        receive(&mut state, addr, Message::Piece(0, 0, vec![1; PIECE_LEN]));
+
+        settle(&mut state, &disk_rx);
+        assert_eq!(state.file.piece_state(0), Some(PieceState::Missing));
+        assert_eq!(state.downloaded(), 0);
+        assert_eq!(state.stats.received, PIECE_LEN * 2);
+        assert_eq!(state.stats.unrequested, PIECE_LEN);
+    }
+
+    #[test]
+    fn bad_pieces_are_blamed_on_whoever_sent_them() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let honest: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let corrupt: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [honest, corrupt] {
+            let (peer, peer_rx) = peer_info(2);
+            state.peers.insert(addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        let block = Block::new(0, 0, &[1; PIECE_LEN]);
+        state.requested.insert(1, (block.info(), corrupt));
+        receive(
+            &mut state,
+            corrupt,
+            Message::Piece(0, 0, vec![1; PIECE_LEN]),
+        );
+        let block = Block::new(1, 0, &[0; PIECE_LEN]);
+        state.requested.insert(2, (block.info(), honest));
+        receive(&mut state, honest, Message::Piece(1, 0, vec![0; PIECE_LEN]));
+        settle(&mut state, &disk_rx);
+
+        assert_eq!(
+            state.peers[&corrupt].misbehavior,
+            misbehavior::weight(PeerWarning::HashFailed)
+        );
+        assert_eq!(state.peers[&honest].misbehavior, 0);
+        assert_eq!(state.peers[&corrupt].hash_strikes, 1);
+        assert_eq!(state.peers[&honest].hash_strikes, 0);
+        assert!(state.file.contributors(0).is_empty());
+        assert!(state.file.contributors(1).is_empty());
+
+        // a block we already have is only counted as wasted, whoever sends it
+        state.requested.insert(3, (block.info(), corrupt));
+        receive(
+            &mut state,
+            corrupt,
+            Message::Piece(1, 0, vec![0; PIECE_LEN]),
+        );
+        assert_eq!(state.stats.redundant, PIECE_LEN);
+        assert_eq!(
+            state.peers[&corrupt].misbehavior,
+            misbehavior::weight(PeerWarning::HashFailed)
+        );
+    }
+
+    #[test]
+    fn repeat_bad_piece_senders_are_banned() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(4, PIECE_LEN);
+        let corrupt: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (peer, _peer_rx) = peer_info(4);
+        state.peers.insert(corrupt, peer);
+
+        // far enough apart that the score alone would never get there
+        for piece in 0..misbehavior::HASH_FAILURE_STRIKES {
+            assert!(state.peers.contains_key(&corrupt));
+            let block = Block::new(piece as usize, 0, &[1; PIECE_LEN]);
+            state
+                .requested
+                .insert(piece as u64, (block.info(), corrupt));
+            receive(
+                &mut state,
+                corrupt,
+                Message::Piece(piece, 0, vec![1; PIECE_LEN]),
+            );
+            settle(&mut state, &disk_rx);
+            if let Some(peer_info) = state.peers.get_mut(&corrupt) {
+                peer_info.misbehavior = 0;
+            }
+        }
+
+        assert!(!state.peers.contains_key(&corrupt));
+        assert!(state
+            .bans
+            .is_banned(&PeerKey::dialed(corrupt), Instant::now()));
+        assert_eq!(state.stats.misbehavior_bans, 1);
+    }
+
+    #[test]
+    fn flooded_channel_stays_bounded_and_sheds_work() {
+        const CAPACITY: usize = 64;
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 2000;
+
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        state.args.channel_soft_limit = 16;
+
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (peer, _peer_rx) = peer_info(2);
+        state.peers.insert(addr, peer);
+
+        // synthetic peers that send as fast as they can
+        let (tx, rx) = channel::bounded(CAPACITY);
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|_| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for _ in 0..PER_PRODUCER {
+                        let resp = PeerResponse::MessageReceived(addr, Message::Keepalive);
+                        tx.send(Response::Peer(resp)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        // main stalls until the channel is full
+        while rx.len() < CAPACITY {
+            thread::yield_now();
+        }
+
+        let mut handled = 0;
+        let mut shed = 0;
+        for resp in rx.iter() {
+            if record_channel_depth(&mut state, rx.len()) {
+                shed += 1;
+            }
+            if let Response::Peer(resp) = resp {
+                handle_peer_response(&mut state, resp).unwrap();
+            }
+            handled += 1;
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // nothing was lost, the backlog never grew past the channel's capacity,
+        // and main noticed it was behind
+        assert_eq!(handled, PRODUCERS * PER_PRODUCER);
+        assert!(state.stats.max_channel_depth <= CAPACITY);
+        assert!(state.stats.max_channel_depth > state.args.channel_soft_limit);
+        assert!(shed > 0);
+        assert_eq!(state.stats.channel_depth, 0);
+    }
+
+    #[test]
+    fn recheck_stops_serving_corrupted_pieces_and_downloads_them_again() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        let (mut peer, peer_rx) = peer_info(2);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        peer.peer_choked = false;
+        peer.has = PieceSet::all(peer.has.len());
+        insert_peer(&mut state, addr, peer);
+
+        // the whole download comes in, and is served to the peer
+        for piece in 0..2 {
+            let block = BlockInfo {
+                piece,
+                range: 0..PIECE_LEN,
+            };
+            state.requested.insert(piece as u64, (block, addr));
+            let msg = Message::Piece(piece as u32, 0, vec![0; PIECE_LEN]);
+            receive(&mut state, addr, msg);
+            settle(&mut state, &disk_rx);
+        }
+        assert_eq!(state.file.bitvec().count_ones(), 2);
+        let uploads = |peer_rx: &channel::Receiver<PeerRequest>| -> Vec<u32> {
+            peer_rx
+                .try_iter()
+                .filter_map(|req| match req {
+                    PeerRequest::Upload(Message::Piece(piece, _, _), _) => Some(piece),
+                    _ => None,
+                })
+                .collect()
+        };
+        receive(&mut state, addr, Message::Request(1, 0, 1024));
+        settle(&mut state, &disk_rx);
+        assert_eq!(uploads(&peer_rx), [1]);
+
+        // piece 1 goes bad on disk behind our back, which a recheck finds
+        let mut file = File::options()
+            .write(true)
+            .open(download_path(&state))
+            .unwrap();
+        file.seek(SeekFrom::Start(PIECE_LEN as u64)).unwrap();
+        file.write_all(&[1; 16]).unwrap();
+        drop(file);
+        state.file.recheck();
+        settle(&mut state, &disk_rx);
+        assert_eq!(state.file.bitvec().iter_ones().collect::<Vec<_>>(), [0]);
+
+        // it's no longer in what we'd advertise, nor served, though what's left still is
+        receive(&mut state, addr, Message::Request(1, 0, 1024));
+        receive(&mut state, addr, Message::Request(0, 0, 1024));
+        settle(&mut state, &disk_rx);
+        assert_eq!(uploads(&peer_rx), [0]);
+        assert!(state.peers.contains_key(&addr));
+
+        // and it's asked for again, and completes once it comes back
+        let mut rng = StdRng::seed_from_u64(0);
+        flush_interest(&mut state);
+        refill_pipelines(&mut state, &mut rng);
+        let requested: Vec<(u64, BlockInfo)> = state
+            .requested
+            .iter()
+            .map(|(&token, (block, _))| (token, block.clone()))
+            .collect();
+        assert!(!requested.is_empty());
+        assert!(requested.iter().all(|(_, block)| block.piece == 1));
+        for (_, block) in requested {
+            let len = block.range.len();
+            let msg = Message::Piece(1, block.range.start as u32, vec![0; len]);
+            receive(&mut state, addr, msg);
+        }
+        settle(&mut state, &disk_rx);
+        assert_eq!(state.file.bitvec().count_ones(), 2);
+    }
+
+    #[test]
+    fn reads_of_a_piece_reset_since_are_not_sent() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(1, PIECE_LEN);
+        let (mut peer, peer_rx) = peer_info(1);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        peer.peer_choked = false;
+        insert_peer(&mut state, addr, peer);
+        let block = BlockInfo {
+            piece: 0,
+            range: 0..PIECE_LEN,
+        };
+        state.requested.insert(0, (block.clone(), addr));
+        receive(&mut state, addr, Message::Piece(0, 0, vec![0; PIECE_LEN]));
+        settle(&mut state, &disk_rx);
+
+        // the peer asks for the piece, which goes bad on disk before it is read
+        let mut file = File::options()
+            .write(true)
+            .open(download_path(&state))
+            .unwrap();
+        file.write_all(&[1; 16]).unwrap();
+        drop(file);
+        receive(&mut state, addr, Message::Request(0, 0, PIECE_LEN as u32));
+        state.file.recheck();
+        state.file.flush().unwrap();
+        let mut responses = disk_rx.try_iter().map(|resp| match resp {
+            Response::Disk(resp) => resp,
+            other => panic!("unexpected response {:?}", other),
+        });
+        let read = responses.next().unwrap();
+        assert!(matches!(read, DiskResponse::Read { .. }));
+        let rechecked = responses.next().unwrap();
+        assert!(matches!(rechecked, DiskResponse::Rechecked(_)));
+        drop(responses);
+
+        // by the time main gets to the read, the recheck has reset the piece and it has been
+        // downloaded again, so what was read is out of date
+        handle_disk_response(&mut state, rechecked).unwrap();
+        assert!(!state.file.is_complete());
+        state.requested.insert(1, (block, addr));
+        receive(&mut state, addr, Message::Piece(0, 0, vec![0; PIECE_LEN]));
+        settle(&mut state, &disk_rx);
+        assert!(state.file.is_complete());
+
+        handle_disk_response(&mut state, read).unwrap();
+        assert_eq!(state.file.reading(), 0);
+        assert!(!peer_rx
+            .try_iter()
+            .any(|req| matches!(req, PeerRequest::Upload(Message::Piece(..), _))));
+    }
+
+    #[test]
+    fn peer_death_removes_peer() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (peer, _peer_rx) = peer_info(2);
+        state.peers.insert(addr, peer);
+
+        let hangup = Hangup {
+            side: Side::Remote,
+            handshaken: true,
+            reset: false,
+            last_message: Some((Direction::Received, "Bitfield")),
+        };
+        handle_peer_response(&mut state, PeerResponse::Death(addr, hangup)).unwrap();
+        assert!(state.peers.is_empty());
+        assert_eq!(
+            state.stats.disconnects.to_string(),
+            "1 after bitfield by peer"
+        );
+
+        // a culled peer is one we were closing
+        let (peer, _peer_rx) = peer_info(2);
+        state.peers.insert(addr, peer);
+        cull_peers(&mut state, 0);
+        assert!(state
+            .stats
+            .to_string()
+            .ends_with("disconnects: 1 after bitfield by peer, 1 closing by us"));
+    }
+
+    #[test]
+    fn lost_peers_are_redialed_later_unless_culled() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let dropped: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let culled: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        for addr in [dropped, culled] {
+            let (peer, _peer_rx) = peer_info(2);
+            state.peers.insert(addr, peer);
+        }
+
+        let hangup = Hangup {
+            side: Side::Remote,
+            handshaken: true,
+            reset: false,
+            last_message: None,
+        };
+        handle_peer_response(&mut state, PeerResponse::Death(dropped, hangup)).unwrap();
+        cull_peers(&mut state, 0);
+        assert!(state.peers.is_empty());
+
+        let now = Instant::now();
+        assert!(!state.reconnects.may_dial(&PeerKey::dialed(dropped), now));
+        assert!(state.reconnects.may_dial(&PeerKey::dialed(culled), now));
+        let history = state.reconnects.history(&PeerKey::dialed(culled)).unwrap();
+        assert_eq!(history.reason, Disconnect::Culled);
+    }
+
+    #[test]
+    fn tracker_peers_are_dialed_a_few_at_a_time() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        state.args.max_connections = 100;
+        let addrs: Vec<SocketAddr> = (0..50)
+            .map(|i| SocketAddr::from(([10, 0, 0, i as u8], 6881)))
+            .collect();
+
+        // a peer we lost lately goes to the back, and a connected one isn't dialed at all
+        let now = Instant::now();
+        let failed = PeerKey::dialed(addrs[0]);
+        state.reconnects.record(failed, Disconnect::Culled, now);
+        let (peer, _peer_rx) = peer_info(2);
+        state.peers.insert(addrs[1], peer);
+
+        for &addr in &addrs {
+            assert!(state.dial_queue.push(addr));
+        }
+        let mut rounds = Vec::new();
+        while !state.dial_queue.is_empty() {
+            state.dial_queue.new_round();
+            rounds.push(next_dials(&mut state));
+            assert_eq!(state.stats.dial_queue, state.dial_queue.len());
+        }
+
+        assert!(rounds.iter().all(|round| round.len() <= 5));
+        assert_eq!(rounds.len(), 10);
+        assert_eq!(rounds[0], addrs[2..7]);
+        let dialed: Vec<SocketAddr> = rounds.concat();
+        assert_eq!(dialed[..48], addrs[2..]);
+        assert_eq!(dialed[48..], [addrs[0]]);
+        assert!(state.stats.to_string().ends_with("distributed copies"));
+
+        // the connection limit holds, counting the peers dialed this round
+        state.args.max_connections = 3;
+        for &addr in &addrs[10..20] {
+            state.dial_queue.push(addr);
+        }
+        state.dial_queue.new_round();
+        assert_eq!(next_dials(&mut state), addrs[10..12]);
+        assert!(state
+            .stats
+            .to_string()
+            .contains("8 peers waiting to be dialed"));
+    }
+
+    #[test]
+    fn peers_from_every_tier_are_merged() {
+        // two trackers, each knowing of different peers
+        let sims: Vec<TrackerSim> = (0..2)
+            .map(|_| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                TrackerSim::spawn(listener, SimConfig::default()).unwrap()
+            })
+            .collect();
+        let urls: Vec<String> = sims.iter().map(TrackerSim::announce_url).collect();
+        let request = |peer: u8| request::Request {
+            info_hash: [0; 20],
+            peer_id: [peer; 20],
+            my_port: 7000 + peer as u16,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+        };
+        for peer in [1, 2] {
+            request(peer).send(&urls[0]).unwrap();
+        }
+        for peer in [3, 4] {
+            request(peer).send(&urls[1]).unwrap();
+        }
+
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        state.trackers = Trackers::new(
+            urls.iter().map(|url| vec![url.clone()]).collect(),
+            AnnounceMode::AllTiers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let (tx, _rx) = channel::unbounded();
+
+        // with no room to dial, everything the trackers hand out stays queued
+        state.args.max_connections = 0;
+        for url in &urls {
+            let data = request(9).send(url).unwrap();
+            on_announced(&mut state, &tx, url.clone(), data);
+        }
+        assert_eq!(state.dial_queue.len(), 4);
+        assert_eq!(
+            state.stats.trackers,
+            urls.iter()
+                .map(|url| (url.clone(), TrackerStatus::Working { peers: 2 }))
+                .collect::<Vec<_>>()
+        );
+        assert!(state.stats.to_string().contains("2 of 2 trackers working"));
+
+        // the first tracker's peers outlive it failing, and aren't queued twice
+        state
+            .trackers
+            .on_failure(&urls[0], "gone".to_owned(), false);
+        let data = request(9).send(&urls[1]).unwrap();
+        on_announced(&mut state, &tx, urls[1].clone(), data);
+        assert_eq!(state.dial_queue.len(), 4);
+
+        state.args.max_connections = 10;
+        state.dial_queue.new_round();
+        let mut ports: Vec<u16> = next_dials(&mut state).iter().map(|a| a.port()).collect();
+        ports.sort();
+        assert_eq!(ports, [7001, 7002, 7003, 7004]);
+
+        // a peer that doesn't resolve is skipped, rather than taking the session down
+        state.args.max_connections = 0;
+        let body = b"d8:intervali60e5:peersld2:ip0:4:porti6881eeee";
+        let data: response::Response = bendy::serde::from_bytes(body).unwrap();
+        on_announced(&mut state, &tx, urls[1].clone(), data);
+        assert_eq!(state.trackers.peers().len(), 3);
+        assert_eq!(state.dial_queue.len(), 2);
+    }
+
+    #[test]
+    fn peers_that_dialed_in_are_the_same_peers_trackers_hand_out() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let advertised: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let ephemeral: SocketAddr = "10.0.0.1:51234".parse().unwrap();
+        let inbound = PeerKey::inbound(ephemeral, None);
+
+        // a ban on the address a tracker gave us turns the host away when it dials in
+        state.bans.ban(&PeerKey::dialed(advertised), Instant::now());
+        assert_eq!(rejection(&state, ephemeral, &inbound), Some("banned"));
+        state.bans = Default::default();
+        assert_eq!(rejection(&state, ephemeral, &inbound), None);
+
+        // while it's connected it isn't dialed, and once it drops us it isn't redialed early
+        let (mut peer, _peer_rx) = peer_info(2);
+        peer.incoming = true;
+        state.peers.insert(ephemeral, peer);
+        assert!(is_connected(&state.peers, &PeerKey::dialed(advertised)));
+        remove_peer(&mut state, ephemeral, Disconnect::Died);
+        let now = Instant::now();
+        assert!(!state.reconnects.may_dial(&PeerKey::dialed(advertised), now));
+        assert_eq!(state.reconnects.history(&inbound).unwrap().count, 1);
+
+        // a peer that says where it listens is held to that port alone
+        let other: SocketAddr = "10.0.0.2:51234".parse().unwrap();
+        let (mut peer, _peer_rx) = peer_info(2);
+        peer.incoming = true;
+        peer.listen_port = Some(6881);
+        state.peers.insert(other, peer);
+        assert!(is_connected(
+            &state.peers,
+            &PeerKey::dialed("10.0.0.2:6881".parse().unwrap())
+        ));
+        assert!(!is_connected(
+            &state.peers,
+            &PeerKey::dialed("10.0.0.2:7000".parse().unwrap())
+        ));
+        remove_peer(&mut state, other, Disconnect::Died);
+        let told = PeerKey::dialed("10.0.0.2:6881".parse().unwrap());
+        assert!(!state.reconnects.may_dial(&told, now));
+        let elsewhere = PeerKey::dialed("10.0.0.2:7000".parse().unwrap());
+        assert!(state.reconnects.may_dial(&elsewhere, now));
+    }
+
+    #[test]
+    fn only_disk_errors_are_fatal() {
+        assert!(is_fatal(&FileError::Truncated.into()));
+        assert!(!is_fatal(&FileError::InvalidPiece(3).into()));
+        assert!(!is_fatal(&anyhow::anyhow!("peer misbehaved")));
+    }
+
+    #[test]
+    fn fatal_errors_have_categories() {
+        let io = std::io::Error::other("disk on fire");
+        assert_eq!(error_category(&FileError::Io(io).into()), "io");
+        assert_eq!(error_category(&FileError::Truncated.into()), "truncated");
+        assert_eq!(error_category(&anyhow::anyhow!("something")), "other");
+    }
+
+    #[test]
+    fn bogus_piece_from_peer_is_not_fatal() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let (peer, _peer_rx) = peer_info(2);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+
+        // a block we asked for, but for a piece that doesn't exist
+        let block = BlockInfo {
+            piece: 5,
+            range: 0..16,
+        };
+        state.requested.insert(1, (block, addr));
+        handle_peer_response(
+            &mut state,
+            PeerResponse::MessageReceived(addr, Message::Piece(5, 0, vec![0; 16])),
+        )
+        .unwrap();
+        assert!(state.peers.contains_key(&addr));
+    }
+
+    #[test]
+    fn burst_of_haves_sends_one_interested() {
+        let (mut state, _timer_rx) = main_state(500, 16);
+        let (peer, peer_rx) = peer_info(500);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+
+        // no Bitfield, just a Have for every piece
+        for piece in 0..500 {
+            receive(&mut state, addr, Message::Have(piece));
+        }
+        flush_interest(&mut state);
+
+        let interested = peer_rx
+            .try_iter()
+            .filter(|req| matches!(req, PeerRequest::SendMessage(Message::Interested)))
+            .count();
+        assert_eq!(interested, 1);
+        assert!(state.peers[&addr].interested);
+    }
+
+    #[test]
+    fn silent_peer_has_nothing() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        let (peer, peer_rx) = peer_info(4);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+
+        // neither a Bitfield nor any Haves
+        state.interest_dirty.insert(addr);
+        flush_interest(&mut state);
+
+        assert!(state.peers[&addr].has.is_empty());
+        assert!(!state.peers[&addr].interested);
+        assert!(peer_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn cancels_and_keepalives_change_nothing() {
+        let (mut state, timer_rx) = main_state(4, PIECE_LEN);
+        let (peer, peer_rx) = peer_info(4);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+
+        receive(&mut state, addr, Message::Cancel(0, 0, 16384));
+        receive(&mut state, addr, Message::Keepalive);
+
+        assert_eq!(state.peers[&addr].misbehavior, 0);
+        assert!(state.interest_dirty.is_empty());
+        assert!(peer_rx.try_recv().is_err());
+        assert!(timer_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn invalid_haves_go_through_log_limiter() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        let (peer, _peer_rx) = peer_info(4);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+
+        // stay short of getting banned
+        for _ in 0..9 {
+            receive(&mut state, addr, Message::Have(1000));
+        }
+        assert_eq!(state.log_limiter.count(addr, PeerWarning::InvalidHave), 9);
+    }
+
+    // a message from `addr` that commits the given violation
+    fn violation(addr: SocketAddr, kind: PeerWarning) -> PeerResponse {
+        let msg = match kind {
+            PeerWarning::InvalidHave => Message::Have(1000),
+            PeerWarning::InvalidBitfield => Message::Bitfield(vec![0; 7]),
+            PeerWarning::UnrequestedPiece => Message::Piece(0, 0, vec![0; 16]),
+            PeerWarning::OversizedRequest => {
+                Message::Request(0, 0, DEFAULT_MAX_BLOCK_LEN as u32 + 1)
+            }
+            PeerWarning::MalformedMessage => return PeerResponse::InvalidMessage(addr, "Have"),
+
+            // these depend on what else has happened, or aren't messages at all
+            PeerWarning::BadPiece
+            | PeerWarning::ChokedRequest
+            | PeerWarning::BadRequest
+            | PeerWarning::MessageFlood
+            | PeerWarning::HashFailed => {
+                panic!("{} isn't committed by any one message", kind)
+            }
+        };
+        PeerResponse::MessageReceived(addr, msg)
+    }
+
+    #[test]
+    fn repeated_violations_get_peer_banned() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let kinds = [
+            PeerWarning::InvalidHave,
+            PeerWarning::InvalidBitfield,
+            PeerWarning::UnrequestedPiece,
+            PeerWarning::OversizedRequest,
+            PeerWarning::MalformedMessage,
+        ];
+
+        for kind in kinds {
+            let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+            let (peer, _peer_rx) = peer_info(4);
+            state.peers.insert(addr, peer);
+
+            let weight = misbehavior::weight(kind);
+            let needed = BAN_THRESHOLD.div_ceil(weight);
+            for _ in 1..needed {
+                handle_peer_response(&mut state, violation(addr, kind)).unwrap();
+            }
+            assert!(state.peers.contains_key(&addr), "{} banned too early", kind);
+
+            handle_peer_response(&mut state, violation(addr, kind)).unwrap();
+            assert!(!state.peers.contains_key(&addr), "{} not banned", kind);
+            assert!(state.bans.is_banned(&PeerKey::dialed(addr), Instant::now()));
+            assert_eq!(state.stats.misbehavior_bans, 1);
+        }
+    }
+
+    #[test]
+    fn requests_only_follow_interested() {
+        let (mut state, _timer_rx) = main_state(2, PIECE_LEN);
+        let (peer, peer_rx) = peer_info(2);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        state.peers.insert(addr, peer);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // a strict peer, which drops any Request that comes before Interested.
+        // Returns how many requests it took.
+        let mut interested = false;
+        let mut strict = || {
+            let mut requests = 0;
+            for req in peer_rx.try_iter() {
+                match req {
+                    PeerRequest::SendMessage(Message::Interested) => interested = true,
+                    PeerRequest::SendMessage(Message::NotInterested) => interested = false,
+                    PeerRequest::SendMessage(Message::Request(..)) => {
+                        assert!(interested, "Request sent before Interested");
+                        requests += 1;
+                    }
+                    _ => (),
+                }
+            }
+            requests
+        };
+
+        // unchoked, but with nothing we want
+        receive(&mut state, addr, Message::Unchoke);
+        refill_pipelines(&mut state, &mut rng);
+        assert_eq!(strict(), 0);
+
+        // a Have leaves interest to be settled, which happens before any requests go out
+        receive(&mut state, addr, Message::Have(1));
+        assert!(!state.peers[&addr].interested);
+        refill_pipelines(&mut state, &mut rng);
+        assert!(state.peers[&addr].interested);
+        assert_eq!(strict(), 1);
+    }
+
+    #[test]
+    fn have_floods_are_capped_per_peer() {
+        let (mut state, _timer_rx) = main_state(1000, PIECE_LEN);
+        state.args.max_have_rate = 100;
+        let flooder: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (peer, _flooder_rx) = peer_info(1000);
+        state.peers.insert(flooder, peer);
+        let (peer, _other_rx) = peer_info(1000);
+        state.peers.insert(other, peer);
+
+        // up to the cap every Have is handled
+        for piece in 0..100 {
+            receive(&mut state, flooder, Message::Have(piece));
+        }
+        assert_eq!(state.availability.get(99), 1);
+        assert_eq!(state.peers[&flooder].misbehavior, 0);
+
+        // past it they are ignored, and the peer is marked down once for it
+        for piece in 100..400 {
+            receive(&mut state, flooder, Message::Have(piece));
+        }
+        assert_eq!(state.availability.get(100), 0);
+        assert_eq!(
+            state.peers[&flooder].misbehavior,
+            misbehavior::weight(PeerWarning::MessageFlood)
+        );
+        assert_eq!(state.stats.flood_ignored, 300);
+
+        // which doesn't hold up anyone else's
+        receive(&mut state, other, Message::Have(500));
+        assert_eq!(state.availability.get(500), 1);
+        assert!(state.peers[&other].has.get(500));
+
+        receive(&mut state, flooder, Message::Have(400));
+        assert!(!state.peers.contains_key(&flooder));
+        assert_eq!(state.stats.flood_disconnects, 1);
+        let history = state.reconnects.history(&PeerKey::dialed(flooder)).unwrap();
+        assert_eq!(history.reason, Disconnect::Flooded);
+        assert!(!state
+            .bans
+            .is_banned(&PeerKey::dialed(flooder), Instant::now()));
+    }
+
+    #[test]
+    fn private_torrents_need_force_for_extra_trackers() {
+        let torrent = b"d8:announce14:http://a/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee";
+        let metainfo = bendy::serde::from_bytes::<MetaInfo>(torrent).unwrap();
+        assert!(metainfo.is_private());
+
+        let argv = ["rittorrent", "--torrent", "a.torrent"];
+        let args = Args::parse_from(argv);
+        assert_eq!(
+            tracker_tiers(&args, &metainfo).unwrap(),
+            vec![vec!["http://a/annce"]]
+        );
+
+        let argv = [&argv[..], &["--tracker", "http://b/announce"]].concat();
+        let err = tracker_tiers(&Args::parse_from(&argv), &metainfo).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{}", err);
+
+        let argv = [&argv[..], &["--force", "--replace-trackers"]].concat();
+        assert_eq!(
+            tracker_tiers(&Args::parse_from(argv), &metainfo).unwrap(),
+            vec![vec!["http://b/announce"]]
+        );
+    }
+
+    #[test]
+    fn replacing_trackers_needs_a_tracker() {
+        let argv = ["rittorrent", "--torrent", "a.torrent", "--replace-trackers"];
+        assert!(Args::try_parse_from(argv).is_err());
+        let argv = [&argv[..], &["--tracker", "udp://b:80"]].concat();
+        assert!(Args::try_parse_from(argv).is_err());
+    }
+
+    #[test]
+    fn metadata_is_served_to_extension_peers() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/debian-11.5.0-amd64-netinst.iso.torrent"
+        );
+        let metainfo = MetaInfo::from_file(path).unwrap();
+        let (mut state, _timer_rx) = main_state(1, PIECE_LEN);
+        state.info_hash = metainfo.info_hash();
+        state.metadata = metainfo.info_bytes();
+        // big enough to take more than one metadata piece
+        assert!(state.metadata.len() > METADATA_PIECE_LEN);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let (tx, rx) = channel::unbounded();
+        accept_connection(
+            &mut state,
+            ConnectionData {
+                peer: stream,
+                addr,
+                handshake: None,
+            },
+            &tx,
+        );
+
+        // a peer that only has the info hash, fetching the rest the way a magnet link would
+        let info_hash = state.info_hash;
+        let remote = thread::spawn(move || {
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut handshake = vec![19];
+            handshake.extend(b"BitTorrent protocol");
+            handshake.extend([0, 0, 0, 0, 0, 0x10, 0, 0]);
+            handshake.extend(info_hash);
+            handshake.extend([7; 20]);
+            client.write_all(&handshake).unwrap();
+
+            let mut theirs = [0u8; 68];
+            client.read_exact(&mut theirs).unwrap();
+            assert_ne!(theirs[25] & 0x10, 0, "extension protocol not advertised");
+
+            let mut reader = BufReader::new(client.try_clone().unwrap());
+            let mut send = |msg: Message| msg.write_to(&mut client).unwrap();
+            let mut metadata = Vec::new();
+            let mut pieces = 0;
+            loop {
+                let Message::Extended(id, payload) = Message::recv(&mut reader).unwrap() else {
+                    continue;
+                };
+                if id == extension::HANDSHAKE_ID {
+                    let size = Handshake::decode(&payload).unwrap().metadata_size;
+                    pieces = size.div_ceil(METADATA_PIECE_LEN);
+                    let mut ours = Handshake::default();
+                    ours.m.insert("ut_metadata".to_owned(), 3);
+                    send(Message::Extended(extension::HANDSHAKE_ID, ours.encode()));
+                    for piece in 0..=pieces {
+                        let request = MetadataMessage::Request(piece).encode();
+                        send(Message::Extended(extension::UT_METADATA_ID, request));
+                    }
+                    continue;
+                }
+
+                // replies come in order, with the id we asked for, and the piece past the end
+                // is rejected
+                assert_eq!(id, 3);
+                match MetadataMessage::decode(&payload).unwrap() {
+                    MetadataMessage::Data { piece, data, .. } => {
+                        assert_eq!(piece * METADATA_PIECE_LEN, metadata.len());
+                        metadata.extend(data);
+                    }
+                    MetadataMessage::Reject(piece) => {
+                        assert_eq!(piece, pieces);
+                        return metadata;
+                    }
+                    msg => panic!("unexpected {:?}", msg),
+                }
+            }
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !remote.is_finished() {
+            assert!(Instant::now() < deadline, "metadata exchange stalled");
+            if let Ok(Response::Peer(resp)) = rx.recv_timeout(Duration::from_millis(50)) {
+                handle_peer_response(&mut state, resp).unwrap();
+            }
+        }
+
+        let metadata = remote.join().unwrap();
+        assert_eq!(<[u8; 20]>::from(Sha1::digest(&metadata)), state.info_hash);
+    }
+
+    #[test]
+    fn download_cap_stops_requests_and_interest() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        state.args.max_download_bytes = Some(2 * PIECE_LEN);
+        state.stats.download_cap = state.args.max_download_bytes;
+        let (mut peer, peer_rx) = peer_info(4);
+        peer.peer_choked = false;
+        peer.interested = true;
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        insert_peer(&mut state, addr, peer);
+        receive(&mut state, addr, Message::Bitfield(vec![0b1111_0000]));
+
+        // only as much is requested as the cap allows, however deep the pipeline
+        let requests = pick_blocks(&state, &mut rand::thread_rng());
+        assert_eq!(requests.len(), 2);
+        for (id, (block, addr)) in requests.into_iter().enumerate() {
+            state.requested.insert(id as u64, (block, addr));
+        }
+        assert!(pick_blocks(&state, &mut rand::thread_rng()).is_empty());
+
+        for (block, _) in state.requested.clone().into_values() {
+            let data = vec![0; block.range.len()];
+            receive(
+                &mut state,
+                addr,
+                Message::Piece(block.piece as u32, 0, data),
+            );
+        }
+        check_caps(&mut state);
+        flush_interest(&mut state);
+
+        assert!(state.caps_reached.download);
+        assert!(pick_blocks(&state, &mut rand::thread_rng()).is_empty());
+        assert!(!state.peers[&addr].interested);
+        assert!(peer_rx
+            .try_iter()
+            .any(|req| matches!(req, PeerRequest::SendMessage(Message::NotInterested))));
+        assert!(state.stats.to_string().contains("download cap"));
+    }
+
+    #[test]
+    fn a_full_disk_pauses_the_download_until_space_is_freed() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        let (mut peer, peer_rx) = peer_info(4);
+        peer.peer_choked = false;
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        insert_peer(&mut state, addr, peer);
+        receive(&mut state, addr, Message::Bitfield(vec![0b1111_0000]));
+        flush_interest(&mut state);
+        assert!(state.peers[&addr].interested);
+        assert!(!pick_blocks(&state, &mut rand::thread_rng()).is_empty());
+
+        // the disk thread throws away the piece it couldn't write
+        state.file.write(Block::new(1, 0, &[0; PIECE_LEN])).unwrap();
+        let error = FileError::NoSpace {
+            needed: 4 * PIECE_LEN,
+            available: 0,
+        };
+        handle_disk_response(&mut state, DiskResponse::NoSpace { piece: 1, error }).unwrap();
+        assert!(state.disk_full.is_some());
+        assert_eq!(state.file.piece_state(1), Some(PieceState::Missing));
+        assert!(pick_blocks(&state, &mut rand::thread_rng()).is_empty());
+        assert_eq!(stall::diagnose(&state).reason, StallReason::DiskFull);
+
+        flush_interest(&mut state);
+        assert!(!state.peers[&addr].interested);
+        assert!(peer_rx
+            .try_iter()
+            .any(|req| matches!(req, PeerRequest::SendMessage(Message::NotInterested))));
+
+        // too little freed to fit a piece is still full
+        handle_disk_response(&mut state, DiskResponse::FreeSpace(Ok(PIECE_LEN - 1))).unwrap();
+        assert!(state.disk_full.is_some());
+
+        handle_disk_response(&mut state, DiskResponse::FreeSpace(Ok(PIECE_LEN))).unwrap();
+        assert!(state.disk_full.is_none());
+        flush_interest(&mut state);
+        assert!(state.peers[&addr].interested);
+        assert!(peer_rx
+            .try_iter()
+            .any(|req| matches!(req, PeerRequest::SendMessage(Message::Interested))));
+        assert!(!pick_blocks(&state, &mut rand::thread_rng()).is_empty());
+    }
+
+    #[test]
+    fn upload_caps_choke_peers() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        for piece in 0..2 {
+            let block = Block::new(piece, 0, &[0; PIECE_LEN]);
+            state.file.write(block).unwrap();
+        }
+        settle(&mut state, &disk_rx);
+        state.args.max_upload_bytes = Some(3 * PIECE_LEN);
+        state.args.max_peer_upload_bytes = Some(2 * PIECE_LEN);
+
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:6881", "10.0.0.2:6881"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let mut receivers = Vec::new();
+        for &addr in &addrs {
+            let (mut peer, peer_rx) = peer_info(2);
+            peer.choked = false;
+            peer.peer_interested = true;
+            insert_peer(&mut state, addr, peer);
+            receivers.push(peer_rx);
+        }
+        let request = |piece| Message::Request(piece, 0, PIECE_LEN as u32);
+        let choked = |peer_rx: &crossbeam::channel::Receiver<PeerRequest>| {
+            peer_rx
+                .try_iter()
+                .any(|req| matches!(req, PeerRequest::SendMessage(Message::Choke)))
+        };
+
+        // the first peer uses up its own cap, and is choked when it asks for more
+        for piece in [0, 1, 0] {
+            receive(&mut state, addrs[0], request(piece));
+            settle(&mut state, &disk_rx);
+        }
+        assert_eq!(state.peers[&addrs[0]].served, 2 * PIECE_LEN);
+        assert!(state.peers[&addrs[0]].choked);
+        assert!(choked(&receivers[0]));
+
+        // the second peer takes the session to its cap, and everyone is choked
+        receive(&mut state, addrs[1], request(1));
+        settle(&mut state, &disk_rx);
+        assert!(!choked(&receivers[1]));
+        check_caps(&mut state);
+        assert!(state.caps_reached.upload);
+        assert!(state.peers.values().all(|peer| peer.choked));
+        assert!(choked(&receivers[1]));
+        assert_eq!(state.stats.served, 3 * PIECE_LEN);
+    }
+
+    #[test]
+    fn uploads_wait_while_too_much_is_queued() {
+        let (mut state, _timer_rx, disk_rx) = main_state_with_disk(2, PIECE_LEN);
+        for piece in 0..2 {
+            let block = Block::new(piece, 0, &[0; PIECE_LEN]);
+            state.file.write(block).unwrap();
+        }
+        settle(&mut state, &disk_rx);
+        state.args.max_queued_upload_bytes = 2 * PIECE_LEN;
+
+        // a peer whose thread isn't writing anything out
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (mut peer, peer_rx) = peer_info(2);
+        peer.choked = false;
+        peer.peer_interested = true;
+        insert_peer(&mut state, addr, peer);
+
+        // two blocks take up all the room, so the third waits, before and after being read
+        for piece in [0, 1, 0] {
+            receive(
+                &mut state,
+                addr,
+                Message::Request(piece, 0, PIECE_LEN as u32),
+            );
+        }
+        assert_eq!(state.deferred_uploads.len(), 1);
+        settle(&mut state, &disk_rx);
+        assert_eq!(state.queued_uploads.bytes(), 2 * PIECE_LEN);
+        resume_uploads(&mut state);
+        assert_eq!(state.deferred_uploads.len(), 1);
+
+        // once the peer thread has written them, it's served
+        let written: Vec<PeerRequest> = peer_rx.try_iter().collect();
+        assert_eq!(written.len(), 2);
+        drop(written);
+        assert_eq!(state.queued_uploads.bytes(), 0);
+        resume_uploads(&mut state);
+        assert!(state.deferred_uploads.is_empty());
+        settle(&mut state, &disk_rx);
+        assert_eq!(state.peers[&addr].served, 3 * PIECE_LEN);
+        assert_eq!(
+            upload_backlog(&state.queued_uploads, &state.file),
+            PIECE_LEN
+        );
+        assert!(matches!(
+            peer_rx.try_recv(),
+            Ok(PeerRequest::Upload(Message::Piece(0, 0, _), _))
+        ));
+    }
+
+    #[test]
+    fn port_check_waits_for_a_way_to_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut state, _timer_rx) = main_state(1, PIECE_LEN);
+        state.port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel::unbounded();
+
+        // no check service, and no tracker has told us our address yet
+        start_port_check(&mut state, None, &tx);
+        assert!(!state.port_check_started);
+
+        start_port_check(&mut state, Some([127, 0, 0, 1].into()), &tx);
+        assert!(state.port_check_started);
+        let Response::PortCheck(reachability) = rx.recv_timeout(Duration::from_secs(5)).unwrap()
+        else {
+            panic!("expected the outcome of the check");
+        };
+        assert_eq!(reachability, Reachability::Open);
+    }
+
+    const CHURN_PEERS: usize = 6;
+    const CHURN_MAX_PIECES: usize = 20;
+
+    // What happens to a swarm of peers in slots 0..CHURN_PEERS, and to our own download.
+    // Pieces are picked by index into however many pieces the case has.
+    #[derive(Clone, Debug)]
+    enum Churn {
+        // a peer sends its bitfield, connecting first if it isn't already
+        Bitfield(usize, Vec<bool>),
+        Have(usize, Index),
+        Leave(usize),
+        Complete(Index),
+    }
+
+    fn churn() -> impl Strategy<Value = Churn> {
+        // seeds and empty peers are worth having often, as they're stored differently
+        let bits = prop_oneof![
+            Just(vec![true; CHURN_MAX_PIECES]),
+            Just(vec![false; CHURN_MAX_PIECES]),
+            prop::collection::vec(any::<bool>(), CHURN_MAX_PIECES),
+        ];
+        prop_oneof![
+            2 => (0..CHURN_PEERS, bits).prop_map(|(slot, bits)| Churn::Bitfield(slot, bits)),
+            4 => (0..CHURN_PEERS, any::<Index>()).prop_map(|(slot, piece)| Churn::Have(slot, piece)),
+            1 => (0..CHURN_PEERS).prop_map(Churn::Leave),
+            1 => any::<Index>().prop_map(Churn::Complete),
+        ]
+    }
+
+    proptest! {
+        // Availability is only ever adjusted, never recounted, so a single missed decrement
+        // would skew piece selection for the rest of the session. After every event it must
+        // match a count made from scratch.
+        #[test]
+        fn availability_survives_churn(
+            piece_count in 1..=CHURN_MAX_PIECES,
+            events in prop::collection::vec(churn(), 1..40),
+            seed: u64,
+        ) {
+            let (mut state, _timer_rx, disk_rx) = main_state_with_disk(piece_count, PIECE_LEN);
+            let addr = |slot: usize| SocketAddr::from(([10, 0, 0, slot as u8 + 1], 6881));
+
+            // sends us our own pieces, without having any as far as availability goes
+            let uploader: SocketAddr = "10.0.1.1:6881".parse().unwrap();
+            let (peer, _uploader_rx) = peer_info(piece_count);
+            insert_peer(&mut state, uploader, peer);
+
+            // what each connected peer has, kept the obvious way
+            let mut model: HashMap<usize, Vec<bool>> = HashMap::new();
+            let mut receivers = Vec::new();
+            let mut rng = StdRng::seed_from_u64(seed);
+
+            for (token, event) in events.into_iter().enumerate() {
+                match event {
+                    Churn::Bitfield(slot, mut bits) => {
+                        bits.truncate(piece_count);
+                        if !model.contains_key(&slot) {
+                            let (mut peer, peer_rx) = peer_info(piece_count);
+                            peer.peer_choked = false;
+                            peer.interested = true;
+                            state.peers.insert(addr(slot), peer);
+                            receivers.push(peer_rx);
+                        }
+                        let bytes = bits.iter().copied().collect::<BitVec<u8, Msb0>>();
+                        receive(&mut state, addr(slot), Message::Bitfield(bytes.into_vec()));
+                        model.insert(slot, bits);
+                    }
+                    Churn::Have(slot, piece) => {
+                        let piece = piece.index(piece_count);
+                        if let Some(has) = model.get_mut(&slot) {
+                            receive(&mut state, addr(slot), Message::Have(piece as u32));
+                            has[piece] = true;
+                        }
+                    }
+                    Churn::Leave(slot) => {
+                        if model.remove(&slot).is_some() {
+                            remove_peer(&mut state, addr(slot), Disconnect::Died);
+                        }
+                    }
+                    Churn::Complete(piece) => {
+                        let piece = piece.index(piece_count);
+                        if !state.file.bitvec()[piece] {
+                            let block = BlockInfo {
+                                piece,
+                                range: 0..PIECE_LEN,
+                            };
+                            state.requested.insert(token as u64, (block, uploader));
+                            receive(
+                                &mut state,
+                                uploader,
+                                Message::Piece(piece as u32, 0, vec![0; PIECE_LEN]),
+                            );
+                            settle(&mut state, &disk_rx);
+                            prop_assert!(state.file.bitvec()[piece]);
+                        }
+                    }
+                }
+
+                for piece in 0..piece_count {
+                    let holders = model.values().filter(|has| has[piece]).count();
+                    prop_assert_eq!(state.availability.get(piece), holders, "piece {}", piece);
+                }
+                for (&slot, has) in &model {
+                    let peer = &state.peers[&addr(slot)];
+                    prop_assert!(has.iter().enumerate().all(|(piece, &b)| peer.has.get(piece) == b));
+                    prop_assert_eq!(peer.is_seed, has.iter().all(|&b| b));
+                }
+                let seeds = model.values().filter(|has| has.iter().all(|&b| b)).count();
+                prop_assert_eq!(state.seeds, seeds);
+
+                // rarest first never goes after a piece nobody has, or one we have already
+                for (block, from) in pick_blocks(&state, &mut rng) {
+                    prop_assert!(state.availability.get(block.piece) > 0);
+                    prop_assert!(state.peers[&from].has.get(block.piece));
+                    prop_assert!(!state.file.bitvec()[block.piece]);
+                }
+            }
+        }
+    }
+}
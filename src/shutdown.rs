@@ -0,0 +1,134 @@
+//! Getting every thread to wind down once the main thread is gone, including when it panicked
+
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Asks every worker thread in the process to exit at its next opportunity
+pub fn request() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Makes any panic request a shutdown, after the usual panic message is printed.
+/// Without this, worker threads could keep the process alive after the main thread died.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        request();
+    }));
+}
+
+/// Shuts a listening socket down when dropped, which wakes the thread blocked accepting on
+/// it. Dropped by the main thread on the way out, whether it returned or panicked.
+#[derive(Debug)]
+pub struct ListenerGuard(TcpListener);
+
+impl ListenerGuard {
+    pub fn new(listener: &TcpListener) -> io::Result<Self> {
+        Ok(Self(listener.try_clone()?))
+    }
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        // Safety: the fd is valid for as long as we hold our clone of the listener
+        unsafe {
+            libc::shutdown(self.0.as_raw_fd(), libc::SHUT_RDWR);
+        }
+    }
+}
+
+/// Whether an error from accept means the listener has been shut down, rather than a problem
+/// with one incoming connection
+pub fn listener_closed(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidInput || requested()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crossbeam::channel;
+
+    use super::ListenerGuard;
+    use crate::connections::{spawn_accept_thread, HandshakeLimiter};
+    use crate::http::DEFAULT_MAX_BODY;
+    use crate::peers::spawn_peer_thread;
+    use crate::timer::spawn_timer_thread;
+    use crate::tracker::spawn_tracker_thread;
+
+    #[test]
+    fn threads_exit_when_main_panics() {
+        let (tx, rx) = channel::bounded(16);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let guard = ListenerGuard::new(&listener).unwrap();
+        let accept = spawn_accept_thread(listener, tx.clone(), HandshakeLimiter::new(4), 100);
+        let (timer_sender, timer) = spawn_timer_thread(tx.clone());
+        let (tracker_sender, tracker) = spawn_tracker_thread(tx.clone(), DEFAULT_MAX_BODY);
+
+        // a peer that completes its handshake and then waits on us
+        let remote_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote_addr = remote_listener.local_addr().unwrap();
+        let stream = TcpStream::connect(remote_addr).unwrap();
+        let (mut remote, _) = remote_listener.accept().unwrap();
+        let (hangup_sender, hangup_rx) = channel::unbounded();
+        let peer_sender = spawn_peer_thread(
+            stream,
+            remote_addr,
+            tx,
+            hangup_sender,
+            [0; 20],
+            [0; 20],
+            None,
+            None,
+            Vec::new(),
+        );
+        remote.write_all(&[0; 68]).unwrap();
+        remote.read_exact(&mut [0; 68]).unwrap();
+
+        // main owns all of these, and loses them when it panics
+        let main = thread::spawn(move || {
+            let _state = (
+                rx,
+                hangup_rx,
+                guard,
+                timer_sender,
+                tracker_sender,
+                peer_sender,
+            );
+            panic!("main loop blew up");
+        });
+        assert!(main.join().is_err());
+
+        let start = Instant::now();
+        for handle in [accept, timer, tracker] {
+            while !handle.is_finished() {
+                assert!(
+                    start.elapsed() < Duration::from_secs(5),
+                    "thread still running"
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+            handle.join().unwrap();
+        }
+
+        // the peer thread closes its connection
+        remote
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(remote.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! Noticing when a download has stopped making progress, and working out why
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::announce::TrackerStatus;
+use crate::session::MainState;
+
+/// How often to check whether the download has stalled
+pub const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The most fundamental reason nothing is being downloaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StallReason {
+    DiskFull,
+    NoPeers,
+    NoPeerHasMissingPieces,
+    AllChoking,
+    RequestsTimingOut,
+    NotRequesting,
+}
+
+impl fmt::Display for StallReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StallReason::DiskFull => "the disk is full, so we've stopped requesting",
+            StallReason::NoPeers => "not connected to any peers",
+            StallReason::NoPeerHasMissingPieces => "no connected peer has a piece we need",
+            StallReason::AllChoking => "every peer with pieces we need is choking us",
+            StallReason::RequestsTimingOut => "unchoked peers aren't answering our requests",
+            StallReason::NotRequesting => "peers have unchoked us, but we aren't requesting",
+        })
+    }
+}
+
+/// What a stalled download looks like, for someone trying to work out what's wrong
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnosis {
+    pub reason: StallReason,
+    pub peers: usize,
+
+    // peers with at least one piece we don't have, and how many of those unchoke us
+    pub useful_peers: usize,
+    pub unchoked_by: usize,
+
+    pub outstanding_requests: usize,
+
+    // trackers whose last announce worked, out of all of them
+    pub working_trackers: usize,
+    pub trackers: usize,
+
+    // pieces we don't have, and how many of those no connected peer has either
+    pub missing_pieces: usize,
+    pub unavailable_pieces: usize,
+    pub first_unavailable: Option<usize>,
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}; {} peers connected, {} with pieces we need, {} unchoking us, \
+             {} requests outstanding, {} of {} trackers working, \
+             {} of {} missing pieces unavailable",
+            self.reason,
+            self.peers,
+            self.useful_peers,
+            self.unchoked_by,
+            self.outstanding_requests,
+            self.working_trackers,
+            self.trackers,
+            self.unavailable_pieces,
+            self.missing_pieces,
+        )?;
+        if let Some(piece) = self.first_unavailable {
+            write!(f, " (first is piece {})", piece)?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks at the session to explain why nothing is being downloaded
+pub fn diagnose(state: &MainState) -> Diagnosis {
+    let ours = state.file.bitvec();
+    let missing: Vec<usize> = ours.iter_zeros().collect();
+    let unavailable: Vec<usize> = missing
+        .iter()
+        .copied()
+        .filter(|&piece| state.availability.get(piece) == 0)
+        .collect();
+
+    let useful: Vec<_> = state
+        .peers
+        .values()
+        .filter(|peer_info| peer_info.has.has_any_missing_from(ours))
+        .collect();
+    let unchoked_by = useful.iter().filter(|p| !p.peer_choked).count();
+
+    let reason = if state.disk_full.is_some() {
+        StallReason::DiskFull
+    } else if state.peers.is_empty() {
+        StallReason::NoPeers
+    } else if useful.is_empty() {
+        StallReason::NoPeerHasMissingPieces
+    } else if unchoked_by == 0 {
+        StallReason::AllChoking
+    } else if !state.requested.is_empty() {
+        StallReason::RequestsTimingOut
+    } else {
+        StallReason::NotRequesting
+    };
+
+    Diagnosis {
+        reason,
+        peers: state.peers.len(),
+        useful_peers: useful.len(),
+        unchoked_by,
+        outstanding_requests: state.requested.len(),
+        working_trackers: state
+            .trackers
+            .iter()
+            .filter(|t| matches!(t.status, TrackerStatus::Working { .. }))
+            .count(),
+        trackers: state.trackers.iter().count(),
+        missing_pieces: missing.len(),
+        unavailable_pieces: unavailable.len(),
+        first_unavailable: unavailable.first().copied(),
+    }
+}
+
+/// Watches the verified byte count, to notice when it hasn't moved for too long
+#[derive(Debug)]
+pub struct StallWatch {
+    downloaded: usize,
+    since: Instant,
+    reported: bool,
+}
+
+impl StallWatch {
+    pub fn new(downloaded: usize, now: Instant) -> Self {
+        Self {
+            downloaded,
+            since: now,
+            reported: false,
+        }
+    }
+
+    /// Returns true the first time `downloaded` has stayed put for `window`.
+    /// Once it moves again, the next stall is reported too.
+    pub fn check(&mut self, downloaded: usize, now: Instant, window: Duration) -> bool {
+        if downloaded != self.downloaded {
+            *self = Self::new(downloaded, now);
+            return false;
+        }
+
+        if self.reported || now.saturating_duration_since(self.since) < window {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+
+    /// Whether a stall has been reported and the download hasn't moved since
+    pub fn is_stalled(&self) -> bool {
+        self.reported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{diagnose, StallReason, StallWatch};
+    use crate::announce::{AnnounceMode, Trackers};
+    use crate::file::BlockInfo;
+    use crate::test_utils::{insert_peer, main_state, peer_info};
+
+    const PIECES: usize = 4;
+
+    fn addr(i: usize) -> SocketAddr {
+        format!("10.0.0.{}:6881", i).parse().unwrap()
+    }
+
+    #[test]
+    fn each_kind_of_stall_is_told_apart() {
+        let (mut state, _timer_rx) = main_state(PIECES, 16);
+        assert_eq!(diagnose(&state).reason, StallReason::NoPeers);
+
+        // a peer with nothing at all
+        let (peer, _rx0) = peer_info(PIECES);
+        insert_peer(&mut state, addr(0), peer);
+        let diagnosis = diagnose(&state);
+        assert_eq!(diagnosis.reason, StallReason::NoPeerHasMissingPieces);
+        assert_eq!(diagnosis.unavailable_pieces, PIECES);
+        assert_eq!(diagnosis.first_unavailable, Some(0));
+
+        // one with the first half, choking us
+        let (mut peer, _rx1) = peer_info(PIECES);
+        for piece in 0..2 {
+            peer.has.insert(piece);
+        }
+        insert_peer(&mut state, addr(1), peer);
+        let diagnosis = diagnose(&state);
+        assert_eq!(diagnosis.reason, StallReason::AllChoking);
+        assert_eq!(diagnosis.useful_peers, 1);
+        assert_eq!(diagnosis.unavailable_pieces, 2);
+        assert_eq!(diagnosis.first_unavailable, Some(2));
+
+        // it unchokes us, but we never ask it for anything
+        state.peers.get_mut(&addr(1)).unwrap().peer_choked = false;
+        assert_eq!(diagnose(&state).reason, StallReason::NotRequesting);
+
+        // or we do, and it never answers
+        let block = BlockInfo {
+            piece: 0,
+            range: 0..16,
+        };
+        state.requested.insert(1, (block, addr(1)));
+        let diagnosis = diagnose(&state);
+        assert_eq!(diagnosis.reason, StallReason::RequestsTimingOut);
+        assert_eq!(diagnosis.unchoked_by, 1);
+        assert_eq!(diagnosis.outstanding_requests, 1);
+        assert!(diagnosis
+            .to_string()
+            .starts_with("unchoked peers aren't answering our requests; 2 peers connected"));
+    }
+
+    #[test]
+    fn tracker_health_is_reported() {
+        let (mut state, _timer_rx) = main_state(PIECES, 16);
+        state.trackers = Trackers::new(
+            vec![
+                vec!["http://a/announce".to_owned()],
+                vec!["http://b/announce".to_owned()],
+            ],
+            AnnounceMode::AllTiers,
+            &mut StdRng::seed_from_u64(0),
+        );
+        state
+            .trackers
+            .on_success("http://a/announce", Vec::new(), None, None);
+        state
+            .trackers
+            .on_failure("http://b/announce", "connection refused".to_owned(), false);
+
+        let diagnosis = diagnose(&state);
+        assert_eq!((diagnosis.working_trackers, diagnosis.trackers), (1, 2));
+    }
+
+    #[test]
+    fn stalls_are_reported_once_until_progress_resumes() {
+        let window = Duration::from_secs(300);
+        let start = Instant::now();
+        let mut watch = StallWatch::new(0, start);
+
+        assert!(!watch.check(0, start + window / 2, window));
+        assert!(watch.check(0, start + window, window));
+        assert!(watch.is_stalled());
+        assert!(!watch.check(0, start + window * 2, window));
+
+        // progress starts the clock over
+        assert!(!watch.check(100, start + window * 2, window));
+        assert!(!watch.is_stalled());
+        assert!(!watch.check(100, start + window * 3 - Duration::from_secs(1), window));
+        assert!(watch.check(100, start + window * 3, window));
+    }
+}
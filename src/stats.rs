@@ -0,0 +1,201 @@
+use std::fmt;
+
+use crate::announce::TrackerStatus;
+use crate::hangup::Disconnects;
+use crate::portcheck::Reachability;
+use crate::rate::RateWindow;
+use crate::stall::StallReason;
+use crate::units::format_size;
+
+/// Session-wide counters
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    // payload bytes peer threads have written out to peers. As of the last tick.
+    pub uploaded: usize,
+
+    // payload bytes handed to peer threads to send, whether or not they got written. Upload
+    // caps go by this, so they hold while blocks are still on their way out.
+    pub served: usize,
+
+    // payload bytes that were part of a piece that passed its hash check
+    pub downloaded: usize,
+
+    // every payload byte we have received, including data we end up throwing away
+    pub received: usize,
+
+    // bytes of everything but piece data: handshakes, message headers, and every other
+    // message. As of the last tick.
+    pub protocol_sent: usize,
+    pub protocol_received: usize,
+
+    // payload bytes we received without having an outstanding request for them
+    pub unrequested: usize,
+
+    // payload bytes of blocks we had already got from someone else by the time they arrived
+    pub redundant: usize,
+
+    // payload bytes of blocks that arrived after we cancelled their request, which are
+    // thrown away
+    pub late: usize,
+
+    // number of times a peer was put on probation for not answering requests
+    pub probation_events: usize,
+
+    // messages waiting on the main thread, as of the last event and at worst
+    pub channel_depth: usize,
+    pub max_channel_depth: usize,
+
+    // piece data read for peers or waiting to be written to them, as of the last tick
+    pub queued_upload_bytes: usize,
+
+    // number of strategy passes skipped because the main thread was backed up
+    pub shed_passes: usize,
+
+    // number of peers banned for protocol violations, and the worst score of a connected peer
+    pub misbehavior_bans: usize,
+    pub worst_misbehavior: u32,
+
+    // Haves and Requests ignored for coming too fast, and peers dropped for keeping at it
+    pub flood_ignored: usize,
+    pub flood_disconnects: usize,
+
+    // connected peers with every piece, and the rest, as of the last tick
+    pub seeds: usize,
+    pub partial_peers: usize,
+
+    // copies of the rarest piece among us and our peers, plus the fraction of pieces with
+    // more copies than that, as of the last tick. Below 1.0 the download can't complete.
+    pub distributed_copies: f64,
+
+    // incoming connections partway through the handshake, those turned away because too
+    // many were, and handshakes that ran out of time
+    pub handshaking: usize,
+    pub handshakes_rejected: usize,
+    pub handshakes_expired: usize,
+
+    // incoming connections hung up on for wanting a torrent we aren't serving
+    pub unknown_infohash_connections: usize,
+
+    // peers from trackers waiting for their turn to be dialed, as of the last dial
+    pub dial_queue: usize,
+
+    // lost peers, by what they were doing at the time and which side closed the connection
+    pub disconnects: Disconnects,
+
+    // --max-download-bytes and --max-upload-bytes, which `received` and `uploaded` count
+    // towards
+    pub download_cap: Option<usize>,
+    pub upload_cap: Option<usize>,
+
+    // whether peers can reach our listen port, once --check-port has found out
+    pub port: Option<Reachability>,
+
+    // --no-listen, so every peer is one we connected to
+    pub outgoing_only: bool,
+
+    // every tracker and how its last announce went, in tier order
+    pub trackers: Vec<(String, TrackerStatus)>,
+
+    // the torrent's web seeds, and how many of them passed their last probe
+    pub web_seeds: usize,
+    pub usable_web_seeds: usize,
+
+    // why the download has stopped making progress, while it has
+    pub stalled: Option<StallReason>,
+
+    // recent transfer rates, advanced by the main thread's tick
+    pub upload_rate: RateWindow,
+    pub download_rate: RateWindow,
+    pub protocol_upload_rate: RateWindow,
+    pub protocol_download_rate: RateWindow,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uploaded {}, downloaded {} ({} received, {} unrequested, {} late, {} redundant), \
+             protocol overhead {} sent, {} received, peak backlog {} messages, {} peers banned for misbehaving, \
+             connected to {} seeds and {} other peers, {:.3} distributed copies",
+            format_size(self.uploaded),
+            format_size(self.downloaded),
+            format_size(self.received),
+            format_size(self.unrequested),
+            format_size(self.late),
+            format_size(self.redundant),
+            format_size(self.protocol_sent),
+            format_size(self.protocol_received),
+            self.max_channel_depth,
+            self.misbehavior_bans,
+            self.seeds,
+            self.partial_peers,
+            self.distributed_copies
+        )?;
+        if let Some(cap) = self.download_cap {
+            write!(
+                f,
+                ", {} of {} download cap",
+                format_size(self.received),
+                format_size(cap)
+            )?;
+        }
+        if let Some(cap) = self.upload_cap {
+            write!(
+                f,
+                ", {} of {} upload cap",
+                format_size(self.uploaded),
+                format_size(cap)
+            )?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ", listen port {}", port)?;
+        }
+        if self.outgoing_only {
+            write!(f, ", outgoing connections only")?;
+        }
+        if !self.trackers.is_empty() {
+            let working = self
+                .trackers
+                .iter()
+                .filter(|(_, status)| matches!(status, TrackerStatus::Working { .. }))
+                .count();
+            write!(
+                f,
+                ", {} of {} trackers working",
+                working,
+                self.trackers.len()
+            )?;
+        }
+        if self.web_seeds > 0 {
+            write!(
+                f,
+                ", {} of {} web seeds usable",
+                self.usable_web_seeds, self.web_seeds
+            )?;
+        }
+        if !self.disconnects.is_empty() {
+            write!(f, ", disconnects: {}", self.disconnects)?;
+        }
+        if self.queued_upload_bytes > 0 {
+            write!(
+                f,
+                ", {} queued for upload",
+                format_size(self.queued_upload_bytes)
+            )?;
+        }
+        if self.dial_queue > 0 {
+            write!(f, ", {} peers waiting to be dialed", self.dial_queue)?;
+        }
+        if self.flood_ignored > 0 || self.flood_disconnects > 0 {
+            write!(
+                f,
+                ", {} flooded messages ignored and {} peers dropped for flooding",
+                self.flood_ignored, self.flood_disconnects
+            )?;
+        }
+        if let Some(reason) = self.stalled {
+            write!(f, ", stalled: {}", reason)?;
+        }
+        Ok(())
+    }
+}
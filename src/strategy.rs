@@ -1,27 +1,89 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 
 use rand::seq::SliceRandom;
+use rand::Rng;
 
 use crate::{
-    args::ARGS,
+    caps,
     file::{self, BlockInfo},
-    MainState,
+    latency::PROBE_PIPELINE_DEPTH,
+    probation::PROBATION_PIPELINE_DEPTH,
+    session::{MainState, PeerInfo},
+    stream::READAHEAD_PIECES,
 };
 
-pub fn pick_blocks(state: &MainState) -> Vec<(file::BlockInfo, SocketAddr)> {
+// Whether we may send `peer_info` requests: it must have unchoked us, and we must have told it
+// we're interested first, or strict clients drop the requests without a word
+fn may_request(peer_info: &PeerInfo) -> bool {
+    peer_info.interested && !peer_info.peer_choked
+}
+
+// For every rare piece, the fastest peer we may request it from.
+// `addrs` is in request order, which settles ties.
+fn fastest_holders(state: &MainState, addrs: &[SocketAddr]) -> HashMap<usize, SocketAddr> {
+    let mut holders: HashMap<usize, (SocketAddr, f64)> = HashMap::new();
+    for &addr in addrs {
+        let peer_info = &state.peers[&addr];
+        if !may_request(peer_info) {
+            continue;
+        }
+
+        let rate = peer_info.upload_rate.rate();
+        for piece in peer_info.has.iter_ones() {
+            if state.availability.get(piece) >= state.args.rare_piece_threshold {
+                continue;
+            }
+            match holders.get(&piece) {
+                Some(&(_, fastest)) if fastest >= rate => (),
+                _ => {
+                    holders.insert(piece, (addr, rate));
+                }
+            }
+        }
+    }
+
+    holders
+        .into_iter()
+        .map(|(piece, (addr, _))| (piece, addr))
+        .collect()
+}
+
+pub fn pick_blocks(state: &MainState, rng: &mut impl Rng) -> Vec<(file::BlockInfo, SocketAddr)> {
     let mut ret = Vec::new();
 
-    // random order
+    // peers may not have heard we're no longer interested yet
+    if state.disk_full.is_some() {
+        return ret;
+    }
+
+    // Partial peers go first, fastest first, so they take the pieces they have before a seed
+    // can. Seeds then share out what's left, rather than one seed being asked for everything.
+    // Ties are broken at random, starting from a sorted list so that the map's own ordering
+    // doesn't leak in and the same rng always gives the same order.
     let mut addrs: Vec<SocketAddr> = state.peers.keys().map(|x| *x).collect();
-    addrs.shuffle(&mut rand::thread_rng());
+    addrs.sort();
+    addrs.shuffle(rng);
+    addrs.sort_by(|a, b| {
+        let (a, b) = (&state.peers[a], &state.peers[b]);
+        a.is_seed
+            .cmp(&b.is_seed)
+            .then(b.upload_rate.rate().total_cmp(&a.upload_rate.rate()))
+    });
+
+    let fastest = fastest_holders(state, &addrs);
+
+    // what's left under the download cap, if there is one
+    let mut budget = caps::download_budget(state);
 
     let mut iter = addrs.iter();
     while let Some(&addr) = iter.next() {
         // get the peer info
         let peer_info = state.peers.get(&addr).unwrap();
 
-        // if we're being choked, don't do anything
-        if peer_info.peer_choked {
+        // if we're being choked, or haven't said we're interested, don't do anything
+        if !may_request(peer_info) {
             continue;
         }
 
@@ -32,9 +94,53 @@ pub fn pick_blocks(state: &MainState) -> Vec<(file::BlockInfo, SocketAddr)> {
             .filter(|&(_, (_, a))| *a == addr)
             .count();
 
+        // peers on probation only get a single request until they prove themselves, and so do
+        // peers so slow that a full pipeline would time out before it was delivered
+        let pipeline_depth = if peer_info.probation {
+            PROBATION_PIPELINE_DEPTH
+        } else if peer_info.latency.exceeds(state.args.request_timeout) {
+            PROBE_PIPELINE_DEPTH
+        } else {
+            state.args.pipeline_depth
+        };
+
+        // High priority pieces come first, then pieces just ahead of where a streaming client
+        // is reading. Then rare pieces this peer is the fastest source of, then common pieces,
+        // and last rare pieces some faster peer should be getting. Within each group, pieces
+        // we've started go first, those with the least left to go first, so they get finished
+        // and can be shared. Rarer pieces go first after that. Skipped pieces are left out.
+        let readahead = state
+            .stream_position
+            .and_then(|o| state.file.piece_at(o))
+            .map(|first| first..first + READAHEAD_PIECES);
+        let mut pieces: Vec<usize> = state
+            .file
+            .unfilled_by_priority()
+            .filter(|&piece| peer_info.has.get(piece))
+            .collect();
+        pieces.sort_by_cached_key(|&piece| {
+            let priority = state.file.priority(piece).unwrap_or_default();
+            let streaming = readahead.as_ref().is_some_and(|r| r.contains(&piece));
+            let group = match fastest.get(&piece) {
+                Some(&holder) if holder == addr => 0,
+                None => 1,
+                Some(_) => 2,
+            };
+            let left = match state.file.piece_progress(piece) {
+                Some((filled, total)) if filled > 0 => total - filled,
+                _ => usize::MAX,
+            };
+            (
+                Reverse(priority),
+                !streaming,
+                group,
+                left,
+                state.availability.get(piece),
+            )
+        });
+
         // keep requesting blocks until we reach pipeline depth
-        let mut iter_ones = peer_info.has.iter_ones();
-        'outer: while let Some(piece) = iter_ones.next() {
+        'outer: for piece in pieces {
             // What blocks are outstanding for this piece?
             let Some(ranges) = state.file.get_unfilled(piece) else {
                 continue;
@@ -42,10 +148,15 @@ pub fn pick_blocks(state: &MainState) -> Vec<(file::BlockInfo, SocketAddr)> {
 
             for range in ranges {
                 // if we have reached pipeline depth, stop making requests
-                if count >= ARGS.pipeline_depth {
+                if count >= pipeline_depth {
                     break 'outer;
                 }
 
+                // nobody gets anything more once the download cap is spoken for
+                if budget == Some(0) {
+                    return ret;
+                }
+
                 // construct BlockInfo
                 let block_info = BlockInfo {
                     piece: piece,
@@ -65,6 +176,9 @@ pub fn pick_blocks(state: &MainState) -> Vec<(file::BlockInfo, SocketAddr)> {
                 }
 
                 // otherwise, add this block
+                if let Some(budget) = &mut budget {
+                    *budget = budget.saturating_sub(range.len());
+                }
                 ret.push((block_info, addr));
 
                 // and increment count
@@ -75,3 +189,251 @@ pub fn pick_blocks(state: &MainState) -> Vec<(file::BlockInfo, SocketAddr)> {
 
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::file::{Block, Priority};
+    use crate::piece_set::PieceSet;
+    use crate::session::MainState;
+    use crate::test_utils::{insert_peer, main_state, main_state_with_priorities, peer_info};
+
+    use super::pick_blocks;
+
+    const PIECE_LEN: usize = 16384;
+
+    #[test]
+    fn stream_position_is_requested_first() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        let (mut peer, _peer_rx) = peer_info(4);
+        peer.peer_choked = false;
+        peer.interested = true;
+        peer.has = PieceSet::all(peer.has.len());
+
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        insert_peer(&mut state, addr, peer);
+
+        let order = |state: &_| -> Vec<usize> {
+            pick_blocks(state, &mut rand::thread_rng())
+                .iter()
+                .map(|(b, _)| b.piece)
+                .collect()
+        };
+        assert_eq!(order(&state), vec![0, 1, 2, 3]);
+
+        state.stream_position = Some(PIECE_LEN * 2 + 100);
+        assert_eq!(order(&state), vec![2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn high_priority_pieces_go_first_and_skipped_ones_never() {
+        let priorities = [
+            (4, Priority::High),
+            (1, Priority::Skip),
+            (5, Priority::Skip),
+        ];
+        let (mut state, _timer_rx) = main_state_with_priorities(6, PIECE_LEN, &priorities);
+        let (mut peer, _peer_rx) = peer_info(6);
+        peer.peer_choked = false;
+        peer.interested = true;
+        peer.has = PieceSet::all(peer.has.len());
+        insert_peer(&mut state, "127.0.0.1:6881".parse().unwrap(), peer);
+
+        let order: Vec<usize> = pick_blocks(&state, &mut rand::thread_rng())
+            .iter()
+            .map(|(b, _)| b.piece)
+            .collect();
+        assert_eq!(order, [4, 0, 2, 3]);
+
+        // high priority wins out over where a stream is reading
+        state.stream_position = Some(PIECE_LEN * 3);
+        let order: Vec<usize> = pick_blocks(&state, &mut rand::thread_rng())
+            .iter()
+            .map(|(b, _)| b.piece)
+            .collect();
+        assert_eq!(order, [4, 3, 0, 2]);
+    }
+
+    #[test]
+    fn nearly_complete_pieces_are_finished_first() {
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN * 4);
+        state.args.pipeline_depth = 16;
+        let (mut peer, _peer_rx) = peer_info(4);
+        peer.peer_choked = false;
+        peer.interested = true;
+        peer.has = PieceSet::all(peer.has.len());
+        insert_peer(&mut state, "127.0.0.1:6881".parse().unwrap(), peer);
+
+        // piece 2 has one block left, and piece 1 three
+        for (piece, offset) in [(1, 0), (2, 0), (2, 1), (2, 3)] {
+            let block = Block::new(piece, offset * PIECE_LEN, &[0; PIECE_LEN]);
+            state.file.write(block).unwrap();
+        }
+
+        let requests = pick_blocks(&state, &mut rand::thread_rng());
+        let order: Vec<(usize, usize)> = requests
+            .iter()
+            .map(|(b, _)| (b.piece, b.range.start / PIECE_LEN))
+            .collect();
+        assert_eq!(order[..4], [(2, 2), (1, 1), (1, 2), (1, 3)]);
+        assert!(order[4..]
+            .iter()
+            .all(|&(piece, _)| piece == 0 || piece == 3));
+    }
+
+    #[test]
+    fn requests_spread_across_seeds_and_partials() {
+        const DEPTH: usize = 2;
+
+        let (mut state, _timer_rx) = main_state(8, PIECE_LEN);
+        state.args.pipeline_depth = DEPTH;
+
+        let partial: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let seeds: [SocketAddr; 2] = [
+            "10.0.0.2:6881".parse().unwrap(),
+            "10.0.0.3:6881".parse().unwrap(),
+        ];
+        let mut receivers = Vec::new();
+        for addr in [partial, seeds[0], seeds[1]] {
+            let (mut peer, peer_rx) = peer_info(8);
+            peer.peer_choked = false;
+            peer.interested = true;
+            if addr == partial {
+                for piece in 0..DEPTH {
+                    peer.has.insert(piece);
+                }
+            } else {
+                peer.has = PieceSet::all(peer.has.len());
+                peer.is_seed = true;
+            }
+            insert_peer(&mut state, addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        // whatever order the peers come out of the map in, the partial peer gets its
+        // pieces and every peer gets a full pipeline
+        for _ in 0..20 {
+            let requests = pick_blocks(&state, &mut rand::thread_rng());
+            for addr in [partial, seeds[0], seeds[1]] {
+                let pieces: Vec<usize> = requests
+                    .iter()
+                    .filter(|(_, a)| *a == addr)
+                    .map(|(b, _)| b.piece)
+                    .collect();
+                assert_eq!(pieces.len(), DEPTH);
+                if addr == partial {
+                    assert!(pieces.iter().all(|&piece| piece < DEPTH));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rare_pieces_go_to_the_fastest_peer() {
+        const DEPTH: usize = 2;
+
+        let (mut state, _timer_rx) = main_state(4, PIECE_LEN);
+        state.args.pipeline_depth = DEPTH;
+        state.args.rare_piece_threshold = 3;
+
+        // Pieces 1 and 2 are common. Piece 0 is rare, and both the slow partial peer (which is
+        // asked first) and the fast seed have it. Piece 3 only the fast seed has.
+        let slow: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let fast: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let choked: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        let mut receivers = Vec::new();
+        for addr in [slow, fast, choked] {
+            let (mut peer, peer_rx) = peer_info(4);
+            peer.peer_choked = addr == choked;
+            peer.interested = true;
+            if addr == slow {
+                for piece in 0..3 {
+                    peer.has.insert(piece);
+                }
+                peer.upload_rate.record(1000);
+            } else if addr == fast {
+                peer.has = PieceSet::all(peer.has.len());
+                peer.is_seed = true;
+                peer.upload_rate.record(100_000);
+            } else {
+                for piece in 1..3 {
+                    peer.has.insert(piece);
+                }
+            }
+            peer.upload_rate
+                .advance(Instant::now() + Duration::from_secs(1));
+            insert_peer(&mut state, addr, peer);
+            receivers.push(peer_rx);
+        }
+
+        for _ in 0..20 {
+            let requests = pick_blocks(&state, &mut rand::thread_rng());
+            let pieces = |addr| -> Vec<usize> {
+                requests
+                    .iter()
+                    .filter(|(_, a)| *a == addr)
+                    .map(|(b, _)| b.piece)
+                    .collect()
+            };
+
+            // the slow peer is left the common pieces, rarest first for the fast one
+            assert_eq!(pieces(slow), vec![1, 2]);
+            assert_eq!(pieces(fast), vec![3, 0]);
+        }
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_blocks() {
+        // a fresh state each time, so the peer map comes out in a different order
+        let state = || -> MainState {
+            let (mut state, _timer_rx) = main_state(8, PIECE_LEN);
+            state.args.pipeline_depth = 2;
+            for i in 1..=4 {
+                let (mut peer, _peer_rx) = peer_info(8);
+                peer.peer_choked = false;
+                peer.interested = true;
+                peer.has = PieceSet::all(peer.has.len());
+                let addr: SocketAddr = format!("10.0.0.{}:6881", i).parse().unwrap();
+                insert_peer(&mut state, addr, peer);
+            }
+            state
+        };
+        let pick = |seed| pick_blocks(&state(), &mut StdRng::seed_from_u64(seed));
+
+        assert_eq!(pick(7), pick(7));
+        assert!((0..20).any(|seed| pick(seed) != pick(7)));
+    }
+
+    #[test]
+    fn slow_peers_only_get_a_probe() {
+        let (mut state, _timer_rx) = main_state(8, PIECE_LEN);
+        state.args.pipeline_depth = 4;
+        state.args.request_timeout = Duration::from_secs(10);
+
+        let (mut peer, _peer_rx) = peer_info(8);
+        peer.peer_choked = false;
+        peer.interested = true;
+        peer.has = PieceSet::all(peer.has.len());
+        peer.latency.observe(Duration::from_secs(15));
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        insert_peer(&mut state, addr, peer);
+
+        let requests = |state: &_| pick_blocks(state, &mut rand::thread_rng()).len();
+        assert_eq!(requests(&state), 1);
+
+        // still slow after a block that only just made it
+        let latency = &mut state.peers.get_mut(&addr).unwrap().latency;
+        latency.observe(Duration::from_secs(9));
+        assert_eq!(requests(&state), 1);
+
+        // but a couple of quick ones bring it back to a full pipeline
+        let latency = &mut state.peers.get_mut(&addr).unwrap().latency;
+        latency.observe(Duration::from_secs(1));
+        latency.observe(Duration::from_secs(1));
+        assert_eq!(requests(&state), 4);
+    }
+}
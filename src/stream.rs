@@ -0,0 +1,395 @@
+//! Tiny HTTP server that lets a media player read the file while it is still downloading
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::{self, Sender};
+use log::{debug, info, warn};
+
+use crate::session::MainState;
+use crate::shutdown;
+use crate::threads::Response;
+
+/// Path the file is served at
+const STREAM_PATH: &str = "/file";
+
+/// Maximum number of stream connections being served at once
+const MAX_CONNECTIONS: usize = 4;
+
+/// Largest amount of data we hand out in response to a single request
+const MAX_RESPONSE_LEN: usize = 4 * 1024 * 1024;
+
+/// How long clients should wait before retrying data we don't have yet
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of pieces from the current stream position that get downloaded first
+pub const READAHEAD_PIECES: usize = 8;
+
+/// A request from a stream connection for file data.
+/// The main thread replies with as much of the range as has been verified, which may be nothing.
+#[derive(Debug)]
+pub struct StreamRead {
+    pub offset: usize,
+    pub len: usize,
+    pub reply: Sender<Vec<u8>>,
+}
+
+/// Handle a [StreamRead] on the main thread, recording where the client is reading so the
+/// strategy can prioritize the pieces just ahead of it. The disk thread does the reading, and
+/// replies to the connection itself.
+pub fn serve_read(state: &mut MainState, read: StreamRead) {
+    state.stream_position = Some(read.offset);
+    state.file.read_range(read.offset, read.len, read.reply);
+}
+
+pub fn spawn_stream_thread(listener: TcpListener, file_len: usize, sender: Sender<Response>) {
+    let active = Arc::new(AtomicUsize::new(0));
+
+    thread::spawn(move || loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if shutdown::listener_closed(&e) => break,
+            Err(_) => continue,
+        };
+
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            warn!("Too many stream connections, turning one away");
+            let _ = write_unavailable(&mut stream);
+            continue;
+        }
+
+        let active = active.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, file_len, &sender) {
+                debug!("Stream connection failed: {:?}", e);
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    });
+}
+
+fn handle_connection(stream: TcpStream, file_len: usize, sender: &Sender<Response>) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    // Request line, e.g. "GET /file HTTP/1.1"
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    // We only care about the Range header
+    let mut range_header = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range_header = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    info!(
+        "Stream request: {} {} (range {:?})",
+        method, path, range_header
+    );
+
+    if method != "GET" {
+        return write_head(&mut writer, "405 Method Not Allowed", &[], 0);
+    }
+    if path != STREAM_PATH {
+        return write_head(&mut writer, "404 Not Found", &[], 0);
+    }
+
+    let Some(range_header) = range_header else {
+        return write_whole_file(&mut writer, file_len, sender);
+    };
+
+    let Some(range) = parse_range(&range_header, file_len) else {
+        let content_range = format!("bytes */{}", file_len);
+        return write_head(
+            &mut writer,
+            "416 Range Not Satisfiable",
+            &[("Content-Range", &content_range)],
+            0,
+        );
+    };
+
+    let len = range.len().min(MAX_RESPONSE_LEN);
+    let data = read(sender, range.start, len)?;
+    if data.is_empty() {
+        return write_unavailable(&mut writer);
+    }
+
+    // We may serve less than was asked for; Content-Range tells the client what it got
+    let content_range = format!(
+        "bytes {}-{}/{}",
+        range.start,
+        range.start + data.len() - 1,
+        file_len
+    );
+    write_head(
+        &mut writer,
+        "206 Partial Content",
+        &[("Content-Range", &content_range)],
+        data.len(),
+    )?;
+    writer.write_all(&data)?;
+
+    Ok(())
+}
+
+// Without a Range header the client wants everything, so keep sending as data is verified
+fn write_whole_file(
+    writer: &mut TcpStream,
+    file_len: usize,
+    sender: &Sender<Response>,
+) -> Result<()> {
+    let mut offset = 0;
+    let mut data = read(sender, offset, file_len.min(MAX_RESPONSE_LEN))?;
+    if data.is_empty() && file_len > 0 {
+        return write_unavailable(writer);
+    }
+
+    write_head(writer, "200 OK", &[], file_len)?;
+    loop {
+        writer.write_all(&data)?;
+        offset += data.len();
+        if offset >= file_len {
+            return Ok(());
+        }
+
+        data = read(sender, offset, (file_len - offset).min(MAX_RESPONSE_LEN))?;
+        if data.is_empty() {
+            thread::sleep(RETRY_AFTER);
+        }
+    }
+}
+
+// Ask the main thread for verified file data
+fn read(sender: &Sender<Response>, offset: usize, len: usize) -> Result<Vec<u8>> {
+    let (reply, rx) = channel::bounded(1);
+    sender
+        .send(Response::Stream(StreamRead { offset, len, reply }))
+        .map_err(|_| anyhow!("main thread hung up"))?;
+
+    Ok(rx.recv()?)
+}
+
+fn write_unavailable(writer: &mut impl Write) -> Result<()> {
+    let retry_after = RETRY_AFTER.as_secs().to_string();
+    write_head(
+        writer,
+        "503 Service Unavailable",
+        &[("Retry-After", &retry_after)],
+        0,
+    )
+}
+
+fn write_head(
+    writer: &mut impl Write,
+    status: &str,
+    headers: &[(&str, &str)],
+    content_length: usize,
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    head.push_str("Content-Type: application/octet-stream\r\n");
+    head.push_str("Accept-Ranges: bytes\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", content_length));
+    head.push_str("Connection: close\r\n\r\n");
+
+    writer.write_all(head.as_bytes())?;
+    Ok(())
+}
+
+/// Parse the value of a Range header into the byte range it refers to.
+/// Only the first range of a multi-range request is honored.
+/// Returns [None] if the header is malformed or the range can't be satisfied.
+fn parse_range(header: &str, file_len: usize) -> Option<Range<usize>> {
+    let spec = header.strip_prefix("bytes=")?.split(',').next()?.trim();
+    let (start, end) = spec.split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        // last n bytes
+        ("", n) => {
+            let n: usize = n.parse().ok()?;
+            file_len.saturating_sub(n)..file_len
+        }
+        // from start to end of file
+        (start, "") => start.parse().ok()?..file_len,
+        // inclusive range, clamped to end of file
+        (start, end) => {
+            let start: usize = start.parse().ok()?;
+            let end: usize = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            start..end.saturating_add(1).min(file_len)
+        }
+    };
+
+    if range.start >= file_len || range.is_empty() {
+        return None;
+    }
+
+    Some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    use crossbeam::channel;
+
+    use crate::file::Block;
+    use crate::test_utils::main_state;
+    use crate::threads::Response;
+
+    use super::{parse_range, serve_read, spawn_stream_thread, StreamRead};
+
+    const PIECE_LEN: usize = 16384;
+    const FILE_LEN: usize = PIECE_LEN * 3;
+
+    #[test]
+    fn parse_range_forms() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(0..100));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(500..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=0-9, 20-29", 1000), Some(0..10));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=10-5", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("lines=0-10", 1000), None);
+        assert_eq!(parse_range("bytes=abc", 1000), None);
+    }
+
+    #[test]
+    fn serve_read_records_position() {
+        let (mut state, _timer_rx) = main_state(3, PIECE_LEN);
+        let (reply, rx) = channel::bounded(1);
+
+        serve_read(
+            &mut state,
+            StreamRead {
+                offset: PIECE_LEN + 10,
+                len: 100,
+                reply,
+            },
+        );
+
+        assert!(rx.recv().unwrap().is_empty());
+        assert_eq!(state.stream_position, Some(PIECE_LEN + 10));
+    }
+
+    // Starts a stream server backed by a file where only the first piece is complete
+    fn serve_partial_file() -> SocketAddr {
+        let (mut state, _timer_rx) = main_state(3, PIECE_LEN);
+        state
+            .file
+            .write(Block::new(0, 0, &[0u8; PIECE_LEN]))
+            .unwrap();
+
+        let (tx, rx) = channel::unbounded();
+        thread::spawn(move || {
+            for resp in rx.iter() {
+                if let Response::Stream(read) = resp {
+                    serve_read(&mut state, read);
+                }
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn_stream_thread(listener, FILE_LEN, tx);
+
+        addr
+    }
+
+    // Returns the head and body of the response
+    fn get(addr: SocketAddr, range: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /file HTTP/1.1\r\nHost: localhost\r\nRange: {}\r\n\r\n",
+            range
+        )
+        .unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn range_within_complete_piece() {
+        let addr = serve_partial_file();
+
+        let (head, body) = get(addr, "bytes=100-199");
+        assert!(head.starts_with("HTTP/1.1 206 "), "{}", head);
+        assert!(head.contains(&format!("Content-Range: bytes 100-199/{}", FILE_LEN)));
+        assert_eq!(body, vec![0u8; 100]);
+    }
+
+    #[test]
+    fn range_truncated_at_incomplete_piece() {
+        let addr = serve_partial_file();
+
+        let (head, body) = get(addr, "bytes=16000-");
+        assert!(head.starts_with("HTTP/1.1 206 "), "{}", head);
+        assert!(head.contains(&format!(
+            "Content-Range: bytes 16000-{}/{}",
+            PIECE_LEN - 1,
+            FILE_LEN
+        )));
+        assert_eq!(body.len(), PIECE_LEN - 16000);
+    }
+
+    #[test]
+    fn range_in_incomplete_piece_is_unavailable() {
+        let addr = serve_partial_file();
+
+        let (head, body) = get(addr, &format!("bytes={}-", PIECE_LEN * 2));
+        assert!(head.starts_with("HTTP/1.1 503 "), "{}", head);
+        assert!(head.contains("Retry-After: 1"));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn range_past_end_is_unsatisfiable() {
+        let addr = serve_partial_file();
+
+        let (head, _) = get(addr, &format!("bytes={}-", FILE_LEN));
+        assert!(head.starts_with("HTTP/1.1 416 "), "{}", head);
+    }
+}
@@ -0,0 +1,230 @@
+//! Helpers for building main thread state in tests, without spawning a session
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::Parser;
+use crossbeam::channel::{self, Receiver};
+use rand::{rngs::StdRng, SeedableRng};
+use sha1::{Digest, Sha1};
+
+use crate::address_watch::AddressWatch;
+use crate::announce::{AnnounceMode, Trackers};
+use crate::args::Args;
+use crate::availability::Availability;
+use crate::caps::Reached;
+use crate::dial_queue::DialQueue;
+use crate::events::Events;
+use crate::file::{Disk, DownloadFile, Priority};
+use crate::hash::Sha1PieceHasher;
+use crate::hooks::Hooks;
+use crate::latency::Latency;
+use crate::log_limiter::LogLimiter;
+use crate::misbehavior::{Bans, MessageRates};
+use crate::peers::{BlockBuffers, PeerRequest};
+use crate::piece_set::PieceSet;
+use crate::progress::ProgressOutput;
+use crate::rate::RateWindow;
+use crate::reconnect::Reconnects;
+use crate::session::{self, MainState, PeerInfo, SessionPhase, DIGEST_SIZE};
+use crate::stats::Stats;
+use crate::threads::Response;
+use crate::timer::TimerRequest;
+use crate::webseed::WebSeeds;
+
+// What the download is called, in the state's output directory
+const DOWNLOAD_NAME: &str = "download";
+
+/// Creates a [MainState] backed by a temporary file with `piece_count` pieces of `piece_len`
+/// bytes each, where every piece is expected to be all zeroes.
+/// The returned receiver sees everything sent to the timer thread.
+pub fn main_state(piece_count: usize, piece_len: usize) -> (MainState, Receiver<TimerRequest>) {
+    let (state, timer_rx, _) = main_state_with_disk(piece_count, piece_len);
+    (state, timer_rx)
+}
+
+/// Like [main_state], but also returns the receiver the disk thread reports to, for
+/// [settle] to apply what it says
+pub fn main_state_with_disk(
+    piece_count: usize,
+    piece_len: usize,
+) -> (MainState, Receiver<TimerRequest>, Receiver<Response>) {
+    let (timer_sender, timer_rx) = channel::unbounded();
+    let (disk_sender, disk_rx) = channel::unbounded();
+
+    // so whatever the session saves goes somewhere that is cleaned up, not the working directory
+    let scratch_dir = tempfile::tempdir().unwrap();
+    let dir = scratch_dir.path().to_str().unwrap();
+    let args = Args::parse_from([
+        "rittorrent",
+        "--torrent",
+        "test.torrent",
+        "--output-dir",
+        dir,
+        "--state-dir",
+        dir,
+    ]);
+    let file = zeroed_file(&args.output_dir, piece_count, piece_len);
+    let hooks = Hooks::new(&args, String::new(), [0; DIGEST_SIZE], PathBuf::new());
+    let state = MainState {
+        peers: HashMap::new(),
+        seeds: 0,
+        availability: Availability::new(piece_count),
+        upload_slots: 4,
+        phase: SessionPhase::Leeching,
+        metadata: Vec::new(),
+        traffic: Arc::default(),
+        unannounced: Vec::new(),
+        unsaved_pieces: 0,
+        queued_uploads: Arc::default(),
+        deferred_uploads: VecDeque::new(),
+        file: Disk::spawn(file, disk_sender),
+        timer_sender,
+        hangup_sender: channel::unbounded().0,
+        requested: HashMap::new(),
+        trackers: Trackers::new(
+            Vec::new(),
+            AnnounceMode::Tiered,
+            &mut StdRng::seed_from_u64(0),
+        ),
+        stats: Stats::default(),
+        stream_position: None,
+        interest_dirty: HashSet::new(),
+        log_limiter: LogLimiter::default(),
+        bans: Bans::default(),
+        reconnects: Reconnects::default(),
+        dial_queue: DialQueue::default(),
+        demand: None,
+        allocation: None,
+        above_low_water: false,
+        stale_run: None,
+        address_watch: AddressWatch::default(),
+        caps_reached: Reached::default(),
+        disk_full: None,
+        events: Events::default(),
+        progress: ProgressOutput::default(),
+        rng: StdRng::seed_from_u64(0),
+        _scratch_dir: Some(scratch_dir),
+        args,
+        info_hash: [0; DIGEST_SIZE],
+        peer_id: [0; 20],
+        piece_count,
+        port: 0,
+        port_check_started: false,
+        hooks,
+        web_seeds: WebSeeds::default(),
+    };
+
+    (state, timer_rx, disk_rx)
+}
+
+/// Like [main_state], but with each piece in `priorities` set to that priority
+pub fn main_state_with_priorities(
+    piece_count: usize,
+    piece_len: usize,
+    priorities: &[(usize, Priority)],
+) -> (MainState, Receiver<TimerRequest>) {
+    let (mut state, timer_rx) = main_state(piece_count, piece_len);
+    let mut file = zeroed_file(&state.args.output_dir, piece_count, piece_len);
+    for &(piece, priority) in priorities {
+        file.set_piece_priority(piece, priority).unwrap();
+    }
+
+    // the first disk thread goes away along with its end of the channel
+    let (disk_sender, _) = channel::unbounded();
+    state.file = Disk::spawn(file, disk_sender);
+    (state, timer_rx)
+}
+
+/// Where the file behind a state from [main_state] is, for tests to get at it behind the
+/// session's back
+pub fn download_path(state: &MainState) -> PathBuf {
+    state.args.output_dir.join(DOWNLOAD_NAME)
+}
+
+// A file in `dir` of `piece_count` pieces that are meant to be all zeroes
+fn zeroed_file(dir: &Path, piece_count: usize, piece_len: usize) -> DownloadFile {
+    let hash: [u8; DIGEST_SIZE] = Sha1::digest(vec![0u8; piece_len]).into();
+    let hashes = vec![hash; piece_count];
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dir.join(DOWNLOAD_NAME))
+        .unwrap();
+    DownloadFile::new_from_file(
+        file,
+        &hashes,
+        piece_len,
+        piece_len * piece_count,
+        Box::new(Sha1PieceHasher::default()),
+    )
+    .unwrap()
+}
+
+/// Waits for the disk thread to finish everything sent to it, then handles what it reported
+/// the way the session would
+pub fn settle(state: &mut MainState, disk_rx: &Receiver<Response>) {
+    state.file.flush().unwrap();
+    for resp in disk_rx.try_iter() {
+        if let Response::Disk(resp) = resp {
+            session::handle_disk_response(state, resp).unwrap();
+        }
+    }
+}
+
+/// Creates a [PeerInfo] for a freshly connected peer that has been sent an empty Bitfield,
+/// without spawning a peer thread.
+/// The returned receiver sees everything main sends to the peer.
+pub fn peer_info(piece_count: usize) -> (PeerInfo, Receiver<PeerRequest>) {
+    let (sender, rx) = channel::unbounded();
+    let peer_info = PeerInfo {
+        sender,
+        choked: false,
+        interested: false,
+        peer_choked: true,
+        peer_interested: false,
+        has: PieceSet::none(piece_count),
+        is_seed: false,
+        uploaded: 0,
+        downloaded: 0,
+        served: 0,
+        uploaded_recently: 0,
+        downloaded_recently: 0,
+        upload_rate: RateWindow::default(),
+        download_rate: RateWindow::default(),
+        choked_rounds: 0,
+        waiting_since: None,
+        probation: false,
+        misbehavior: 0,
+        hash_strikes: 0,
+        congested: false,
+        sent_bitfield: true,
+        pieces_told: 0,
+        bitfield_covers: 0,
+        message_rates: MessageRates::default(),
+        ut_metadata: None,
+        incoming: false,
+        listen_port: None,
+        traffic: Arc::default(),
+        buffers: BlockBuffers::default(),
+        latency: Latency::default(),
+        sent_at: HashMap::new(),
+        cancelled: Vec::new(),
+    };
+
+    (peer_info, rx)
+}
+
+/// Adds a peer to `state` the way a connection followed by a Bitfield would, so the pieces it
+/// has are counted towards their availability, and it counts as a seed if it has them all
+pub fn insert_peer(state: &mut MainState, addr: SocketAddr, mut peer_info: PeerInfo) {
+    state.availability.add(&peer_info.has);
+    peer_info.is_seed = peer_info.has.is_all();
+    state.seeds += peer_info.is_seed as usize;
+    state.peers.insert(addr, peer_info);
+}
@@ -1,14 +1,38 @@
-use anyhow::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use crate::connections::ConnectionData;
+use crate::control::ControlCommand;
+use crate::file::DiskResponse;
 use crate::peers::PeerResponse;
+use crate::portcheck::Reachability;
+use crate::scheduler::Allocation;
+use crate::stream::StreamRead;
 use crate::timer::TimerResponse;
 use crate::tracker;
+use crate::webseed::ProbeError;
+
+/// Capacity of the channel every thread uses to talk to the main thread.
+/// Generous, but finite so a stalled main thread can't make it grow without bound.
+pub const MAIN_CHANNEL_CAPACITY: usize = 1 << 16;
+
+/// How long a peer thread waits on a full main channel before giving up on its peer
+pub const PEER_SEND_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub enum Response {
     Connection(ConnectionData),
+    ConnectFailed(SocketAddr),
     Peer(PeerResponse),
-    Tracker(Result<tracker::response::Response>),
+    Tracker(
+        String,
+        Result<tracker::response::Response, tracker::TrackerError>,
+    ),
     Timer(TimerResponse),
+    Control(ControlCommand),
+    Stream(StreamRead),
+    PortCheck(Reachability),
+    WebSeed(String, Result<(), ProbeError>),
+    Disk(DiskResponse),
+    Schedule(Allocation),
 }
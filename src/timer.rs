@@ -1,12 +1,14 @@
 use std::{
     collections::{BTreeSet, HashMap},
-    thread,
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
+use crate::shutdown;
 use crate::threads::{self, Response};
 
-use crossbeam::channel::{self, Sender};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::debug;
 
 pub type Token = u64;
 
@@ -34,15 +36,31 @@ struct Timer {
     repeat: bool,
 }
 
-pub fn spawn_timer_thread(sender: Sender<threads::Response>) -> Sender<TimerRequest> {
+// Where a repeating timer that was due at `expiration` goes next.
+// Stays on the timer's original cadence, but if we have fallen more than a period behind the
+// missed repeats are skipped, so the timer fires once rather than in a burst of stale events.
+fn next_expiration(expiration: Instant, timer_len: Duration, now: Instant) -> Instant {
+    let next = expiration + timer_len;
+    if next > now || timer_len.is_zero() {
+        return next.max(now);
+    }
+
+    let periods = now.duration_since(expiration).as_nanos() / timer_len.as_nanos() + 1;
+    expiration + timer_len * periods as u32
+}
+
+/// Spawns the timer thread, which exits once the main thread hangs up on it
+pub fn spawn_timer_thread(
+    sender: Sender<threads::Response>,
+) -> (Sender<TimerRequest>, JoinHandle<()>) {
     let (tx, rx) = channel::unbounded::<TimerRequest>();
 
-    thread::spawn(move || {
+    let handle = thread::spawn(move || {
         //let mut timers = BinaryHeap::new();
         let mut id_map = HashMap::new();
         let mut timers = BTreeSet::new();
 
-        loop {
+        while !shutdown::requested() {
             let timeout = timers
                 .iter()
                 .next()
@@ -50,8 +68,8 @@ pub fn spawn_timer_thread(sender: Sender<threads::Response>) -> Sender<TimerRequ
                 .unwrap_or(Duration::MAX);
 
             // see if we have a new timer to process
-            if let Ok(req) = rx.recv_timeout(timeout) {
-                match req {
+            match rx.recv_timeout(timeout) {
+                Ok(req) => match req {
                     TimerRequest::Timer(req) => {
                         let expiration = Instant::now()
                             .checked_add(req.timer_len)
@@ -73,7 +91,9 @@ pub fn spawn_timer_thread(sender: Sender<threads::Response>) -> Sender<TimerRequ
                             id_map.remove(&id).unwrap();
                         }
                     }
-                }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
             }
 
             // check for timer expirations
@@ -85,15 +105,18 @@ pub fn spawn_timer_thread(sender: Sender<threads::Response>) -> Sender<TimerRequ
                     assert!(timers.remove(&timer));
                     id_map.remove(&timer.id).unwrap();
 
-                    sender
+                    if sender
                         .send(Response::Timer(TimerResponse { id: timer.id }))
-                        .unwrap();
+                        .is_err()
+                    {
+                        debug!("Timer thread exiting, main thread is gone");
+                        return;
+                    }
 
                     // place timer back on if it is a repeating timer
                     if timer.repeat {
-                        let expiration = Instant::now()
-                            .checked_add(timer.timer_len)
-                            .expect("Invalid timer!");
+                        let expiration =
+                            next_expiration(timer.expiration, timer.timer_len, Instant::now());
                         let new_timer = Timer {
                             expiration,
                             timer_len: timer.timer_len,
@@ -110,9 +133,11 @@ pub fn spawn_timer_thread(sender: Sender<threads::Response>) -> Sender<TimerRequ
                 }
             }
         }
+
+        debug!("Timer thread exiting");
     });
 
-    tx
+    (tx, handle)
 }
 
 #[cfg(test)]
@@ -123,13 +148,13 @@ mod tests {
 
     use crossbeam::channel;
 
-    use super::{spawn_timer_thread, TimerRequest};
+    use super::{next_expiration, spawn_timer_thread, TimerRequest};
 
     #[test]
     fn timer_thread_basic() {
         let (sender, receiver) = channel::unbounded();
 
-        let timer_sender = spawn_timer_thread(sender);
+        let (timer_sender, _) = spawn_timer_thread(sender);
 
         // this is terrible for testing but oh well it probably works fine
         let duration = Duration::from_millis(100);
@@ -153,4 +178,26 @@ mod tests {
         assert_eq!(resp.id, 727);
         assert!(before.elapsed() >= duration);
     }
+
+    #[test]
+    fn next_expiration_on_time() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        let next = next_expiration(start, second, start + Duration::from_millis(5));
+        assert_eq!(next, start + second);
+    }
+
+    #[test]
+    fn next_expiration_coalesces_stall() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        // the timer thread was stuck for ten and a half seconds: fire once, then get back
+        // onto the original one-second cadence
+        let now = start + Duration::from_millis(10_500);
+        let next = next_expiration(start, second, now);
+        assert_eq!(next, start + second * 11);
+        assert!(next > now);
+    }
 }
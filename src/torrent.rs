@@ -1,18 +1,70 @@
-use std::collections::HashMap;
+//! Parsing metainfo (.torrent) files
 
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
 use bendy::{serde::to_bytes, value::Value};
-use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha1::digest::Digest;
 use sha1::Sha1;
 
+use crate::bencode::{self, Document};
+use crate::encoding;
+use crate::units::format_size;
+
 const DIGEST_SIZE: usize = 20;
 
+/// Longest pieces we handle unless told otherwise. Longer ones exist, but every piece being
+/// downloaded or hashed costs memory in proportion to its length.
+pub const DEFAULT_MAX_PIECE_LENGTH: usize = 64 * 1024 * 1024;
+
+/// The contents of a metainfo file, for a single-file torrent
+///
+/// ```
+/// use rittorrent::torrent::MetaInfo;
+///
+/// let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/flatland.torrent");
+/// let metainfo = MetaInfo::from_file(path)?;
+///
+/// // a 20-byte SHA-1 hash for every piece
+/// let pieces = metainfo.info.length.div_ceil(metainfo.info.piece_length);
+/// assert_eq!(metainfo.piece_count(), pieces);
+/// assert_eq!(metainfo.info.pieces.len(), pieces * 20);
+/// println!("{} has info hash {:02x?}", metainfo.name().display, metainfo.info_hash());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct MetaInfo<'a> {
     pub announce: String,
 
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+
+    /// The encoding of `info.name`, which some old clients set when they didn't use UTF-8
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub encoding: String,
+
     #[serde(borrow = "'a")]
     pub info: Info<'a>,
+
+    /// DHT bootstrap nodes (BEP 5) as host and port, which trackerless torrents have in place
+    /// of trackers
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_nodes",
+        serialize_with = "serialize_nodes"
+    )]
+    pub nodes: Option<Vec<(String, u16)>>,
+
+    /// Everything else, such as the web seeds in `url-list`
+    #[serde(flatten, borrow = "'a")]
+    pub remaining: HashMap<String, Value<'a>>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -23,7 +75,9 @@ pub struct Info<'a> {
     #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
 
-    pub name: String,
+    /// Not necessarily UTF-8, so kept as it was for the info hash. See [MetaInfo::name].
+    #[serde(with = "serde_bytes")]
+    pub name: Vec<u8>,
 
     pub length: usize,
 
@@ -31,21 +85,304 @@ pub struct Info<'a> {
     pub remaining: HashMap<String, Value<'a>>,
 }
 
+/// A torrent's name, made presentable
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Name {
+    /// For showing to the user. May contain replacement characters.
+    pub display: String,
+
+    /// For the downloaded file. A single path component, never `.` or `..`.
+    pub file_name: String,
+}
+
+// "host:port", with an IPv6 host in brackets
+fn parse_host_port(node: &str) -> Option<(String, u16)> {
+    let (host, port) = node.trim().rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let port: u16 = port.parse().ok()?;
+
+    (!host.is_empty() && port != 0).then(|| (host.to_owned(), port))
+}
+
+fn parse_node(node: &Value) -> Option<(String, u16)> {
+    match node {
+        Value::List(pair) => {
+            let [Value::Bytes(host), port] = pair.as_slice() else {
+                return None;
+            };
+            let port = match port {
+                Value::Integer(port) => u16::try_from(*port).ok()?,
+                Value::Bytes(port) => std::str::from_utf8(port).ok()?.trim().parse().ok()?,
+                _ => return None,
+            };
+            let host = std::str::from_utf8(host).ok()?;
+
+            (!host.is_empty() && port != 0).then(|| (host.to_owned(), port))
+        }
+        Value::Bytes(node) => parse_host_port(std::str::from_utf8(node).ok()?),
+        _ => None,
+    }
+}
+
+// BEP 5 has `nodes` as a list of [host, port] pairs. Torrents in the wild also have the pairs
+// as "host:port" strings, ports as strings, or the whole list as one string of "host:port"s
+// separated by commas or whitespace. Nodes that make no sense are skipped.
+fn deserialize_nodes<'de, D>(deserializer: D) -> Result<Option<Vec<(String, u16)>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let nodes = match Value::deserialize(deserializer)? {
+        Value::List(nodes) => nodes.iter().filter_map(parse_node).collect(),
+        Value::Bytes(nodes) => String::from_utf8_lossy(&nodes)
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter_map(parse_host_port)
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(Some(nodes))
+}
+
+// Written as the list itself, where bendy would wrap a Some in a list of its own
+fn serialize_nodes<S>(nodes: &Option<Vec<(String, u16)>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    nodes.as_deref().unwrap_or_default().serialize(serializer)
+}
+
+impl MetaInfo<'static> {
+    /// Reads and parses a metainfo file from disk
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read torrent file {}", path.display()))?;
+        let metainfo = bencode::from_bytes::<MetaInfo>(&data, Document::Torrent)
+            .map_err(|e| anyhow!("Failed to parse torrent file {}: {}", path.display(), e))?;
+
+        Ok(metainfo.into_owned())
+    }
+}
+
 impl MetaInfo<'_> {
+    /// Copies any data borrowed from the original buffer, so the metainfo can outlive it
+    pub fn into_owned(self) -> MetaInfo<'static> {
+        MetaInfo {
+            announce: self.announce,
+            announce_list: self.announce_list,
+            encoding: self.encoding,
+            info: Info {
+                piece_length: self.info.piece_length,
+                pieces: self.info.pieces,
+                name: self.info.name,
+                length: self.info.length,
+                remaining: self
+                    .info
+                    .remaining
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into_owned()))
+                    .collect(),
+            },
+            nodes: self.nodes,
+            remaining: self
+                .remaining
+                .into_iter()
+                .map(|(k, v)| (k, v.into_owned()))
+                .collect(),
+        }
+    }
+
+    /// Checks that the pieces add up to the file, and that they are no longer than
+    /// `max_piece_length`
+    pub fn validate(&self, max_piece_length: usize) -> Result<()> {
+        let info = &self.info;
+        if info.piece_length == 0 {
+            bail!("Torrent has a piece length of 0");
+        }
+        if info.piece_length > max_piece_length {
+            bail!(
+                "Torrent has {} pieces, longer than the {} allowed; pass --max-piece-size {} to \
+                 download it anyway",
+                format_size(info.piece_length),
+                format_size(max_piece_length),
+                info.piece_length
+            );
+        }
+        if !info.pieces.len().is_multiple_of(DIGEST_SIZE) {
+            bail!("Torrent piece hashes aren't a whole number of hashes");
+        }
+        let expected = info.length.div_ceil(info.piece_length);
+        if self.piece_count() != expected {
+            bail!(
+                "Torrent has {} piece hashes, but {} pieces of {}",
+                self.piece_count(),
+                expected,
+                format_size(info.piece_length)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the tiers of trackers for this torrent, as described in BEP 12.
+    /// Falls back to a single tier containing `announce` if there is no announce-list.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.iter().any(|tier| !tier.is_empty()) {
+            self.announce_list.clone()
+        } else {
+            vec![vec![self.announce.clone()]]
+        }
+    }
+
+    /// How many pieces the torrent is split into, one for every hash in `info.pieces`
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.len() / DIGEST_SIZE
+    }
+
+    /// The SHA-1 hash of every piece, in order
+    pub fn piece_hashes(&self) -> Vec<[u8; DIGEST_SIZE]> {
+        self.info
+            .pieces
+            .chunks_exact(DIGEST_SIZE)
+            .map(|hash| hash.try_into().unwrap())
+            .collect()
+    }
+
+    /// Whether the torrent is private (BEP 27), meaning peers should only come from its own
+    /// trackers
+    pub fn is_private(&self) -> bool {
+        matches!(self.info.remaining.get("private"), Some(Value::Integer(1)))
+    }
+
+    /// The URLs of the torrent's web seeds (BEP 19), skipping any that aren't valid UTF-8.
+    /// `url-list` may be a single URL or a list of them.
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match self.remaining.get("url-list") {
+            Some(Value::Bytes(url)) => vec![url],
+            Some(Value::List(urls)) => urls
+                .iter()
+                .filter_map(|url| match url {
+                    Value::Bytes(url) => Some(url),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        urls.into_iter()
+            .filter_map(|url| std::str::from_utf8(url).ok())
+            .filter(|url| !url.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// The torrent's DHT bootstrap nodes, if it has any
+    pub fn nodes(&self) -> &[(String, u16)] {
+        self.nodes.as_deref().unwrap_or_default()
+    }
+
+    /// Works out what to call the torrent, logging any conversion that was needed.
+    ///
+    /// The name is decoded with the declared `encoding`, or as UTF-8 if there is none. If that
+    /// fails, it is shown with replacement characters, and saved under an ASCII transliteration.
+    pub fn name(&self) -> Name {
+        let raw = &self.info.name;
+        let label = match self.encoding.as_str() {
+            "" => "UTF-8",
+            label => label,
+        };
+
+        let (display, file_name) = match encoding::decode(raw, label) {
+            Ok(name) => {
+                if !self.encoding.is_empty() {
+                    info!("Decoded torrent name {:?} from {}", name, label);
+                }
+                (name.clone(), name)
+            }
+            Err(e) => {
+                let display = String::from_utf8_lossy(raw).into_owned();
+                let file_name = encoding::transliterate(raw);
+                warn!(
+                    "Torrent name is {}, showing it as {:?} and saving it as {:?}",
+                    e, display, file_name
+                );
+                (display, file_name)
+            }
+        };
+
+        let sanitized = encoding::sanitize_file_name(&file_name);
+        if sanitized != file_name {
+            warn!(
+                "Torrent name {:?} is not a safe file name, saving it as {:?}",
+                file_name, sanitized
+            );
+        }
+
+        Name {
+            display,
+            file_name: sanitized,
+        }
+    }
+
+    /// The bencoded info dictionary, which is what peers fetch as the torrent's metadata
+    pub fn info_bytes(&self) -> Vec<u8> {
+        to_bytes(&self.info).unwrap()
+    }
+
+    /// SHA-1 of the bencoded info dictionary, which identifies the torrent to trackers and peers
     pub fn info_hash(&self) -> [u8; DIGEST_SIZE] {
         let mut hasher = Sha1::new();
-        hasher.update(to_bytes(&self.info).unwrap());
+        hasher.update(self.info_bytes());
         hasher.finalize().into()
     }
 }
 
+/// Prints what the metainfo file at `path` describes
+pub fn print(path: &Path) -> Result<()> {
+    let metainfo = MetaInfo::from_file(path)?;
+    let info_hash: String = metainfo
+        .info_hash()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    println!("Name:      {}", metainfo.name().display);
+    println!("Info hash: {}", info_hash);
+    println!(
+        "Size:      {} in {} pieces of {}",
+        format_size(metainfo.info.length),
+        metainfo.piece_count(),
+        format_size(metainfo.info.piece_length)
+    );
+    if metainfo.is_private() {
+        println!("Private:   yes");
+    }
+    for (i, tier) in metainfo.tiers().iter().enumerate() {
+        println!("Tier {}:    {}", i, tier.join(", "));
+    }
+    for url in metainfo.web_seeds() {
+        println!("Web seed:  {}", url);
+    }
+    for (host, port) in metainfo.nodes() {
+        if host.contains(':') {
+            println!("DHT node:  [{}]:{}", host, port);
+        } else {
+            println!("DHT node:  {}:{}", host, port);
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use bendy::serde::{from_bytes, to_bytes};
     use hex_literal::hex;
-    use std::{fs::File, io::Read, path::PathBuf};
+    use std::{collections::HashMap, fs::File, io::Read, path::PathBuf};
 
-    use super::MetaInfo;
+    use super::{Info, MetaInfo, Name, DEFAULT_MAX_PIECE_LENGTH};
 
     #[test]
     fn meta_file_deserialize_flatland() {
@@ -59,6 +396,13 @@ mod tests {
         let info = from_bytes::<MetaInfo>(&result).unwrap();
 
         assert_eq!(info.announce, "http://128.8.126.63:21212/announce");
+        assert_eq!(
+            info.tiers(),
+            vec![
+                vec![info.announce.clone()],
+                vec!["udp://128.8.126.63:21212".to_owned()]
+            ]
+        );
 
         let hash = info.info_hash();
         assert_eq!(hash, hex!("d4437aed681cb06c5ecbcf2c7f590ae8a3f73aeb"));
@@ -80,4 +424,237 @@ mod tests {
         let hash = info.info_hash();
         assert_eq!(hash, hex!("d55be2cd263efa84aeb9495333a4fabc428a4250"));
     }
+
+    #[test]
+    fn meta_file_announce_list() {
+        let torrent = b"d8:announce14:http://a/annce13:announce-listll14:http://a/annceel14:http://b/annceee4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let info = from_bytes::<MetaInfo>(torrent).unwrap();
+
+        assert_eq!(
+            info.tiers(),
+            vec![
+                vec!["http://a/annce".to_owned()],
+                vec!["http://b/annce".to_owned()]
+            ]
+        );
+    }
+
+    #[test]
+    fn meta_file_from_file_owns_data() {
+        let mut debian_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        debian_path.push("resources/debian-11.5.0-amd64-netinst.iso.torrent");
+
+        let info = MetaInfo::from_file(&debian_path).unwrap();
+
+        assert_eq!(info.announce, "http://bttracker.debian.org:6969/announce");
+        assert_eq!(
+            info.info_hash(),
+            hex!("d55be2cd263efa84aeb9495333a4fabc428a4250")
+        );
+    }
+
+    #[test]
+    fn piece_count_comes_from_the_hashes() {
+        // debian's piece count is a multiple of 8, flatland's isn't
+        for (name, count) in [
+            ("debian-11.5.0-amd64-netinst.iso.torrent", 1528),
+            ("flatland.torrent", 7),
+        ] {
+            let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+            path.push("resources");
+            path.push(name);
+            let metainfo = MetaInfo::from_file(path).unwrap();
+
+            let hashes = metainfo.piece_hashes();
+            assert_eq!(metainfo.piece_count(), count);
+            assert_eq!(hashes.len(), count);
+            assert_eq!(hashes[0][..], metainfo.info.pieces[..20]);
+        }
+    }
+
+    #[test]
+    fn latin1_name_without_encoding() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/latin1.torrent");
+        let raw = std::fs::read(&path).unwrap();
+
+        let info = MetaInfo::from_file(&path).unwrap();
+        assert_eq!(info.info.name, b"Caf\xe9 cr\xe8me.txt");
+        assert_eq!(
+            info.name(),
+            Name {
+                display: "Caf\u{fffd} cr\u{fffd}me.txt".to_owned(),
+                file_name: "Cafe creme.txt".to_owned(),
+            }
+        );
+
+        // the name goes back out exactly as it came in, or the info hash would change
+        let info_bytes = info.info_bytes();
+        assert!(raw.windows(info_bytes.len()).any(|w| w == info_bytes));
+    }
+
+    #[test]
+    fn declared_encoding_is_used() {
+        let torrent = b"d8:announce14:http://a/annce8:encoding10:ISO-8859-14:infod6:lengthi1e4:name9:na\xefve.txt12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let info = from_bytes::<MetaInfo>(torrent).unwrap();
+
+        assert_eq!(info.encoding, "ISO-8859-1");
+        assert_eq!(info.name().display, "na\u{ef}ve.txt");
+        assert_eq!(info.name().file_name, "na\u{ef}ve.txt");
+    }
+
+    #[test]
+    fn names_cannot_escape_the_output_directory() {
+        let torrent = b"d8:announce14:http://a/annce4:infod6:lengthi1e4:name5:../..12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let info = from_bytes::<MetaInfo>(torrent).unwrap();
+
+        assert_eq!(info.name().display, "../..");
+        assert_eq!(info.name().file_name, ".._..");
+    }
+
+    #[test]
+    fn web_seeds_are_one_url_or_a_list() {
+        let torrent = b"d8:announce14:http://a/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list14:http://m/a.isoe";
+        let info = from_bytes::<MetaInfo>(torrent).unwrap();
+        assert_eq!(info.web_seeds(), vec!["http://m/a.iso".to_owned()]);
+
+        let torrent = b"d8:announce14:http://a/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-listl9:http://m/0:i3e9:http://n/ee";
+        let info = from_bytes::<MetaInfo>(torrent).unwrap().into_owned();
+        assert_eq!(
+            info.web_seeds(),
+            vec!["http://m/".to_owned(), "http://n/".to_owned()]
+        );
+
+        let torrent = b"d8:announce14:http://a/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(from_bytes::<MetaInfo>(torrent)
+            .unwrap()
+            .web_seeds()
+            .is_empty());
+    }
+
+    // A torrent with `nodes` set to the bencoded `nodes`
+    fn with_nodes(nodes: &str) -> Vec<u8> {
+        format!("d8:announce14:http://a/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodes{}e", nodes).into_bytes()
+    }
+
+    fn nodes(pairs: &[(&str, u16)]) -> Vec<(String, u16)> {
+        pairs
+            .iter()
+            .map(|&(host, port)| (host.to_owned(), port))
+            .collect()
+    }
+
+    #[test]
+    fn nodes_in_every_shape() {
+        // as BEP 5 has them
+        let torrent = with_nodes("ll12:router.a.orgi6881eel7:1.2.3.4i6882eee");
+        let info = from_bytes::<MetaInfo>(&torrent).unwrap().into_owned();
+        assert_eq!(
+            info.nodes(),
+            nodes(&[("router.a.org", 6881), ("1.2.3.4", 6882)])
+        );
+        assert!(!info.remaining.contains_key("nodes"));
+
+        // pairs as strings, and ports as strings
+        let torrent = with_nodes("l14:router.a.org:1l7:[::1]:2ee");
+        let info = from_bytes::<MetaInfo>(&torrent).unwrap();
+        assert_eq!(info.nodes(), nodes(&[("router.a.org", 1)]));
+        let torrent = with_nodes("ll7:1.2.3.43: 80eee");
+        let info = from_bytes::<MetaInfo>(&torrent).unwrap();
+        assert_eq!(info.nodes(), nodes(&[("1.2.3.4", 80)]));
+
+        // the whole list as one string
+        let torrent = with_nodes("21:a.b:1, [::1]:2\n c.d:3");
+        let info = from_bytes::<MetaInfo>(&torrent).unwrap();
+        assert_eq!(info.nodes(), nodes(&[("a.b", 1), ("::1", 2), ("c.d", 3)]));
+    }
+
+    #[test]
+    fn nonsense_nodes_are_skipped() {
+        let torrent = with_nodes("li3el0:i1eel7:1.2.3.4i70000eel7:1.2.3.4i0ee3:a:be");
+        let info = from_bytes::<MetaInfo>(&torrent).unwrap();
+        assert_eq!(info.nodes, Some(Vec::new()));
+
+        let torrent = with_nodes("i5e");
+        assert!(from_bytes::<MetaInfo>(&torrent).unwrap().nodes().is_empty());
+
+        // and a torrent without any has none
+        let torrent = b"d8:announce14:http://a/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert_eq!(from_bytes::<MetaInfo>(torrent).unwrap().nodes, None);
+    }
+
+    #[test]
+    fn nodes_are_written_back_as_pairs() {
+        let torrent = with_nodes("21:router.a.org:6881 b:2");
+        let info = from_bytes::<MetaInfo>(&torrent).unwrap();
+        let written = to_bytes(&info).unwrap();
+        assert!(
+            String::from_utf8_lossy(&written).contains("5:nodesll12:router.a.orgi6881eel1:bi2eee")
+        );
+
+        let read = from_bytes::<MetaInfo>(&written).unwrap();
+        assert_eq!(read.nodes(), info.nodes());
+        assert_eq!(read.info_hash(), info.info_hash());
+    }
+
+    // A metainfo for `length` bytes in pieces of `piece_length`, with `hashes` made-up hashes
+    fn synthetic(piece_length: usize, length: usize, hashes: usize) -> MetaInfo<'static> {
+        MetaInfo {
+            announce: "http://127.0.0.1/announce".to_owned(),
+            announce_list: Vec::new(),
+            encoding: String::new(),
+            info: Info {
+                piece_length,
+                pieces: vec![0xab; hashes * 20],
+                name: b"big.iso".to_vec(),
+                length,
+                remaining: HashMap::new(),
+            },
+            nodes: None,
+            remaining: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn pieces_past_the_limit_are_refused() {
+        const MIB: usize = 1024 * 1024;
+
+        // 512MiB pieces, which nothing is allocated for
+        let big = synthetic(512 * MIB, 4096 * MIB + 1, 9);
+        let err = big.validate(DEFAULT_MAX_PIECE_LENGTH).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("512.00 MiB"), "{}", message);
+        assert!(
+            message.contains("--max-piece-size 536870912"),
+            "{}",
+            message
+        );
+        big.validate(512 * MIB).unwrap();
+
+        synthetic(DEFAULT_MAX_PIECE_LENGTH, 10, 1)
+            .validate(DEFAULT_MAX_PIECE_LENGTH)
+            .unwrap();
+        for name in [
+            "debian-11.5.0-amd64-netinst.iso.torrent",
+            "flatland.torrent",
+        ] {
+            let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+            path.push("resources");
+            path.push(name);
+            let metainfo = MetaInfo::from_file(path).unwrap();
+            metainfo.validate(DEFAULT_MAX_PIECE_LENGTH).unwrap();
+        }
+    }
+
+    #[test]
+    fn pieces_must_add_up_to_the_file() {
+        assert!(synthetic(0, 0, 0).validate(usize::MAX).is_err());
+        assert!(synthetic(16384, 16384 * 3, 2).validate(usize::MAX).is_err());
+        assert!(synthetic(16384, 16384 * 3, 4).validate(usize::MAX).is_err());
+
+        let mut ragged = synthetic(16384, 16384 * 3, 3);
+        ragged.validate(usize::MAX).unwrap();
+        ragged.info.pieces.pop();
+        assert!(ragged.validate(usize::MAX).is_err());
+    }
 }
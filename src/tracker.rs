@@ -1,11 +1,33 @@
+//! Announcing to HTTP trackers.
+//!
+//! [request::Request] and [response::Response] are public so other tools can talk to
+//! trackers. The thread the client announces from is not.
+
 pub mod request {
-    #[derive(Debug)]
+    #[derive(Clone, Copy, Debug)]
     pub enum Event {
         Started,
         Completed,
         Stopped,
     }
 
+    /// An announce to a tracker, sent with [Request::send]
+    ///
+    /// ```no_run
+    /// use rittorrent::tracker::request::{Event, Request};
+    ///
+    /// let request = Request {
+    ///     info_hash: [0; 20],
+    ///     peer_id: *b"-RT0001-000000000000",
+    ///     my_port: 6881,
+    ///     uploaded: 0,
+    ///     downloaded: 0,
+    ///     left: 1 << 20,
+    ///     event: Some(Event::Started),
+    /// };
+    /// let response = request.send("http://tracker.example/announce").unwrap();
+    /// println!("{} peers, next announce in {}s", response.peers.len(), response.interval);
+    /// ```
     #[derive(Debug)]
     pub struct Request {
         pub info_hash: [u8; 20],
@@ -20,14 +42,18 @@ pub mod request {
 
 pub mod response {
     use std::borrow::Cow;
-    use std::net::Ipv4Addr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
 
     use bendy::value::Value;
     use log::error;
     use serde::Deserializer;
     use serde::{Deserialize, Serialize};
 
+    use crate::compact;
+
     #[derive(Serialize, Deserialize, PartialEq)]
+    #[non_exhaustive]
     pub struct Peer {
         //#[serde(rename = "peer id", with = "serde_bytes")]
         //pub peer_id: Vec<u8>,
@@ -36,18 +62,56 @@ pub mod response {
         pub port: u16,
     }
 
+    /// A successful announce. Failures are turned into [crate::tracker::TrackerError::Failure].
     #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    #[non_exhaustive]
     pub struct Response {
         #[serde(default)]
         pub interval: u64,
 
+        #[serde(rename = "min interval", default)]
+        pub(super) min_interval: u64,
+
         #[serde(default, deserialize_with = "deserialize_peers")]
         pub peers: Vec<Peer>,
 
+        /// Our address as the tracker sees it (BEP 24), if it says
+        #[serde(
+            rename = "external ip",
+            default,
+            with = "serde_bytes",
+            skip_serializing_if = "Vec::is_empty"
+        )]
+        pub(super) external_ip: Vec<u8>,
+
         #[serde(rename = "failure reason", default)]
         pub(super) failure_reason: String,
     }
 
+    impl Response {
+        /// How long the tracker wants us to wait between announces, if it says
+        pub fn interval(&self) -> Option<Duration> {
+            (self.interval > 0).then(|| Duration::from_secs(self.interval))
+        }
+
+        /// The shortest time the tracker wants between our announces, if it says
+        pub fn min_interval(&self) -> Option<Duration> {
+            (self.min_interval > 0).then(|| Duration::from_secs(self.min_interval))
+        }
+
+        /// Our address as the tracker sees it, if it sent one we can make sense of
+        pub fn external_ip(&self) -> Option<IpAddr> {
+            let ip = &self.external_ip[..];
+            if let Ok(v4) = <[u8; 4]>::try_from(ip) {
+                Some(Ipv4Addr::from(v4).into())
+            } else if let Ok(v6) = <[u8; 16]>::try_from(ip) {
+                Some(Ipv6Addr::from(v6).into())
+            } else {
+                None
+            }
+        }
+    }
+
     fn deserialize_peers<'de, D>(deserializer: D) -> Result<Vec<Peer>, D::Error>
     where
         D: Deserializer<'de>,
@@ -56,23 +120,17 @@ pub mod response {
 
         match Value::deserialize(deserializer)? {
             Value::Bytes(bytes) => {
-                const IP_SIZE: usize = 4;
-                const PORT_SIZE: usize = 2;
-                const ENTRY_SIZE: usize = IP_SIZE + PORT_SIZE;
-
-                for chunk in bytes.chunks_exact(ENTRY_SIZE) {
-                    let ip =
-                        Ipv4Addr::from(u32::from_be_bytes(chunk[0..IP_SIZE].try_into().unwrap()))
-                            .to_string();
-                    let port = u16::from_be_bytes(chunk[IP_SIZE..ENTRY_SIZE].try_into().unwrap());
-
-                    peers.push(Peer { ip, port });
+                for addr in compact::decode_v4(&bytes).map_err(serde::de::Error::custom)? {
+                    peers.push(Peer {
+                        ip: addr.ip().to_string(),
+                        port: addr.port(),
+                    });
                 }
             }
             Value::List(list) => {
                 for val in list {
                     let Value::Dict(mut map) = val else {
-                        return Err(serde::de::Error::custom("peers list entry was not a Dict"))
+                        return Err(serde::de::Error::custom("peers list entry was not a Dict"));
                     };
 
                     let Some(Value::Bytes(ip)) = map.remove(&Cow::Borrowed(&b"ip"[..])) else {
@@ -81,7 +139,8 @@ pub mod response {
                         continue;
                     };
 
-                    let Some(Value::Integer(port)) = map.remove(&Cow::Borrowed(&b"port"[..])) else {
+                    let Some(Value::Integer(port)) = map.remove(&Cow::Borrowed(&b"port"[..]))
+                    else {
                         //return Err(serde::de::Error::custom("peers list entry does not contain key 'port'"))
                         error!("peers list entry does not contain key 'port'");
                         continue;
@@ -117,23 +176,63 @@ pub mod response {
     }
 }
 
-use std::thread;
+use std::thread::{self, JoinHandle};
 
-use anyhow::{anyhow, Result};
-use bendy::serde::from_bytes;
 use crossbeam::channel::{self, Sender};
 use format_bytes::format_bytes;
+use log::debug;
+use thiserror::Error;
 
 use request::Request;
 use response::Response;
 
-use crate::http::http_get;
+use crate::bencode::{self, Document};
+use crate::http::{http_get, HttpError, DEFAULT_MAX_BODY};
 use crate::threads;
 
 const NUM_WANT: usize = 500;
 
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TrackerError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] HttpError),
+
+    #[error("tracker returned HTTP status {0}")]
+    Status(u32),
+
+    #[error("invalid tracker response: {0}")]
+    InvalidResponse(String),
+
+    #[error("tracker refused request: {0}")]
+    Failure(String),
+}
+
+impl TrackerError {
+    /// Whether retrying the same tracker is pointless, as opposed to a failure that may
+    /// go away by itself (network trouble, an overloaded tracker)
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            TrackerError::Http(e) => matches!(
+                e,
+                HttpError::InvalidUrl(_) | HttpError::UnsupportedScheme(_) | HttpError::NoHost
+            ),
+            TrackerError::Status(status) => (400..500).contains(status),
+            TrackerError::InvalidResponse(_) => false,
+            TrackerError::Failure(_) => true,
+        }
+    }
+}
+
 impl Request {
-    pub fn send(&self, url: &str) -> Result<Response> {
+    /// Announces to the tracker at `url`, blocking until it answers.
+    /// Responses over 1 MiB are refused.
+    pub fn send(&self, url: &str) -> Result<Response, TrackerError> {
+        self.send_with_limit(url, DEFAULT_MAX_BODY)
+    }
+
+    /// Like [Request::send], refusing responses over `max_body` bytes
+    pub fn send_with_limit(&self, url: &str, max_body: usize) -> Result<Response, TrackerError> {
         // Try to send the HTTP request
         use request::Event::*;
         let port = self.my_port.to_string();
@@ -160,11 +259,17 @@ impl Request {
             ("numwant", &format_bytes!(b"{}", NUM_WANT)),
         ];
 
-        let http_response = http_get(url, &query)?;
-        let tracker_response = from_bytes::<Response>(&http_response.content)?;
+        let http_response = http_get(url, &query, max_body)?;
+        if http_response.status >= 400 {
+            return Err(TrackerError::Status(http_response.status));
+        }
+
+        let tracker_response =
+            bencode::from_bytes::<Response>(&http_response.content, Document::TrackerResponse)
+                .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
 
         if tracker_response.interval == 0 {
-            Err(anyhow!(tracker_response.failure_reason))
+            Err(TrackerError::Failure(tracker_response.failure_reason))
         } else {
             Ok(tracker_response)
         }
@@ -172,30 +277,188 @@ impl Request {
 }
 
 #[derive(Debug)]
-pub struct TrackerRequest {
+pub(crate) struct TrackerRequest {
     pub url: String,
     pub request: Request,
+
+    // a Stopped announce for an earlier session of ours that never sent one, which is sent
+    // first so the tracker drops it before hearing about this one
+    pub stale: Option<Request>,
 }
 
-pub fn spawn_tracker_thread(sender: Sender<threads::Response>) -> Sender<TrackerRequest> {
+pub(crate) fn spawn_tracker_thread(
+    sender: Sender<threads::Response>,
+    max_body: usize,
+) -> (Sender<TrackerRequest>, JoinHandle<()>) {
     let (tx, rx) = channel::unbounded::<TrackerRequest>();
 
-    thread::spawn(move || {
+    let handle = thread::spawn(move || {
         // main loop for tracker-interaction thread
         for req in rx {
-            let result = req.request.send(&req.url);
-            sender.send(threads::Response::Tracker(result)).expect("hi");
+            // trackers are independent, so don't let a slow one hold up the others
+            let sender = sender.clone();
+            thread::spawn(move || {
+                if let Some(stale) = &req.stale {
+                    if let Err(e) = stale.send_with_limit(&req.url, max_body) {
+                        debug!(
+                            "Failed to announce the last session stopped to {}: {}",
+                            req.url, e
+                        );
+                    }
+                }
+                let result = req.request.send_with_limit(&req.url, max_body);
+
+                // nobody is left to care about the response if main has gone away
+                let _ = sender.send(threads::Response::Tracker(req.url, result));
+            });
         }
     });
 
-    tx
+    (tx, handle)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use hex_literal::hex;
 
-    use super::request::Request;
+    use bendy::serde::from_bytes;
+
+    use std::net::TcpListener;
+
+    use crossbeam::channel;
+
+    use super::request::{Event, Request};
+    use super::response::Response;
+    use super::{spawn_tracker_thread, TrackerError, TrackerRequest};
+    use crate::http::{HttpError, DEFAULT_MAX_BODY};
+    use crate::threads;
+    use crate::tracker_sim::{SimConfig, TrackerSim};
+
+    fn request() -> Request {
+        Request {
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+            my_port: 5000,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+        }
+    }
+
+    #[test]
+    fn stale_sessions_are_stopped_before_announcing() {
+        let sim = TrackerSim::spawn(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            SimConfig::default(),
+        )
+        .unwrap();
+        let url = sim.announce_url();
+        let old = |event| Request {
+            peer_id: [1; 20],
+            my_port: 6881,
+            event: Some(event),
+            ..request()
+        };
+        old(Event::Started).send(&url).unwrap();
+        assert_eq!(sim.swarm_size(&[0; 20]), 1);
+
+        let (tx, rx) = channel::unbounded();
+        let (sender, _) = spawn_tracker_thread(tx, DEFAULT_MAX_BODY);
+        sender
+            .send(TrackerRequest {
+                url: url.clone(),
+                request: Request {
+                    peer_id: [2; 20],
+                    my_port: 6882,
+                    event: Some(Event::Started),
+                    ..request()
+                },
+                stale: Some(old(Event::Stopped)),
+            })
+            .unwrap();
+
+        // the tracker has forgotten the old session by the time it answers the new one
+        let response = match rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+            threads::Response::Tracker(announced, result) => {
+                assert_eq!(announced, url);
+                result.unwrap()
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(response.peers.is_empty());
+        assert_eq!(sim.swarm_size(&[0; 20]), 1);
+    }
+
+    #[test]
+    fn unsupported_tracker_is_permanent() {
+        let err = request().send("udp://127.0.0.1:1/announce").unwrap_err();
+        assert!(matches!(
+            err,
+            TrackerError::Http(HttpError::UnsupportedScheme(_))
+        ));
+        assert!(err.is_permanent());
+    }
+
+    #[test]
+    fn unreachable_tracker_is_transient() {
+        // nothing listens on port 1, so the connection is refused
+        let err = request().send("http://127.0.0.1:1/announce").unwrap_err();
+        assert!(matches!(err, TrackerError::Http(HttpError::Io(_))));
+        assert!(!err.is_permanent());
+    }
+
+    #[test]
+    fn external_ip_is_optional() {
+        let response: Response =
+            from_bytes(b"d11:external ip4:\xcb\x00\x71\x078:intervali60ee").unwrap();
+        assert_eq!(response.external_ip(), Some([203, 0, 113, 7].into()));
+
+        let response: Response = from_bytes(b"d8:intervali60ee").unwrap();
+        assert_eq!(response.external_ip(), None);
+
+        let response: Response = from_bytes(b"d11:external ip3:abc8:intervali60ee").unwrap();
+        assert_eq!(response.external_ip(), None);
+    }
+
+    #[test]
+    fn min_interval_is_optional() {
+        let response: Response = from_bytes(b"d8:intervali60e12:min intervali30ee").unwrap();
+        assert_eq!(response.min_interval(), Some(Duration::from_secs(30)));
+
+        let response: Response = from_bytes(b"d8:intervali60ee").unwrap();
+        assert_eq!(response.min_interval(), None);
+    }
+
+    #[test]
+    fn compact_peers() {
+        let response: Response = from_bytes(
+            b"d8:intervali60e5:peers12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e",
+        )
+        .unwrap();
+        let peers: Vec<(&str, u16)> = response
+            .peers
+            .iter()
+            .map(|p| (p.ip.as_str(), p.port))
+            .collect();
+        assert_eq!(peers, [("10.0.0.1", 6881), ("10.0.0.2", 6882)]);
+
+        // a peer cut short means the response was mangled
+        assert!(
+            from_bytes::<Response>(b"d8:intervali60e5:peers7:\x0a\x00\x00\x01\x1a\xe1\x0ae")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn status_classification() {
+        assert!(TrackerError::Status(404).is_permanent());
+        assert!(!TrackerError::Status(503).is_permanent());
+        assert!(TrackerError::Failure("unregistered torrent".to_owned()).is_permanent());
+        assert!(!TrackerError::InvalidResponse("truncated".to_owned()).is_permanent());
+    }
 
     #[test]
     fn send_test_1() {
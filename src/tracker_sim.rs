@@ -0,0 +1,415 @@
+//! A small HTTP tracker that keeps its swarms in memory, for trying out announce scheduling,
+//! failover and peer discovery on one machine
+//!
+//! Every info hash gets a swarm of whoever announced to it, which they leave by sending a
+//! Stopped event. Peers are sent in the compact form unless an announce asks for `compact=0`.
+//! The `tracker-sim` binary runs one from the command line, and tests can spawn one
+//! in-process with [TrackerSim::spawn].
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bendy::encoding::ToBencode;
+use bendy::value::Value;
+use log::{debug, info};
+
+use crate::compact;
+use crate::shutdown::{self, ListenerGuard};
+
+const ANNOUNCE_PATH: &str = "/announce";
+
+// how many peers an announce gets if it doesn't say, and the most it can ask for
+const DEFAULT_NUM_WANT: usize = 50;
+const MAX_NUM_WANT: usize = 200;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the tracker answers announces
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Seconds between announces the tracker asks for
+    pub interval: u64,
+
+    /// The `min interval` sent along with it, if any
+    pub min_interval: Option<u64>,
+
+    /// Refuse every announce with this failure reason
+    pub failure: Option<String>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            min_interval: None,
+            failure: None,
+        }
+    }
+}
+
+// A peer in a swarm, and whether it said it had the whole torrent
+#[derive(Clone, Copy, Debug)]
+struct SwarmPeer {
+    addr: SocketAddr,
+    seed: bool,
+}
+
+// Peers by peer id, in swarms by info hash
+type Swarms = HashMap<[u8; 20], HashMap<[u8; 20], SwarmPeer>>;
+
+#[derive(Debug)]
+struct Shared {
+    config: SimConfig,
+    swarms: Swarms,
+}
+
+/// A running tracker, which stops listening when dropped
+#[derive(Debug)]
+pub struct TrackerSim {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    _guard: ListenerGuard,
+}
+
+impl TrackerSim {
+    /// Starts answering announces on `listener`, from a thread of its own
+    pub fn spawn(listener: TcpListener, config: SimConfig) -> io::Result<Self> {
+        let addr = listener.local_addr()?;
+        let guard = ListenerGuard::new(&listener)?;
+        let shared = Arc::new(Mutex::new(Shared {
+            config,
+            swarms: HashMap::new(),
+        }));
+
+        let accepting = shared.clone();
+        thread::spawn(move || loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if shutdown::listener_closed(&e) => break,
+                Err(_) => continue,
+            };
+
+            let shared = accepting.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &shared) {
+                    debug!("Tracker connection failed: {:?}", e);
+                }
+            });
+        });
+
+        Ok(Self {
+            addr,
+            shared,
+            _guard: guard,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL to announce to
+    pub fn announce_url(&self) -> String {
+        format!("http://{}{}", self.addr, ANNOUNCE_PATH)
+    }
+
+    /// Starts or stops refusing every announce with `failure`
+    pub fn set_failure(&self, failure: Option<String>) {
+        self.shared.lock().unwrap().config.failure = failure;
+    }
+
+    /// How many peers are in the swarm for `info_hash`
+    pub fn swarm_size(&self, info_hash: &[u8; 20]) -> usize {
+        let shared = self.shared.lock().unwrap();
+        shared.swarms.get(info_hash).map_or(0, HashMap::len)
+    }
+}
+
+fn handle_connection(stream: TcpStream, shared: &Mutex<Shared>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let remote = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    // Request line, e.g. "GET /announce?info_hash=... HTTP/1.1"
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+
+    // the headers don't matter, but have to be read past
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if method != "GET" || path != ANNOUNCE_PATH {
+        info!("{} {} from {}: not found", method, path, remote);
+        return write_response(&mut writer, "404 Not Found", b"");
+    }
+
+    let params = parse_query(query);
+    let mut shared = shared.lock().unwrap();
+    let body = match announce(&mut shared, &params, remote.ip()) {
+        Ok((peers, body)) => {
+            info!("Announce from {}: sent {} peers", remote, peers);
+            body
+        }
+        Err(reason) => {
+            info!("Announce from {}: failed with {:?}", remote, reason);
+            failure(&reason)
+        }
+    };
+    drop(shared);
+
+    write_response(&mut writer, "200 OK", &body)
+}
+
+// Splits a query string into its percent-decoded parameters, which may be binary
+fn parse_query(query: &str) -> HashMap<&str, Vec<u8>> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| {
+            let value = urlencoding::decode_binary(value.as_bytes()).into_owned();
+            (name, value)
+        })
+        .collect()
+}
+
+// Adds the announcing peer to its swarm, or takes it out if it is stopping, and answers with
+// the rest of the swarm. Returns how many peers were sent along with the bencoded response,
+// or the reason to refuse the announce with.
+fn announce(
+    shared: &mut Shared,
+    params: &HashMap<&str, Vec<u8>>,
+    remote: IpAddr,
+) -> Result<(usize, Vec<u8>), String> {
+    if let Some(reason) = &shared.config.failure {
+        return Err(reason.clone());
+    }
+
+    let id = |name: &str| -> Result<[u8; 20], String> {
+        params
+            .get(name)
+            .and_then(|value| <[u8; 20]>::try_from(&value[..]).ok())
+            .ok_or_else(|| format!("missing or invalid {}", name))
+    };
+    let number = |name: &str| -> Option<usize> {
+        let value = params.get(name)?;
+        std::str::from_utf8(value).ok()?.parse().ok()
+    };
+    let info_hash = id("info_hash")?;
+    let peer_id = id("peer_id")?;
+    let port = number("port")
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or("missing or invalid port")?;
+    let num_want = number("numwant")
+        .unwrap_or(DEFAULT_NUM_WANT)
+        .min(MAX_NUM_WANT);
+    let compact = params.get("compact").is_none_or(|value| value != b"0");
+
+    let swarm = shared.swarms.entry(info_hash).or_default();
+    if params.get("event").is_some_and(|event| event == b"stopped") {
+        swarm.remove(&peer_id);
+    } else {
+        let peer = SwarmPeer {
+            addr: SocketAddr::new(remote, port),
+            seed: number("left") == Some(0),
+        };
+        swarm.insert(peer_id, peer);
+    }
+
+    let seeds = swarm.values().filter(|peer| peer.seed).count();
+    let others: Vec<(&[u8; 20], SocketAddr)> = swarm
+        .iter()
+        .filter(|(id, _)| **id != peer_id)
+        .map(|(id, peer)| (id, peer.addr))
+        .take(num_want)
+        .collect();
+
+    let mut response = BTreeMap::new();
+    let mut put = |key: &'static str, value: Value<'static>| {
+        response.insert(Cow::Borrowed(key.as_bytes()), value);
+    };
+    put("complete", Value::Integer(seeds as i64));
+    put("incomplete", Value::Integer((swarm.len() - seeds) as i64));
+    put("interval", Value::Integer(shared.config.interval as i64));
+    if let Some(min_interval) = shared.config.min_interval {
+        put("min interval", Value::Integer(min_interval as i64));
+    }
+    if compact {
+        let addrs = || others.iter().map(|(_, addr)| *addr);
+        put("peers", Value::Bytes(compact::encode_v4(addrs()).into()));
+        let v6 = compact::encode_v6(addrs());
+        if !v6.is_empty() {
+            put("peers6", Value::Bytes(v6.into()));
+        }
+    } else {
+        let peers = others
+            .iter()
+            .map(|(id, addr)| {
+                let mut peer = BTreeMap::new();
+                peer.insert(
+                    Cow::Borrowed(&b"ip"[..]),
+                    Value::Bytes(addr.ip().to_string().into_bytes().into()),
+                );
+                peer.insert(
+                    Cow::Borrowed(&b"peer id"[..]),
+                    Value::Bytes(id.to_vec().into()),
+                );
+                peer.insert(
+                    Cow::Borrowed(&b"port"[..]),
+                    Value::Integer(addr.port() as i64),
+                );
+                Value::Dict(peer)
+            })
+            .collect();
+        put("peers", Value::List(peers));
+    }
+
+    let body = Value::Dict(response)
+        .to_bencode()
+        .map_err(|e| e.to_string())?;
+    Ok((others.len(), body))
+}
+
+// The bencoded response refusing an announce because of `reason`
+fn failure(reason: &str) -> Vec<u8> {
+    let mut response = BTreeMap::new();
+    response.insert(
+        Cow::Borrowed(&b"failure reason"[..]),
+        Value::Bytes(reason.as_bytes().into()),
+    );
+    Value::Dict(response)
+        .to_bencode()
+        .expect("a failure reason can always be encoded")
+}
+
+fn write_response(writer: &mut impl Write, status: &str, body: &[u8]) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use bendy::serde::from_bytes;
+
+    use super::{SimConfig, TrackerSim};
+    use crate::http::{http_get, DEFAULT_MAX_BODY};
+    use crate::tracker::request::{Event, Request};
+    use crate::tracker::response::Response;
+    use crate::tracker::TrackerError;
+
+    fn sim(config: SimConfig) -> TrackerSim {
+        TrackerSim::spawn(TcpListener::bind("127.0.0.1:0").unwrap(), config).unwrap()
+    }
+
+    fn request(peer: u8, event: Option<Event>) -> Request {
+        Request {
+            info_hash: [7; 20],
+            peer_id: [peer; 20],
+            my_port: 6880 + peer as u16,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event,
+        }
+    }
+
+    fn ports(response: &Response) -> Vec<u16> {
+        response.peers.iter().map(|peer| peer.port).collect()
+    }
+
+    #[test]
+    fn two_announcers_see_each_other() {
+        let sim = sim(SimConfig {
+            interval: 30,
+            min_interval: Some(10),
+            failure: None,
+        });
+        let url = sim.announce_url();
+
+        let first = request(1, Some(Event::Started)).send(&url).unwrap();
+        assert!(first.peers.is_empty());
+        assert_eq!(first.interval, 30);
+        assert_eq!(
+            first.min_interval(),
+            Some(std::time::Duration::from_secs(10))
+        );
+
+        let second = request(2, Some(Event::Started)).send(&url).unwrap();
+        assert_eq!(ports(&second), [6881]);
+        assert_eq!(second.peers[0].ip, "127.0.0.1");
+        let first = request(1, None).send(&url).unwrap();
+        assert_eq!(ports(&first), [6882]);
+        assert_eq!(sim.swarm_size(&[7; 20]), 2);
+
+        // leaving takes a peer out of the swarm, and other torrents never mix in
+        request(2, Some(Event::Stopped)).send(&url).unwrap();
+        assert!(request(1, None).send(&url).unwrap().peers.is_empty());
+        let other = Request {
+            info_hash: [8; 20],
+            ..request(3, None)
+        };
+        assert!(other.send(&url).unwrap().peers.is_empty());
+    }
+
+    #[test]
+    fn peers_are_listed_unless_compact() {
+        let sim = sim(SimConfig::default());
+        let url = sim.announce_url();
+        request(1, None).send(&url).unwrap();
+
+        let query: [(&str, &[u8]); 4] = [
+            ("info_hash", &[7; 20]),
+            ("peer_id", &[2; 20]),
+            ("port", b"6882"),
+            ("compact", b"0"),
+        ];
+        let response = http_get(&url, &query, DEFAULT_MAX_BODY).unwrap();
+        assert!(response.content.windows(7).any(|w| w == b"peer id"));
+        let response: Response = from_bytes(&response.content).unwrap();
+        assert_eq!(ports(&response), [6881]);
+    }
+
+    #[test]
+    fn failures_can_be_injected() {
+        let sim = sim(SimConfig::default());
+        let url = sim.announce_url();
+
+        sim.set_failure(Some("unregistered torrent".to_owned()));
+        let err = request(1, None).send(&url).unwrap_err();
+        assert!(matches!(&err, TrackerError::Failure(reason) if reason == "unregistered torrent"));
+        assert_eq!(sim.swarm_size(&[7; 20]), 0);
+
+        sim.set_failure(None);
+        request(1, None).send(&url).unwrap();
+        assert_eq!(sim.swarm_size(&[7; 20]), 1);
+
+        let missing_id = [("info_hash", &[7u8; 20][..]), ("port", b"1")];
+        let response = http_get(&url, &missing_id, DEFAULT_MAX_BODY).unwrap();
+        let reason = b"missing or invalid peer_id";
+        assert!(response.content.windows(reason.len()).any(|w| w == reason));
+        assert!(
+            http_get(&format!("http://{}/scrape", sim.local_addr()), &[], 1024)
+                .is_ok_and(|response| response.status == 404)
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Reading durations and sizes the way people write them, and writing them back the same way.
+//!
+//! The parsers are meant for clap's `value_parser`, so they return a message for the user
+//! rather than an error type.
+
+use std::time::Duration;
+
+// Accepted after a size. Single letters are SI, like the two-letter forms.
+const SIZE_UNITS: &[(&str, u128)] = &[
+    ("", 1),
+    ("B", 1),
+    ("K", 1000),
+    ("k", 1000),
+    ("KB", 1000),
+    ("kB", 1000),
+    ("KiB", 1 << 10),
+    ("M", 1000 * 1000),
+    ("MB", 1000 * 1000),
+    ("MiB", 1 << 20),
+    ("G", 1000 * 1000 * 1000),
+    ("GB", 1000 * 1000 * 1000),
+    ("GiB", 1 << 30),
+    ("T", 1000 * 1000 * 1000 * 1000),
+    ("TB", 1000 * 1000 * 1000 * 1000),
+    ("TiB", 1 << 40),
+];
+
+// Accepted after a duration, in milliseconds
+const DURATION_UNITS: &[(&str, u128)] = &[
+    ("", 1000),
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("d", 24 * 60 * 60 * 1000),
+];
+
+// Splits "1.5 MiB" into the number and the unit
+fn split(s: &str) -> Result<(&str, &str), String> {
+    let s = s.trim();
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(end);
+
+    if number.is_empty() {
+        return Err(if s.starts_with('-') {
+            format!("{:?} is negative", s)
+        } else {
+            format!("{:?} doesn't start with a number", s)
+        });
+    }
+    Ok((number, unit.trim_start()))
+}
+
+// Multiplies a decimal number by a whole multiplier, exactly.
+// Fails if the result isn't whole, or doesn't fit a u64.
+fn scale(number: &str, multiplier: u128, what: &str) -> Result<u64, String> {
+    let (whole, frac) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && frac.is_empty() || frac.contains('.') {
+        return Err(format!("{:?} is not a number", number));
+    }
+
+    let too_large = || format!("{} is too large", number);
+    let digits: u128 = format!("{}{}", whole, frac)
+        .parse()
+        .map_err(|_| too_large())?;
+    let denominator = 10u128
+        .checked_pow(frac.len() as u32)
+        .ok_or_else(too_large)?;
+    let scaled = digits.checked_mul(multiplier).ok_or_else(too_large)?;
+
+    if scaled % denominator != 0 {
+        return Err(format!("{} is not a whole number of {}", number, what));
+    }
+    u64::try_from(scaled / denominator).map_err(|_| too_large())
+}
+
+/// Parses a size in bytes, such as `500K`, `2MB` or `1.5MiB`.
+///
+/// Decimal (K, M, G, T, with or without a B) and binary (KiB, MiB, GiB, TiB) units are both
+/// accepted. A bare number is in bytes.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (number, unit) = split(s)?;
+    let Some(&(_, multiplier)) = SIZE_UNITS.iter().find(|(name, _)| *name == unit) else {
+        if unit.ends_with('b') {
+            return Err(format!(
+                "{:?}: a lowercase b means bits, use B for bytes",
+                s.trim()
+            ));
+        }
+        return Err(format!(
+            "unknown unit {:?}, expected one of B, K, KB, KiB, M, MB, MiB, G, GB, GiB, T, TB, TiB",
+            unit
+        ));
+    };
+
+    let bytes = scale(number, multiplier, "bytes")?;
+    usize::try_from(bytes).map_err(|_| format!("{} is too large", s.trim()))
+}
+
+/// Parses a duration, such as `500ms`, `90s`, `15m`, `2h` or `1d`. A bare number is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = split(s)?;
+    let Some(&(_, multiplier)) = DURATION_UNITS.iter().find(|(name, _)| *name == unit) else {
+        return Err(format!(
+            "unknown unit {:?}, expected one of ms, s, m, h, d",
+            unit
+        ));
+    };
+
+    scale(number, multiplier, "milliseconds").map(Duration::from_millis)
+}
+
+/// Formats a number of bytes with binary units, in a form [parse_size] reads back
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a transfer rate in bytes per second
+pub fn format_rate(bytes_per_second: f64) -> String {
+    format!("{}/s", format_size(bytes_per_second.max(0.0) as usize))
+}
+
+/// Formats a duration in the largest unit that represents it exactly, in a form
+/// [parse_duration] reads back
+pub fn format_duration(duration: Duration) -> String {
+    let ms = duration.as_millis();
+    if ms == 0 {
+        return "0s".to_owned();
+    }
+
+    let (name, len) = DURATION_UNITS[1..]
+        .iter()
+        .rev()
+        .find(|(_, len)| ms.is_multiple_of(*len))
+        .unwrap();
+    format!("{}{}", ms / len, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_duration, format_rate, format_size, parse_duration, parse_size};
+
+    #[test]
+    fn sizes() {
+        let cases = [
+            ("0", 0),
+            ("4096", 4096),
+            ("12B", 12),
+            ("500K", 500_000),
+            ("500k", 500_000),
+            ("500 kB", 500_000),
+            ("2M", 2_000_000),
+            ("2MB", 2_000_000),
+            ("1.5MiB", 1_572_864),
+            ("1GiB", 1 << 30),
+            (".5K", 500),
+            ("1.KiB", 1024),
+            ("  3 TB ", 3_000_000_000_000),
+        ];
+        for (input, bytes) in cases {
+            assert_eq!(parse_size(input), Ok(bytes), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn bad_sizes_are_explained() {
+        let cases = [
+            ("", "doesn't start with a number"),
+            ("-5M", "negative"),
+            ("M", "doesn't start with a number"),
+            ("5Mb", "bits"),
+            ("5 kb", "bits"),
+            ("5 mB", "unknown unit"),
+            ("5 MiBs", "unknown unit"),
+            ("1.2.3M", "not a number"),
+            (".", "not a number"),
+            ("0.3", "not a whole number of bytes"),
+            ("1.0001K", "not a whole number of bytes"),
+            ("100000000000000000000", "too large"),
+            ("99999999999999999999999999999999999999999", "too large"),
+            ("20000000TiB", "too large"),
+        ];
+        for (input, message) in cases {
+            let err = parse_size(input).unwrap_err();
+            assert!(err.contains(message), "{:?}: {}", input, err);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        let cases = [
+            ("0", 0),
+            ("12", 12_000),
+            ("90s", 90_000),
+            ("15m", 900_000),
+            ("2h", 7_200_000),
+            ("1d", 86_400_000),
+            ("250ms", 250),
+            ("1.5s", 1500),
+            ("0.5 h", 1_800_000),
+        ];
+        for (input, ms) in cases {
+            assert_eq!(
+                parse_duration(input),
+                Ok(Duration::from_millis(ms)),
+                "{:?}",
+                input
+            );
+        }
+
+        for (input, message) in [
+            ("-1s", "negative"),
+            ("5 min", "unknown unit"),
+            ("1.5ms", "not a whole number of milliseconds"),
+            ("99999999999999999999d", "too large"),
+            ("s", "doesn't start with a number"),
+        ] {
+            let err = parse_duration(input).unwrap_err();
+            assert!(err.contains(message), "{:?}: {}", input, err);
+        }
+    }
+
+    #[test]
+    fn formatting_reads_back() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.50 KiB");
+        assert_eq!(format_size(3 << 30), "3.00 GiB");
+        assert_eq!(format_rate(1_572_864.0), "1.50 MiB/s");
+
+        for bytes in [0, 1000, 1536, 5 << 20, 3 << 30] {
+            assert_eq!(parse_size(&format_size(bytes)), Ok(bytes));
+        }
+
+        for (ms, text) in [
+            (0, "0s"),
+            (250, "250ms"),
+            (90_000, "90s"),
+            (900_000, "15m"),
+            (7_200_000, "2h"),
+        ] {
+            let duration = Duration::from_millis(ms);
+            assert_eq!(format_duration(duration), text);
+            assert_eq!(parse_duration(text), Ok(duration));
+        }
+    }
+}
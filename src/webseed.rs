@@ -0,0 +1,312 @@
+//! Checking web seeds (BEP 19) before trusting them
+//!
+//! A web seed is a plain HTTP server with a copy of the file. It is only any use to us if it
+//! serves byte ranges of a file of the right length, so every mirror is probed first. Mirrors
+//! that fail are left alone for a while, then probed again.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::Sender;
+use log::{info, warn};
+use thiserror::Error;
+use urlencoding::encode;
+
+use crate::http::{http_request, HttpError};
+use crate::threads::Response;
+
+/// How long a mirror that failed its probe is left before trying it again
+pub const REPROBE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] HttpError),
+
+    #[error("server answered with status {0}")]
+    Status(u32),
+
+    #[error("server doesn't serve byte ranges")]
+    NoRanges,
+
+    #[error("server didn't say how long the file is")]
+    NoLength,
+
+    #[error("file is {actual} bytes, expected {expected}")]
+    WrongLength { expected: usize, actual: usize },
+}
+
+type Result<T> = std::result::Result<T, ProbeError>;
+
+/// Where a mirror keeps the file. A URL ending in `/` names a directory, which the file is in
+/// under the torrent's name.
+pub fn file_url(url: &str, name: &str) -> String {
+    if url.ends_with('/') {
+        format!("{}{}", url, encode(name))
+    } else {
+        url.to_owned()
+    }
+}
+
+fn check_length(expected: usize, actual: usize) -> Result<()> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(ProbeError::WrongLength { expected, actual })
+    }
+}
+
+// Asks for the first byte, which a server that does ranges answers with a 206 that gives the
+// full length in its Content-Range
+fn probe_range(url: &str, length: usize) -> Result<()> {
+    let response = match http_request("GET", url, &[], &[("Range", "bytes=0-0")], 1) {
+        Ok(response) => response,
+        // it's sending the whole file, so it ignored the Range header
+        Err(HttpError::BodyTooLarge(_)) => return Err(ProbeError::NoRanges),
+        Err(e) => return Err(e.into()),
+    };
+
+    match response.status {
+        206 => {}
+        200 => return Err(ProbeError::NoRanges),
+        status => return Err(ProbeError::Status(status)),
+    }
+
+    // "bytes 0-0/<length>", with any whitespace already stripped
+    let actual = response
+        .header("Content-Range")
+        .and_then(|range| range.rsplit_once('/'))
+        .and_then(|(_, total)| total.parse().ok())
+        .ok_or(ProbeError::NoLength)?;
+    check_length(length, actual)
+}
+
+/// Checks that the mirror at `url` has a file of `length` bytes, and serves ranges of it.
+///
+/// A HEAD request comes first. Servers that don't do HEAD, or don't say whether they do
+/// ranges, are asked for the first byte of the file instead.
+pub fn probe(url: &str, length: usize) -> Result<()> {
+    let response = http_request("HEAD", url, &[], &[], 0)?;
+    match response.status {
+        200 => {
+            let actual = response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok())
+                .ok_or(ProbeError::NoLength)?;
+            check_length(length, actual)?;
+
+            if response
+                .header("Accept-Ranges")
+                .is_some_and(|units| units.eq_ignore_ascii_case("bytes"))
+            {
+                Ok(())
+            } else {
+                probe_range(url, length)
+            }
+        }
+        405 | 501 => probe_range(url, length),
+        status => Err(ProbeError::Status(status)),
+    }
+}
+
+/// Probes `url` on a thread of its own, and sends main the outcome as a [Response::WebSeed]
+pub fn spawn_probe(sender: Sender<Response>, url: String, length: usize) {
+    thread::spawn(move || {
+        let result = probe(&url, length);
+        let _ = sender.send(Response::WebSeed(url, result));
+    });
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Unprobed,
+    Probing,
+    Usable,
+    Unusable { retry_at: Instant },
+}
+
+/// Every web seed of the torrent, and what their probes made of them
+#[derive(Debug, Default)]
+pub struct WebSeeds {
+    mirrors: Vec<(String, Status)>,
+
+    // of the file, which every mirror must match
+    length: usize,
+}
+
+impl WebSeeds {
+    pub fn new(urls: Vec<String>, length: usize) -> Self {
+        Self {
+            mirrors: urls
+                .into_iter()
+                .map(|url| (url, Status::Unprobed))
+                .collect(),
+            length,
+        }
+    }
+
+    // Mirrors that are due a probe, which are marked as being probed
+    fn due(&mut self, now: Instant) -> Vec<String> {
+        self.mirrors
+            .iter_mut()
+            .filter(|(_, status)| match *status {
+                Status::Unprobed => true,
+                Status::Unusable { retry_at } => now >= retry_at,
+                Status::Probing | Status::Usable => false,
+            })
+            .map(|(url, status)| {
+                *status = Status::Probing;
+                url.clone()
+            })
+            .collect()
+    }
+
+    /// Starts a probe of every mirror that is due one
+    pub fn probe_due(&mut self, sender: &Sender<Response>, now: Instant) {
+        for url in self.due(now) {
+            spawn_probe(sender.clone(), url, self.length);
+        }
+    }
+
+    /// Takes note of how a probe of `url` went
+    pub fn record(&mut self, url: &str, result: Result<()>, now: Instant) {
+        let Some((_, status)) = self.mirrors.iter_mut().find(|(u, _)| u == url) else {
+            return;
+        };
+
+        *status = match result {
+            Ok(()) => {
+                info!("Web seed {} is usable", url);
+                Status::Usable
+            }
+            Err(e) => {
+                warn!("Not using web seed {} for now: {}", url, e);
+                Status::Unusable {
+                    retry_at: now + REPROBE_INTERVAL,
+                }
+            }
+        };
+    }
+
+    /// Mirrors whose last probe passed
+    pub fn usable(&self) -> impl Iterator<Item = &str> {
+        self.mirrors
+            .iter()
+            .filter(|(_, status)| *status == Status::Usable)
+            .map(|(url, _)| url.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    use super::{file_url, probe, ProbeError, WebSeeds, REPROBE_INTERVAL};
+
+    const LENGTH: usize = 1000;
+
+    // Serves every request with whatever `respond` makes of its method and Range header.
+    // Returns the URL of the file.
+    fn mirror(respond: fn(&str, Option<&str>) -> String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.iso", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut method = String::new();
+                let mut range = None;
+                for line in reader.by_ref().lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if method.is_empty() {
+                        method = line.split(' ').next().unwrap().to_owned();
+                    } else if let Some(value) = line.strip_prefix("Range: ") {
+                        range = Some(value.to_owned());
+                    }
+                }
+                let _ = stream.write_all(respond(&method, range.as_deref()).as_bytes());
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn good_mirrors_pass() {
+        // says it all in the HEAD response
+        let url = mirror(|_, _| {
+            "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nAccept-Ranges: bytes\r\n\r\n".to_owned()
+        });
+        probe(&url, LENGTH).unwrap();
+
+        // doesn't do HEAD, but answers a range request
+        let url = mirror(|method, range| {
+            match (method, range) {
+            ("GET", Some("bytes=0-0")) => {
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: 1\r\nContent-Range: bytes 0-0/1000\r\n\r\nx"
+                    .to_owned()
+            }
+            _ => "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_owned(),
+        }
+        });
+        probe(&url, LENGTH).unwrap();
+    }
+
+    #[test]
+    fn mirrors_without_ranges_fail() {
+        // ignores the Range header, and sends the whole file
+        let url = mirror(|method, _| {
+            let head = "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n";
+            match method {
+                "HEAD" => head.to_owned(),
+                _ => format!("{}{}", head, "x".repeat(1000)),
+            }
+        });
+        assert!(matches!(probe(&url, LENGTH), Err(ProbeError::NoRanges)));
+    }
+
+    #[test]
+    fn mirrors_with_the_wrong_file_fail() {
+        let url = mirror(|_, _| {
+            "HTTP/1.1 200 OK\r\nContent-Length: 999\r\nAccept-Ranges: bytes\r\n\r\n".to_owned()
+        });
+        assert!(matches!(
+            probe(&url, LENGTH),
+            Err(ProbeError::WrongLength {
+                expected: 1000,
+                actual: 999
+            })
+        ));
+
+        let url = mirror(|_, _| "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned());
+        assert!(matches!(probe(&url, LENGTH), Err(ProbeError::Status(404))));
+    }
+
+    #[test]
+    fn failed_mirrors_are_probed_again_later() {
+        let mut seeds = WebSeeds::new(vec!["http://a/".to_owned(), "http://b/".to_owned()], 1);
+        let now = Instant::now();
+        assert_eq!(seeds.due(now), ["http://a/", "http://b/"]);
+        assert!(seeds.due(now).is_empty());
+
+        seeds.record("http://a/", Ok(()), now);
+        seeds.record("http://b/", Err(ProbeError::NoRanges), now);
+        assert_eq!(seeds.usable().collect::<Vec<_>>(), ["http://a/"]);
+        assert!(seeds.due(now).is_empty());
+        assert_eq!(seeds.due(now + REPROBE_INTERVAL), ["http://b/"]);
+    }
+
+    #[test]
+    fn directory_urls_get_the_file_name() {
+        assert_eq!(
+            file_url("http://m/pub/", "a b.iso"),
+            "http://m/pub/a%20b.iso"
+        );
+        assert_eq!(file_url("http://m/a.iso", "b.iso"), "http://m/a.iso");
+    }
+}